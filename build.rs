@@ -0,0 +1,13 @@
+// Record the git revision being built, for server info.
+fn main() {
+    let revision = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output().ok()
+        .filter(| output | output.status.success())
+        .map(| output | String::from_utf8_lossy(&output.stdout).trim().to_string());
+    if let Some(revision) = revision {
+        println!("cargo:rustc-env=BYTESERVER_GIT_REVISION={}", revision);
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,224 @@
+// The data file's writer
+//
+// Writes to the data file, appending voted transactions and marking
+// finished ones, are made by a dedicated thread that owns the file's
+// write handle and takes requests, in order, from a channel.  Writes
+// are never interleaved, callers don't contend for a file lock, and
+// the order in which data reach the file is the order of requests.
+//
+// Writes are positional, so they don't depend on, or move, the file
+// positions of handles shared with readers.
+//
+// Optionally, disk space is allocated ahead of appends, in big
+// chunks, so the file system doesn't have to find space for each
+// append, and the file is less fragmented.  The space is allocated
+// past the end of the file, without changing its size.
+
+use std::os::unix::fs::FileExt;
+
+use crate::util;
+
+type Reply<T> = std::sync::mpsc::Sender<std::io::Result<T>>;
+
+enum Request {
+    // Append the first length bytes of a file
+    Append(std::fs::File, u64, Reply<u64>),
+    // Write data at a position, and fsync if asked
+    Write(u64, Vec<u8>, bool, Reply<()>),
+    Sync(Reply<()>),
+    End(Reply<u64>),
+    Replace(std::fs::File, Reply<()>),
+}
+
+pub struct Appender {
+    requests: Option<std::sync::mpsc::Sender<Request>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+    // Bytes of space to allocate at a time, or 0
+    preallocation: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+impl Appender {
+
+    /// Start a thread writing to file.  It exits when the appender is
+    /// dropped.
+    pub fn new(file: std::fs::File) -> Appender {
+        let (requests, receiver) = std::sync::mpsc::channel();
+        let preallocation = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let chunk = preallocation.clone();
+        let thread = std::thread::spawn(move || {
+            let mut file = file;
+            // The end of the space allocated ahead of appends
+            let mut allocated = 0u64;
+            for request in receiver {
+                match request {
+                    Request::Append(data, length, reply) => {
+                        reply.send(append(&file, &data, length, &chunk, &mut allocated));
+                    },
+                    Request::Write(pos, data, sync, reply) => {
+                        reply.send(file.write_all_at(&data, pos).and_then(| _ | {
+                            if sync { file.sync_all() } else { Ok(()) }
+                        }));
+                    },
+                    Request::Sync(reply) => {
+                        reply.send(file.sync_all());
+                    },
+                    Request::End(reply) => {
+                        reply.send(file.metadata().map(| m | m.len()));
+                    },
+                    Request::Replace(new, reply) => {
+                        file = new;
+                        allocated = 0;
+                        reply.send(Ok(()));
+                    },
+                }
+            }
+        });
+        Appender { requests: Some(requests), thread: Some(thread), preallocation }
+    }
+
+    /// Allocate disk space for appends this many bytes at a time, or,
+    /// if it's 0, as they're made.
+    pub fn set_preallocation(&self, bytes: u64) {
+        self.preallocation.store(bytes, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn preallocation(&self) -> u64 {
+        self.preallocation.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Append the first length bytes of data to the file, returning
+    /// the position they were written at.
+    pub fn append(&self, data: std::fs::File, length: u64) -> std::io::Result<u64> {
+        self.call(| reply | Request::Append(data, length, reply))
+    }
+
+    /// Write data at pos, and fsync the file if sync is true.
+    pub fn write(&self, pos: u64, data: &[u8], sync: bool) -> std::io::Result<()> {
+        self.call(| reply | Request::Write(pos, data.to_vec(), sync, reply))
+    }
+
+    /// Fsync the file, once earlier requests are done.
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.call(Request::Sync)
+    }
+
+    /// The size of the file, once earlier requests are done
+    pub fn end(&self) -> std::io::Result<u64> {
+        self.call(Request::End)
+    }
+
+    /// Write to file from now on, e.g. because it replaced the file
+    /// written so far.
+    pub fn replace(&self, file: std::fs::File) -> std::io::Result<()> {
+        self.call(| reply | Request::Replace(file, reply))
+    }
+
+    fn call<T>(&self, request: impl FnOnce(Reply<T>) -> Request) -> std::io::Result<T> {
+        let (reply, result) = std::sync::mpsc::channel();
+        self.requests.as_ref().unwrap().send(request(reply))
+            .map_err(| _ | util::io_error("The appender thread exited"))?;
+        result.recv().map_err(| _ | util::io_error("The appender thread exited"))?
+    }
+}
+
+impl Drop for Appender {
+    fn drop(&mut self) {
+        self.requests.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join();
+        }
+    }
+}
+
+fn append(file: &std::fs::File, data: &std::fs::File, length: u64,
+          chunk: &std::sync::atomic::AtomicU64, allocated: &mut u64)
+          -> std::io::Result<u64> {
+    let pos = file.metadata()?.len();
+    let size = chunk.load(std::sync::atomic::Ordering::Relaxed);
+    if size > 0 && pos + length > *allocated {
+        // Allocate up to a multiple of the chunk size past the data.
+        let end = (pos + length).div_ceil(size) * size;
+        match util::preallocate(file, pos, end - pos) {
+            Ok(_) => *allocated = end,
+            Err(err) => {
+                // Appends work without it, so stop trying.
+                tracing::warn!("Couldn't preallocate data file space, so not trying again: {}",
+                               err);
+                chunk.store(0, std::sync::atomic::Ordering::Relaxed);
+            },
+        }
+    }
+    let mut buf = vec![0u8; std::cmp::min(length, 1 << 16) as usize];
+    let mut copied = 0;
+    while copied < length {
+        let size = std::cmp::min(length - copied, buf.len() as u64) as usize;
+        data.read_exact_at(&mut buf[.. size], copied)?;
+        file.write_all_at(&buf[.. size], pos + copied)?;
+        copied += size as u64;
+    }
+    Ok(pos)
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::prelude::*;
+
+    #[test]
+    fn works() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        std::fs::write(&path, b"FS21").unwrap();
+        let appender = Appender::new(
+            std::fs::OpenOptions::new().read(true).write(true).open(&path).unwrap());
+        let mut data = tempfile::tempfile().unwrap();
+        data.write_all(&vec![7u8; 100_000]).unwrap();
+        assert_eq!(appender.append(data.try_clone().unwrap(), 70_000).unwrap(), 4);
+        assert_eq!(appender.append(data, 3).unwrap(), 70_004);
+        appender.write(4, b"TTTT", true).unwrap();
+        assert_eq!(appender.end().unwrap(), 70_007);
+        appender.sync().unwrap();
+
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(&written[.. 8], b"FS21TTTT");
+        assert!(written[8 ..].iter().all(| b | *b == 7));
+
+        let new = util::test::test_path(&tmpdir, "new.fs");
+        std::fs::write(&new, b"FS21").unwrap();
+        appender.replace(
+            std::fs::OpenOptions::new().read(true).write(true).open(&new).unwrap()).unwrap();
+        let data = tempfile::tempfile().unwrap();
+        (&data).write_all(b"more").unwrap();
+        assert_eq!(appender.append(data, 4).unwrap(), 4);
+        drop(appender);
+        assert_eq!(std::fs::read(&new).unwrap(), b"FS21more");
+        assert_eq!(std::fs::read(&path).unwrap().len(), 70_007);
+    }
+
+    #[test]
+    fn preallocation() {
+        use std::os::unix::fs::MetadataExt;
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        std::fs::write(&path, b"FS21").unwrap();
+        let appender = Appender::new(
+            std::fs::OpenOptions::new().read(true).write(true).open(&path).unwrap());
+        appender.set_preallocation(1 << 20);
+        assert_eq!(appender.preallocation(), 1 << 20);
+        let mut data = tempfile::tempfile().unwrap();
+        data.write_all(b"data").unwrap();
+        assert_eq!(appender.append(data.try_clone().unwrap(), 4).unwrap(), 4);
+        assert_eq!(appender.append(data, 4).unwrap(), 8);
+
+        // The file's size is what was written, but space is allocated
+        // past it, unless the file system can't do that:
+        assert_eq!(appender.end().unwrap(), 12);
+        assert_eq!(std::fs::read(&path).unwrap(), b"FS21datadata");
+        if appender.preallocation() > 0 {
+            assert!(std::fs::metadata(&path).unwrap().blocks() * 512 >= 1 << 20);
+        }
+    }
+}
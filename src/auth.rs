@@ -0,0 +1,197 @@
+// Client authentication
+//
+// When a server has an authenticator, clients authenticate after the
+// handshake and before registering.  They may first ask for a
+// challenge, for authenticators, like HMAC challenge/response, that
+// don't want secrets sent over the wire.
+//
+// Clients connecting over TLS with certificates are identified by
+// them instead.  Either way, permissions can limit which storages a
+// user may use, and whether it may write to them.
+
+use anyhow::{anyhow, Context, Result};
+
+pub trait Authenticator: Send + Sync {
+
+    /// Make a challenge for a client to respond to.  Each call should
+    /// return a new challenge.  Authenticators that don't use
+    /// challenges can return an empty vector.
+    fn challenge(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    /// Check a user's response to a challenge.  The challenge is
+    /// empty if the client didn't ask for one.
+    fn authenticate(&self, user: &str, challenge: &[u8], response: &[u8]) -> bool;
+}
+
+/// Authenticate users by password, where the response is the password.
+///
+/// Passwords are sent in the clear, so this should only be used over
+/// connections that are encrypted or otherwise trusted.
+#[derive(Debug, Default)]
+pub struct Passwords {
+    passwords: std::collections::HashMap<String, Vec<u8>>,
+}
+
+impl Passwords {
+
+    pub fn new() -> Passwords {
+        Passwords::default()
+    }
+
+    /// Load passwords from a file with a ``USER:PASSWORD`` line per
+    /// user.  Blank lines and lines starting with ``#`` are ignored.
+    pub fn load(path: &str) -> Result<Passwords> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading {}", path))?;
+        let mut passwords = Passwords::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, password) = line.split_once(':')
+                .ok_or_else(|| anyhow!("{}:{}: expected USER:PASSWORD", path, n + 1))?;
+            passwords.add(user, password);
+        }
+        Ok(passwords)
+    }
+
+    pub fn add(&mut self, user: &str, password: &str) {
+        self.passwords.insert(user.to_string(), password.as_bytes().to_vec());
+    }
+}
+
+// Compare in time that depends only on the lengths, so timing doesn't
+// reveal how much of a guess was right.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, | d, (x, y) | d | (x ^ y)) == 0
+}
+
+impl Authenticator for Passwords {
+    fn authenticate(&self, user: &str, _challenge: &[u8], response: &[u8]) -> bool {
+        match self.passwords.get(user) {
+            Some(password) => same(password, response),
+            None => false,
+        }
+    }
+}
+
+/// What a user may do with a storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Users' access to storages
+#[derive(Debug, Default)]
+pub struct Permissions {
+    // (user, storage) -> access, where either may be "*", for any
+    grants: std::collections::HashMap<(String, String), Access>,
+}
+
+impl Permissions {
+
+    pub fn new() -> Permissions {
+        Permissions::default()
+    }
+
+    /// Load permissions from a file with ``USER STORAGE ACCESS``
+    /// lines, where ACCESS is ``read`` or ``write`` and a user or
+    /// storage of ``*`` means any.  Blank lines and lines starting
+    /// with ``#`` are ignored.
+    pub fn load(path: &str) -> Result<Permissions> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading {}", path))?;
+        let mut permissions = Permissions::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad = || anyhow!("{}:{}: expected USER STORAGE read|write", path, n + 1);
+            let (user, storage, access) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [user, storage, "read"] => (user, storage, Access::Read),
+                [user, storage, "write"] => (user, storage, Access::Write),
+                _ => return Err(bad()),
+            };
+            permissions.grant(user, storage, access);
+        }
+        Ok(permissions)
+    }
+
+    pub fn grant(&mut self, user: &str, storage: &str, access: Access) {
+        let granted = self.grants.entry((user.to_string(), storage.to_string()))
+            .or_insert(access);
+        *granted = std::cmp::max(*granted, access);
+    }
+
+    /// A user's access to a storage, if any.  Write access implies
+    /// read access.  Users that haven't been identified only get
+    /// what's granted to ``*``.
+    pub fn access(&self, user: Option<&str>, storage: &str) -> Option<Access> {
+        let mut access = None;
+        for user in ["*"].into_iter().chain(user) {
+            for storage in ["*", storage] {
+                access = access.max(
+                    self.grants.get(&(user.to_string(), storage.to_string())).copied());
+            }
+        }
+        access
+    }
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::util;
+
+    #[test]
+    fn passwords() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "passwords");
+        std::fs::write(&path, "# users\nbob:secret\n\nalice:a:b\n").unwrap();
+        let passwords = Passwords::load(&path).unwrap();
+        assert!(passwords.challenge().is_empty());
+        assert!(passwords.authenticate("bob", b"", b"secret"));
+        assert!(passwords.authenticate("alice", b"", b"a:b"));
+        assert!(! passwords.authenticate("bob", b"", b"secreT"));
+        assert!(! passwords.authenticate("bob", b"", b"secret "));
+        assert!(! passwords.authenticate("carol", b"", b"secret"));
+
+        std::fs::write(&path, "bob\n").unwrap();
+        assert!(Passwords::load(&path).is_err());
+    }
+
+    #[test]
+    fn permissions() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "permissions");
+        std::fs::write(&path, "# who what how\n\
+                               bob 1 write\n\
+                               bob 2   read\n\
+                               \n\
+                               alice * read\n\
+                               alice 2 write\n\
+                               * public read\n").unwrap();
+        let permissions = Permissions::load(&path).unwrap();
+        assert_eq!(permissions.access(Some("bob"), "1"), Some(Access::Write));
+        assert_eq!(permissions.access(Some("bob"), "2"), Some(Access::Read));
+        assert_eq!(permissions.access(Some("bob"), "3"), None);
+        assert_eq!(permissions.access(Some("alice"), "1"), Some(Access::Read));
+        assert_eq!(permissions.access(Some("alice"), "2"), Some(Access::Write));
+        assert_eq!(permissions.access(Some("bob"), "public"), Some(Access::Read));
+        assert_eq!(permissions.access(None, "public"), Some(Access::Read));
+        assert_eq!(permissions.access(None, "1"), None);
+
+        std::fs::write(&path, "bob 1 delete\n").unwrap();
+        assert!(Permissions::load(&path).is_err());
+        std::fs::write(&path, "bob 1\n").unwrap();
+        assert!(Permissions::load(&path).is_err());
+    }
+}
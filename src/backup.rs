@@ -0,0 +1,221 @@
+// Incremental backups, like repozo's
+//
+// A backup directory holds full copies of a data file and deltas,
+// each with the data committed after the end of the file before it,
+// and a list of them, in order, in BACKUPS_FILE, with a line per file:
+//
+//   NAME START END TID CHECKSUM
+//
+// START and END are the positions in the data file the file's data
+// were copied from, TID, in hex, is the last transaction in the file,
+// and CHECKSUM, in hex, is a CRC-32 of the file.  Full copies start
+// at 0.  A data file is restored by concatenating the last full copy
+// with the deltas after it.
+//
+// A copy of the data file's zstd dictionaries, if it has any, is kept
+// in DICTIONARIES_FILE, and replaced by each backup.  Dictionaries
+// are only ever added, so the latest copy has those of every record.
+use std::io::prelude::*;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::dictionaries;
+use crate::mapped;
+use crate::storage;
+use crate::util;
+
+pub const BACKUPS_FILE: &str = "backups.dat";
+pub const FULL_SUFFIX: &str = ".fs";
+pub const DELTA_SUFFIX: &str = ".deltafs";
+pub const DICTIONARIES_FILE: &str = "dictionaries";
+const TMP_NAME: &str = "backup.tmp";
+const DICTIONARIES_TMP_NAME: &str = "dictionaries.tmp";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub start: u64,
+    pub end: u64,
+    pub tid: util::Tid,
+    pub checksum: u32,
+}
+
+impl Entry {
+
+    /// Whether the file is a full copy, rather than a delta
+    pub fn full(&self) -> bool {
+        self.start == 0
+    }
+
+    fn parse(line: &str) -> Result<Entry> {
+        let bad = || anyhow!("Bad backup list entry {:?}", line);
+        match line.split(' ').collect::<Vec<&str>>().as_slice() {
+            [name, start, end, tid, checksum] => Ok(Entry {
+                name: name.to_string(),
+                start: start.parse().map_err(| _ | bad())?,
+                end: end.parse().map_err(| _ | bad())?,
+                tid: util::p64(u64::from_str_radix(tid, 16).map_err(| _ | bad())?),
+                checksum: u32::from_str_radix(checksum, 16).map_err(| _ | bad())?,
+            }),
+            _ => Err(bad()),
+        }
+    }
+
+    fn line(&self) -> String {
+        format!("{} {} {} {} {:08x}\n",
+                self.name, self.start, self.end, util::hex(&self.tid), self.checksum)
+    }
+}
+
+fn path(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir, name)
+}
+
+/// The files in a backup directory, oldest first.  A directory
+/// without a list has none.
+pub fn entries(dir: &str) -> Result<Vec<Entry>> {
+    match std::fs::read_to_string(path(dir, BACKUPS_FILE)) {
+        Ok(text) => text.lines().map(Entry::parse).collect(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(err) => Err(err).context("reading backup list"),
+    }
+}
+
+/// Where backup files are written before they're added to the list
+pub fn tmp_path(dir: &str) -> String {
+    path(dir, TMP_NAME)
+}
+
+/// Add the backup file written to tmp_path, with the data between
+/// start and end, to a directory's list, after the given entries.
+pub fn add(dir: &str, entries: &[Entry], start: u64, end: u64, tid: &util::Tid)
+           -> Result<Entry> {
+    let tmp = tmp_path(dir);
+    let checksum = copy(std::fs::File::open(&tmp).context("opening backup file")?,
+                        end - start, &mut std::io::sink())?;
+    let suffix = if start == 0 { FULL_SUFFIX } else { DELTA_SUFFIX };
+    let entry = Entry {
+        name: format!("{:08}{}", entries.len() + 1, suffix),
+        start, end, tid: *tid, checksum,
+    };
+    std::fs::rename(&tmp, path(dir, &entry.name)).context("renaming backup file")?;
+    let mut list = std::fs::OpenOptions::new()
+        .create(true).append(true).open(path(dir, BACKUPS_FILE))
+        .context("opening backup list")?;
+    list.write_all(entry.line().as_bytes()).context("writing backup list")?;
+    list.sync_all().context("fsync")?;
+    Ok(entry)
+}
+
+/// Copy the dictionaries of the data file at path, if it has any, to
+/// a directory, replacing the copy made by the last backup.
+pub fn copy_dictionaries(dir: &str, path: &str) -> Result<()> {
+    let dictionaries = dictionaries::file_path(path);
+    if ! std::path::Path::new(&dictionaries).exists() {
+        return Ok(());
+    }
+    let tmp = self::path(dir, DICTIONARIES_TMP_NAME);
+    std::fs::copy(&dictionaries, &tmp).context("copying dictionaries")?;
+    std::fs::File::open(&tmp).and_then(| file | file.sync_all()).context("fsync")?;
+    std::fs::rename(&tmp, self::path(dir, DICTIONARIES_FILE)).context("renaming dictionaries")?;
+    Ok(())
+}
+
+// Copy length bytes from reader to out, returning their CRC-32.
+fn copy(reader: impl Read, length: u64, out: &mut dyn Write) -> Result<u32> {
+    let mut reader = reader.take(length);
+    let mut buf = vec![0u8; 1 << 16];
+    let (mut crc, mut read) = (0, 0);
+    loop {
+        let n = reader.read(&mut buf).context("reading backup data")?;
+        if n == 0 {
+            break;
+        }
+        crc = util::crc32(crc, &buf[.. n]);
+        out.write_all(&buf[.. n]).context("writing backup data")?;
+        read += n as u64;
+    }
+    util::io_assert(read == length, "Backup data are too short")?;
+    Ok(crc)
+}
+
+/// Whether a data file still has the data a backup file was copied
+/// from, so later data can be added to the backup with a delta.
+///
+/// Packs change data files' records and their positions, and are
+/// caught here, without reading more than the backup file's range.
+pub fn matches(file: &std::fs::File, entry: &Entry) -> Result<bool> {
+    if file.metadata().context("getting data file size")?.len() < entry.end {
+        return Ok(false);
+    }
+    let mut reader = mapped::Reader::file(file);
+    util::seek(&mut reader, entry.start)?;
+    Ok(copy(reader, entry.end - entry.start, &mut std::io::sink())? == entry.checksum)
+}
+
+/// Restore the data file backed up in a directory, as of its last
+/// backup, to path, which mustn't exist, along with its dictionaries,
+/// if it has any.  The index is rebuilt when the restored file is
+/// opened.
+///
+/// Returns the entry of the last backup file used.
+pub fn restore(dir: &str, path: &str) -> Result<Entry> {
+    let entries = entries(dir)?;
+    let first = entries.iter().rposition(Entry::full)
+        .ok_or_else(|| anyhow!("No full backup in {}", dir))?;
+    let mut out = std::fs::OpenOptions::new()
+        .write(true).create_new(true).open(path)
+        .context("creating restored data file")?;
+    // So servers don't open it half restored
+    storage::lock_data_file(&out, path).context("locking restored data file")?;
+    let mut end = 0;
+    for entry in &entries[first ..] {
+        if entry.start != end {
+            return Err(anyhow!("Backup file {} doesn't follow the one before it",
+                               entry.name));
+        }
+        let file = std::fs::File::open(self::path(dir, &entry.name))
+            .with_context(|| format!("opening backup file {}", entry.name))?;
+        if file.metadata()?.len() != entry.end - entry.start ||
+            copy(file, entry.end - entry.start, &mut out)? != entry.checksum {
+            return Err(anyhow!("Backup file {} is damaged", entry.name));
+        }
+        end = entry.end;
+    }
+    out.sync_all().context("fsync")?;
+    let dictionaries = self::path(dir, DICTIONARIES_FILE);
+    if std::path::Path::new(&dictionaries).exists() {
+        std::fs::copy(&dictionaries, dictionaries::file_path(path))
+            .context("restoring dictionaries")?;
+    }
+    Ok(entries[entries.len() - 1].clone())
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn entries() {
+        let entry = Entry { name: "00000002.deltafs".to_string(), start: 4096, end: 5000,
+                            tid: util::p64(0x03e1), checksum: 0xdead };
+        assert_eq!(entry.line(), "00000002.deltafs 4096 5000 00000000000003e1 0000dead\n");
+        assert_eq!(Entry::parse(entry.line().trim_end()).unwrap(), entry);
+        assert!(! entry.full());
+        assert!(Entry::parse("00000002.deltafs 4096 5000").is_err());
+        assert!(Entry::parse("00000002.deltafs 4096 5000 xx 0000dead").is_err());
+
+        let dir = util::test::dir();
+        let dir = dir.path().to_str().unwrap();
+        assert_eq!(super::entries(dir).unwrap(), vec![]);
+        std::fs::write(tmp_path(dir), b"data").unwrap();
+        let added = add(dir, &[], 0, 4, &util::p64(1)).unwrap();
+        assert_eq!(added, Entry { name: "00000001.fs".to_string(), start: 0, end: 4,
+                                  tid: util::p64(1), checksum: util::crc32(0, b"data") });
+        assert!(added.full());
+        assert_eq!(super::entries(dir).unwrap(), vec![added]);
+    }
+}
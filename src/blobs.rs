@@ -0,0 +1,276 @@
+// Blob files
+//
+// Blobs are kept in a directory with ZODB's "bushy" layout, so blob
+// directories can be copied to and from ZODB storages: each byte of
+// an oid is a directory level, and a revision's file is named for
+// its tid, as in 0x00/0x00/0x00/0x00/0x00/0x00/0x00/0x07/0x03ea1f.blob.
+// Blobs being uploaded are written to the tmp subdirectory, and
+// moved into place when their transactions are voted.
+//
+// A blob directory can be shared with clients on the same file
+// system.  Clients then write new blobs to their objects'
+// directories themselves, and read blobs directly, so only file
+// names are sent.
+
+use std::io::prelude::*;
+
+use crate::util;
+
+pub const LAYOUT: &str = "bushy";
+const LAYOUT_FILE: &str = ".layout";
+const TMP: &str = "tmp";
+const SUFFIX: &str = ".blob";
+
+pub struct BlobDir {
+    path: std::path::PathBuf,
+    shared: bool,
+    temporaries: std::sync::atomic::AtomicU64,
+}
+
+impl BlobDir {
+
+    /// Open a blob directory, creating it if necessary.  If it's
+    /// shared, clients may store blobs they wrote to it.
+    pub fn open<P: AsRef<std::path::Path>>(path: P, shared: bool)
+                                           -> std::io::Result<BlobDir> {
+        let path = path.as_ref().to_path_buf();
+        make_dirs(&path.join(TMP))?;
+        let layout_path = path.join(LAYOUT_FILE);
+        match std::fs::read_to_string(&layout_path) {
+            Ok(layout) => util::io_assert(
+                layout.trim() == LAYOUT,
+                &format!("{} has the {} layout, rather than {}",
+                         path.display(), layout.trim(), LAYOUT))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound =>
+                std::fs::write(&layout_path, LAYOUT)?,
+            Err(err) => return Err(err),
+        }
+        Ok(BlobDir { path, shared, temporaries: std::sync::atomic::AtomicU64::new(0) })
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// The file a client wrote to a shared blob directory, given its
+    /// name in the object's directory, which ZEO clients end with
+    /// .tmp, or .tmp and a character.
+    pub fn shared_path(&self, oid: &util::Oid, filename: &str)
+                       -> std::io::Result<std::path::PathBuf> {
+        util::io_assert(self.shared, "The blob directory isn't shared")?;
+        let mut trimmed = filename.chars();
+        trimmed.next_back();
+        util::io_assert(
+            ! filename.contains('/') &&
+                (filename.ends_with(".tmp") || trimmed.as_str().ends_with(".tmp")),
+            &format!("Invalid blob file name {}", filename))?;
+        Ok(self.oid_path(oid).join(filename))
+    }
+
+    /// The directory holding an object's blob files
+    pub fn oid_path(&self, oid: &util::Oid) -> std::path::PathBuf {
+        let mut path = self.path.clone();
+        for byte in oid.iter() {
+            path.push(format!("0x{:02x}", byte));
+        }
+        path
+    }
+
+    /// The file for an object's blob as of the transaction with id tid
+    pub fn blob_path(&self, oid: &util::Oid, tid: &util::Tid) -> std::path::PathBuf {
+        self.oid_path(oid).join(repr(tid) + SUFFIX)
+    }
+
+    /// List committed blob files, with their objects' ids, their
+    /// transactions' ids and their sizes.
+    ///
+    /// Other files, such as uploads in progress, are skipped.
+    pub fn files(&self) -> std::io::Result<Vec<(util::Oid, util::Tid, u64)>> {
+        let mut found = vec![];
+        self.walk(&self.path, &mut vec![], &mut found)?;
+        Ok(found)
+    }
+
+    // Collect the blob files under dir, whose path below the blob
+    // directory spells oid bytes
+    fn walk(&self, dir: &std::path::Path, oid: &mut Vec<u8>,
+            found: &mut Vec<(util::Oid, util::Tid, u64)>) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            if oid.len() < 8 {
+                match name.strip_prefix("0x").map(| byte | u8::from_str_radix(byte, 16)) {
+                    Some(Ok(byte)) if name.len() == 4 && entry.file_type()?.is_dir() => {
+                        oid.push(byte);
+                        self.walk(&entry.path(), oid, found)?;
+                        oid.pop();
+                    },
+                    _ => {},
+                }
+                continue;
+            }
+            let tid = match name.strip_suffix(SUFFIX).and_then(| name | name.strip_prefix("0x"))
+                .map(| tid | u64::from_str_radix(tid, 16)) {
+                    Some(Ok(tid)) => util::p64(tid),
+                    _ => continue,
+                };
+            let oid: util::Oid = oid[..].try_into().unwrap();
+            if self.blob_path(&oid, &tid) == entry.path() {
+                found.push((oid, tid, entry.metadata()?.len()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Make an empty file to upload a blob to.
+    pub fn temporary(&self) -> std::io::Result<(std::fs::File, std::path::PathBuf)> {
+        loop {
+            let n = self.temporaries.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let path = self.path.join(TMP).join(
+                format!("{}-{}.tmp", std::process::id(), n));
+            match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((file, path)),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Move an uploaded blob into place as the object's blob as of
+    /// tid, returning its path.
+    ///
+    /// As in ZODB, a blob that can't be renamed, such as one on
+    /// another file system, is copied, and committed blobs are made
+    /// read-only, since clients sharing the directory open them
+    /// directly.
+    pub fn store(&self, oid: &util::Oid, tid: &util::Tid, uploaded: &std::path::Path)
+                 -> std::io::Result<std::path::PathBuf> {
+        make_dirs(&self.oid_path(oid))?;
+        let path = self.blob_path(oid, tid);
+        if std::fs::rename(uploaded, &path).is_err() {
+            std::fs::copy(uploaded, &path)?;
+            std::fs::remove_file(uploaded)?;
+        }
+        let mut permissions = std::fs::metadata(&path)?.permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&path, permissions)?;
+        Ok(path)
+    }
+
+    /// Open an object's blob as of tid.
+    pub fn load(&self, oid: &util::Oid, tid: &util::Tid) -> std::io::Result<std::fs::File> {
+        std::fs::File::open(self.blob_path(oid, tid))
+    }
+
+    /// Read an open blob in chunks of up to size bytes, calling chunk
+    /// with each.
+    pub fn read_chunks<F>(mut file: std::fs::File, size: usize, mut chunk: F)
+                          -> anyhow::Result<()>
+    where F: FnMut(&[u8]) -> anyhow::Result<()> {
+        let mut buf = vec![0u8; size];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            chunk(&buf[..n])?;
+        }
+    }
+}
+
+// Blob directories are private, as ZODB makes them.
+fn make_dirs(path: &std::path::Path) -> std::io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(path)
+}
+
+// ZODB's utils.tid_repr: hex, without leading zeros, but with 2
+// digits per byte.
+fn repr(tid: &util::Tid) -> String {
+    let hex = util::hex(tid);
+    let mut digits = hex.trim_start_matches('0').to_string();
+    if ! digits.len().is_multiple_of(2) {
+        digits.insert(0, '0');
+    }
+    if digits.is_empty() {
+        digits.push_str("00");
+    }
+    format!("0x{}", digits)
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn layout() {
+        let tmpdir = util::test::dir();
+        let path = tmpdir.path().join("blobs");
+        let blobs = BlobDir::open(&path, false).unwrap();
+        assert_eq!(std::fs::read_to_string(path.join(".layout")).unwrap(), "bushy");
+        assert_eq!(
+            blobs.blob_path(&util::p64(7), &[0, 0, 0, 0, 0, 3, 0xea, 0x1f]),
+            path.join("0x00/0x00/0x00/0x00/0x00/0x00/0x00/0x07/0x03ea1f.blob"));
+        assert_eq!(
+            blobs.blob_path(&util::p64(0x1ff), &[0, 0, 0, 0, 0, 0, 0x12, 0x34]),
+            path.join("0x00/0x00/0x00/0x00/0x00/0x00/0x01/0xff/0x1234.blob"));
+        assert_eq!(repr(&util::Z64), "0x00");
+
+        // Uploads are moved into place:
+        let (mut file, uploaded) = blobs.temporary().unwrap();
+        assert!(uploaded.starts_with(path.join("tmp")));
+        file.write_all(b"data").unwrap();
+        let tid = util::p64(42);
+        let stored = blobs.store(&util::p64(1), &tid, &uploaded).unwrap();
+        assert!(! uploaded.exists());
+        assert_eq!(stored, blobs.blob_path(&util::p64(1), &tid));
+        assert!(std::fs::metadata(&stored).unwrap().permissions().readonly());
+        let mut chunks = vec![];
+        BlobDir::read_chunks(blobs.load(&util::p64(1), &tid).unwrap(), 3, | chunk | {
+            chunks.push(chunk.to_vec());
+            Ok(())
+        }).unwrap();
+        assert_eq!(chunks, vec![b"dat".to_vec(), b"a".to_vec()]);
+
+        // Committed blobs can be listed, skipping other files:
+        std::fs::write(blobs.oid_path(&util::p64(1)).join("x.tmp"), b"").unwrap();
+        std::fs::write(blobs.oid_path(&util::p64(1)).join("0x002a.blob"), b"").unwrap();
+        blobs.temporary().unwrap();
+        assert_eq!(blobs.files().unwrap(), vec![(util::p64(1), tid, 4)]);
+
+        // Clients may only store blobs they wrote if the directory is
+        // shared:
+        assert!(blobs.shared_path(&util::p64(1), "x.tmp").is_err());
+
+        // Directories with other layouts aren't used:
+        drop(blobs);
+        assert!(BlobDir::open(&path, true).is_ok());
+        std::fs::write(path.join(".layout"), "lawn").unwrap();
+        assert!(BlobDir::open(&path, true).is_err());
+    }
+
+    #[test]
+    fn shared() {
+        let tmpdir = util::test::dir();
+        let blobs = BlobDir::open(tmpdir.path(), true).unwrap();
+        let oid = util::p64(3);
+        assert_eq!(blobs.shared_path(&oid, "abc.tmp").unwrap(),
+                   blobs.oid_path(&oid).join("abc.tmp"));
+        assert!(blobs.shared_path(&oid, "abc.tmpx").is_ok());
+        assert!(blobs.shared_path(&oid, "abc.tmpxy").is_err());
+        assert!(blobs.shared_path(&oid, "abc.blob").is_err());
+        assert!(blobs.shared_path(&oid, "../../x.tmp").is_err());
+        assert!(blobs.shared_path(&oid, "é.tmpé").is_ok());
+    }
+}
@@ -0,0 +1,128 @@
+// Cache of recently loaded records
+//
+// Object data are kept by oid and tid, so hot objects can be loaded
+// without reading the data file.  Records never change, and packs
+// don't change their tids, so entries stay good until evicted, least
+// recently used first, to keep the data within a number of bytes.
+
+use crate::util;
+
+type Key = (util::Oid, util::Tid);
+
+pub struct RecordCache {
+    capacity: u64,
+    size: u64,
+    // key -> (data, last use)
+    records: std::collections::HashMap<Key, (util::Bytes, u64)>,
+    // last use -> key, least recent first
+    uses: std::collections::BTreeMap<u64, Key>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// How well a cache is doing
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub capacity: u64,
+    pub size: u64,
+    pub records: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+impl RecordCache {
+
+    /// Make a cache holding up to capacity bytes of data.
+    pub fn new(capacity: u64) -> RecordCache {
+        RecordCache {
+            capacity, size: 0, records: std::collections::HashMap::new(),
+            uses: std::collections::BTreeMap::new(), clock: 0, hits: 0, misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, oid: &util::Oid, tid: &util::Tid) -> Option<&util::Bytes> {
+        self.clock += 1;
+        match self.records.get_mut(&(*oid, *tid)) {
+            Some((data, used)) => {
+                self.uses.remove(used);
+                *used = self.clock;
+                self.uses.insert(self.clock, (*oid, *tid));
+                self.hits += 1;
+                Some(data)
+            },
+            None => {
+                self.misses += 1;
+                None
+            },
+        }
+    }
+
+    /// Add a record's data, unless it's bigger than the cache.
+    pub fn put(&mut self, oid: util::Oid, tid: util::Tid, data: util::Bytes) {
+        let length = data.len() as u64;
+        if length > self.capacity || self.records.contains_key(&(oid, tid)) {
+            return;
+        }
+        while self.size + length > self.capacity {
+            let (_, key) = self.uses.pop_first().unwrap();
+            let (evicted, _) = self.records.remove(&key).unwrap();
+            self.size -= evicted.len() as u64;
+        }
+        self.clock += 1;
+        self.size += length;
+        self.uses.insert(self.clock, (oid, tid));
+        self.records.insert((oid, tid), (data, self.clock));
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            capacity: self.capacity, size: self.size, records: self.records.len() as u64,
+            hits: self.hits, misses: self.misses,
+        }
+    }
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn works() {
+        let mut cache = RecordCache::new(10);
+        let (o1, o2, o3) = (util::p64(1), util::p64(2), util::p64(3));
+        let tid = util::p64(42);
+        assert_eq!(cache.get(&o1, &tid), None);
+        cache.put(o1, tid, b"1111".to_vec());
+        cache.put(o2, tid, b"2222".to_vec());
+        assert_eq!(cache.get(&o1, &tid), Some(&b"1111".to_vec()));
+        assert_eq!(cache.get(&o1, &util::p64(41)), None);
+
+        // Object 2 was used least recently:
+        cache.put(o3, tid, b"3333".to_vec());
+        assert_eq!(cache.get(&o2, &tid), None);
+        assert!(cache.get(&o1, &tid).is_some());
+        assert!(cache.get(&o3, &tid).is_some());
+
+        // Records bigger than the cache aren't added:
+        cache.put(o2, tid, vec![0; 11]);
+        assert_eq!(cache.get(&o2, &tid), None);
+
+        let stats = cache.stats();
+        assert_eq!(stats, CacheStats { capacity: 10, size: 8, records: 2, hits: 3, misses: 4 });
+        assert_eq!(stats.hit_rate(), 3.0 / 7.0);
+        assert_eq!(CacheStats::default().hit_rate(), 0.0);
+    }
+}
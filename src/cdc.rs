@@ -0,0 +1,148 @@
+// Change data capture
+//
+// Committed changes are sent to sinks, so downstream systems, like
+// search indexes and caches, can be kept in sync with a storage.
+// Sinks run in their own thread, so slow sinks don't hold up
+// commits.
+use std::io::prelude::*;
+
+use anyhow::{Context, Result};
+
+use crate::util;
+
+/// A committed transaction
+#[derive(Debug, Clone, PartialEq)]
+pub struct Commit {
+    pub tid: util::Tid,
+    pub oids: Vec<util::Oid>,
+}
+
+pub trait Sink: Send {
+    fn name(&self) -> String;
+    fn commit(&mut self, commit: &Commit) -> Result<()>;
+}
+
+/// Send commits to sinks in a new thread.
+///
+/// The thread exits when the returned sender is dropped.
+pub fn start(mut sinks: Vec<Box<dyn Sink>>) -> std::sync::mpsc::Sender<Commit> {
+    let (send, receive) = std::sync::mpsc::channel::<Commit>();
+    std::thread::spawn(move || {
+        for commit in receive.iter() {
+            for sink in sinks.iter_mut() {
+                if let Err(err) = sink.commit(&commit) {
+                    tracing::warn!("change sink {} failed for {}: {:#}",
+                                   sink.name(), util::hex(&commit.tid), err);
+                }
+            }
+        }
+    });
+    send
+}
+
+/// A journal file, with a line per transaction, with its id and the
+/// ids of the objects it changed, in hex:
+///
+/// ```text
+/// 03c1b2a0b6f8e811 0000000000000000 0000000000000003
+/// ```
+pub struct Journal {
+    path: String,
+    file: std::fs::File,
+}
+
+impl Journal {
+    /// Open a journal, appending to it if it exists.
+    pub fn open(path: &str) -> Result<Journal> {
+        let file = std::fs::OpenOptions::new()
+            .create(true).append(true).open(path)
+            .with_context(|| format!("opening journal {}", path))?;
+        Ok(Journal { path: path.to_string(), file })
+    }
+}
+
+impl Sink for Journal {
+    fn name(&self) -> String {
+        self.path.clone()
+    }
+
+    fn commit(&mut self, commit: &Commit) -> Result<()> {
+        let mut line = util::hex(&commit.tid);
+        for oid in commit.oids.iter() {
+            line.push(' ');
+            line.push_str(&util::hex(oid));
+        }
+        line.push('\n');
+        self.file.write_all(line.as_bytes()).context("writing journal")
+    }
+}
+
+/// Post commits as JSON, like ``{"tid": "03c1...", "oids": ["00..."]}``,
+/// to an HTTP endpoint, such as a Kafka REST proxy.
+#[cfg(feature = "webhook")]
+pub struct Webhook {
+    host: String,
+    path: String,
+}
+
+#[cfg(feature = "webhook")]
+impl Webhook {
+    /// Url has the form ``http://HOST:PORT/PATH``.  HTTPS isn't
+    /// supported.
+    pub fn new(url: &str) -> Result<Webhook> {
+        let rest = url.strip_prefix("http://")
+            .ok_or_else(|| anyhow::anyhow!("Webhook urls must start with http://"))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        Ok(Webhook { host: host.to_string(), path: path.to_string() })
+    }
+}
+
+#[cfg(feature = "webhook")]
+impl Sink for Webhook {
+    fn name(&self) -> String {
+        format!("http://{}{}", self.host, self.path)
+    }
+
+    fn commit(&mut self, commit: &Commit) -> Result<()> {
+        let oids: Vec<String> = commit.oids.iter()
+            .map(| oid | format!("\"{}\"", util::hex(oid))).collect();
+        let body = format!("{{\"tid\": \"{}\", \"oids\": [{}]}}",
+                           util::hex(&commit.tid), oids.join(", "));
+        let mut stream = std::net::TcpStream::connect(&self.host)
+            .with_context(|| format!("connecting to {}", self.host))?;
+        write!(stream,
+               "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+                Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+               self.path, self.host, body.len(), body).context("posting")?;
+        let mut status = String::new();
+        std::io::BufReader::new(stream).read_line(&mut status)
+            .context("reading response")?;
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(anyhow::anyhow!("Unexpected response {:?}", status.trim_end())),
+        }
+    }
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn journal() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "changes");
+        let commit = Commit { tid: util::p64(0x3c1), oids: vec![util::Z64, util::p64(3)] };
+        Journal::open(&path).unwrap().commit(&commit).unwrap();
+        Journal::open(&path).unwrap().commit(&Commit { oids: vec![], ..commit }).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(),
+                   "00000000000003c1 0000000000000000 0000000000000003\n\
+                    00000000000003c1\n");
+    }
+}
@@ -0,0 +1,150 @@
+// Cache of object revision chains
+//
+// Historical loads walk an object's data records back from its
+// current record through previous pointers.  The records walked are
+// never changed, so their headers are remembered, keyed by the
+// object's current record position.  A commit moves the current
+// record, which makes the cached chain unreachable.
+//
+// Storages can also keep an index of all revisions, so historical
+// loads can find revisions with a binary search, rather than a walk,
+// at the cost of memory for every revision in the file.
+
+use crate::util;
+
+/// A data record header, as needed to load its data
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Link {
+    pub tid: util::Tid,
+    pub pos: u64,
+    pub length: u64,
+    pub previous: u64,
+    pub checksum: u32,
+    pub compressed: bool,
+}
+
+pub struct ChainCache {
+    capacity: usize,
+    // oid -> (position of current record, links from newest)
+    chains: std::collections::HashMap<util::Oid, (u64, Vec<Link>)>,
+}
+
+impl ChainCache {
+
+    /// Make a cache holding chains for up to capacity objects.
+    pub fn new(capacity: usize) -> ChainCache {
+        ChainCache { capacity, chains: std::collections::HashMap::new() }
+    }
+
+    /// Get the links known for the object whose current record is at
+    /// head.
+    pub fn get(&self, oid: &util::Oid, head: u64) -> Option<&Vec<Link>> {
+        match self.chains.get(oid) {
+            Some((h, links)) if *h == head => Some(links),
+            _ => None,
+        }
+    }
+
+    pub fn put(&mut self, oid: util::Oid, head: u64, links: Vec<Link>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.chains.len() >= self.capacity && ! self.chains.contains_key(&oid) {
+            // Make room.  Which chain goes doesn't matter much.
+            let victim = *self.chains.keys().next().unwrap();
+            self.chains.remove(&victim);
+        }
+        self.chains.insert(oid, (head, links));
+    }
+
+    /// Forget all chains, e.g. because records were moved.
+    pub fn clear(&mut self) {
+        self.chains.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.chains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chains.is_empty()
+    }
+}
+
+/// Every revision of every object, oldest first, as (tid, position)
+#[derive(Debug, Default)]
+pub struct RevisionIndex {
+    revisions: std::collections::HashMap<util::Oid, Vec<(util::Tid, u64)>>,
+}
+
+impl RevisionIndex {
+
+    /// Add an object's revision, which must be newer than the ones
+    /// already added.
+    pub fn add(&mut self, oid: util::Oid, tid: util::Tid, pos: u64) {
+        self.revisions.entry(oid).or_default().push((tid, pos));
+    }
+
+    /// Get the revisions of the object whose current record is at
+    /// head, if they're known.
+    pub fn get(&self, oid: &util::Oid, head: u64) -> Option<&[(util::Tid, u64)]> {
+        match self.revisions.get(oid) {
+            Some(revisions) if revisions.last().map(| r | r.1) == Some(head) =>
+                Some(revisions),
+            _ => None,
+        }
+    }
+
+    /// The number of revisions
+    pub fn revisions(&self) -> usize {
+        self.revisions.values().map(| revisions | revisions.len()).sum()
+    }
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn link(pos: u64) -> Link {
+        Link { tid: util::p64(pos), pos, length: 3, previous: pos / 2, checksum: 0,
+               compressed: false }
+    }
+
+    #[test]
+    fn works() {
+        let mut cache = ChainCache::new(2);
+        cache.put(util::p64(1), 100, vec![link(100), link(50)]);
+        assert_eq!(cache.get(&util::p64(1), 100), Some(&vec![link(100), link(50)]));
+        // Stale after the object's current record moves:
+        assert_eq!(cache.get(&util::p64(1), 200), None);
+        assert_eq!(cache.get(&util::p64(2), 100), None);
+
+        cache.put(util::p64(2), 300, vec![link(300)]);
+        cache.put(util::p64(2), 400, vec![link(400)]);
+        assert_eq!(cache.len(), 2);
+        cache.put(util::p64(3), 500, vec![link(500)]);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&util::p64(3), 500).is_some());
+
+        let mut cache = ChainCache::new(0);
+        cache.put(util::p64(1), 100, vec![link(100)]);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn revisions() {
+        let mut index = RevisionIndex::default();
+        index.add(util::p64(1), util::p64(10), 100);
+        index.add(util::p64(1), util::p64(20), 200);
+        index.add(util::p64(2), util::p64(20), 210);
+        assert_eq!(index.get(&util::p64(1), 200),
+                   Some(&[(util::p64(10), 100), (util::p64(20), 200)][..]));
+        // Unknown if the object's current record isn't the last known:
+        assert_eq!(index.get(&util::p64(1), 100), None);
+        assert_eq!(index.get(&util::p64(3), 100), None);
+        assert_eq!(index.revisions(), 3);
+    }
+}
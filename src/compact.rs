@@ -0,0 +1,296 @@
+// Offline rewriting of data files
+use std::io::prelude::*;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::dictionaries;
+use crate::index;
+use crate::records;
+use crate::scan;
+use crate::storage;
+use crate::util;
+
+pub const COMPACT_SUFFIX: &str = ".compact";
+pub const OLD_SUFFIX: &str = ".old";
+
+#[derive(Debug, PartialEq)]
+pub struct Compacted {
+    pub transactions: u64,
+    pub old_size: u64,
+    pub new_size: u64,
+}
+
+/// Copy the committed transactions in a data file to a new file in
+/// the current format, leaving out padding.
+///
+/// Previous pointers are updated to reflect new record positions,
+/// and records and transactions without checksums get them.
+/// Returns the new file's index and first and last tids.
+pub fn rewrite(path: &str, new_path: &str)
+               -> Result<(index::Index, util::Tid, util::Tid, u64)> {
+    rewrite_in(path, new_path, records::FORMAT_VERSION)
+}
+
+/// Copy the committed transactions in a data file to a new file in a
+/// format version, as rewrite does.  Records only get checksums if
+/// the version has them.  Fails if a record can't be written in the
+/// version, as older versions have smaller limits, and no compressed
+/// records before version 3.
+pub fn rewrite_in(path: &str, new_path: &str, version: u32)
+                  -> Result<(index::Index, util::Tid, util::Tid, u64)> {
+    let header = {
+        let mut file = std::fs::File::open(path).context("opening data file")?;
+        records::FileHeader::read(&mut file).context("reading file header")?
+    };
+    let mut out = std::io::BufWriter::new(
+        std::fs::OpenOptions::new()
+            .write(true).create_new(true).open(new_path)
+            .context("creating new file")?);
+    header.with_version(version).write(&mut out)?;
+
+    let mut new_index = index::Index::new();
+    let mut first = util::Z64;
+    let mut last = util::Z64;
+    let mut count = 0u64;
+    let mut pos = records::HEADER_SIZE;
+    let mut it = scan::TransactionIterator::open(path)?;
+    while let Some(record) = it.next() {
+        let record = record?;
+        let headers = record.data_headers(it.reader())?;
+        // Headers are a different size in some versions:
+        let length = 4 + records::transaction_header_length(version) +
+            (record.user.len() + record.desc.len() + record.ext.len()) as u64 +
+            headers.iter().map(| (_, dh) | records::data_header_size(version) + dh.length)
+            .sum::<u64>() + 8;
+        if length > records::max_transaction_length(version) {
+            return Err(anyhow!("The transaction at {} is too large for format version {}",
+                               record.pos, version));
+        }
+        let mut header = records::TransactionHeader {
+            length, checksum: 0, id: record.tid(), ndata: record.header.ndata,
+            luser: record.header.luser, ldesc: record.header.ldesc,
+            lext: record.header.lext,
+        };
+        let mut buf = Vec::with_capacity(length as usize);
+        buf.write_all(storage::TRANSACTION_MARKER)?;
+        header.write(&mut buf, version)?;
+        buf.write_all(&record.user)?;
+        buf.write_all(&record.desc)?;
+        buf.write_all(&record.ext)?;
+        let mut checksum = records::TransactionChecksum::of_record(&buf, version);
+        for (dpos, dh) in headers {
+            if dh.length > records::max_data_length(version) {
+                return Err(anyhow!("The data record at {} is too large for format version {}",
+                                   dpos, version));
+            }
+            if dh.compressed && ! records::compression(version) {
+                return Err(anyhow!("The data record at {} is compressed, \
+                                    which format version {} doesn't allow",
+                                   dpos, version));
+            }
+            util::seek(it.reader(), dpos + records::data_header_size(record.version))?;
+            let data = util::read_sized(it.reader(), dh.length as usize)
+                .context("reading record data")?;
+            // Records from version 1 files get checksums:
+            let data_checksum = if dh.checksum == 0 && records::checksums(version) {
+                util::crc32(0, &data)
+            }
+            else {
+                dh.checksum
+            };
+            checksum.add(&dh.id, data_checksum);
+            let previous = new_index.get(&dh.id).cloned().unwrap_or(0);
+            new_index.insert(dh.id, pos + buf.len() as u64);
+            records::DataHeader {
+                previous, checksum: data_checksum, offset: buf.len() as u64, ..dh
+            }.write(&mut buf, version)?;
+            buf.write_all(&data)?;
+        }
+        util::write_u64(&mut buf, length)?;
+        if records::checksums(version) {
+            header.checksum = checksum.value();
+            header.write(&mut &mut buf[4..], version)?;
+        }
+        out.write_all(&buf).context("writing transaction")?;
+        if count == 0 {
+            first = record.tid();
+        }
+        last = record.tid();
+        count += 1;
+        pos += length;
+    }
+    let file = out.into_inner().map_err(| e | anyhow!("flushing: {}", e))?;
+    file.sync_all().context("fsync")?;
+    Ok((new_index, first, last, count))
+}
+
+/// Check that two data files have the same committed transactions
+/// and data.  Compressed data of both are decompressed with the
+/// dictionaries kept with the file at path.
+pub fn verify_same(path: &str, other_path: &str) -> Result<()> {
+    let dictionaries = dictionaries::Dictionaries::load(path).context("loading dictionaries")?;
+    let mut it = scan::TransactionIterator::open(path)?;
+    let mut other_it = scan::TransactionIterator::open(other_path)?;
+    loop {
+        match (it.next(), other_it.next()) {
+            (None, None) => return Ok(()),
+            (Some(record), Some(other)) => {
+                let (record, other) = (record?, other?);
+                if (record.tid(), &record.user, &record.desc, &record.ext) !=
+                    (other.tid(), &other.user, &other.desc, &other.ext) {
+                        return Err(anyhow!(
+                            "Transaction at {} differs from transaction at {}",
+                            record.pos, other.pos));
+                    }
+                let headers = record.data_headers(it.reader())?;
+                let other_headers = other.data_headers(other_it.reader())?;
+                if headers.len() != other_headers.len() {
+                    return Err(anyhow!(
+                        "Transaction at {} has a different number of records",
+                        other.pos));
+                }
+                for ((pos, dh), (other_pos, other_dh)) in
+                    headers.iter().zip(other_headers.iter()) {
+                        if (dh.id, dh.tid) != (other_dh.id, other_dh.tid) ||
+                            scan::read_data(it.reader(), *pos, dh, record.version,
+                                            &dictionaries)? !=
+                            scan::read_data(other_it.reader(), *other_pos,
+                                            other_dh, other.version, &dictionaries)? {
+                                return Err(anyhow!(
+                                    "Data record at {} differs from record at {}",
+                                    pos, other_pos));
+                            }
+                    }
+            },
+            (_, None) => return Err(anyhow!("{} is missing transactions",
+                                            other_path)),
+            (None, _) => return Err(anyhow!("{} has extra transactions",
+                                            other_path)),
+        }
+    }
+}
+
+/// Rewrite a data file, verify the result, and swap it into place.
+///
+/// The original file is kept with an ".old" suffix.  If there's
+/// already a file with that name, perhaps the only copy from an
+/// earlier compaction, nothing is done.  Fails if the storage is in
+/// use.
+pub fn compact(path: &str) -> Result<Compacted> {
+    let _locked = storage::lock_data_path(path).context("locking data file")?;
+    let old_path = String::from(path) + OLD_SUFFIX;
+    if std::path::Path::new(&old_path).exists() {
+        return Err(anyhow!("{} exists, move or remove it first", old_path));
+    }
+    let new_path = String::from(path) + COMPACT_SUFFIX;
+    if std::path::Path::new(&new_path).exists() {
+        std::fs::remove_file(&new_path).context("removing old compact file")?;
+    }
+    let old_size = std::fs::metadata(path).context("data file")?.len();
+    let (index, first, last, count) = rewrite(path, &new_path)?;
+    verify_same(path, &new_path)?;
+    let new_size = std::fs::metadata(&new_path)?.len();
+
+    let index_path = String::from(path) + storage::INDEX_SUFFIX;
+    index::remove_index(&index_path).context("removing index")?;
+    std::fs::rename(path, old_path).context("renaming original")?;
+    std::fs::rename(&new_path, path).context("renaming compacted file")?;
+    if count > 0 {
+        index::save_index(&index, &index_path, new_size, &first, &last)
+            .context("saving index")?;
+    }
+    Ok(Compacted { transactions: count, old_size, new_size })
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::fsck;
+    use crate::storage::testing;
+    use crate::transaction;
+
+    #[test]
+    fn works() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        testing::make_sample(
+            &path,
+            vec![vec![(util::p64(0), b"000")],
+                 vec![(util::p64(0), b"111"), (util::p64(1), b"aaa")],
+                 vec![(util::p64(1), b"bbb")],
+            ]).unwrap();
+
+        // Turn the first transaction into padding, as if it had been
+        // aborted.  Fix up the previous pointer that refered to it.
+        let mut file = std::fs::OpenOptions::new()
+            .read(true).write(true).open(&path).unwrap();
+        let first = scan::read_transaction(
+            &mut file, records::HEADER_SIZE, records::FORMAT_VERSION).unwrap();
+        util::seek(&mut file, first.pos).unwrap();
+        file.write_all(transaction::PADDING_MARKER).unwrap();
+        util::seek(&mut file, first.end() + 4
+                   + records::transaction_header_length(records::FORMAT_VERSION)
+                   + records::data_previous_offset(records::FORMAT_VERSION)).unwrap();
+        util::write_u64(&mut file, 0).unwrap();
+        drop(file);
+        assert!(fsck::check(&path).unwrap().ok());
+
+        let compacted = compact(&path).unwrap();
+        assert_eq!(compacted.transactions, 2);
+        assert_eq!(compacted.old_size - compacted.new_size, first.header.length);
+        verify_same(&(path.clone() + OLD_SUFFIX), &path).unwrap();
+
+        let report = fsck::check(&path).unwrap();
+        assert_eq!(report.problems, vec![]);
+        assert_eq!(report.transactions, 2);
+        assert_eq!(report.records, 3);
+
+        // The original isn't overwritten by another compaction:
+        let old = std::fs::read(path.clone() + OLD_SUFFIX).unwrap();
+        assert_eq!(compact(&path).unwrap_err().to_string(),
+                   format!("{}.old exists, move or remove it first", path));
+        assert_eq!(std::fs::read(path.clone() + OLD_SUFFIX).unwrap(), old);
+    }
+
+    fn data_headers(path: &str) -> Vec<(u64, records::DataHeader)> {
+        let mut it = scan::TransactionIterator::open(path).unwrap();
+        let mut headers = vec![];
+        while let Some(record) = it.next() {
+            headers.extend(record.unwrap().data_headers(it.reader()).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn upgrades_version_1_files() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        testing::make_sample(
+            &path,
+            vec![vec![(util::p64(0), b"000")],
+                 vec![(util::p64(0), b"111"), (util::p64(1), b"aaa")],
+            ]).unwrap();
+
+        testing::make_version_1(&path).unwrap();
+        assert!(fsck::check(&path).unwrap().ok());
+        assert!(data_headers(&path).iter().all(| (_, dh) | dh.checksum == 0));
+
+        compact(&path).unwrap();
+        let header = records::FileHeader::read(&mut std::fs::File::open(&path).unwrap())
+            .unwrap();
+        assert_eq!(header.version, records::FORMAT_VERSION);
+        let headers = data_headers(&path);
+        assert_eq!(headers.len(), 3);
+        assert!(headers.iter().all(| (_, dh) | dh.checksum != 0));
+        let mut it = scan::TransactionIterator::open(&path).unwrap();
+        while let Some(record) = it.next() {
+            let record = record.unwrap();
+            assert_ne!(record.header.checksum, 0);
+            assert_eq!(record.checksum(it.reader()).unwrap(), record.header.checksum);
+        }
+        assert!(fsck::check(&path).unwrap().ok());
+    }
+}
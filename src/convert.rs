@@ -0,0 +1,227 @@
+// Conversion of legacy little-endian data files
+//
+// Early versions of this crate wrote file, transaction and data
+// headers with little-endian integers.  The layout was otherwise the
+// same.  Tids and oids are byte strings and are copied unchanged.
+use std::io::prelude::*;
+
+use anyhow::{anyhow, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::compact;
+use crate::index;
+use crate::records;
+use crate::storage;
+use crate::transaction;
+use crate::util;
+
+pub const CONVERT_SUFFIX: &str = ".converted";
+
+/// Return whether a data file is in the legacy little-endian format.
+pub fn is_little_endian(path: &str) -> Result<bool> {
+    let mut file = std::fs::File::open(path).context("opening data file")?;
+    records::read_version(&mut file).context("reading header marker")?;
+    let buf = util::read8(&mut file)?;
+    if (&buf[..]).read_u64::<LittleEndian>()? == records::HEADER_SIZE {
+        Ok(true)
+    }
+    else if util::read_u64(&mut &buf[..])? == records::HEADER_SIZE {
+        Ok(false)
+    }
+    else {
+        Err(anyhow!("Bad header length in {}", path))
+    }
+}
+
+fn read_u16(r: &mut dyn Read) -> std::io::Result<u16> {
+    r.read_u16::<LittleEndian>()
+}
+
+fn read_u32(r: &mut dyn Read) -> std::io::Result<u32> {
+    r.read_u32::<LittleEndian>()
+}
+
+fn read_u64(r: &mut dyn Read) -> std::io::Result<u64> {
+    r.read_u64::<LittleEndian>()
+}
+
+/// Write a big-endian copy of a little-endian data file.
+///
+/// Returns the number of transaction (and padding) records converted.
+pub fn convert(path: &str, new_path: &str) -> Result<u64> {
+    let file = std::fs::File::open(path).context("opening data file")?;
+    let size = file.metadata()?.len();
+    let mut reader = std::io::BufReader::new(file);
+    let mut out = std::io::BufWriter::new(
+        std::fs::OpenOptions::new()
+            .write(true).create_new(true).open(new_path)
+            .context("creating new file")?);
+
+    // File header.  The padding between the previous-file name and
+    // the trailing length is copied as is.
+    let version = records::read_version(&mut reader)?;
+    util::io_assert(read_u64(&mut reader)? == records::HEADER_SIZE,
+                    "Not a little-endian data file")?;
+    let alignment = read_u64(&mut reader)?;
+    let lprevious = read_u16(&mut reader)?;
+    out.write_all(records::marker(version))?;
+    util::write_u64(&mut out, records::HEADER_SIZE)?;
+    util::write_u64(&mut out, alignment)?;
+    util::write_u16(&mut out, lprevious)?;
+    let rest = util::read_sized(&mut reader, records::HEADER_SIZE as usize - 22)?;
+    util::io_assert((&rest[rest.len() - 8..]).read_u64::<LittleEndian>()? ==
+                    records::HEADER_SIZE, "Bad header extra length")?;
+    out.write_all(&rest[.. rest.len() - 8])?;
+    util::write_u64(&mut out, records::HEADER_SIZE)?;
+
+    let mut count = 0u64;
+    let mut pos = records::HEADER_SIZE;
+    while pos < size {
+        let marker = util::read4(&mut reader)?;
+        if marker != storage::TRANSACTION_MARKER &&
+            marker != transaction::PADDING_MARKER {
+                return Err(anyhow!("Bad record marker {:?} at {}", marker, pos));
+            }
+        let length = read_u64(&mut reader)?;
+        let tid = util::read8(&mut reader)?;
+        let ndata = read_u32(&mut reader)?;
+        let luser = read_u16(&mut reader)?;
+        let ldesc = read_u16(&mut reader)?;
+        let lext = read_u32(&mut reader)?;
+        out.write_all(&marker)?;
+        util::write_u64(&mut out, length)?;
+        out.write_all(&tid)?;
+        util::write_u32(&mut out, ndata)?;
+        util::write_u16(&mut out, luser)?;
+        util::write_u16(&mut out, ldesc)?;
+        util::write_u32(&mut out, lext)?;
+        let meta = util::read_sized(
+            &mut reader, luser as usize + ldesc as usize + lext as usize)?;
+        out.write_all(&meta)?;
+        for _ in 0 .. ndata {
+            let dlen = read_u32(&mut reader)?;
+            let oid = util::read8(&mut reader)?;
+            let dtid = util::read8(&mut reader)?;
+            let previous = read_u64(&mut reader)?;
+            let offset = read_u64(&mut reader)?;
+            util::write_u32(&mut out, dlen)?;
+            out.write_all(&oid)?;
+            out.write_all(&dtid)?;
+            util::write_u64(&mut out, previous)?;
+            util::write_u64(&mut out, offset)?;
+            let data = util::read_sized(&mut reader, dlen as usize)?;
+            out.write_all(&data)?;
+        }
+        util::io_assert(read_u64(&mut reader)? == length,
+                        &format!("Bad redundant length at {}", pos))?;
+        util::write_u64(&mut out, length)?;
+        pos += length;
+        count += 1;
+    }
+    let file = out.into_inner().map_err(| e | anyhow!("flushing: {}", e))?;
+    file.sync_all().context("fsync")?;
+    Ok(count)
+}
+
+/// Convert a legacy data file in place, keeping the original with an
+/// ".old" suffix.  Returns None if the file didn't need converting.
+/// Fails if the storage is in use.
+pub fn convert_in_place(path: &str) -> Result<Option<u64>> {
+    let _locked = storage::lock_data_path(path).context("locking data file")?;
+    if ! is_little_endian(path)? {
+        return Ok(None);
+    }
+    let new_path = String::from(path) + CONVERT_SUFFIX;
+    if std::path::Path::new(&new_path).exists() {
+        std::fs::remove_file(&new_path).context("removing old converted file")?;
+    }
+    let count = convert(path, &new_path)?;
+
+    // Any index was written by the old code too, and would be misread.
+    let index_path = String::from(path) + storage::INDEX_SUFFIX;
+    index::remove_index(&index_path).context("removing index")?;
+    std::fs::rename(path, String::from(path) + compact::OLD_SUFFIX)
+        .context("renaming original")?;
+    std::fs::rename(&new_path, path).context("renaming converted file")?;
+    Ok(Some(count))
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use byteorder::{BigEndian, WriteBytesExt};
+    use crate::fsck;
+    use crate::scan;
+    use crate::storage::testing;
+
+    // Make a little-endian copy of a (big-endian) data file, the hard way.
+    fn to_little_endian(path: &str, new_path: &str) {
+        let mut data = std::fs::read(path).unwrap();
+        let swap = | data: &mut Vec<u8>, pos: usize, size: usize | {
+            data[pos .. pos + size].reverse();
+        };
+        swap(&mut data, 4, 8);
+        swap(&mut data, 12, 8);
+        swap(&mut data, 20, 2);
+        swap(&mut data, 4088, 8);
+        let mut it = scan::TransactionIterator::open(path).unwrap().with_padding();
+        while let Some(record) = it.next() {
+            let record = record.unwrap();
+            let pos = record.pos as usize;
+            swap(&mut data, pos + 4, 8);
+            swap(&mut data, pos + 20, 4);
+            swap(&mut data, pos + 24, 2);
+            swap(&mut data, pos + 26, 2);
+            swap(&mut data, pos + 28, 4);
+            for (dpos, _) in record.data_headers(it.reader()).unwrap() {
+                let dpos = dpos as usize;
+                swap(&mut data, dpos, 4);
+                swap(&mut data, dpos + 20, 8);
+                swap(&mut data, dpos + 28, 8);
+            }
+            swap(&mut data, record.end() as usize - 8, 8);
+        }
+        std::fs::write(new_path, data).unwrap();
+    }
+
+    #[test]
+    fn works() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        let legacy = util::test::test_path(&tmpdir, "legacy.fs");
+        testing::make_sample(
+            &path,
+            vec![vec![(util::p64(0), b"000")],
+                 vec![(util::p64(0), b"111"), (util::p64(1), b"aaa")],
+            ]).unwrap();
+        // Legacy files predate checksums:
+        testing::make_version_1(&path).unwrap();
+        to_little_endian(&path, &legacy);
+
+        assert!(! is_little_endian(&path).unwrap());
+        assert!(is_little_endian(&legacy).unwrap());
+        assert!(! fsck::check(&legacy).unwrap().ok());
+
+        assert_eq!(convert_in_place(&legacy).unwrap(), Some(2));
+        assert_eq!(std::fs::read(&legacy).unwrap(), std::fs::read(&path).unwrap());
+        assert_eq!(convert_in_place(&legacy).unwrap(), None);
+    }
+
+    #[test]
+    fn open_refuses_legacy_files() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        let mut data = vec![0u8; 0];
+        data.extend_from_slice(records::HEADER_MARKER);
+        data.write_u64::<LittleEndian>(records::HEADER_SIZE).unwrap();
+        data.write_u64::<BigEndian>(0).unwrap();
+        std::fs::write(&path, data).unwrap();
+
+        let err = records::FileHeader::read(
+            &mut std::fs::File::open(&path).unwrap()).err().unwrap();
+        assert!(err.to_string().contains("little-endian"));
+    }
+}
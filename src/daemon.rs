@@ -0,0 +1,117 @@
+// Running in the background, for classic init systems
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{anyhow, Context, Result};
+
+/// Fork into the background, detached from the terminal.
+///
+/// The parent process exits, and the child carries on, in a new
+/// session, with standard input from /dev/null.  Standard output and
+/// error go to /dev/null too, unless they've been redirected to a log
+/// file.  This must be called before starting any threads, since only
+/// the calling thread survives a fork.  The working directory is left
+/// alone, so relative paths still work.
+pub fn daemonize(keep_output: bool) -> Result<()> {
+    fork()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(std::io::Error::last_os_error()).context("setsid");
+    }
+    // Again, so we're not a session leader and can't reacquire a
+    // terminal.
+    fork()?;
+    let null = std::fs::OpenOptions::new().read(true).write(true).open("/dev/null")
+        .context("opening /dev/null")?;
+    redirect(&null, &[0])?;
+    if ! keep_output {
+        redirect(&null, &[1, 2])?;
+    }
+    Ok(())
+}
+
+// Fork, with the parent exiting.
+fn fork() -> Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()).context("fork"),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+fn redirect(file: &std::fs::File, fds: &[i32]) -> Result<()> {
+    for fd in fds {
+        if unsafe { libc::dup2(file.as_raw_fd(), *fd) } < 0 {
+            return Err(std::io::Error::last_os_error()).context("redirecting output");
+        }
+    }
+    Ok(())
+}
+
+/// Send standard output and error, and so logs, to the end of a file.
+pub fn log_to(path: &str) -> Result<()> {
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(path)
+        .with_context(|| format!("opening log file {}", path))?;
+    redirect(&file, &[1, 2])
+}
+
+/// A file containing the server's process id, removed when dropped
+pub struct Pidfile {
+    path: String,
+}
+
+impl Pidfile {
+
+    /// Write the current process id to path.  Fails if the file names
+    /// a process that's still running, presumably another server.
+    pub fn create(path: &str) -> Result<Pidfile> {
+        if let Ok(old) = std::fs::read_to_string(path) {
+            if let Ok(pid) = old.trim().parse::<libc::pid_t>() {
+                if pid > 0 && unsafe { libc::kill(pid, 0) } == 0 {
+                    return Err(anyhow!("{} names a running process, {}", path, pid));
+                }
+            }
+        }
+        let mut file = std::fs::File::create(path)
+            .with_context(|| format!("creating pidfile {}", path))?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Pidfile { path: path.to_string() })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::util;
+
+    #[test]
+    fn pidfiles() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "byteserver.pid");
+        {
+            let _pidfile = Pidfile::create(&path).unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap(),
+                       format!("{}\n", std::process::id()));
+
+            // We're running, so another server can't use the file.
+            assert!(Pidfile::create(&path).is_err());
+        }
+        assert!(! std::path::Path::new(&path).exists());
+
+        // Files naming dead processes, or garbage, are replaced.
+        for old in ["999999999\n", "nonsense"] {
+            std::fs::write(&path, old).unwrap();
+            let _pidfile = Pidfile::create(&path).unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap(),
+                       format!("{}\n", std::process::id()));
+        }
+    }
+}
@@ -0,0 +1,234 @@
+// Zstd dictionaries for compressing data records
+//
+// Small records, such as typical pickles, compress poorly on their
+// own, but well with a dictionary trained from records like them.
+// Dictionaries are trained offline, from a sample of a data file's
+// records, and kept in a file next to it, named with
+// DICTIONARIES_SUFFIX, which holds each dictionary, oldest first,
+// preceded by its length, as a 4-byte big-endian integer.
+//
+// New records are compressed with the newest dictionary.  Older ones
+// are kept, as records compressed with them can't be read without
+// them.  Zstd records the id of the dictionary data were compressed
+// with in their frame, so compressed records name the dictionary
+// they need.
+use std::io::prelude::*;
+
+use anyhow::{anyhow, Context, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::records;
+use crate::scan;
+use crate::util;
+
+pub const DICTIONARIES_SUFFIX: &str = ".dictionaries";
+
+/// The most records sampled to train a dictionary, by default
+pub const DEFAULT_SAMPLE: usize = 10_000;
+
+/// The biggest dictionary trained, by default, which is zstd's
+pub const DEFAULT_SIZE: usize = 110 * 1024;
+
+// Bigger records compress well enough without a dictionary, and
+// would crowd out the small ones dictionaries are for.
+const MAX_SAMPLE_SIZE: u64 = 1 << 16;
+
+/// The path of the dictionaries file of the data file at path
+pub fn file_path(path: &str) -> String {
+    path.to_string() + DICTIONARIES_SUFFIX
+}
+
+/// A data file's dictionaries, prepared for compressing and
+/// decompressing
+#[derive(Default)]
+pub struct Dictionaries {
+    // Ids, oldest first
+    ids: Vec<u32>,
+    decoders: std::collections::HashMap<u32, zstd::dict::DecoderDictionary<'static>>,
+    // For the newest dictionary
+    encoder: Option<zstd::dict::EncoderDictionary<'static>>,
+}
+
+impl Dictionaries {
+
+    /// Load the dictionaries of the data file at path.  A data file
+    /// without a dictionaries file has none.
+    pub fn load(path: &str) -> std::io::Result<Dictionaries> {
+        Dictionaries::new(&read(&file_path(path))?)
+    }
+
+    fn new(dictionaries: &[Vec<u8>]) -> std::io::Result<Dictionaries> {
+        let mut prepared = Dictionaries::default();
+        for dictionary in dictionaries {
+            let id = id(dictionary)?;
+            prepared.ids.push(id);
+            prepared.decoders.insert(id, zstd::dict::DecoderDictionary::copy(dictionary));
+        }
+        prepared.encoder = dictionaries.last().map(
+            | dictionary | zstd::dict::EncoderDictionary::copy(
+                dictionary, records::COMPRESSION_LEVEL));
+        Ok(prepared)
+    }
+
+    /// The dictionaries' ids, oldest first
+    pub fn ids(&self) -> &[u32] {
+        &self.ids
+    }
+
+    /// The dictionary new records are compressed with, if any
+    pub fn encoder(&self) -> Option<&zstd::dict::EncoderDictionary<'static>> {
+        self.encoder.as_ref()
+    }
+
+    /// The dictionary with an id, which records compressed with it
+    /// can't be read without.
+    pub fn decoder(&self, id: u32) -> std::io::Result<&zstd::dict::DecoderDictionary<'static>> {
+        self.decoders.get(&id).ok_or_else(|| util::io_error(&format!(
+            "Data were compressed with dictionary {}, which isn't in the \
+             dictionaries file", id)))
+    }
+}
+
+// A dictionary's id, which zstd chooses when it's trained
+fn id(dictionary: &[u8]) -> std::io::Result<u32> {
+    zstd::zstd_safe::get_dict_id_from_dict(dictionary).map(| id | id.get())
+        .ok_or_else(|| util::io_error("Invalid dictionary"))
+}
+
+// Read the dictionaries in a dictionaries file, oldest first.
+fn read(path: &str) -> std::io::Result<Vec<Vec<u8>>> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let mut reader = &data[..];
+    let mut dictionaries = vec![];
+    while ! reader.is_empty() {
+        let length = reader.read_u32::<BigEndian>()? as usize;
+        dictionaries.push(util::read_sized(&mut reader, length)?);
+    }
+    Ok(dictionaries)
+}
+
+// Write a dictionaries file atomically, like an index.
+fn write(path: &str, dictionaries: &[Vec<u8>]) -> std::io::Result<()> {
+    let tmp_path = path.to_string() + crate::index::TMP_SUFFIX;
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+    for dictionary in dictionaries {
+        writer.write_u32::<BigEndian>(dictionary.len() as u32)?;
+        writer.write_all(dictionary)?;
+    }
+    writer.into_inner().map_err(| err | err.into_error())?.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// A newly-trained dictionary
+#[derive(Debug)]
+pub struct Trained {
+    pub id: u32,
+    pub size: usize,
+    pub samples: usize,
+}
+
+/// Train a dictionary of at most size bytes from at most sample
+/// records of the data file at path, spread through the file, and
+/// add it to the file's dictionaries, so servers that open the file
+/// from now on compress new records with it, if they compress
+/// records.  Records bigger than 64 KiB aren't sampled.
+pub fn train(path: &str, sample: usize, size: usize) -> Result<Trained> {
+    let dictionaries_path = file_path(path);
+    let mut dictionaries = read(&dictionaries_path).context("reading dictionaries")?;
+    let prepared = Dictionaries::new(&dictionaries).context("reading dictionaries")?;
+    let sampled = | header: &records::DataHeader |
+        header.length > 0 && header.length <= MAX_SAMPLE_SIZE;
+
+    let mut it = scan::TransactionIterator::open(path)?;
+    let mut count = 0u64;
+    while let Some(record) = it.next() {
+        let headers = record?.data_headers(it.reader())?;
+        count += headers.iter().filter(| (_, header) | sampled(header)).count() as u64;
+    }
+    let every = std::cmp::max(1, count / std::cmp::max(1, sample as u64));
+
+    let mut it = scan::TransactionIterator::open(path)?;
+    let version = it.version();
+    let mut samples = vec![];
+    let mut seen = 0u64;
+    while let Some(record) = it.next() {
+        for (pos, header) in record?.data_headers(it.reader())? {
+            if sampled(&header) {
+                if seen.is_multiple_of(every) && samples.len() < sample {
+                    samples.push(scan::read_data(it.reader(), pos, &header, version,
+                                                 &prepared)?);
+                }
+                seen += 1;
+            }
+        }
+    }
+    if samples.is_empty() {
+        return Err(anyhow!("{} has no records to train a dictionary from", path));
+    }
+
+    let dictionary = zstd::dict::from_samples(&samples, size)
+        .with_context(|| format!("training a dictionary from {} records", samples.len()))?;
+    let id = id(&dictionary)?;
+    if prepared.ids.contains(&id) {
+        return Err(anyhow!("{} already has a dictionary with the new one's id, {}",
+                           dictionaries_path, id));
+    }
+    let trained = Trained { id, size: dictionary.len(), samples: samples.len() };
+    dictionaries.push(dictionary);
+    write(&dictionaries_path, &dictionaries).context("writing dictionaries")?;
+    Ok(trained)
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::storage::testing;
+
+    #[test]
+    fn train() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        let records: Vec<Vec<u8>> = (0 .. 1000u64).map(
+            | i | format!("cmyapp.models\nPerson\nq\x01)q\x02}}q\x03(X\x04name\
+                           q\x04X\x05p{:04}q\x05X\x03ageq\x06K{}u.", i, i % 90)
+                .into_bytes()).collect();
+        testing::make_sample(
+            &path, records.chunks(100).enumerate().map(
+                | (t, chunk) | chunk.iter().enumerate().map(
+                    | (i, data) | (util::p64((t * 100 + i) as u64), &data[..]))
+                    .collect())
+                .collect()).unwrap();
+        assert_eq!(Dictionaries::load(&path).unwrap().ids(), &[] as &[u32]);
+
+        let trained = super::train(&path, 500, 4096).unwrap();
+        assert_eq!(trained.samples, 500);
+        assert!(trained.size <= 4096);
+        let dictionaries = Dictionaries::load(&path).unwrap();
+        assert_eq!(dictionaries.ids(), &[trained.id]);
+
+        // Small records are made smaller, and name their dictionary:
+        let data = &records[42];
+        assert_eq!(records::compress(data, &Dictionaries::default()).unwrap(), None);
+        let compressed = records::compress(data, &dictionaries).unwrap().unwrap();
+        assert!(compressed.len() < data.len() / 2);
+        assert_eq!(records::decompress(&compressed, &dictionaries).unwrap(), *data);
+        assert!(records::decompress(&compressed, &Dictionaries::default()).is_err());
+
+        // Later dictionaries are added, and used for new records,
+        // and earlier ones are kept, for old records:
+        let newer = super::train(&path, 100, 2048).unwrap();
+        let dictionaries = Dictionaries::load(&path).unwrap();
+        assert_eq!(dictionaries.ids(), &[trained.id, newer.id]);
+        assert_eq!(records::decompress(&compressed, &dictionaries).unwrap(), *data);
+        let recompressed = records::compress(data, &dictionaries).unwrap().unwrap();
+        assert_eq!(zstd::zstd_safe::get_dict_id_from_frame(&recompressed).unwrap().get(),
+                   newer.id);
+    }
+}
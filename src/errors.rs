@@ -1,6 +1,141 @@
+// ZODB exceptions reported to clients
+//
+// Error responses have the exception name and a tuple of arguments,
+// ending with a correlation id.
+use anyhow::{Context, Result};
 
-#[derive(thiserror::Error, Debug)]
+use crate::msg;
+use crate::msgmacros::*;
+use crate::util;
+use crate::writer;
+
+#[derive(thiserror::Error, Debug, Clone)]
 pub enum POSError {
+    /// Arguments: (oid,)
     #[error("ZODB.POSException.POSKeyError")]
     Key([u8;8]),
+    /// Arguments: (oid,)
+    #[error("ZODB.POSException.ReadConflictError")]
+    ReadConflict(util::Oid),
+    /// Arguments: (oid, (committed, serial))
+    #[error("ZODB.POSException.ConflictError")]
+    Conflict { oid: util::Oid, committed: util::Tid, serial: util::Tid },
+    /// Arguments: (message,)
+    #[error("ZODB.POSException.StorageTransactionError")]
+    StorageTransaction(String),
+    /// Arguments: ()
+    #[error("ZODB.POSException.ReadOnlyError")]
+    ReadOnly,
+    /// Arguments: (message,)
+    #[error("ZODB.POSException.StorageError")]
+    Storage(String),
+    /// Arguments: (message,)
+    #[error("ZEO.Exceptions.AuthError")]
+    Auth(String),
+    /// Arguments: (message,)
+    #[error("ZODB.POSException.TransientError")]
+    Transient(String),
+    /// A transaction that exceeded a storage's per-transaction
+    /// limits.  Arguments: (message,)
+    #[error("ZODB.POSException.TransactionTooLargeError")]
+    TooLarge(String),
+    /// Data that don't match their checksum, reported to clients as
+    /// a StorageError.  Arguments: (message,)
+    #[error("ZODB.POSException.StorageError")]
+    Corrupted(String),
+}
+
+impl POSError {
+
+    /// Convert an internal error.  Errors that aren't already
+    /// POSErrors, or io errors wrapping them, become StorageErrors.
+    pub fn from_error(err: anyhow::Error) -> POSError {
+        match err.downcast::<POSError>() {
+            Ok(err) => err,
+            Err(err) => match err.downcast_ref::<std::io::Error>()
+                .and_then(| err | err.get_ref())
+                .and_then(| err | err.downcast_ref::<POSError>()) {
+                    Some(err) => err.clone(),
+                    None => POSError::Storage(format!("{:#}", err)),
+                },
+        }
+    }
+
+    /// Log the error and encode an error response for request id
+    /// on a connection.
+    pub fn response(&self, connection: u64, id: i64) -> Result<Vec<u8>> {
+        let name = self.to_string();
+        let cid = writer::correlation_id(connection, id);
+        Ok(match self {
+            POSError::Key(oid) | POSError::ReadConflict(oid) => {
+                tracing::info!("[{}] {} {}", cid, name, util::hex(oid));
+                error_response!(id, (name, (msg::bytes(oid), cid)))
+            },
+            POSError::Conflict { oid, committed, serial } => {
+                tracing::info!("[{}] {} {}", cid, name, util::hex(oid));
+                error_response!(
+                    id, (name, (msg::bytes(oid),
+                                (msg::bytes(committed), msg::bytes(serial)),
+                                cid)))
+            },
+            POSError::StorageTransaction(message) | POSError::Storage(message) |
+            POSError::Auth(message) => {
+                tracing::error!("[{}] {}: {}", cid, name, message);
+                error_response!(id, (name, (message, cid)))
+            },
+            POSError::Corrupted(message) => {
+                tracing::error!("[{}] Data file corruption: {}", cid, message);
+                error_response!(id, (name, (message, cid)))
+            },
+            POSError::Transient(message) | POSError::TooLarge(message) => {
+                tracing::warn!("[{}] {}: {}", cid, name, message);
+                error_response!(id, (name, (message, cid)))
+            },
+            POSError::ReadOnly => {
+                tracing::info!("[{}] {}", cid, name);
+                error_response!(id, (name, (cid,)))
+            },
+        })
+    }
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn internal_errors_become_storage_errors() {
+        let err = POSError::from_error(
+            anyhow::Error::new(POSError::ReadOnly).context("locking"));
+        assert!(matches!(err, POSError::ReadOnly));
+        let err = POSError::from_error(
+            anyhow::anyhow!("disk full").context("writing tmp"));
+        match err {
+            POSError::Storage(message) => assert_eq!(message, "writing tmp: disk full"),
+            _ => panic!("expected a storage error"),
+        }
+        let err = POSError::from_error(
+            anyhow::Error::new(std::io::Error::other(POSError::TooLarge("big".into())))
+                .context("save"));
+        assert!(matches!(err, POSError::TooLarge(message) if message == "big"));
+    }
+
+    #[test]
+    fn responses() {
+        let response = POSError::Conflict {
+            oid: util::p64(1), committed: util::p64(3), serial: util::p64(2) }
+            .response(7, 42).unwrap();
+        type Bytes = serde::bytes::ByteBuf;
+        type Payload = (Bytes, (Bytes, Bytes), String);
+        let (id, code, (name, (oid, (committed, serial), cid))):
+        (i64, String, (String, Payload)) =
+            decode!(&mut &response[4..], "decoding").unwrap();
+        assert_eq!((id, &code as &str, &name as &str, &cid as &str),
+                   (42, "E", "ZODB.POSException.ConflictError", "7.42"));
+        assert_eq!((&*oid, &*committed, &*serial),
+                   (&util::p64(1)[..], &util::p64(3)[..], &util::p64(2)[..]));
+    }
 }
@@ -0,0 +1,639 @@
+// Offline data-file checking and repair
+use std::io::prelude::*;
+
+use anyhow::{Context, Result};
+
+use crate::index;
+use crate::records;
+use crate::storage;
+use crate::transaction;
+use crate::util;
+
+pub const QUARANTINE_SUFFIX: &str = ".quarantine";
+
+// marker + transaction header + redundant length
+fn min_transaction_length(version: u32) -> u64 {
+    4 + records::transaction_header_length(version) + 8
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    pub pos: u64,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub size: u64,
+    pub transactions: u64,
+    pub records: u64,
+    pub problems: Vec<Problem>,
+
+    // Where a record couldn't be framed, if anywhere.  Everything
+    // from here on is unusable.
+    pub tail: Option<u64>,
+
+    // (pos, length) of transactions whose framing is intact but whose
+    // contents are bad.
+    pub bad: Vec<(u64, u64)>,
+
+    // (field pos, value) previous-pointer fixes needed if bad
+    // transactions are excised.
+    previous_fixes: Vec<(u64, u64)>,
+
+    // The index computed from the good transactions.
+    pub index: index::Index,
+    pub first_tid: util::Tid,
+    pub last_tid: util::Tid,
+}
+
+impl Report {
+    pub fn ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    // End of the usable part of the file
+    pub fn end(&self) -> u64 {
+        self.tail.unwrap_or(self.size)
+    }
+
+    fn problem(&mut self, pos: u64, message: String) {
+        self.problems.push(Problem { pos, message });
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RepairOptions {
+    pub truncate: bool,
+    pub rebuild_index: bool,
+    pub quarantine: bool,
+}
+
+pub fn check(path: &str) -> Result<Report> {
+    let file = std::fs::File::open(path).context("opening data file")?;
+    let size = file.metadata()?.len();
+    let mut problems = vec![];
+    let saved_index = load_saved_index(path, &mut problems);
+    let mut report = check_file(
+        file, size, saved_index.as_ref().map(| (index, segment_size, _, end) | {
+            (index, *segment_size, *end)
+        }))?;
+    problems.append(&mut report.problems);
+    report.problems = problems;
+    Ok(report)
+}
+
+/// Check the first size bytes of a data file, and, if given, an
+/// index of them, with the index's segment size and end tid.
+pub fn check_file(file: std::fs::File, size: u64,
+                  expected: Option<(&index::Index, u64, util::Tid)>)
+                  -> Result<Report> {
+    let mut reader = std::io::BufReader::new(file);
+
+    let mut report = Report {
+        size, transactions: 0, records: 0, problems: vec![],
+        tail: None, bad: vec![], previous_fixes: vec![],
+        index: index::Index::new(), first_tid: util::Z64, last_tid: util::Z64,
+    };
+
+    let version = match records::FileHeader::read(&mut reader) {
+        Ok(header) => header.version,
+        Err(err) => {
+            report.problem(0, format!("Bad file header: {}", err));
+            report.tail = Some(0);
+            return Ok(report);
+        },
+    };
+
+    let min_transaction_length = min_transaction_length(version);
+    let mut pos = records::HEADER_SIZE;
+    if let Some((index, segment_size, end)) = expected {
+        if segment_size == pos {
+            compare_index(&mut report, index, &end, pos);
+        }
+    }
+    while pos < size {
+        if size - pos < min_transaction_length {
+            report.problem(pos, format!("Truncated record, {} bytes", size - pos));
+            report.tail = Some(pos);
+            break;
+        }
+        util::seek(&mut reader, pos)?;
+        let marker = util::read4(&mut reader)?;
+        let (_, length) = records::split_length(util::read_u64(&mut reader)?, version);
+        let tid = util::read8(&mut reader)?;
+        if marker != storage::TRANSACTION_MARKER &&
+            marker != transaction::PADDING_MARKER {
+                report.problem(pos, format!("Bad record marker {:?}", marker));
+                report.tail = Some(pos);
+                break;
+            }
+        if length < min_transaction_length || length > size - pos {
+            report.problem(pos, format!("Bad record length {}", length));
+            report.tail = Some(pos);
+            break;
+        }
+        util::seek(&mut reader, pos + length - 8)?;
+        let redundant = util::read_u64(&mut reader)?;
+        if redundant != length {
+            report.problem(
+                pos + length - 8,
+                format!("Redundant length {} doesn't match {}",
+                        redundant, length));
+            report.tail = Some(pos);
+            break;
+        }
+        if pos == records::HEADER_SIZE {
+            report.first_tid = tid;
+        }
+
+        if marker == storage::TRANSACTION_MARKER {
+            match check_transaction(&mut reader, &mut report, pos, length, version)? {
+                Ok((tid, records)) => {
+                    for (oid, dpos) in records.iter() {
+                        report.index.insert(*oid, *dpos);
+                    }
+                    report.records += records.len() as u64;
+                    report.transactions += 1;
+                    report.last_tid = tid;
+                },
+                Err(problem) => {
+                    report.problems.push(problem);
+                    report.bad.push((pos, length));
+                }
+            }
+        }
+        pos += length;
+
+        if let Some((index, segment_size, end)) = expected {
+            if segment_size == pos {
+                compare_index(&mut report, index, &end, pos);
+            }
+        }
+    }
+
+    if let Some((_, segment_size, _)) = expected {
+        if segment_size > report.end() {
+            report.problem(
+                segment_size,
+                format!("Index segment size {} is past the end of the data",
+                        segment_size));
+        }
+    }
+
+    Ok(report)
+}
+
+type SavedIndex = (index::Index, u64, util::Tid, util::Tid);
+
+fn load_saved_index(path: &str, problems: &mut Vec<Problem>) -> Option<SavedIndex> {
+    let index_path = String::from(path) + storage::INDEX_SUFFIX;
+    if ! std::path::Path::new(&index_path).exists() {
+        return None;
+    }
+    match index::load_index(&index_path) {
+        Ok(saved) => Some(saved),
+        Err(err) => {
+            problems.push(Problem {
+                pos: 0, message: format!("Unreadable index file: {}", err) });
+            None
+        }
+    }
+}
+
+fn compare_index(report: &mut Report, saved_index: &index::Index, end: &util::Tid,
+                 pos: u64) {
+    if *end != report.last_tid {
+        report.problem(pos, format!("Index end tid {:?} doesn't match {:?}",
+                                    end, report.last_tid));
+    }
+    let mut mismatches: Vec<Problem> = vec![];
+    for (oid, dpos) in report.index.iter() {
+        if saved_index.get(oid) != Some(dpos) {
+            mismatches.push(Problem {
+                pos: *dpos,
+                message: format!("Index entry for {:?} is {:?}, should be {}",
+                                 oid, saved_index.get(oid), dpos),
+            });
+        }
+    }
+    for (oid, dpos) in saved_index.iter() {
+        if ! report.index.contains_key(oid) {
+            mismatches.push(Problem {
+                pos: *dpos,
+                message: format!("Index has extra entry for {:?}", oid),
+            });
+        }
+    }
+    report.problems.extend(mismatches);
+}
+
+type Checked = std::result::Result<(util::Tid, Vec<(util::Oid, u64)>), Problem>;
+
+// Check the contents of a transaction record whose framing has
+// already been checked.  I/O errors are returned in the outer
+// result, problems in the inner one.
+fn check_transaction<R: Read + Seek>(
+    reader: &mut R, report: &mut Report, pos: u64, length: u64, version: u32)
+    -> std::io::Result<Checked> {
+    let bad = | pos: u64, message: String | Ok(Err(Problem { pos, message }));
+
+    util::seek(reader, pos + 4)?;
+    let header = records::TransactionHeader::read(reader, version)?;
+    if header.id <= report.last_tid {
+        return bad(pos, format!("Transaction id {:?} isn't after {:?}",
+                                header.id, report.last_tid));
+    }
+    let end = pos + length - 8;
+    let mut dpos = pos + 4 + records::transaction_header_length(version) +
+        header.luser as u64 + header.ldesc as u64 + header.lext as u64;
+    if dpos > end {
+        return bad(pos, String::from("Transaction metadata overruns record"));
+    }
+    util::seek(reader, pos)?;
+    let mut checksum = records::TransactionChecksum::of_record(
+        &util::read_sized(reader, (dpos - pos) as usize)?, version);
+    let data_header_size = records::data_header_size(version);
+
+    let mut records: Vec<(util::Oid, u64)> = vec![];
+    let mut seen = std::collections::HashSet::new();
+    for _ in 0 .. header.ndata {
+        if dpos + data_header_size > end {
+            return bad(dpos, String::from("Data header overruns transaction"));
+        }
+        util::seek(reader, dpos)?;
+        let dh = records::DataHeader::read(reader, version)?;
+        checksum.add(&dh.id, dh.checksum);
+        if dpos + data_header_size + dh.length > end {
+            return bad(dpos, format!("Data length {} overruns transaction",
+                                     dh.length));
+        }
+        if dh.tid != header.id {
+            return bad(dpos, format!("Data tid {:?} doesn't match {:?}",
+                                     dh.tid, header.id));
+        }
+        if dh.offset != dpos - pos {
+            return bad(dpos, format!("Data offset {} should be {}",
+                                     dh.offset, dpos - pos));
+        }
+        if dh.checksum != 0 && records::read_checksum(reader, dh.length)? != dh.checksum {
+            return bad(dpos, format!("Data for {:?} don't match their checksum", dh.id));
+        }
+        if ! seen.insert(dh.id) {
+            return bad(dpos, format!("Duplicate record for {:?}", dh.id));
+        }
+        let expected = report.index.get(&dh.id).cloned().unwrap_or(0);
+        if dh.previous != expected {
+            let excised = report.bad.iter().any(
+                | &(bpos, blen) | dh.previous >= bpos && dh.previous < bpos + blen);
+            if ! excised {
+                return bad(dpos, format!("Previous pointer {} should be {}",
+                                         dh.previous, expected));
+            }
+            // The previous record is in a bad transaction. This is
+            // fixable if the bad transaction is excised.
+            report.problem(dpos, format!(
+                "Previous pointer {} refers to a bad transaction", dh.previous));
+            report.previous_fixes.push(
+                (dpos + records::data_previous_offset(version), expected));
+        }
+        records.push((dh.id, dpos));
+        dpos += data_header_size + dh.length;
+    }
+    if dpos != end {
+        return bad(dpos, format!("{} unaccounted bytes in transaction",
+                                 end - dpos));
+    }
+    if header.checksum != 0 && checksum.value() != header.checksum {
+        return bad(pos, String::from("Transaction doesn't match its checksum"));
+    }
+    Ok(Ok((header.id, records)))
+}
+
+/// Check a data file and repair the problems the options allow.
+///
+/// Returns a report from checking the file after repairs are made.
+/// Fails if the storage is in use.
+pub fn repair(path: &str, options: &RepairOptions) -> Result<Report> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true).write(true).open(path).context("opening data file")?;
+    storage::lock_data_file(&file, path).context("locking data file")?;
+    let report = check(path)?;
+    if report.ok() {
+        return Ok(report);
+    }
+
+    let mut quarantine = if options.quarantine {
+        Some(std::fs::OpenOptions::new()
+             .create(true).append(true)
+             .open(String::from(path) + QUARANTINE_SUFFIX)
+             .context("opening quarantine file")?)
+    }
+    else {
+        None
+    };
+
+    // Whether records the index may refer to were changed
+    let mut changed = false;
+
+    if let Some(ref mut qfile) = quarantine {
+        changed = ! report.bad.is_empty();
+        // Move bad transactions aside by copying them to the
+        // quarantine file and turning them into padding.
+        for &(pos, length) in report.bad.iter() {
+            util::seek(&mut file, pos)?;
+            std::io::copy(&mut (&file).take(length), qfile)
+                .context("copying to quarantine")?;
+            util::seek(&mut file, pos)?;
+            file.write_all(transaction::PADDING_MARKER)?;
+        }
+        for &(fpos, previous) in report.previous_fixes.iter() {
+            util::seek(&mut file, fpos)?;
+            util::write_u64(&mut file, previous)?;
+        }
+    }
+
+    if options.truncate {
+        if let Some(tail) = report.tail {
+            if tail >= records::HEADER_SIZE {
+                if let Some(ref mut qfile) = quarantine {
+                    util::seek(&mut file, tail)?;
+                    std::io::copy(&mut (&file).take(report.size - tail), qfile)
+                        .context("copying tail to quarantine")?;
+                }
+                file.set_len(tail).context("truncating")?;
+                changed = true;
+            }
+        }
+    }
+    file.sync_all().context("fsync")?;
+
+    if options.rebuild_index {
+        save_rebuilt_index(path)?;
+    }
+    else if changed {
+        // The saved index no longer matches.  Without it, the
+        // storage indexes the file when it's opened.
+        let index_path = String::from(path) + storage::INDEX_SUFFIX;
+        index::remove_index(&index_path).context("removing index")?;
+    }
+
+    check(path)
+}
+
+/// Replace the index file with one computed from the data file.
+///
+/// If the data file has an unreadable tail, the index file is just
+/// removed.  Fails if the storage is in use.
+pub fn rebuild_index(path: &str) -> Result<()> {
+    let _locked = storage::lock_data_path(path).context("locking data file")?;
+    save_rebuilt_index(path)
+}
+
+fn save_rebuilt_index(path: &str) -> Result<()> {
+    let index_path = String::from(path) + storage::INDEX_SUFFIX;
+    index::remove_index(&index_path).context("removing index")?;
+    let report = check(path)?;
+    if report.transactions > 0 && report.tail.is_none() {
+        index::save_index(&report.index, &index_path, report.size,
+                          &report.first_tid, &report.last_tid)
+            .context("saving index")?;
+    }
+    Ok(())
+}
+
+/// Check index-file entries against the data records they point to.
+///
+/// Every sample'th entry is checked, or every entry if sample is 1.
+/// Unlike check, this doesn't scan the data file, so it can't tell
+/// whether an entry points to an object's latest record, but it's
+/// much faster for large files.
+pub fn check_index(path: &str, sample: usize) -> Result<Vec<Problem>> {
+    let index_path = String::from(path) + storage::INDEX_SUFFIX;
+    let (index, segment_size, start, end) =
+        index::load_index(&index_path).context("loading index")?;
+    let mut file = std::fs::File::open(path).context("opening data file")?;
+    let size = file.metadata()?.len();
+    let version = records::FileHeader::read(&mut file).context("reading file header")?.version;
+    let mut problems = vec![];
+    let mut problem = | pos: u64, message: String | {
+        problems.push(Problem { pos, message })
+    };
+
+    if segment_size > size {
+        problem(segment_size, format!(
+            "Index segment size {} is past the end of the file", segment_size));
+        return Ok(problems);
+    }
+    if segment_size > records::HEADER_SIZE {
+        let mut reader = &file;
+        util::seek(&mut reader, records::HEADER_SIZE + 12)?;
+        if util::read8(&mut reader)? != start {
+            problem(records::HEADER_SIZE, String::from(
+                "Index start doesn't match the first transaction"));
+        }
+        match storage::committed_tid_before(&file, segment_size) {
+            Ok(tid) if tid == end => (),
+            Ok(_) => problem(segment_size, String::from(
+                "Index end doesn't match the last transaction")),
+            Err(err) => problem(segment_size, format!(
+                "Index segment size isn't at a record boundary: {}", err)),
+        }
+    }
+
+    let mut reader = std::io::BufReader::new(&file);
+    for (oid, pos) in index.iter().step_by(sample.max(1)) {
+        let pos = *pos;
+        if pos < records::HEADER_SIZE ||
+            pos + records::data_header_size(version) > segment_size {
+            problem(pos, format!("Index entry for {} is out of range",
+                                 util::hex(oid)));
+            continue;
+        }
+        util::seek(&mut reader, pos)?;
+        let header = records::DataHeader::read(&mut reader, version)?;
+        if &header.id != oid {
+            problem(pos, format!("Index entry for {} points to a record for {}",
+                                 util::hex(oid), util::hex(&header.id)));
+            continue;
+        }
+        if header.tid > end {
+            problem(pos, format!("Index entry for {} is after the index end",
+                                 util::hex(oid)));
+        }
+        let tpos = pos.saturating_sub(header.offset);
+        let marker = if tpos >= records::HEADER_SIZE {
+            util::seek(&mut reader, tpos)?;
+            Some((util::read4(&mut reader)?,
+                  records::split_length(util::read_u64(&mut reader)?, version).1,
+                  util::read8(&mut reader)?))
+        }
+        else {
+            None
+        };
+        match marker {
+            Some((marker, length, tid))
+                if marker == storage::TRANSACTION_MARKER &&
+                tid == header.tid && tpos + length > pos => (),
+            _ => problem(pos, format!(
+                "Index entry for {} isn't in a committed transaction",
+                util::hex(oid))),
+        }
+    }
+    Ok(problems)
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::storage::testing;
+
+    fn sample(path: &String) {
+        testing::make_sample(
+            path,
+            vec![vec![(util::p64(0), b"000")],
+                 vec![(util::p64(0), b"111"), (util::p64(1), b"aaa")],
+                 vec![(util::p64(1), b"bbb")],
+            ]).unwrap();
+    }
+
+    #[test]
+    fn check_good_file() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        sample(&path);
+
+        let report = check(&path).unwrap();
+        assert_eq!(report.problems, vec![]);
+        assert_eq!(report.transactions, 3);
+        assert_eq!(report.records, 4);
+        assert_eq!(report.index.len(), 2);
+    }
+
+    #[test]
+    fn repair_truncates_corrupt_tail() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        sample(&path);
+        let size = std::fs::metadata(&path).unwrap().len();
+        {
+            let mut file = std::fs::OpenOptions::new()
+                .append(true).open(&path).unwrap();
+            file.write_all(b"TTTT\0\0\0garbage").unwrap();
+        }
+
+        let report = check(&path).unwrap();
+        assert_eq!(report.tail, Some(size));
+        assert_eq!(report.problems.len(), 1);
+
+        let report = repair(
+            &path,
+            &RepairOptions { truncate: true, rebuild_index: true,
+                             quarantine: true }).unwrap();
+        assert!(report.ok());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+        assert_eq!(
+            std::fs::metadata(path.clone() + QUARANTINE_SUFFIX).unwrap().len(),
+            14);
+        assert_eq!(index::load_index(
+            &(path.clone() + storage::INDEX_SUFFIX)).unwrap().0, report.index);
+    }
+
+    #[test]
+    fn check_and_rebuild_index() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        sample(&path);
+        rebuild_index(&path).unwrap();
+        assert_eq!(check_index(&path, 1).unwrap(), vec![]);
+
+        // Point an entry at the wrong record:
+        let index_path = path.clone() + storage::INDEX_SUFFIX;
+        let (mut index, segment_size, start, end) =
+            index::load_index(&index_path).unwrap();
+        let pos1 = *index.get(&util::p64(1)).unwrap();
+        index.insert(util::p64(0), pos1);
+        index::save_index(&index, &index_path, segment_size, &start, &end)
+            .unwrap();
+
+        let problems = check_index(&path, 1).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].pos, pos1);
+        // The full check sees it too:
+        assert!(! check(&path).unwrap().ok());
+
+        rebuild_index(&path).unwrap();
+        assert_eq!(check_index(&path, 1).unwrap(), vec![]);
+        assert!(check(&path).unwrap().ok());
+    }
+
+    #[test]
+    fn repair_quarantines_bad_transaction() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        sample(&path);
+        let good = check(&path).unwrap();
+
+        // Clobber the tid in the first data record of the second
+        // transaction.
+        let second = *good.index.get(&util::p64(0)).unwrap();
+        {
+            let mut file = std::fs::OpenOptions::new()
+                .write(true).open(&path).unwrap();
+            util::seek(&mut file, second + records::data_tid_offset(records::FORMAT_VERSION))
+                .unwrap();
+            file.write_all(&util::Z64).unwrap();
+        }
+
+        let report = check(&path).unwrap();
+        assert_eq!(report.bad.len(), 1);
+        assert_eq!(report.transactions, 2);
+        // The third transaction points back into the bad one, and the
+        // index saved when the sample was closed doesn't match:
+        assert_eq!(report.problems.len(), 3);
+
+        let report = repair(
+            &path, &RepairOptions { quarantine: true, ..Default::default() })
+            .unwrap();
+        assert!(report.ok());
+        assert_eq!(report.transactions, 2);
+        assert_eq!(report.records, 2);
+    }
+
+    #[test]
+    fn check_finds_data_that_dont_match_their_checksums() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        sample(&path);
+        let second = *check(&path).unwrap().index.get(&util::p64(0)).unwrap();
+        {
+            let mut file = std::fs::OpenOptions::new()
+                .write(true).open(&path).unwrap();
+            util::seek(&mut file, second + records::data_header_size(records::FORMAT_VERSION))
+                .unwrap();
+            file.write_all(b"x").unwrap();
+        }
+
+        let report = check(&path).unwrap();
+        assert_eq!(report.bad.len(), 1);
+        assert!(report.problems[0].message.contains("checksum"), "{:?}", report.problems);
+
+        // Transaction checksums are checked too:
+        let path = util::test::test_path(&tmpdir, "data2.fs");
+        sample(&path);
+        {
+            let mut file = std::fs::OpenOptions::new()
+                .write(true).open(&path).unwrap();
+            util::seek(&mut file, records::HEADER_SIZE +
+                       records::transaction_checksum_offset(records::FORMAT_VERSION))
+                .unwrap();
+            util::write_u32(&mut file, 42).unwrap();
+        }
+        let report = check(&path).unwrap();
+        assert_eq!(report.bad, vec![(records::HEADER_SIZE, report.bad[0].1)]);
+        assert!(report.problems[0].message.contains("checksum"), "{:?}", report.problems);
+    }
+}
@@ -0,0 +1,146 @@
+// HTTP health and readiness checks, for container orchestrators
+//
+// ``/healthz`` succeeds whenever the server is running.  ``/readyz``
+// succeeds once storages are open, unless a storage's commits are
+// stuck, with a JSON body describing each storage.
+use std::io::prelude::*;
+
+use anyhow::{Context, Result};
+
+use crate::registry;
+use crate::util;
+
+/// How long a voted transaction can wait to be committed before
+/// commits are considered stuck, by default
+pub const DEFAULT_STALL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+pub struct Health {
+    // Set when storages are open
+    storages: std::sync::RwLock<Option<Vec<(String, registry::Storage)>>>,
+    stall_timeout: std::time::Duration,
+}
+
+impl Health {
+
+    /// Commits are considered stuck if a voted transaction has waited
+    /// longer than stall_timeout.
+    pub fn new(stall_timeout: std::time::Duration) -> std::sync::Arc<Health> {
+        std::sync::Arc::new(Health { storages: std::sync::RwLock::new(None), stall_timeout })
+    }
+
+    /// Storages are open, so the server is ready unless commits stall.
+    pub fn ready(&self, registry: &registry::Registry) {
+        *self.storages.write().unwrap() = Some(
+            registry.names().into_iter()
+                .filter_map(| name | registry.get(&name).map(| fs | (name, fs)))
+                .collect());
+    }
+
+    /// Answer requests, one at a time, until the listener fails.
+    pub fn serve(&self, listener: std::net::TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream.context("accepting health check")?;
+            if let Err(err) = self.answer(stream) {
+                tracing::warn!("Health check: {:#}", err);
+            }
+        }
+        Ok(())
+    }
+
+    fn answer(&self, mut stream: std::net::TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+        let mut request = String::new();
+        std::io::BufReader::new(&stream).read_line(&mut request)
+            .context("reading request")?;
+        let (status, body) = match request.split(' ').nth(1) {
+            Some("/healthz") => (200, "{\"status\": \"ok\"}".to_string()),
+            Some("/readyz") => self.readiness(),
+            _ => (404, "{\"status\": \"not found\"}".to_string()),
+        };
+        write!(stream,
+               "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\n\
+                Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+               status, if status == 200 { "OK" } else { "Error" }, body.len(), body)
+            .context("writing response")
+    }
+
+    // Returns a status code and JSON body.
+    fn readiness(&self) -> (u16, String) {
+        let storages = self.storages.read().unwrap();
+        let storages = match *storages {
+            Some(ref storages) => storages,
+            None => return (503, "{\"status\": \"starting\"}".to_string()),
+        };
+        let mut ready = true;
+        let descriptions: Vec<String> = storages.iter().map(| (name, fs) | {
+            let (votes, waited) = fs.commit_queue();
+            let waited = waited.unwrap_or_default();
+            let stalled = waited > self.stall_timeout;
+            ready &= ! stalled;
+            format!("{{\"name\": \"{}\", \"status\": \"{}\", \"last_tid\": \"{}\", \
+                     \"votes\": {}, \"oldest_vote_seconds\": {:.3}}}",
+                    name.replace('\\', "\\\\").replace('"', "\\\""),
+                    if stalled { "stalled" } else { "ok" },
+                    util::hex(&fs.last_transaction()), votes, waited.as_secs_f64())
+        }).collect();
+        (if ready { 200 } else { 503 },
+         format!("{{\"status\": \"{}\", \"storages\": [{}]}}",
+                 if ready { "ok" } else { "stalled" }, descriptions.join(", ")))
+    }
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::storage;
+
+    fn get(address: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn checks() {
+        let tmpdir = util::test::dir();
+        let health = Health::new(std::time::Duration::from_millis(50));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = health.clone();
+        std::thread::spawn(move || server.serve(listener));
+
+        assert!(get(address, "/healthz").starts_with("HTTP/1.1 200 OK\r\n"));
+        let response = get(address, "/readyz");
+        assert!(response.starts_with("HTTP/1.1 503 "));
+        assert!(response.ends_with("{\"status\": \"starting\"}"));
+        assert!(get(address, "/nonsense").starts_with("HTTP/1.1 404 "));
+
+        let mut registry = registry::Registry::new();
+        let fs = registry.open("1", &util::test::test_path(&tmpdir, "data.fs"),
+                               storage::Limits::default(), Default::default()).unwrap();
+        health.ready(&registry);
+        let response = get(address, "/readyz");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(
+            "{\"status\": \"ok\", \"storages\": [{\"name\": \"1\", \"status\": \"ok\", \
+             \"last_tid\": \"0000000000000000\", \"votes\": 0, \
+             \"oldest_vote_seconds\": 0.000}]}"));
+
+        // A transaction that votes and never finishes stalls commits:
+        let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+        trans.save(util::Z64, util::Z64, b"data").unwrap();
+        fs.lock(&trans, Box::new(| _ | ())).unwrap();
+        trans.locked().unwrap();
+        fs.stage(&mut trans).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        let response = get(address, "/readyz");
+        assert!(response.starts_with("HTTP/1.1 503 "));
+        assert!(response.contains("\"status\": \"stalled\""));
+        assert!(response.contains("\"votes\": 1"));
+    }
+}
@@ -1,19 +1,68 @@
 // File-storage index-file and mmap index
 use std::io::prelude::*;
 
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt};
 
 use crate::util;
 
 pub type Index = std::collections::btree_map::BTreeMap<util::Oid, u64>;
     
-static MAGIC: &'static [u8] = b"fs2i";
+// Index files start with a magic number and a format version.
+// Files written before index files had versions start with
+// V1_MAGIC, and are version 1.
+static MAGIC: &[u8] = b"fs3i";
+static V1_MAGIC: &[u8] = b"fs2i";
+/// The format version of index files written
+pub const INDEX_VERSION: u32 = 2;
+static JOURNAL_MAGIC: &[u8] = b"fs2j";
 
+/// Suffix of the journal of an index file's path
+pub const JOURNAL_SUFFIX: &str = ".journal";
+
+// Size of a journal entry's start, end, tid and change count
+const JOURNAL_ENTRY_HEADER_SIZE: usize = 28;
+
+pub fn journal_path(path: &str) -> String {
+    String::from(path) + JOURNAL_SUFFIX
+}
+
+/// The size of the index file saved for an index with the given
+/// number of objects.
+pub fn saved_size(objects: usize) -> u64 {
+    (MAGIC.len() + 36 + objects * 16) as u64
+}
+
+/// Remove an index file, and its journal, if they exist.
+pub fn remove_index(path: &str) -> std::io::Result<()> {
+    for path in [String::from(path), journal_path(path)] {
+        if std::path::Path::new(&path).exists() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Suffix of the temporary file an index is written to, before it's
+/// renamed over the index file
+pub const TMP_SUFFIX: &str = ".tmp";
+
+/// Save an index atomically: it's written to a temporary file, which
+/// is synced and then renamed over the index file, so a crash leaves
+/// either the old index or the new one.
 pub fn save_index(index: &Index, path: &str,
               segment_size: u64, start: &util::Tid, end: &util::Tid)
               -> std::io::Result<()> {
+    let tmp_path = String::from(path) + TMP_SUFFIX;
+    write_index(index, &tmp_path, segment_size, start, end)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+fn write_index(index: &Index, path: &str,
+               segment_size: u64, start: &util::Tid, end: &util::Tid)
+               -> std::io::Result<()> {
     let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
     writer.write_all(MAGIC)?;
+    writer.write_u32::<byteorder::BigEndian>(INDEX_VERSION)?;
     writer.write_u64::<byteorder::BigEndian>(index.len() as u64)?;
     writer.write_u64::<byteorder::BigEndian>(segment_size)?;
     writer.write_all(start)?;
@@ -22,12 +71,32 @@ pub fn save_index(index: &Index, path: &str,
         writer.write_all(key)?;
         writer.write_u64::<byteorder::BigEndian>(*value)?;
     }
-    Ok(())
+    writer.into_inner().map_err(| err | err.into_error())?.sync_all()
 }
 
+/// Read an index file's magic number and version, returning the
+/// version.  Versions newer than this code writes are errors.
+pub fn read_version(reader: &mut dyn std::io::Read) -> std::io::Result<u32> {
+    let magic = util::read4(reader)?;
+    if magic == V1_MAGIC {
+        return Ok(1);
+    }
+    util::io_assert(magic == MAGIC, "bad magic")?;
+    let version = reader.read_u32::<byteorder::BigEndian>()?;
+    util::io_assert(version <= INDEX_VERSION,
+                    &format!("Unsupported index version {}", version))?;
+    Ok(version)
+}
+
+/// The format version of the index file at path
+pub fn index_version(path: &str) -> std::io::Result<u32> {
+    read_version(&mut std::fs::File::open(path)?)
+}
+
+/// Load an index file, in the current format or an older one.
 pub fn load_index(path: &str) -> std::io::Result<(Index, u64, util::Tid, util::Tid)> {
     let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
-    util::check_magic(&mut reader, MAGIC)?;
+    read_version(&mut reader)?;
     let index_length = reader.read_u64::<byteorder::BigEndian>()?;
     let segment_size = reader.read_u64::<byteorder::BigEndian>()?;
     let start = util::read8(&mut reader)?;
@@ -40,6 +109,111 @@ pub fn load_index(path: &str) -> std::io::Result<(Index, u64, util::Tid, util::T
     Ok((index, segment_size, start, end))
 }
 
+/// Changes made to an index since it was saved, appended as
+/// transactions are committed, so the saved index can be brought up
+/// to date without rewriting it.
+///
+/// Each entry has the part of the data file a transaction extended
+/// the index to, the transaction's id and the new positions of the
+/// objects it wrote, followed by a checksum, so an entry left partly
+/// written by a crash is recognized.
+pub struct Journal {
+    file: std::fs::File,
+    size: u64,
+}
+
+impl Journal {
+
+    /// Create an empty journal for the index file at path, replacing
+    /// any journal there.
+    pub fn create(path: &str) -> std::io::Result<Journal> {
+        let mut file = std::fs::File::create(journal_path(path))?;
+        file.write_all(JOURNAL_MAGIC)?;
+        Ok(Journal { file, size: JOURNAL_MAGIC.len() as u64 })
+    }
+
+    /// Append the changes made by a transaction that extended the
+    /// data covered by the index from start to end.
+    pub fn append<I>(&mut self, start: u64, end: u64, tid: &util::Tid, changes: I)
+                     -> std::io::Result<()>
+    where I: ExactSizeIterator<Item=(util::Oid, u64)> {
+        let mut entry = Vec::with_capacity(
+            JOURNAL_ENTRY_HEADER_SIZE + changes.len() * 16 + 4);
+        entry.write_u64::<byteorder::BigEndian>(start)?;
+        entry.write_u64::<byteorder::BigEndian>(end)?;
+        entry.write_all(tid)?;
+        entry.write_u32::<byteorder::BigEndian>(changes.len() as u32)?;
+        for (oid, pos) in changes {
+            entry.write_all(&oid)?;
+            entry.write_u64::<byteorder::BigEndian>(pos)?;
+        }
+        let checksum = util::crc32(0, &entry);
+        entry.write_u32::<byteorder::BigEndian>(checksum)?;
+        self.file.write_all(&entry)?;
+        self.size += entry.len() as u64;
+        Ok(())
+    }
+
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// Bring an index saved at path up to date from its journal, if
+/// there is one.
+///
+/// The index covers the first segment_size bytes of the data file,
+/// the last transaction in which is end.  Entries are applied while
+/// they follow on from the data covered, and reading stops at an
+/// entry that doesn't, or that's incomplete or doesn't match its
+/// checksum.  Returns the number of entries applied.
+pub fn replay_journal(path: &str, index: &mut Index, segment_size: &mut u64,
+                      end: &mut util::Tid)
+                      -> std::io::Result<u64> {
+    let path = journal_path(path);
+    if ! std::path::Path::new(&path).exists() {
+        return Ok(0);
+    }
+    let mut data = Vec::new();
+    std::fs::File::open(&path)?.read_to_end(&mut data)?;
+    util::io_assert(data.starts_with(JOURNAL_MAGIC), "Bad index journal magic")?;
+    let mut entries = &data[JOURNAL_MAGIC.len()..];
+    let mut applied = 0u64;
+    while entries.len() >= JOURNAL_ENTRY_HEADER_SIZE {
+        let count = byteorder::BigEndian::read_u32(&entries[24..28]) as usize;
+        let length = JOURNAL_ENTRY_HEADER_SIZE + count * 16 + 4;
+        if entries.len() < length {
+            break;
+        }
+        let (entry, rest) = entries.split_at(length);
+        let (changes, checksum) = entry.split_at(length - 4);
+        if util::crc32(0, changes) != byteorder::BigEndian::read_u32(checksum) {
+            break;
+        }
+        let start = byteorder::BigEndian::read_u64(&entry[0..8]);
+        let entry_end = byteorder::BigEndian::read_u64(&entry[8..16]);
+        if entry_end > *segment_size {
+            if start != *segment_size {
+                break;
+            }
+            for change in changes[JOURNAL_ENTRY_HEADER_SIZE..].chunks(16) {
+                let mut oid = util::Z64;
+                oid.copy_from_slice(&change[..8]);
+                index.insert(oid, byteorder::BigEndian::read_u64(&change[8..]));
+            }
+            *segment_size = entry_end;
+            end.copy_from_slice(&entry[16..24]);
+            applied += 1;
+        }
+        entries = rest;
+    }
+    Ok(applied)
+}
+
 // ======================================================================
 
 #[cfg(test)]
@@ -66,6 +240,97 @@ mod tests {
         save_index(&index, &path, segment_size, &start, &end).unwrap();
 
         assert_eq!(load_index(&path).unwrap(),
-                   (index, segment_size, start, end));
+                   (index.clone(), segment_size, start, end));
+
+        // Saving replaces the index file, without leaving the
+        // temporary file it was written to:
+        index.insert(util::p64(10), 1);
+        save_index(&index, &path, segment_size, &start, &end).unwrap();
+        assert_eq!(load_index(&path).unwrap().0, index);
+        assert!(! std::path::Path::new(&(path.clone() + TMP_SUFFIX)).exists());
+        assert_eq!(index_version(&path).unwrap(), INDEX_VERSION);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), saved_size(index.len()));
+    }
+
+    #[test]
+    fn versions() {
+        let tmpdir = util::test::dir();
+        let path = String::from(tmpdir.path().join("index").to_str().unwrap());
+        let mut index = Index::new();
+        index.insert(util::p64(1), 4096);
+        save_index(&index, &path, 9999, &util::p64(1), &util::p64(2)).unwrap();
+        let saved = std::fs::read(&path).unwrap();
+
+        // Version 1 files, without a version field, are read:
+        let mut v1 = V1_MAGIC.to_vec();
+        v1.extend_from_slice(&saved[8..]);
+        std::fs::write(&path, &v1).unwrap();
+        assert_eq!(index_version(&path).unwrap(), 1);
+        assert_eq!(load_index(&path).unwrap(),
+                   (index, 9999, util::p64(1), util::p64(2)));
+
+        // Newer versions, and other files, aren't:
+        let mut newer = saved.clone();
+        newer[7] += 1;
+        std::fs::write(&path, &newer).unwrap();
+        assert!(load_index(&path).unwrap_err().to_string().contains("Unsupported"));
+        std::fs::write(&path, b"junk").unwrap();
+        assert!(load_index(&path).is_err());
+    }
+
+    #[test]
+    fn journal() {
+        let tmpdir = util::test::dir();
+        let path = String::from(tmpdir.path().join("index").to_str().unwrap());
+        let replay = | segment_size: u64 | {
+            let mut index = Index::new();
+            let mut size = segment_size;
+            let mut end = util::Z64;
+            let applied = replay_journal(&path, &mut index, &mut size, &mut end).unwrap();
+            (index, size, end, applied)
+        };
+
+        // No journal, no changes:
+        assert_eq!(replay(100), (Index::new(), 100, util::Z64, 0));
+
+        let mut journal = Journal::create(&path).unwrap();
+        journal.append(100, 200, &util::p64(1),
+                       vec![(util::p64(0), 100), (util::p64(1), 150)].into_iter())
+            .unwrap();
+        journal.append(250, 300, &util::p64(2), vec![(util::p64(0), 250)].into_iter())
+            .unwrap();
+        journal.append(200, 250, &util::p64(3), vec![].into_iter()).unwrap();
+        journal.sync().unwrap();
+        assert_eq!(journal.size(),
+                   std::fs::metadata(journal_path(&path)).unwrap().len());
+
+        // Entries are applied while they follow on from the data
+        // covered, skipping ones for data already covered:
+        let (index, size, end, applied) = replay(100);
+        assert_eq!((size, end, applied), (200, util::p64(1), 1));
+        assert_eq!(index.into_iter().collect::<Vec<_>>(),
+                   vec![(util::p64(0), 100), (util::p64(1), 150)]);
+        let (index, size, end, applied) = replay(250);
+        assert_eq!((size, end, applied), (300, util::p64(2), 1));
+        assert_eq!(index.into_iter().collect::<Vec<_>>(), vec![(util::p64(0), 250)]);
+        assert_eq!(replay(300), (Index::new(), 300, util::Z64, 0));
+
+        // Incomplete and damaged entries are ignored:
+        journal = Journal::create(&path).unwrap();
+        journal.append(100, 200, &util::p64(1), vec![(util::p64(0), 100)].into_iter())
+            .unwrap();
+        journal.append(200, 300, &util::p64(2), vec![(util::p64(0), 200)].into_iter())
+            .unwrap();
+        let mut data = std::fs::read(journal_path(&path)).unwrap();
+        data.truncate(data.len() - 1);
+        std::fs::write(journal_path(&path), &data).unwrap();
+        assert_eq!(replay(100).1, 200);
+        data[10] ^= 1;
+        std::fs::write(journal_path(&path), &data).unwrap();
+        assert_eq!(replay(100), (Index::new(), 100, util::Z64, 0));
+
+        remove_index(&path).unwrap();
+        assert!(! std::path::Path::new(&journal_path(&path)).exists());
+        remove_index(&path).unwrap();
     }
 }
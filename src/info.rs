@@ -0,0 +1,161 @@
+// Information about the running server, so operators can check what's
+// running from the client side.
+use std::collections::BTreeMap;
+
+use crate::msg::InfoValue;
+use crate::storage;
+use crate::tid;
+use crate::util;
+use crate::writer;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_REVISION: &str = match option_env!("BYTESERVER_GIT_REVISION") {
+    Some(revision) => revision,
+    None => "unknown",
+};
+
+/// Optional features clients can check for in get_info.  Undo isn't
+/// supported, so it isn't listed.  Storages with blob directories add
+/// "blobs".
+pub const CAPABILITIES: &[&str] = &[
+    "backup", "checkpoint", "chunked_records", "defer_fsync", "history", "hot_objects",
+    "incremental_backup", "iteration", "load_befores", "pack", "read_view", "record_iternext", "restore",
+];
+
+/// Methods beyond ZEO's, returned by getExtensionMethods, so ZEO
+/// clients can call them on their storages.
+pub const EXTENSION_METHODS: &[&str] = &[
+    "backup", "checkpoint", "defer_fsync", "hot_objects", "incremental_backup", "loadBefores",
+    "load_before_range", "set_read_view",
+];
+
+struct Started {
+    instant: std::time::Instant,
+    time: String,
+    config_digest: String,
+}
+
+static STARTED: std::sync::OnceLock<Started> = std::sync::OnceLock::new();
+
+fn digest(config: &str) -> String {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    config.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+fn start(config: &str) -> Started {
+    Started {
+        instant: std::time::Instant::now(),
+        time: tid::tid_string(&tid::now_tid()),
+        config_digest: digest(config),
+    }
+}
+
+/// Note that the server started, with a description of its
+/// configuration, such as its command-line arguments.  Only the
+/// first call has an effect.
+pub fn started(config: &str) {
+    STARTED.get_or_init(|| start(config));
+}
+
+/// Server version, git revision, start time (UTC), uptime in seconds,
+/// configuration digest and capabilities.
+pub fn server_info() -> BTreeMap<String, InfoValue> {
+    let started = STARTED.get_or_init(|| start(""));
+    let mut info = BTreeMap::new();
+    info.insert("server_version".to_string(), InfoValue::Str(VERSION.to_string()));
+    info.insert("git_revision".to_string(),
+                InfoValue::Str(GIT_REVISION.to_string()));
+    info.insert("start_time".to_string(), InfoValue::Str(started.time.clone()));
+    info.insert("uptime".to_string(),
+                InfoValue::Int(started.instant.elapsed().as_secs()));
+    info.insert("config_digest".to_string(),
+                InfoValue::Str(started.config_digest.clone()));
+    info.insert("capabilities".to_string(), InfoValue::List(
+        CAPABILITIES.iter().map(| c | InfoValue::Str(c.to_string())).collect()));
+    info.insert("supportsUndo".to_string(), InfoValue::Bool(false));
+    info
+}
+
+/// A storage's activity counts and commit-queue state, with ZEO's
+/// server_status names where there are any.
+///
+/// Clients that have sent nothing since the last heartbeat are listed
+/// under missed-heartbeats, with their connection id, name and how
+/// many heartbeats in a row they've missed.
+///
+/// Request latencies are listed under latencies, by method, with
+/// the method name, request count, mean and maximum latency in
+/// seconds, and request counts for each of latency::BUCKETS and
+/// slower.
+///
+/// Record-cache counts are 0 for storages without record caches.
+pub fn storage_status(fs: &storage::FileStorage<writer::Client>)
+                      -> BTreeMap<&'static str, InfoValue> {
+    let started = STARTED.get_or_init(|| start(""));
+    let status = fs.status();
+    let missed = fs.clients().iter()
+        .filter(| client | client.missed_heartbeats() > 0)
+        .map(| client | InfoValue::List(vec![
+            InfoValue::Int(client.connection()),
+            InfoValue::Str(client.name().to_string()),
+            InfoValue::Int(client.missed_heartbeats()),
+        ]))
+        .collect();
+    let cache = fs.record_cache_stats().unwrap_or_default();
+    let latencies = fs.latencies().histograms().into_iter()
+        .map(| (method, histogram) | InfoValue::List(vec![
+            InfoValue::Str(method),
+            InfoValue::Int(histogram.count),
+            InfoValue::Float(histogram.mean().as_secs_f64()),
+            InfoValue::Float(histogram.max.as_secs_f64()),
+            InfoValue::List(histogram.buckets.into_iter().map(InfoValue::Int).collect()),
+        ]))
+        .collect();
+    [
+        ("storage", InfoValue::Str(fs.path().to_string())),
+        ("start", InfoValue::Str(started.time.clone())),
+        ("connections", InfoValue::Int(status.clients as u64)),
+        ("commits", InfoValue::Int(status.commits)),
+        ("aborts", InfoValue::Int(status.aborts)),
+        ("conflicts", InfoValue::Int(status.conflicts)),
+        ("waiting", InfoValue::Int(status.lock_waits as u64)),
+        ("voted", InfoValue::Int(status.voted as u64)),
+        ("last-transaction", InfoValue::Str(util::hex(&status.last_tid))),
+        ("latencies", InfoValue::List(latencies)),
+        ("missed-heartbeats", InfoValue::List(missed)),
+        ("record-cache-size", InfoValue::Int(cache.size)),
+        ("record-cache-hits", InfoValue::Int(cache.hits)),
+        ("record-cache-misses", InfoValue::Int(cache.misses)),
+        ("record-cache-hit-rate", InfoValue::Float(cache.hit_rate())),
+    ].into_iter().collect()
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn works() {
+        started("--storage 1=data.fs");
+        let info = server_info();
+        assert_eq!(info["server_version"], InfoValue::Str(VERSION.to_string()));
+        assert_eq!(info["config_digest"],
+                   InfoValue::Str(digest("--storage 1=data.fs")));
+        assert_ne!(digest("--storage 1=data.fs"), digest("--storage 1=other.fs"));
+        assert!(matches!(info["uptime"], InfoValue::Int(_)));
+        match info["capabilities"] {
+            InfoValue::List(ref capabilities) =>
+                assert!(capabilities.contains(&InfoValue::Str("pack".to_string()))),
+            _ => panic!("bad capabilities"),
+        }
+
+        // Later calls don't change anything:
+        started("");
+        assert_eq!(server_info()["config_digest"], info["config_digest"]);
+    }
+}
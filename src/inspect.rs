@@ -0,0 +1,258 @@
+// Offline inspection of data-file contents
+use anyhow::{anyhow, Context, Result};
+
+use crate::scan;
+use crate::tid;
+use crate::util;
+
+#[derive(Debug)]
+pub struct Revision {
+    pub pos: u64,
+    pub tid: util::Tid,
+    pub size: u64,
+    pub previous: u64,
+    // The owning transaction:
+    pub transaction_pos: u64,
+    pub user: util::Bytes,
+    pub desc: util::Bytes,
+    pub ext: util::Bytes,
+}
+
+impl std::fmt::Display for Revision {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {} size={} pos={} previous={} transaction={} \
+                   user={:?} description={:?} extension={:?}",
+               util::hex(&self.tid), tid::tid_string(&self.tid), self.size,
+               self.pos, self.previous, self.transaction_pos,
+               String::from_utf8_lossy(&self.user),
+               String::from_utf8_lossy(&self.desc),
+               String::from_utf8_lossy(&self.ext))
+    }
+}
+
+/// Parse an oid (or tid) given as a decimal number, or in hex with a
+/// leading 0x.
+pub fn parse_id(s: &str) -> Result<[u8; 8]> {
+    let v = if let Some(h) = s.strip_prefix("0x") {
+        u64::from_str_radix(h, 16)
+    }
+    else {
+        s.parse::<u64>()
+    };
+    Ok(util::p64(v.with_context(|| format!("bad id {}", s))?))
+}
+
+/// Parse a UTC time given as YYYY-MM-DD, optionally followed by a
+/// space or T and HH:MM or HH:MM:SS, into a tid.
+pub fn parse_time(s: &str) -> Result<util::Tid> {
+    let bad = || anyhow!("bad time {}", s);
+    let (date, time) = match s.find([' ', 'T']) {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, "00:00"),
+    };
+    let date = date.split('-').map(| p | p.parse::<u32>())
+        .collect::<std::result::Result<Vec<u32>, _>>().map_err(| _ | bad())?;
+    let time = time.split(':').map(| p | p.parse::<f64>())
+        .collect::<std::result::Result<Vec<f64>, _>>().map_err(| _ | bad())?;
+    match (date.as_slice(), time.as_slice()) {
+        ([year, month, day], [hour, minute]) |
+        ([year, month, day], [hour, minute, _])
+            if *year >= 1900 && (1..=12).contains(month) &&
+            (1..=31).contains(day) && *hour < 24.0 && *minute < 60.0 =>
+            Ok(tid::make_tid(*year, *month, *day, *hour as u32, *minute as u32,
+                             *time.get(2).unwrap_or(&0.0))),
+        _ => Err(bad()),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SearchCriteria {
+    // Transactions committed at or after this tid
+    pub after: Option<util::Tid>,
+    // Transactions committed before this tid
+    pub before: Option<util::Tid>,
+    // Substrings of the user and description
+    pub user: Option<String>,
+    pub description: Option<String>,
+}
+
+impl SearchCriteria {
+    fn matches(&self, record: &scan::TransactionRecord) -> bool {
+        let contains = | data: &[u8], s: &Option<String> | match s {
+            Some(s) => String::from_utf8_lossy(data).contains(s.as_str()),
+            None => true,
+        };
+        self.after.is_none_or(| t | record.tid() >= t) &&
+            self.before.is_none_or(| t | record.tid() < t) &&
+            contains(&record.user, &self.user) &&
+            contains(&record.desc, &self.description)
+    }
+}
+
+#[derive(Debug)]
+pub struct TransactionSummary {
+    pub pos: u64,
+    pub tid: util::Tid,
+    pub user: util::Bytes,
+    pub desc: util::Bytes,
+    pub oids: u32,
+}
+
+impl std::fmt::Display for TransactionSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {} oids={} pos={} user={:?} description={:?}",
+               util::hex(&self.tid), tid::tid_string(&self.tid), self.oids,
+               self.pos,
+               String::from_utf8_lossy(&self.user),
+               String::from_utf8_lossy(&self.desc))
+    }
+}
+
+/// Find committed transactions matching the given criteria, by
+/// scanning transaction headers.
+pub fn search(path: &str, criteria: &SearchCriteria)
+              -> Result<Vec<TransactionSummary>> {
+    let mut found = vec![];
+    for record in scan::TransactionIterator::open(path)? {
+        let record = record?;
+        if criteria.before.is_some_and(| t | record.tid() >= t) {
+            break; // Tids increase through the file
+        }
+        if criteria.matches(&record) {
+            found.push(TransactionSummary {
+                pos: record.pos, tid: record.tid(), oids: record.header.ndata,
+                user: record.user, desc: record.desc,
+            });
+        }
+    }
+    Ok(found)
+}
+
+/// Return the revisions of an object, newest first.
+pub fn oid_history(path: &str, oid: &util::Oid) -> Result<Vec<Revision>> {
+    let mut it = scan::TransactionIterator::open(path)?;
+    let mut current: Option<u64> = None;
+    while let Some(record) = it.next() {
+        let record = record?;
+        for (pos, header) in record.data_headers(it.reader())? {
+            if &header.id == oid {
+                current = Some(pos);
+            }
+        }
+    }
+
+    let mut revisions = vec![];
+    let mut pos = current.unwrap_or(0);
+    let version = it.version();
+    while pos != 0 {
+        util::seek(it.reader(), pos)?;
+        let header = crate::records::DataHeader::read(it.reader(), version)
+            .context("reading data header")?;
+        if &header.id != oid || header.offset > pos {
+            return Err(anyhow!("Bad revision chain for {} at {}",
+                               util::hex(oid), pos));
+        }
+        let transaction = scan::read_transaction(it.reader(), pos - header.offset, version)?;
+        revisions.push(Revision {
+            pos, tid: header.tid, size: header.length,
+            previous: header.previous, transaction_pos: transaction.pos,
+            user: transaction.user, desc: transaction.desc, ext: transaction.ext,
+        });
+        if header.previous >= pos {
+            return Err(anyhow!("Bad previous pointer for {} at {}",
+                               util::hex(oid), pos));
+        }
+        pos = header.previous;
+    }
+    Ok(revisions)
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::storage::testing;
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(parse_id("42").unwrap(), util::p64(42));
+        assert_eq!(parse_id("0x2a").unwrap(), util::p64(42));
+        assert!(parse_id("x").is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("2016-01-02").unwrap(),
+                   tid::make_tid(2016, 1, 2, 0, 0, 0.0));
+        assert_eq!(parse_time("2016-01-02 03:04").unwrap(),
+                   tid::make_tid(2016, 1, 2, 3, 4, 0.0));
+        assert_eq!(parse_time("2016-01-02T03:04:56.5").unwrap(),
+                   tid::make_tid(2016, 1, 2, 3, 4, 56.5));
+        assert!(parse_time("2016-13-02").is_err());
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        let fs = crate::storage::FileStorage::<testing::NullClient>::open(
+            path.clone()).unwrap();
+        for (user, desc, oids) in [("bob", "add", vec![0]),
+                                   ("sally", "add more", vec![1, 2]),
+                                   ("bob", "fix", vec![3])] {
+            let mut trans = fs.tpc_begin(user.as_bytes(), desc.as_bytes(), b"")
+                .unwrap();
+            for oid in oids {
+                trans.save(util::p64(oid), util::Z64, b"x").unwrap();
+            }
+            fs.lock(&trans, Box::new(| _ | ())).unwrap();
+            trans.locked().unwrap();
+            assert_eq!(fs.stage(&mut trans).unwrap().len(), 0);
+            fs.tpc_finish(&trans.id, testing::NullClient).unwrap();
+        }
+
+        let tids = | criteria | search(&path, &criteria).unwrap().iter()
+            .map(| t | (String::from_utf8(t.desc.clone()).unwrap(), t.oids))
+            .collect::<Vec<(String, u32)>>();
+        assert_eq!(tids(SearchCriteria { user: Some("bob".into()),
+                                         ..Default::default() }),
+                   vec![("add".into(), 1), ("fix".into(), 1)]);
+        assert_eq!(tids(SearchCriteria { description: Some("add".into()),
+                                         ..Default::default() }),
+                   vec![("add".into(), 1), ("add more".into(), 2)]);
+
+        let all = search(&path, &SearchCriteria::default()).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(tids(SearchCriteria { after: Some(all[1].tid),
+                                         ..Default::default() }),
+                   vec![("add more".into(), 2), ("fix".into(), 1)]);
+        assert_eq!(tids(SearchCriteria { before: Some(all[1].tid),
+                                         ..Default::default() }),
+                   vec![("add".into(), 1)]);
+    }
+
+    #[test]
+    fn test_oid_history() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        testing::make_sample(
+            &path,
+            vec![vec![(util::p64(0), b"000")],
+                 vec![(util::p64(1), b"a")],
+                 vec![(util::p64(0), b"11"), (util::p64(1), b"bb")],
+            ]).unwrap();
+
+        let history = oid_history(&path, &util::p64(0)).unwrap();
+        assert_eq!(history.iter().map(| r | r.size).collect::<Vec<u64>>(),
+                   vec![2, 3]);
+        assert!(history[0].tid > history[1].tid);
+        assert_eq!(history[0].previous, history[1].pos);
+        assert_eq!(history[1].previous, 0);
+        assert_eq!(history[1].transaction_pos, crate::records::HEADER_SIZE);
+
+        assert_eq!(oid_history(&path, &util::p64(9)).unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,220 @@
+// Server-side iterators over transactions and their records, for the
+// iterator_* protocol methods, used by tools that copy or verify
+// whole databases
+use anyhow::Result;
+
+use crate::dictionaries;
+use crate::errors;
+use crate::records;
+use crate::scan;
+use crate::storage;
+use crate::util;
+
+/// A data record returned by a record iterator
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub oid: util::Oid,
+    pub tid: util::Tid,
+    pub data: util::Bytes,
+}
+
+struct Transactions {
+    it: scan::TransactionIterator,
+    // The data file's, for its records' compressed data
+    dictionaries: std::sync::Arc<dictionaries::Dictionaries>,
+    start: Option<util::Tid>,
+    stop: Option<util::Tid>,
+    // The transaction last returned, to iterate over its records
+    last: Option<scan::TransactionRecord>,
+}
+
+struct Records {
+    file: std::fs::File,
+    headers: std::vec::IntoIter<(u64, records::DataHeader)>,
+    // The data file's format version
+    version: u32,
+    dictionaries: std::sync::Arc<dictionaries::Dictionaries>,
+}
+
+enum Iterator {
+    Transactions(Transactions),
+    Records(Records),
+}
+
+/// A connection's iterators, by id
+///
+/// Iterators are removed when they're exhausted or garbage
+/// collected.  Each has an open file.
+#[derive(Default)]
+pub struct Iterators {
+    last_id: u64,
+    iterators: std::collections::HashMap<u64, Iterator>,
+}
+
+impl Iterators {
+
+    fn add(&mut self, iterator: Iterator) -> u64 {
+        self.last_id += 1;
+        self.iterators.insert(self.last_id, iterator);
+        self.last_id
+    }
+
+    fn get(&mut self, id: u64) -> Result<&mut Iterator> {
+        Ok(self.iterators.get_mut(&id).ok_or_else(
+            || errors::POSError::Storage(format!("No iterator {}", id)))?)
+    }
+
+    /// Start iterating over the transactions from it with ids from
+    /// start through stop, returning the new iterator's id.  Record
+    /// data are decompressed with the data file's dictionaries.
+    pub fn start(&mut self, it: scan::TransactionIterator,
+                 dictionaries: std::sync::Arc<dictionaries::Dictionaries>,
+                 start: Option<util::Tid>, stop: Option<util::Tid>)
+                 -> u64 {
+        self.add(Iterator::Transactions(Transactions {
+            it, dictionaries, start, stop, last: None }))
+    }
+
+    /// Get the next transaction, or None, removing the iterator, if
+    /// there are no more.
+    pub fn next_transaction(&mut self, id: u64) -> Result<Option<storage::TransactionInfo>> {
+        let transactions = match self.get(id)? {
+            Iterator::Transactions(transactions) => transactions,
+            _ => return Err(errors::POSError::Storage(
+                format!("{} isn't a transaction iterator", id)))?,
+        };
+        let record = loop {
+            match transactions.it.next().transpose()? {
+                Some(record) if transactions.start.is_some_and(| t | record.tid() < t) => {},
+                Some(record) if transactions.stop.is_none_or(| t | record.tid() <= t) =>
+                    break Some(record),
+                _ => break None,
+            }
+        };
+        match record {
+            Some(record) => {
+                let info = storage::TransactionInfo {
+                    tid: record.tid(), user: record.user.clone(),
+                    description: record.desc.clone(), extension: record.ext.clone() };
+                transactions.last = Some(record);
+                Ok(Some(info))
+            },
+            None => {
+                self.iterators.remove(&id);
+                Ok(None)
+            },
+        }
+    }
+
+    /// Start iterating over the records of the transaction last
+    /// returned by a transaction iterator, which must have id tid,
+    /// returning the new iterator's id.
+    pub fn start_records(&mut self, id: u64, tid: &util::Tid) -> Result<u64> {
+        let (it, dictionaries, record) = match self.get(id)? {
+            Iterator::Transactions(Transactions { it, dictionaries, last: Some(record), .. })
+                if &record.tid() == tid => (it, dictionaries.clone(), record),
+            _ => return Err(errors::POSError::Storage(
+                "Out-of-order request for a record iterator".to_string()))?,
+        };
+        let headers = record.data_headers(it.reader())?;
+        let file = it.reader().get_ref().try_clone()?;
+        let version = record.version;
+        Ok(self.add(Iterator::Records(Records {
+            file, headers: headers.into_iter(), version, dictionaries })))
+    }
+
+    /// Get the next record, or None, removing the iterator, if there
+    /// are no more.
+    pub fn next_record(&mut self, id: u64) -> Result<Option<Record>> {
+        let records = match self.get(id)? {
+            Iterator::Records(records) => records,
+            _ => return Err(errors::POSError::Storage(
+                format!("{} isn't a record iterator", id)))?,
+        };
+        match records.headers.next() {
+            Some((pos, header)) => Ok(Some(Record {
+                oid: header.id, tid: header.tid,
+                data: scan::read_data(&mut records.file, pos, &header, records.version,
+                                      &records.dictionaries)?,
+            })),
+            None => {
+                self.iterators.remove(&id);
+                Ok(None)
+            },
+        }
+    }
+
+    /// Remove iterators that clients are done with.
+    pub fn gc(&mut self, ids: &[u64]) {
+        for id in ids {
+            self.iterators.remove(id);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.iterators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iterators.is_empty()
+    }
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::storage::testing;
+
+    #[test]
+    fn works() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        testing::make_sample(
+            &path,
+            vec![vec![(util::p64(0), b"000")],
+                 vec![(util::p64(0), b"111"), (util::p64(1), b"aaa")],
+                 vec![(util::p64(1), b"bbb")],
+            ]).unwrap();
+        let tids: Vec<util::Tid> = scan::TransactionIterator::open(&path).unwrap()
+            .map(| r | r.unwrap().tid()).collect();
+        let mut iterators = Iterators::default();
+
+        // All transactions:
+        let id = iterators.start(scan::TransactionIterator::open(&path).unwrap(),
+                                 Default::default(), None, None);
+        let mut found = vec![];
+        while let Some(info) = iterators.next_transaction(id).unwrap() {
+            found.push(info.tid);
+            let records = iterators.start_records(id, &info.tid).unwrap();
+            while let Some(record) = iterators.next_record(records).unwrap() {
+                assert_eq!(record.tid, info.tid);
+                if info.tid == tids[1] && record.oid == util::p64(1) {
+                    assert_eq!(record.data, b"aaa".to_vec());
+                }
+            }
+        }
+        assert_eq!(found, tids);
+        assert!(iterators.is_empty());
+
+        // A range, with records out of order:
+        let id = iterators.start(scan::TransactionIterator::open(&path).unwrap(),
+                                 Default::default(), Some(tids[1]), Some(tids[1]));
+        let info = iterators.next_transaction(id).unwrap().unwrap();
+        assert_eq!(info.tid, tids[1]);
+        assert!(iterators.start_records(id, &tids[0]).is_err());
+        let records = iterators.start_records(id, &tids[1]).unwrap();
+        assert_eq!(iterators.next_record(records).unwrap().unwrap(),
+                   Record { oid: util::p64(0), tid: tids[1], data: b"111".to_vec() });
+        assert_eq!(iterators.next_transaction(id).unwrap(), None);
+
+        // Unknown and mixed-up ids are errors:
+        assert!(iterators.next_transaction(id).is_err());
+        assert!(iterators.next_transaction(records).is_err());
+
+        iterators.gc(&[records]);
+        assert!(iterators.is_empty());
+    }
+}
@@ -0,0 +1,166 @@
+// Request latency
+//
+// Connections note when each request arrives, by message id, and
+// when its response is written, so operators can see which methods
+// are slow.  Latencies are kept per storage, in a histogram per
+// method, and requests slower than a storage's threshold are logged.
+
+use crate::msg;
+use crate::writer;
+
+/// Upper bounds, in milliseconds, of the histogram buckets.  A last
+/// bucket counts slower requests.
+pub const BUCKETS: &[u64] = &[1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub count: u64,
+    pub total: std::time::Duration,
+    pub max: std::time::Duration,
+    // Requests in each of BUCKETS, and slower
+    pub buckets: Vec<u64>,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram { count: 0, total: Default::default(), max: Default::default(),
+                    buckets: vec![0; BUCKETS.len() + 1] }
+    }
+}
+
+impl Histogram {
+
+    pub fn record(&mut self, elapsed: std::time::Duration) {
+        self.count += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+        let millis = elapsed.as_secs_f64() * 1000.0;
+        let bucket = BUCKETS.iter().position(| &bound | millis <= bound as f64)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket] += 1;
+    }
+
+    pub fn mean(&self) -> std::time::Duration {
+        self.total.checked_div(self.count as u32).unwrap_or_default()
+    }
+}
+
+/// A storage's request latencies, by method
+#[derive(Debug, Default)]
+pub struct Latencies {
+    methods: std::sync::Mutex<std::collections::BTreeMap<String, Histogram>>,
+    // Requests that take longer are logged.
+    slow: std::sync::Mutex<Option<std::time::Duration>>,
+}
+
+impl Latencies {
+
+    /// Log requests that take longer than slow, or none if it's None.
+    pub fn set_slow(&self, slow: Option<std::time::Duration>) {
+        *self.slow.lock().unwrap() = slow;
+    }
+
+    pub fn record(&self, connection: u64, id: i64, method: &str,
+                  elapsed: std::time::Duration) {
+        if self.slow.lock().unwrap().is_some_and(| slow | elapsed > slow) {
+            tracing::warn!("[{}] Slow {} request: {:.3} seconds",
+                           writer::correlation_id(connection, id), method, elapsed.as_secs_f64());
+        }
+        self.methods.lock().unwrap().entry(method.to_string()).or_default()
+            .record(elapsed);
+    }
+
+    /// The histograms of methods that have been called
+    pub fn histograms(&self) -> std::collections::BTreeMap<String, Histogram> {
+        self.methods.lock().unwrap().clone()
+    }
+}
+
+/// A connection's requests that haven't been responded to yet
+#[derive(Debug)]
+pub struct Requests {
+    connection: u64,
+    latencies: std::sync::Arc<Latencies>,
+    pending: std::sync::Mutex<std::collections::HashMap<i64, (String, std::time::Instant)>>,
+}
+
+impl Requests {
+
+    pub fn new(connection: u64, latencies: std::sync::Arc<Latencies>) -> Requests {
+        Requests { connection, latencies, pending: Default::default() }
+    }
+
+    /// Note that a request with message id id arrived.
+    pub fn received(&self, id: i64, method: String) {
+        self.pending.lock().unwrap().insert(id, (method, std::time::Instant::now()));
+    }
+
+    /// Record the latency of the request a message responds to, if
+    /// it's a response.  The message is size-prefixed msgpack, as
+    /// the writer gets it before encoding.
+    pub fn sent(&self, message: &[u8]) {
+        if let Some(id) = response_id(message) {
+            let received = self.pending.lock().unwrap().remove(&id);
+            if let Some((method, time)) = received {
+                self.latencies.record(self.connection, id, &method, time.elapsed());
+            }
+        }
+    }
+}
+
+// The message id of a response or error response
+fn response_id(message: &[u8]) -> Option<i64> {
+    match msg::pre_parse(&mut message.get(4..)?) {
+        Ok((id, kind)) if id > 0 && (kind == "R" || kind == "E") => Some(id),
+        _ => None,
+    }
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::msgmacros::*;
+    use anyhow::{Context, Result};
+
+    #[test]
+    fn histogram() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.mean(), std::time::Duration::ZERO);
+        histogram.record(std::time::Duration::from_micros(500));
+        histogram.record(std::time::Duration::from_millis(3));
+        histogram.record(std::time::Duration::from_secs(60));
+        assert_eq!(histogram.count, 3);
+        assert_eq!(histogram.max, std::time::Duration::from_secs(60));
+        assert_eq!(histogram.mean(), std::time::Duration::from_nanos(20_001_166_666));
+        let mut expected = vec![0; BUCKETS.len() + 1];
+        expected[0] = 1;
+        expected[2] = 1;
+        expected[BUCKETS.len()] = 1;
+        assert_eq!(histogram.buckets, expected);
+    }
+
+    #[test]
+    fn requests() -> Result<()> {
+        let latencies = std::sync::Arc::new(Latencies::default());
+        let requests = Requests::new(1, latencies.clone());
+        requests.received(1, "loadBefore".to_string());
+        requests.received(2, "vote".to_string());
+
+        // Async messages and responses to other requests are ignored:
+        requests.sent(&message!(0, "info", (1,)));
+        requests.sent(&response!(3, ()));
+        assert!(latencies.histograms().is_empty());
+
+        requests.sent(&response!(1, ()));
+        requests.sent(&error_response!(2, ("error", ())));
+        requests.sent(&response!(1, ()));
+        let histograms = latencies.histograms();
+        assert_eq!(histograms.keys().collect::<Vec<_>>(), vec!["loadBefore", "vote"]);
+        assert_eq!(histograms["loadBefore"].count, 1);
+        assert_eq!(histograms["vote"].count, 1);
+        Ok(())
+    }
+}
@@ -15,14 +15,43 @@ pub mod util;
 #[macro_use]
 pub mod msgmacros;
 
+pub mod log;
+
+mod appender;
+pub mod auth;
+pub mod backup;
+pub mod blobs;
+pub mod cache;
+pub mod cdc;
+mod chains;
+pub mod compact;
+pub mod convert;
+pub mod daemon;
+pub mod dictionaries;
 pub mod errors;
+pub mod fsck;
+pub mod health;
+pub mod info;
 pub mod storage;
 mod index;
+pub mod inspect;
+pub mod iterators;
+pub mod latency;
 mod lock;
+mod mapped;
+pub mod monitor;
 pub mod msg;
+pub mod pickle;
+pub mod pack;
 mod pool;
+pub mod proxy;
+pub mod reactor;
 mod records;
 pub mod reader;
+pub mod registry;
+pub mod scan;
+pub mod stats;
 pub mod writer;
 pub mod tid;
+pub mod tls;
 mod transaction;
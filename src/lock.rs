@@ -70,6 +70,11 @@ impl LockManager {
 
     }
 
+    /// The number of transactions waiting for locks
+    pub fn waiting(&self) -> usize {
+        self.locking.values().filter(| l | ! l.want.is_empty()).count()
+    }
+
     pub fn release(&mut self, id: &util::Tid) {
         // Release any locks held for the given id. This has no effect of no
         // locks are held.
@@ -142,6 +147,7 @@ mod tests {
         assert!(! l2_12.borrow().is_locked);
         assert!(! l3_12.borrow().is_locked);
         assert!(! l4_3.borrow().is_locked);
+        assert_eq!(lm.waiting(), 3);
 
         let l5_4 = newt(5);
         lock(&mut lm, l5_4.clone(), vec![4]);
@@ -161,5 +167,6 @@ mod tests {
         assert!(  l3_12.borrow().is_locked);
         assert!(  l4_3.borrow().is_locked);
         assert!(  l5_4.borrow().is_locked);
+        assert_eq!(lm.waiting(), 0);
     }
 }
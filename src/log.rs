@@ -0,0 +1,115 @@
+// Server logging, with tracing
+//
+// Events are logged with tracing's macros, within spans for the
+// connection and request being handled, whose fields are logged with
+// each event.  The server's subscriber writes events to standard
+// output, as text or, for log collectors, as JSON lines.
+
+/// A subscriber logging events at level or more severe to
+/// make_writer, as JSON lines if json is true.
+pub fn subscriber<W>(level: tracing::Level, json: bool, make_writer: W)
+                     -> Box<dyn tracing::Subscriber + Send + Sync>
+    where W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + Send + Sync + 'static
+{
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_ansi(false)
+        .with_target(false)
+        .with_writer(make_writer);
+    if json {
+        Box::new(builder.json().flatten_event(true).with_current_span(false).finish())
+    }
+    else {
+        Box::new(builder.finish())
+    }
+}
+
+/// Log events at level or more severe to standard output, as JSON
+/// lines if json is true.
+pub fn configure(level: tracing::Level, json: bool) -> anyhow::Result<()> {
+    tracing::subscriber::set_global_default(subscriber(level, json, std::io::stdout))?;
+    Ok(())
+}
+
+/// A span adding a connection's id and client to the events logged in
+/// it.
+///
+/// Spans are at the error level, so they're enabled, and their fields
+/// logged, whatever level is configured.
+pub fn connection(connection: u64, client: &str) -> tracing::span::EnteredSpan {
+    tracing::error_span!("connection", id = connection, client).entered()
+}
+
+/// A span adding a request's message id to the events logged in it
+pub fn request(id: i64) -> tracing::span::EnteredSpan {
+    tracing::error_span!("request", id).entered()
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Output(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output {
+        fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+        }
+    }
+
+    fn logged(level: tracing::Level, json: bool, log: impl FnOnce()) -> String {
+        let output = Output::default();
+        let writer = output.clone();
+        tracing::subscriber::with_default(
+            subscriber(level, json, move || writer.clone()), log);
+        output.take()
+    }
+
+    #[test]
+    fn formatting() {
+        let line = logged(tracing::Level::INFO, false, || tracing::warn!("a {}", 1));
+        assert!(line.ends_with(" WARN a 1\n"), "{}", line);
+
+        let line = logged(tracing::Level::INFO, false, || {
+            let _connection = connection(3, "1.2.3.4:5");
+            let _request = request(7);
+            tracing::info!("hi");
+        });
+        assert!(line.ends_with(
+            " INFO connection{id=3 client=\"1.2.3.4:5\"}:request{id=7}: hi\n"), "{}", line);
+
+        let line = logged(tracing::Level::INFO, true, || {
+            let _connection = connection(3, "1.2.3.4:5");
+            let _request = request(7);
+            tracing::error!("say \"hi\"");
+        });
+        assert!(line.starts_with("{\"timestamp\":\""), "{}", line);
+        assert!(line.ends_with(
+            "\"level\":\"ERROR\",\"message\":\"say \\\"hi\\\"\",\
+             \"spans\":[{\"client\":\"1.2.3.4:5\",\"id\":3,\"name\":\"connection\"},\
+             {\"id\":7,\"name\":\"request\"}]}\n"), "{}", line);
+
+        // Events less severe than the configured level aren't logged,
+        // but spans' fields are logged with events that are:
+        assert_eq!(logged(tracing::Level::WARN, false, || tracing::info!("hi")), "");
+        let line = logged(tracing::Level::WARN, false, || {
+            let _request = request(7);
+            tracing::warn!("hi");
+        });
+        assert!(line.ends_with(" WARN request{id=7}: hi\n"), "{}", line);
+    }
+}
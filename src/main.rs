@@ -1,39 +1,724 @@
 extern crate byteserver;
 
+use anyhow::{anyhow, Context, Result};
+
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(| a | a.as_str()) {
+        Some("fsck") => fsck(&args[1..]),
+        Some("stats") => stats(&args[1..]),
+        Some("space") => space(&args[1..]),
+        Some("compact") => compact(&args[1..]),
+        Some("train-dictionary") => train_dictionary(&args[1..]),
+        Some("convert") => convert(&args[1..]),
+        Some("blob-gc") => blob_gc(&args[1..]),
+        Some("oid") => oid(&args[1..]),
+        Some("search") => search(&args[1..]),
+        Some("check-index") => check_index(&args[1..]),
+        Some("restore-backup") => restore_backup(&args[1..]),
+        None => serve(&[]),
+        Some(flag) if flag.starts_with("--") => serve(&args),
+        Some(command) => Err(anyhow!("Unknown command {}", command)),
+    };
+    if let Err(err) = result {
+        eprintln!("{:#}", err);
+        std::process::exit(1);
+    }
+}
+
+// Split arguments into flags (starting with --) and positional arguments.
+fn split_args(args: &[String]) -> (Vec<&str>, Vec<&str>) {
+    args.iter()
+        .map(| a | a.as_str())
+        .partition(| a | a.starts_with("--"))
+}
+
+fn fsck(args: &[String]) -> Result<()> {
+    let (flags, paths) = split_args(args);
+    let mut options = byteserver::fsck::RepairOptions::default();
+    for flag in flags {
+        match flag {
+            "--repair" => {
+                options.truncate = true;
+                options.rebuild_index = true;
+                options.quarantine = true;
+            },
+            "--truncate" => options.truncate = true,
+            "--rebuild-index" => options.rebuild_index = true,
+            "--quarantine" => options.quarantine = true,
+            _ => return Err(anyhow!("Unknown fsck option {}", flag)),
+        }
+    }
+    let path = match paths.as_slice() {
+        [path] => *path,
+        _ => return Err(anyhow!(
+            "Usage: byteserver fsck [--repair] [--truncate] [--rebuild-index] \
+             [--quarantine] PATH")),
+    };
+
+    let report = byteserver::fsck::check(path)?;
+    for problem in report.problems.iter() {
+        println!("{}: {}", problem.pos, problem.message);
+    }
+    let report =
+        if ! report.ok() &&
+        (options.truncate || options.rebuild_index || options.quarantine) {
+            println!("Repairing");
+            let report = byteserver::fsck::repair(path, &options)?;
+            for problem in report.problems.iter() {
+                println!("{}: {}", problem.pos, problem.message);
+            }
+            report
+        }
+        else {
+            report
+        };
+    println!("{} transactions, {} data records, {} objects, {} problems",
+             report.transactions, report.records, report.index.len(),
+             report.problems.len());
+    if report.ok() { Ok(()) } else { Err(anyhow!("{} is damaged", path)) }
+}
+
+fn check_index(args: &[String]) -> Result<()> {
+    let usage = || anyhow!(
+        "Usage: byteserver check-index [--sample N] [--rebuild] PATH");
+    let mut sample = 1usize;
+    let mut rebuild = false;
+    let mut path: Option<&String> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sample" => sample =
+                args.next().ok_or_else(usage)?.parse().map_err(| _ | usage())?,
+            "--rebuild" => rebuild = true,
+            _ if path.is_none() && ! arg.starts_with("--") => path = Some(arg),
+            _ => return Err(usage()),
+        }
+    }
+    let path = path.ok_or_else(usage)?;
+    let problems = byteserver::fsck::check_index(path, sample)?;
+    for problem in problems.iter() {
+        println!("{}: {}", problem.pos, problem.message);
+    }
+    if problems.is_empty() {
+        println!("No problems found");
+    }
+    else if rebuild {
+        byteserver::fsck::rebuild_index(path)?;
+        println!("Rebuilt the index");
+    }
+    else {
+        return Err(anyhow!("The index for {} is inconsistent", path));
+    }
+    Ok(())
+}
+
+fn stats(args: &[String]) -> Result<()> {
+    let path = match args {
+        [path] => path,
+        _ => return Err(anyhow!("Usage: byteserver stats PATH")),
+    };
+    println!("{}", byteserver::stats::file_stats(path)?);
+    Ok(())
+}
+
+fn space(args: &[String]) -> Result<()> {
+    let usage = || anyhow!("Usage: byteserver space [--top N] PATH");
+    let mut top = 20usize;
+    let mut path: Option<&String> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--top" => top =
+                args.next().ok_or_else(usage)?.parse().map_err(| _ | usage())?,
+            _ if path.is_none() && ! arg.starts_with("--") => path = Some(arg),
+            _ => return Err(usage()),
+        }
+    }
+    let path = path.ok_or_else(usage)?;
+    for object in byteserver::stats::space_by_oid(path, top)? {
+        println!("{}", object);
+    }
+    Ok(())
+}
+
+fn compact(args: &[String]) -> Result<()> {
+    let path = match args {
+        [path] => path,
+        _ => return Err(anyhow!("Usage: byteserver compact PATH")),
+    };
+    let compacted = byteserver::compact::compact(path)?;
+    println!("Rewrote {} transactions, {} bytes -> {} bytes. \
+              The original was saved as {}{}",
+             compacted.transactions, compacted.old_size, compacted.new_size,
+             path, byteserver::compact::OLD_SUFFIX);
+    Ok(())
+}
+
+fn train_dictionary(args: &[String]) -> Result<()> {
+    let usage = || anyhow!(
+        "Usage: byteserver train-dictionary [--sample RECORDS] [--size BYTES] PATH");
+    let mut sample = byteserver::dictionaries::DEFAULT_SAMPLE;
+    let mut size = byteserver::dictionaries::DEFAULT_SIZE;
+    let mut path: Option<&String> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sample" => sample =
+                args.next().ok_or_else(usage)?.parse().map_err(| _ | usage())?,
+            "--size" => size =
+                args.next().ok_or_else(usage)?.parse().map_err(| _ | usage())?,
+            _ if path.is_none() && ! arg.starts_with("--") => path = Some(arg),
+            _ => return Err(usage()),
+        }
+    }
+    let path = path.ok_or_else(usage)?;
+    let trained = byteserver::dictionaries::train(path, sample, size)?;
+    println!("Trained dictionary {}, of {} bytes, from {} records, and added it to {}. \
+              Servers started from now on compress new records with it.",
+             trained.id, trained.size, trained.samples,
+             byteserver::dictionaries::file_path(path));
+    Ok(())
+}
+
+fn blob_gc(args: &[String]) -> Result<()> {
+    let (flags, paths) = split_args(args);
+    let (path, blob_dir, dry_run) = match (flags.as_slice(), paths.as_slice()) {
+        ([], [path, blob_dir]) => (*path, *blob_dir, false),
+        (["--dry-run"], [path, blob_dir]) => (*path, *blob_dir, true),
+        _ => return Err(anyhow!("Usage: byteserver blob-gc [--dry-run] PATH BLOB-DIR")),
+    };
+    let garbage = byteserver::pack::collect_blobs(path, blob_dir, dry_run)?;
+    for file in garbage.files.iter() {
+        println!("{}", file.display());
+    }
+    println!("{} {} blob files, {} bytes", if dry_run { "Would remove" } else { "Removed" },
+             garbage.files.len(), garbage.bytes);
+    Ok(())
+}
+
+fn convert(args: &[String]) -> Result<()> {
+    let path = match args {
+        [path] => path,
+        _ => return Err(anyhow!("Usage: byteserver convert PATH")),
+    };
+    match byteserver::convert::convert_in_place(path)? {
+        Some(count) =>
+            println!("Converted {} records. The original was saved as {}{}",
+                     count, path, byteserver::compact::OLD_SUFFIX),
+        None => println!("{} is already in the current format", path),
+    }
+    Ok(())
+}
+
+fn restore_backup(args: &[String]) -> Result<()> {
+    let (dir, path) = match args {
+        [dir, path] => (dir, path),
+        _ => return Err(anyhow!("Usage: byteserver restore-backup DIR PATH")),
+    };
+    let last = byteserver::backup::restore(dir, path)?;
+    println!("Restored {} bytes, as of {}, to {}",
+             last.end, byteserver::tid::tid_string(&last.tid), path);
+    Ok(())
+}
+
+fn oid(args: &[String]) -> Result<()> {
+    let (path, oid) = match args {
+        [path, oid] => (path, byteserver::inspect::parse_id(oid)?),
+        _ => return Err(anyhow!("Usage: byteserver oid PATH OID")),
+    };
+    let history = byteserver::inspect::oid_history(path, &oid)?;
+    if history.is_empty() {
+        return Err(anyhow!("No records for {}", byteserver::util::hex(&oid)));
+    }
+    for revision in history.iter() {
+        println!("{}", revision);
+    }
+    Ok(())
+}
+
+fn search(args: &[String]) -> Result<()> {
+    let usage = || anyhow!(
+        "Usage: byteserver search [--after TIME] [--before TIME] [--user USER] \
+         [--description TEXT] PATH");
+    let mut criteria = byteserver::inspect::SearchCriteria::default();
+    let mut path: Option<&String> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--after" | "--before" | "--user" | "--description" => {
+                let value = args.next().ok_or_else(usage)?;
+                match arg.as_str() {
+                    "--after" => criteria.after =
+                        Some(byteserver::inspect::parse_time(value)?),
+                    "--before" => criteria.before =
+                        Some(byteserver::inspect::parse_time(value)?),
+                    "--user" => criteria.user = Some(value.clone()),
+                    _ => criteria.description = Some(value.clone()),
+                }
+            },
+            _ if path.is_none() && ! arg.starts_with("--") => path = Some(arg),
+            _ => return Err(usage()),
+        }
+    }
+    let path = path.ok_or_else(usage)?;
+    for found in byteserver::inspect::search(path, &criteria)? {
+        println!("{}", found);
+    }
+    Ok(())
+}
 
-    // TODO, options :)
-    let fs = std::sync::Arc::new(
-        byteserver::storage::FileStorage::<byteserver::writer::Client>::open(
-            String::from("data.fs")).unwrap());
-    
-    let listener = std::net::TcpListener::bind("127.0.0.1:8080").unwrap();
-
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                stream.set_nodelay(true).unwrap();
-                println!("Accepted {:?} {}", stream, stream.nodelay().unwrap());
-                let (send, receive) = std::sync::mpsc::channel();
-
-                let client = byteserver::writer::Client::new(
-                    stream.peer_addr().unwrap().to_string(), send.clone());
-                fs.add_client(client.clone());
-
-                let read_fs = fs.clone();
-                let read_stream = stream.try_clone().unwrap();
-                std::thread::spawn(
-                    move ||
-                        byteserver::reader::reader(
-                            read_fs, read_stream, send).unwrap());
-
-                let write_fs = fs.clone();
-                std::thread::spawn(
-                    move ||
-                        byteserver::writer::writer(
-                            write_fs, stream, receive, client).unwrap());
+struct StorageSpec<'a> {
+    name: &'a str,
+    path: &'a str,
+    limits: byteserver::storage::Limits,
+    pool_sizes: byteserver::storage::PoolSizes,
+    sample_rate: u32,
+    invalidation_queue: usize,
+    blob_dir: Option<&'a str>,
+    shared_blobs: bool,
+    backup_dir: Option<&'a str>,
+    pack_schedule: Option<byteserver::storage::PackSchedule>,
+    slow_requests: Option<std::time::Duration>,
+    revision_index: bool,
+    index_journal: bool,
+    mapped_reads: bool,
+    record_cache: u64,
+    preallocation: u64,
+    compression: bool,
+    journals: Vec<&'a str>,
+    webhooks: Vec<&'a str>,
+}
+
+impl StorageSpec<'_> {
+    fn change_sinks(&self) -> Result<Vec<Box<dyn byteserver::cdc::Sink>>> {
+        let mut sinks: Vec<Box<dyn byteserver::cdc::Sink>> = vec![];
+        for path in self.journals.iter() {
+            sinks.push(Box::new(byteserver::cdc::Journal::open(path)?));
+        }
+        #[cfg(feature = "webhook")]
+        for url in self.webhooks.iter() {
+            sinks.push(Box::new(byteserver::cdc::Webhook::new(url)?));
+        }
+        #[cfg(not(feature = "webhook"))]
+        if let Some(url) = self.webhooks.first() {
+            return Err(anyhow!("Can't post changes to {}: \
+                                byteserver was built without webhook support", url));
+        }
+        Ok(sinks)
+    }
+}
+
+// Parse NAME=PATH[,OPTION[=VALUE]]...
+fn parse_storage(spec: &str) -> Result<StorageSpec<'_>> {
+    let bad = || anyhow!("Bad storage specification {}", spec);
+    let mut parts = spec.split(',');
+    let (name, path) = parts.next().and_then(| p | p.split_once('='))
+        .ok_or_else(bad)?;
+    let mut parsed = StorageSpec {
+        name, path,
+        limits: Default::default(), pool_sizes: Default::default(),
+        sample_rate: 0,
+        invalidation_queue: byteserver::storage::INVALIDATION_QUEUE_SIZE,
+        blob_dir: None, shared_blobs: false, backup_dir: None, pack_schedule: None,
+        slow_requests: None,
+        revision_index: false, index_journal: false, mapped_reads: false, record_cache: 0,
+        preallocation: 0, compression: false,
+        journals: vec![], webhooks: vec![],
+    };
+    let days = | v: &str | v.parse::<f64>().ok()
+        .and_then(| days | std::time::Duration::try_from_secs_f64(days * 86400.0).ok())
+        .ok_or_else(bad);
+    let (mut pack_every, mut pack_keep, mut pack_rate) = (None, None, None);
+    for option in parts {
+        match option.split_once('=') {
+            Some(("max-size", v)) =>
+                parsed.limits.max_size = Some(v.parse().map_err(| _ | bad())?),
+            Some(("min-free-space", v)) =>
+                parsed.limits.min_free_space = Some(v.parse().map_err(| _ | bad())?),
+            Some(("max-clients", v)) =>
+                parsed.limits.max_clients = Some(v.parse().map_err(| _ | bad())?),
+            Some(("warn-at", v)) =>
+                parsed.limits.warning_percent = Some(
+                    v.trim_end_matches('%').parse().map_err(| _ | bad())?),
+            Some(("sample-loads", v)) =>
+                parsed.sample_rate = v.parse().map_err(| _ | bad())?,
+            Some(("invalidation-queue", v)) =>
+                parsed.invalidation_queue = v.parse().map_err(| _ | bad())?,
+            Some(("blob-dir", v)) => parsed.blob_dir = Some(v),
+            Some(("backup-dir", v)) => parsed.backup_dir = Some(v),
+            Some(("pack-every", v)) => pack_every = Some(days(v)?),
+            Some(("pack-keep", v)) => pack_keep = Some(days(v)?),
+            Some(("pack-rate", v)) => pack_rate = Some(v.parse().map_err(| _ | bad())?),
+            Some(("record-cache", v)) =>
+                parsed.record_cache = v.parse().map_err(| _ | bad())?,
+            Some(("preallocate", v)) =>
+                parsed.preallocation = v.parse().map_err(| _ | bad())?,
+            Some(("tmps", v)) =>
+                parsed.pool_sizes.tmps = v.parse().map_err(| _ | bad())?,
+            Some(("journal", v)) => parsed.journals.push(v),
+            Some(("webhook", v)) => parsed.webhooks.push(v),
+            Some(("max-transaction-size", v)) =>
+                parsed.limits.max_transaction_size = Some(v.parse().map_err(| _ | bad())?),
+            Some(("max-transaction-records", v)) =>
+                parsed.limits.max_transaction_records = Some(v.parse().map_err(| _ | bad())?),
+            Some(("max-voted", v)) =>
+                parsed.limits.max_voted = Some(v.parse().map_err(| _ | bad())?),
+            Some(("finish-timeout", v)) =>
+                parsed.limits.finish_timeout = Some(
+                    std::time::Duration::try_from_secs_f64(
+                        v.parse().map_err(| _ | bad())?).map_err(| _ | bad())?),
+            Some(("slow-requests", v)) =>
+                parsed.slow_requests = Some(
+                    std::time::Duration::try_from_secs_f64(
+                        v.parse().map_err(| _ | bad())?).map_err(| _ | bad())?),
+            None if option == "read-only" => parsed.limits.read_only = true,
+            None if option == "shared-blobs" => parsed.shared_blobs = true,
+            None if option == "revision-index" => parsed.revision_index = true,
+            None if option == "index-journal" => parsed.index_journal = true,
+            None if option == "mmap" => parsed.mapped_reads = true,
+            None if option == "compress" => parsed.compression = true,
+            _ => return Err(bad()),
+        }
+    }
+    if parsed.shared_blobs && parsed.blob_dir.is_none() {
+        return Err(anyhow!("Shared blobs need a blob-dir, in {}", spec));
+    }
+    match pack_every {
+        Some(interval) if ! interval.is_zero() =>
+            parsed.pack_schedule = Some(byteserver::storage::PackSchedule {
+                interval, keep: pack_keep.unwrap_or_default(), rate: pack_rate,
+            }),
+        Some(_) => return Err(bad()),
+        None if pack_keep.is_some() || pack_rate.is_some() =>
+            return Err(anyhow!("Scheduled packs need pack-every, in {}", spec)),
+        None => {},
+    }
+    Ok(parsed)
+}
+
+struct ListenerSpec<'a> {
+    address: &'a str,
+    read_only: bool,
+    proxy_protocol: bool,
+    buffers: byteserver::reactor::Buffers,
+}
+
+// Parse ADDRESS[,read-only][,proxy-protocol][,read-buffer=BYTES]
+// [,write-buffer=BYTES][,socket-buffer=BYTES]
+fn parse_listener(spec: &str) -> Result<ListenerSpec<'_>> {
+    let mut parts = spec.split(',');
+    let mut parsed = ListenerSpec {
+        address: parts.next().unwrap_or(""), read_only: false, proxy_protocol: false,
+        buffers: Default::default(),
+    };
+    let bad = || anyhow!("Bad listener specification {}", spec);
+    for option in parts {
+        match option.split_once('=') {
+            Some(("read-buffer", size)) =>
+                parsed.buffers.read = size.parse().map_err(| _ | bad())?,
+            Some(("write-buffer", size)) =>
+                parsed.buffers.write = size.parse().map_err(| _ | bad())?,
+            Some(("socket-buffer", size)) =>
+                parsed.buffers.socket = size.parse().map_err(| _ | bad())?,
+            Some(_) => return Err(bad()),
+            None => match option {
+                "read-only" => parsed.read_only = true,
+                "proxy-protocol" => parsed.proxy_protocol = true,
+                _ => return Err(bad()),
             },
-            Err(e) => { println!("WTF {}", e) }
         }
     }
+    Ok(parsed)
+}
+
+// Connection handling shared by listeners
+#[derive(Clone)]
+struct Serving {
+    registry: std::sync::Arc<byteserver::registry::Registry>,
+    reactor: std::sync::Arc<byteserver::reactor::Reactor>,
+    admission: Option<std::sync::Arc<byteserver::registry::Admission>>,
+    // Whether connections beyond the admission limit wait
+    queue: bool,
+    idle_timeout: Option<std::time::Duration>,
+    tls: Option<std::sync::Arc<rustls::ServerConfig>>,
+}
+
+// Read a connection's PROXY header, if it should have one, and do
+// the TLS handshake, if we serve TLS, returning the client's name
+// and the stream to talk to it over.
+fn start(mut stream: std::net::TcpStream, proxy_protocol: bool,
+         tls: Option<&std::sync::Arc<rustls::ServerConfig>>)
+         -> Result<(String, byteserver::tls::Stream)> {
+    let mut name = stream.peer_addr()?.to_string();
+    if proxy_protocol {
+        if let Some(client) = byteserver::proxy::read_header(&mut stream)
+            .with_context(|| name.clone())?
+        {
+            name = client.to_string();
+        }
+    }
+    let stream = match tls {
+        Some(config) => byteserver::tls::accept(stream, config)
+            .with_context(|| name.clone())?,
+        None => byteserver::tls::Stream::Plain(stream),
+    };
+    Ok((name, stream))
+}
+
+// Accept connections, handing them to the reactor once they've
+// registered.
+//
+// With an admission limit, connections beyond it wait until others
+// close if queue is true, and are rejected otherwise.  With an idle
+// timeout, clients that send nothing for that long are disconnected.
+fn accept(listener: std::net::TcpListener, proxy_protocol: bool, read_only: bool,
+          buffers: byteserver::reactor::Buffers, serving: Serving) {
+    loop {
+        // When queueing, wait for a slot before accepting, so waiting
+        // connections stay in the listen backlog.
+        let mut slot = match serving.admission {
+            Some(ref admission) if serving.queue => Some(admission.admit()),
+            _ => None,
+        };
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) => { tracing::error!("Accepting failed: {}", e); continue },
+        };
+        if slot.is_none() {
+            if let Some(ref admission) = serving.admission {
+                slot = admission.try_admit();
+                if slot.is_none() {
+                    tracing::warn!("Rejected {:?}: too many connections", stream);
+                    let reason = format!("Too many connections ({})", admission.max());
+                    let tls = serving.tls.clone();
+                    std::thread::spawn(move || {
+                        let _ = stream.set_read_timeout(
+                            Some(std::time::Duration::from_secs(10)));
+                        if let Ok((_, stream)) = start(stream, proxy_protocol, tls.as_ref()) {
+                            if let Ok(read_stream) = stream.try_clone() {
+                                let _ = byteserver::registry::reject(
+                                    &reason, read_stream, stream);
+                            }
+                        }
+                    });
+                    continue;
+                }
+            }
+        }
+        if let Err(err) = stream.set_nodelay(true) {
+            tracing::warn!("{:?}: {}", stream, err);
+            continue;
+        }
+        tracing::info!("Accepted {:?}", stream);
+        let serving = serving.clone();
+        // Connections get threads only while they're being set up.
+        std::thread::spawn(
+            move || {
+                if let Err(err) = stream.set_read_timeout(serving.idle_timeout) {
+                    tracing::warn!("{:?}: {}", stream, err);
+                    return;
+                }
+                let (name, stream) = match start(stream, proxy_protocol, serving.tls.as_ref()) {
+                    Ok(started) => started,
+                    Err(err) => {
+                        tracing::warn!("{:#}", err);
+                        return;
+                    },
+                };
+                if let Err(err) = serving.reactor.serve(
+                    &serving.registry, name.clone(), read_only, stream, buffers, slot) {
+                    tracing::warn!("{}: {:#}", name, err);
+                }
+            });
+    }
+}
+
+fn serve(args: &[String]) -> Result<()> {
+    let usage = || anyhow!(
+        "Usage: byteserver [--listen ADDRESS[,read-only][,proxy-protocol]\
+         [,read-buffer=BYTES][,write-buffer=BYTES][,socket-buffer=BYTES]]... \
+         [--max-connections N [--queue-connections]] [--idle-timeout SECONDS] \
+         [--heartbeat SECONDS] [--info-interval SECONDS] [--workers N] [--health ADDRESS] [--monitor ADDRESS] \
+         [--log-level LEVEL] [--log-json] \
+         [--daemon] [--pidfile PATH] [--log-file PATH] \
+         [--proxy-protocol] [--passwords PATH] [--permissions PATH] [--read-only] \
+         [--tls-certificate PATH --tls-key PATH [--tls-client-ca PATH]] \
+         [--storage NAME=PATH[,max-size=BYTES][,min-free-space=BYTES][,max-clients=N][,read-only]\
+         [,warn-at=PERCENT][,sample-loads=N][,invalidation-queue=N]\
+         [,blob-dir=PATH[,shared-blobs]][,backup-dir=PATH][,pack-every=DAYS[,pack-keep=DAYS][,pack-rate=BYTES]][,tmps=N][,record-cache=BYTES][,preallocate=BYTES][,compress][,mmap][,revision-index][,index-journal][,finish-timeout=SECONDS][,max-voted=N]\
+         [,max-transaction-size=BYTES][,max-transaction-records=N]\
+         [,slow-requests=SECONDS][,journal=PATH][,webhook=URL]]...");
+    byteserver::info::started(&args.join(" "));
+    let mut registry = byteserver::registry::Registry::new();
+    let mut proxy_protocol = false;
+    let mut listeners = vec![];
+    let mut admission = None;
+    let mut queue = false;
+    let mut idle_timeout = None;
+    let mut heartbeat = None;
+    let mut info_interval = None;
+    let mut workers = byteserver::reactor::DEFAULT_WORKERS;
+    let mut health = None;
+    let mut monitor = None;
+    let mut log_level = tracing::Level::INFO;
+    let mut log_json = false;
+    let mut daemon = false;
+    let mut pidfile = None;
+    let mut log_file = None;
+    let (mut tls_certificate, mut tls_key, mut tls_client_ca) = (None, None, None);
+    let mut storages = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--proxy-protocol" => proxy_protocol = true,
+            "--max-connections" => admission = Some(byteserver::registry::Admission::new(
+                args.next().ok_or_else(usage)?.parse().map_err(| _ | usage())?)),
+            "--queue-connections" => queue = true,
+            "--idle-timeout" => idle_timeout = Some(
+                std::time::Duration::try_from_secs_f64(
+                    args.next().ok_or_else(usage)?.parse().map_err(| _ | usage())?)
+                    .ok().filter(| timeout | !timeout.is_zero()).ok_or_else(usage)?),
+            "--heartbeat" => heartbeat = Some(
+                std::time::Duration::try_from_secs_f64(
+                    args.next().ok_or_else(usage)?.parse().map_err(| _ | usage())?)
+                    .ok().filter(| interval | !interval.is_zero()).ok_or_else(usage)?),
+            "--info-interval" => info_interval = Some(
+                std::time::Duration::try_from_secs_f64(
+                    args.next().ok_or_else(usage)?.parse().map_err(| _ | usage())?)
+                    .ok().filter(| interval | !interval.is_zero()).ok_or_else(usage)?),
+            "--workers" => workers =
+                args.next().ok_or_else(usage)?.parse().map_err(| _ | usage())?,
+            "--listen" => listeners.push(parse_listener(args.next().ok_or_else(usage)?)?),
+            "--read-only" => registry.set_read_only(true),
+            "--passwords" => registry.set_authenticator(Box::new(
+                byteserver::auth::Passwords::load(args.next().ok_or_else(usage)?)?)),
+            "--permissions" => registry.set_permissions(
+                byteserver::auth::Permissions::load(args.next().ok_or_else(usage)?)?),
+            "--tls-certificate" => tls_certificate = Some(args.next().ok_or_else(usage)?),
+            "--tls-key" => tls_key = Some(args.next().ok_or_else(usage)?),
+            "--tls-client-ca" => tls_client_ca = Some(args.next().ok_or_else(usage)?),
+            "--log-level" => log_level = args.next().ok_or_else(usage)?.parse()?,
+            "--log-json" => log_json = true,
+            "--daemon" => daemon = true,
+            "--pidfile" => pidfile = Some(args.next().ok_or_else(usage)?),
+            "--log-file" => log_file = Some(args.next().ok_or_else(usage)?),
+            "--health" => health = Some(args.next().ok_or_else(usage)?),
+            "--monitor" => monitor = Some(args.next().ok_or_else(usage)?),
+            "--storage" => storages.push(parse_storage(args.next().ok_or_else(usage)?)?),
+            _ => return Err(usage()),
+        }
+    }
+
+    let tls = match (tls_certificate, tls_key) {
+        (Some(certificate), Some(key)) => Some(byteserver::tls::server_config(
+            certificate, key, tls_client_ca.map(| path | path.as_str()))?),
+        (None, None) if tls_client_ca.is_none() => None,
+        _ => return Err(usage()),
+    };
+
+    byteserver::log::configure(log_level, log_json)?;
+    if let Some(path) = log_file {
+        byteserver::daemon::log_to(path)?;
+    }
+    // Before any threads are started:
+    if daemon {
+        byteserver::daemon::daemonize(log_file.is_some())?;
+    }
+    let _pidfile = match pidfile {
+        Some(path) => Some(byteserver::daemon::Pidfile::create(path)?),
+        None => None,
+    };
+
+    // Answer health checks while storages open, which can take a
+    // while if indexes have to be rebuilt.
+    let health = match health {
+        Some(address) => {
+            let listener = std::net::TcpListener::bind(address)
+                .with_context(|| format!("listening on {}", address))?;
+            tracing::info!("Health checks on {}", listener.local_addr()?);
+            let health = byteserver::health::Health::new(
+                byteserver::health::DEFAULT_STALL_TIMEOUT);
+            let server = health.clone();
+            std::thread::spawn(move || server.serve(listener));
+            Some(health)
+        },
+        None => None,
+    };
+    for spec in storages {
+        let sinks = spec.change_sinks()?;
+        let fs = registry.open(spec.name, spec.path, spec.limits, spec.pool_sizes)?;
+        fs.set_access_sampling(spec.sample_rate);
+        fs.latencies().set_slow(spec.slow_requests);
+        fs.set_record_cache_size(spec.record_cache);
+        fs.set_mapped_reads(spec.mapped_reads);
+        fs.set_preallocation(spec.preallocation);
+        fs.set_compression(spec.compression)
+            .with_context(|| format!("compressing records of {}", spec.path))?;
+        if spec.revision_index {
+            fs.set_revision_index(true)
+                .with_context(|| format!("indexing revisions of {}", spec.path))?;
+        }
+        if spec.index_journal {
+            fs.set_index_journal(true)
+                .with_context(|| format!("journaling index changes of {}", spec.path))?;
+        }
+        fs.set_invalidation_queue_size(spec.invalidation_queue);
+        if let Some(path) = spec.blob_dir {
+            fs.set_blob_dir(path, spec.shared_blobs).with_context(|| format!("opening blob directory {}", path))?;
+        }
+        if let Some(path) = spec.backup_dir {
+            fs.set_backup_dir(path).with_context(|| format!("opening backup directory {}", path))?;
+        }
+        if let Some(schedule) = spec.pack_schedule {
+            byteserver::storage::start_packer(&fs, schedule);
+        }
+        fs.set_change_sinks(sinks);
+    }
+    if registry.names().is_empty() {
+        registry.open("1", "data.fs", Default::default(), Default::default())?;
+    }
+    if listeners.is_empty() {
+        listeners.push(parse_listener("127.0.0.1:8080")?);
+    }
+    let serving = Serving {
+        registry: std::sync::Arc::new(registry),
+        reactor: byteserver::reactor::Reactor::new(workers, idle_timeout)?,
+        admission, queue, idle_timeout, tls,
+    };
+    if let Some(interval) = heartbeat {
+        byteserver::registry::start_heartbeats(&serving.registry, interval);
+    }
+    if let Some(interval) = info_interval {
+        byteserver::registry::start_info(&serving.registry, interval);
+    }
+
+    if let Some(address) = monitor {
+        let listener = std::net::TcpListener::bind(address)
+            .with_context(|| format!("listening on {}", address))?;
+        tracing::info!("Monitor on {}", listener.local_addr()?);
+        let registry = serving.registry.clone();
+        std::thread::spawn(move || byteserver::monitor::serve(listener, registry));
+    }
+
+    let mut threads = vec![];
+    for spec in listeners {
+        let listener = std::net::TcpListener::bind(spec.address)
+            .with_context(|| format!("listening on {}", spec.address))?;
+        tracing::info!("Listening on {}{}", listener.local_addr()?,
+                       if spec.read_only { " (read-only)" } else { "" });
+        let serving = serving.clone();
+        let proxy_protocol = proxy_protocol || spec.proxy_protocol;
+        let read_only = spec.read_only;
+        let buffers = spec.buffers;
+        threads.push(std::thread::spawn(
+            move || accept(listener, proxy_protocol, read_only, buffers, serving)));
+    }
+    if let Some(health) = health {
+        health.ready(&serving.registry);
+    }
+    for thread in threads {
+        let _ = thread.join();
+    }
+    Ok(())
 }
@@ -0,0 +1,179 @@
+// Memory-mapped reads of a data file
+//
+// Loads can read records from a map of the data file, rather than
+// with positional reads of a shared file, so the operating system's
+// page cache does the work, without a system call per read.
+//
+// The file grows as transactions are committed, and a map only
+// covers the file as it was when the map was made.  Data past the
+// end of a map are read from the file, and the map is remade once
+// enough has been written past it.  Loads using an older map keep it
+// until they're done.
+//
+// Written data are never changed, and the file is never truncated
+// while it's open: packing writes a new file, after which maps of the
+// old one are dropped.
+
+use std::os::unix::fs::FileExt;
+
+// Bytes written past the end of a map before it's remade
+pub const REMAP_SLACK: u64 = 1 << 24;
+
+/// A read-only map of a data file
+pub struct Mapping {
+    map: memmap::Mmap,
+    file: std::fs::File,
+}
+
+impl Mapping {
+
+    pub fn open(path: &str) -> std::io::Result<Mapping> {
+        let file = std::fs::File::open(path)?;
+        let map = memmap::Mmap::open(&file, memmap::Protection::Read)?;
+        Ok(Mapping { map, file })
+    }
+
+    /// The size of the file when it was mapped
+    pub fn len(&self) -> u64 {
+        self.map.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn reader(&self) -> Reader<'_> {
+        // The mapped data don't change, because written data never
+        // do and the file isn't truncated.
+        Reader { data: unsafe { self.map.as_slice() }, file: &self.file, pos: 0 }
+    }
+}
+
+/// Maps of a data file, remade as it grows
+pub struct Maps {
+    path: String,
+    slack: u64,
+    current: Option<std::sync::Arc<Mapping>>,
+}
+
+impl Maps {
+
+    /// Map the file at path, remapping it when more than slack bytes
+    /// have been written past the end of the current map.
+    pub fn new(path: &str, slack: u64) -> Maps {
+        Maps { path: path.to_string(), slack, current: None }
+    }
+
+    /// Get a map of the file, given the end of the data to be read.
+    pub fn get(&mut self, end: u64) -> std::io::Result<std::sync::Arc<Mapping>> {
+        match self.current {
+            Some(ref mapping) if end <= mapping.len() + self.slack => Ok(mapping.clone()),
+            _ => {
+                let mapping = std::sync::Arc::new(Mapping::open(&self.path)?);
+                self.current = Some(mapping.clone());
+                Ok(mapping)
+            },
+        }
+    }
+
+    /// Forget the current map, e.g. because the file was replaced.
+    pub fn clear(&mut self) {
+        self.current = None;
+    }
+}
+
+/// Reads data from a map, and from its file past the end of the map,
+/// or just from a file.
+///
+/// Files are read with positional reads, so readers sharing a file
+/// don't share a position.  Readers are cheap to copy, and each copy
+/// has its own position.
+#[derive(Clone, Copy)]
+pub struct Reader<'a> {
+    data: &'a [u8],
+    file: &'a std::fs::File,
+    pos: u64,
+}
+
+impl<'a> Reader<'a> {
+
+    /// Read from a file that isn't mapped.
+    pub fn file(file: &'a std::fs::File) -> Reader<'a> {
+        Reader { data: &[], file, pos: 0 }
+    }
+}
+
+impl std::io::Read for Reader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = if self.pos < self.data.len() as u64 {
+            let data = &self.data[self.pos as usize ..];
+            let read = std::cmp::min(buf.len(), data.len());
+            buf[.. read].copy_from_slice(&data[.. read]);
+            read
+        }
+        else {
+            self.file.read_at(buf, self.pos)?
+        };
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl std::io::Seek for Reader<'_> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            std::io::SeekFrom::Start(pos) => Some(pos),
+            std::io::SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+            std::io::SeekFrom::End(delta) =>
+                self.file.metadata()?.len().checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::InvalidInput, "seeking before the start of the file"))?;
+        Ok(self.pos)
+    }
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::prelude::*;
+
+    use crate::util;
+
+    #[test]
+    fn reads_past_the_map() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let mut maps = Maps::new(&path, 5);
+        let mapping = maps.get(10).unwrap();
+        assert_eq!(mapping.len(), 10);
+
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"abcde").unwrap();
+        let mut reader = mapping.reader();
+        reader.seek(std::io::SeekFrom::Start(8)).unwrap();
+        let mut copy = reader;
+        assert_eq!(util::read_sized(&mut reader, 7).unwrap(), b"89abcde");
+        assert_eq!(reader.seek(std::io::SeekFrom::Current(-3)).unwrap(), 12);
+        assert_eq!(util::read_sized(&mut reader, 3).unwrap(), b"cde");
+        assert!(util::read_sized(&mut reader, 1).is_err());
+        assert_eq!(util::read_sized(&mut copy, 2).unwrap(), b"89");
+
+        // The map is remade once more than the slack was written past it:
+        assert!(std::sync::Arc::ptr_eq(&maps.get(15).unwrap(), &mapping));
+        file.write_all(b"f").unwrap();
+        let remapped = maps.get(16).unwrap();
+        assert_eq!(remapped.len(), 16);
+        maps.clear();
+        assert!(! std::sync::Arc::ptr_eq(&maps.get(16).unwrap(), &remapped));
+
+        let file = std::fs::File::open(&path).unwrap();
+        let mut reader = Reader::file(&file);
+        reader.seek(std::io::SeekFrom::End(-2)).unwrap();
+        assert_eq!(util::read_sized(&mut reader, 2).unwrap(), b"ef");
+    }
+}
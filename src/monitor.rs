@@ -0,0 +1,109 @@
+// A text monitor, like ZEO's, for monitoring scripts
+//
+// Clients connect, send a command line and get a response, after
+// which the connection is closed.  ``ruok`` returns a JSON object
+// with each storage's server_status, by storage name.
+use std::io::prelude::*;
+
+use anyhow::{Context, Result};
+
+use crate::msg::InfoValue;
+use crate::info;
+use crate::registry;
+
+/// Answer commands, one at a time, until the listener fails.
+pub fn serve(listener: std::net::TcpListener,
+             registry: std::sync::Arc<registry::Registry>)
+             -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream.context("accepting monitor connection")?;
+        if let Err(err) = answer(stream, &registry) {
+            tracing::warn!("Monitor: {:#}", err);
+        }
+    }
+    Ok(())
+}
+
+fn answer(mut stream: std::net::TcpStream, registry: &registry::Registry) -> Result<()> {
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+    let mut command = String::new();
+    std::io::BufReader::new(&stream).read_line(&mut command)
+        .context("reading command")?;
+    let response = match command.trim() {
+        "ruok" => ruok(registry),
+        command => format!("Unknown command {:?}", command),
+    };
+    writeln!(stream, "{}", response).context("writing response")
+}
+
+fn ruok(registry: &registry::Registry) -> String {
+    let storages: Vec<String> = registry.names().into_iter()
+        .filter_map(| name | registry.get(&name).map(| fs | (name, fs)))
+        .map(| (name, fs) | {
+            let status: Vec<String> = info::storage_status(&fs).iter()
+                .map(| (key, value) | format!("{}: {}", string(key), json(value)))
+                .collect();
+            format!("{}: {{{}}}", string(&name), status.join(", "))
+        })
+        .collect();
+    format!("{{{}}}", storages.join(", "))
+}
+
+fn string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn json(value: &InfoValue) -> String {
+    match value {
+        InfoValue::Int(v) => v.to_string(),
+        InfoValue::Float(v) => v.to_string(),
+        InfoValue::Str(v) => string(v),
+        InfoValue::Bytes(v) => string(&String::from_utf8_lossy(v)),
+        InfoValue::Bool(v) => v.to_string(),
+        InfoValue::List(v) =>
+            format!("[{}]", v.iter().map(json).collect::<Vec<String>>().join(", ")),
+    }
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::storage;
+    use crate::util;
+
+    fn send(address: std::net::SocketAddr, command: &str) -> String {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        writeln!(stream, "{}", command).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn ruok() {
+        let tmpdir = util::test::dir();
+        let mut registry = registry::Registry::new();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        registry.open("1", &path, storage::Limits::default(), Default::default()).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let registry = std::sync::Arc::new(registry);
+        std::thread::spawn(move || serve(listener, registry));
+
+        let response = send(address, "ruok");
+        assert!(response.starts_with(
+            "{\"1\": {\"aborts\": 0, \"commits\": 0, \"conflicts\": 0, \
+             \"connections\": 0, \"last-transaction\": \"0000000000000000\", \
+             \"latencies\": [], \"missed-heartbeats\": [], \
+             \"record-cache-hit-rate\": 0, \"record-cache-hits\": 0, \
+             \"record-cache-misses\": 0, \"record-cache-size\": 0, \"start\": \""), "{}", response);
+        assert!(response.ends_with(
+            &format!("\"storage\": {}, \"voted\": 0, \"waiting\": 0}}}}\n", string(&path))),
+            "{}", response);
+
+        assert_eq!(send(address, "nonsense"), "Unknown command \"nonsense\"\n");
+    }
+}
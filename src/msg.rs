@@ -19,36 +19,393 @@ pub fn size_vec(mut v: Vec<u8>) -> Vec<u8> {
 
 pub const NIL: Option<u32> = None;
 
+/// A sized response for a request id, with a result that's
+/// msgpack-encoded already
+pub fn raw_response(id: i64, result: &[u8]) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    rmp::encode::write_array_len(&mut buf, 3).map_err(| e | anyhow!("{:?}", e))?;
+    rmp::encode::write_sint(&mut buf, id).map_err(| e | anyhow!("{:?}", e))?;
+    rmp::encode::write_str(&mut buf, "R").map_err(| e | anyhow!("{:?}", e))?;
+    buf.extend_from_slice(result);
+    Ok(size_vec(buf))
+}
+
+/// The protocol the server offers when clients connect.  Messages
+/// are encoded with msgpack (M) rather than pickle (Z).
+pub const PROTOCOL: &[u8] = b"M5";
+
+/// Protocols clients may reply with.  The reply selects how the
+/// client's messages are encoded: M5 for msgpack and Z5, which ZEO 5
+/// clients reply with unless they're configured for msgpack, for
+/// pickle.  A +zstd suffix asks for large messages to be compressed,
+/// see Zstd.  Earlier clients aren't supported.
+pub const CLIENT_PROTOCOLS: &[&[u8]] = &[b"M5", b"Z5", b"M5+zstd", b"Z5+zstd"];
+
+/// The suffix of protocols whose messages may be compressed
+pub const ZSTD_SUFFIX: &[u8] = b"+zstd";
+
 pub fn bytes(data: &[u8]) -> serde::bytes::Bytes {
     serde::bytes::Bytes::new(data)
 }
 
+/// A value in an info map, which mixes numbers, strings, bytes,
+/// booleans and lists
+#[derive(Debug, Clone, PartialEq)]
+pub enum InfoValue {
+    Int(u64),
+    Float(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+    Bool(bool),
+    List(Vec<InfoValue>),
+}
+
+impl serde::Serialize for InfoValue {
+    fn serialize<S>(&self, serializer: &mut S) -> std::result::Result<(), S::Error>
+    where S: serde::Serializer {
+        match *self {
+            InfoValue::Int(v) => serializer.serialize_u64(v),
+            InfoValue::Float(v) => serializer.serialize_f64(v),
+            InfoValue::Str(ref v) => serializer.serialize_str(v),
+            InfoValue::Bytes(ref v) => serializer.serialize_bytes(v),
+            InfoValue::Bool(v) => serializer.serialize_bool(v),
+            InfoValue::List(ref v) => v.serialize(serializer),
+        }
+    }
+}
+
+struct InfoValueVisitor;
+
+impl serde::de::Visitor for InfoValueVisitor {
+    type Value = InfoValue;
+
+    fn visit_u64<E>(&mut self, v: u64) -> std::result::Result<InfoValue, E>
+    where E: serde::de::Error {
+        Ok(InfoValue::Int(v))
+    }
+
+    fn visit_f64<E>(&mut self, v: f64) -> std::result::Result<InfoValue, E>
+    where E: serde::de::Error {
+        Ok(InfoValue::Float(v))
+    }
+
+    fn visit_str<E>(&mut self, v: &str) -> std::result::Result<InfoValue, E>
+    where E: serde::de::Error {
+        Ok(InfoValue::Str(v.to_string()))
+    }
+
+    fn visit_bytes<E>(&mut self, v: &[u8]) -> std::result::Result<InfoValue, E>
+    where E: serde::de::Error {
+        Ok(InfoValue::Bytes(v.to_vec()))
+    }
+
+    fn visit_bool<E>(&mut self, v: bool) -> std::result::Result<InfoValue, E>
+    where E: serde::de::Error {
+        Ok(InfoValue::Bool(v))
+    }
+
+    fn visit_seq<V>(&mut self, mut visitor: V) -> std::result::Result<InfoValue, V::Error>
+    where V: serde::de::SeqVisitor {
+        let mut values = vec![];
+        while let Some(value) = visitor.visit()? {
+            values.push(value);
+        }
+        visitor.end()?;
+        Ok(InfoValue::List(values))
+    }
+}
+
+impl serde::Deserialize for InfoValue {
+    fn deserialize<D>(deserializer: &mut D) -> std::result::Result<InfoValue, D::Error>
+    where D: serde::Deserializer {
+        deserializer.deserialize(InfoValueVisitor)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Zeo {
     Raw(Vec<u8>),
     End,
 
     Register(i64, String, bool),
+    AuthChallenge(i64),
+    Authenticate(i64, String, util::Bytes),
     LoadBefore(i64, util::Oid, util::Tid),
+    LoadBefores(i64, Vec<util::Oid>, util::Tid),
+    LoadBeforeRange(i64, util::Oid, util::Tid, u64, u64),
+    LoadSerial(i64, util::Oid, util::Tid),
+    SendBlob(i64, util::Oid, util::Tid),
+    History(i64, util::Oid, u64),
+    RecordIternext(i64, Option<util::Oid>),
+    IteratorStart(i64, Option<util::Tid>, Option<util::Tid>),
+    IteratorNext(i64, u64),
+    IteratorRecordStart(i64, u64, util::Tid),
+    IteratorRecordNext(i64, u64),
+    IteratorGc(Vec<u64>),
+    UndoLog(i64, i64, i64),
+    UndoInfo(i64, i64, i64, Option<std::collections::BTreeMap<String, InfoValue>>),
     GetInfo(i64),
+    GetExtensionMethods(i64),
+    ServerStatus(i64),
     NewOids(i64),
-    TpcBegin(u64, util::Bytes, util::Bytes, util::Bytes),
+    NewOid(i64),
+    LastTransaction(i64),
+    GetInvalidations(i64, util::Tid),
+    TpcBegin(u64, util::Bytes, util::Bytes, util::Bytes, Option<util::Tid>),
     Storea(util::Oid, util::Tid, util::Bytes, u64),
+    StoreaStart(util::Oid, util::Tid, u64, u64),
+    StoreaChunk(util::Bytes, u64),
+    StoreBlobStart,
+    StoreBlobChunk(util::Bytes),
+    StoreBlobEnd(util::Oid, util::Tid, util::Bytes, u64),
+    StoreBlobShared(util::Oid, util::Tid, util::Bytes, String, u64),
+    Restorea(util::Oid, util::Tid, Option<util::Bytes>, Option<util::Tid>, u64),
     Vote(i64, u64),
     TpcFinish(i64, u64),
     TpcAbort(i64, u64),
     Ping(i64),
+    Checkpoint(i64),
+    DeferFsync(i64, bool),
+    HotObjects(i64, u64),
+    Pack(i64, f64, bool),
+    Backup(i64, String),
+    IncrementalBackup(i64, String),
+    SetReadView(i64, Option<util::Tid>),
+    /// A call to an extension method, with its msgpack-encoded arguments
+    Extension(i64, String, util::Bytes),
+    /// A call to a method the server doesn't have
+    Unknown(i64, String),
+    /// A call whose arguments couldn't be parsed, with the error
+    Malformed(i64, String, String),
 
     Locked(i64, u64),
 
-    Finished(i64, util::Tid, u64, u64),
+    Finished(i64, util::Tid, u64, u64, Vec<util::Oid>),
     Invalidate(util::Tid, Vec<util::Oid>),
+    Durable(util::Tid),
+    Heartbeat,
+    Info(u64, u64, util::Tid),
+    Close(String),
+}
+
+impl Zeo {
+    /// The id of a request that gets a response
+    pub fn id(&self) -> Option<i64> {
+        match *self {
+            Zeo::Register(id, _, _) | Zeo::AuthChallenge(id) | Zeo::Authenticate(id, _, _) |
+            Zeo::LoadBefore(id, _, _) | Zeo::LoadBefores(id, _, _) |
+            Zeo::LoadBeforeRange(id, _, _, _, _) |
+            Zeo::LoadSerial(id, _, _) | Zeo::SendBlob(id, _, _) | Zeo::History(id, _, _) |
+            Zeo::RecordIternext(id, _) | Zeo::IteratorStart(id, _, _) | Zeo::IteratorNext(id, _) |
+            Zeo::IteratorRecordStart(id, _, _) | Zeo::IteratorRecordNext(id, _) |
+            Zeo::UndoLog(id, _, _) | Zeo::UndoInfo(id, _, _, _) | Zeo::GetInfo(id) |
+            Zeo::NewOids(id) | Zeo::NewOid(id) | Zeo::LastTransaction(id) |
+            Zeo::GetInvalidations(id, _) |
+            Zeo::GetExtensionMethods(id) | Zeo::ServerStatus(id) |
+            Zeo::Vote(id, _) | Zeo::TpcFinish(id, _) | Zeo::TpcAbort(id, _) |
+            Zeo::Ping(id) | Zeo::Checkpoint(id) | Zeo::DeferFsync(id, _) |
+            Zeo::HotObjects(id, _) | Zeo::Pack(id, _, _) | Zeo::Backup(id, _) |
+            Zeo::IncrementalBackup(id, _) | Zeo::SetReadView(id, _) | Zeo::Locked(id, _) |
+            Zeo::Extension(id, _, _) | Zeo::Unknown(id, _) | Zeo::Malformed(id, _, _) |
+            Zeo::Finished(id, _, _, _, _) => Some(id),
+            _ => None,
+        }
+    }
 }
 
+/// How a connection's messages are encoded
+///
+/// The server works with msgpack.  Codecs convert message bodies,
+/// without their size prefixes, between msgpack and the encoding a
+/// client chose in its handshake.
+pub trait Codec: Send + Sync + std::fmt::Debug {
+    /// Convert a message from the client to msgpack.
+    fn decode(&self, message: Vec<u8>) -> Result<Vec<u8>>;
+
+    /// Convert a msgpack message for the client.
+    fn encode(&self, message: Vec<u8>) -> Result<Vec<u8>>;
+
+    /// Whether the client speaks msgpack, so messages needn't be
+    /// converted
+    fn is_msgpack(&self) -> bool {
+        false
+    }
+
+    /// Convert a sized msgpack message, as made by sencode!, for the
+    /// client.
+    fn frame(&self, mut sized: Vec<u8>) -> Result<Vec<u8>> {
+        if self.is_msgpack() {
+            return Ok(sized);
+        }
+        Ok(size_vec(self.encode(sized.split_off(4))?))
+    }
+}
+
+#[derive(Debug)]
+pub struct Msgpack;
+
+impl Codec for Msgpack {
+    fn decode(&self, message: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(message)
+    }
+    fn encode(&self, message: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(message)
+    }
+    fn is_msgpack(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct Pickle;
+
+impl Codec for Pickle {
+    fn decode(&self, message: Vec<u8>) -> Result<Vec<u8>> {
+        crate::pickle::to_msgpack(&message)
+    }
+    fn encode(&self, message: Vec<u8>) -> Result<Vec<u8>> {
+        crate::pickle::from_msgpack(&message)
+    }
+}
+
+/// Messages smaller than this, once encoded, aren't compressed.
+pub const COMPRESSION_THRESHOLD: usize = 1 << 12;
+
+// Message bodies start with a flag saying whether they're compressed.
+const PLAIN: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+/// Compresses large messages of another codec, for slow links
+///
+/// Each message body, in either direction, starts with a flag byte:
+/// 0 if the rest is the inner codec's message as is, and 1 if it's a
+/// zstd frame holding it.  Messages smaller than
+/// COMPRESSION_THRESHOLD aren't worth compressing, and neither are
+/// those that don't get smaller.  Messages aren't compressed with
+/// dictionaries, which clients don't have.
+#[derive(Debug)]
+pub struct Zstd(pub std::sync::Arc<dyn Codec>);
+
+impl Codec for Zstd {
+    fn decode(&self, message: Vec<u8>) -> Result<Vec<u8>> {
+        let message = match message.split_first() {
+            Some((&PLAIN, body)) => body.to_vec(),
+            Some((&COMPRESSED, body)) => crate::records::decompress(body, &Default::default())?,
+            Some((flag, _)) => return Err(anyhow!("Bad compression flag {}", flag)),
+            None => return Err(anyhow!("Empty message")),
+        };
+        self.0.decode(message)
+    }
+    fn encode(&self, message: Vec<u8>) -> Result<Vec<u8>> {
+        let message = self.0.encode(message)?;
+        if message.len() >= COMPRESSION_THRESHOLD {
+            if let Some(compressed) = crate::records::compress(&message, &Default::default())? {
+                let mut body = Vec::with_capacity(compressed.len() + 1);
+                body.push(COMPRESSED);
+                body.extend_from_slice(&compressed);
+                return Ok(body);
+            }
+        }
+        let mut body = Vec::with_capacity(message.len() + 1);
+        body.push(PLAIN);
+        body.extend_from_slice(&message);
+        Ok(body)
+    }
+}
+
+/// The codec for a protocol a client replied with
+pub fn codec(protocol: &[u8]) -> std::sync::Arc<dyn Codec> {
+    let inner: std::sync::Arc<dyn Codec> = if protocol.starts_with(b"Z") {
+        std::sync::Arc::new(Pickle)
+    }
+    else {
+        std::sync::Arc::new(Msgpack)
+    };
+    if protocol.ends_with(ZSTD_SUFFIX) {
+        std::sync::Arc::new(Zstd(inner))
+    }
+    else {
+        inner
+    }
+}
+
+/// Writes sized msgpack messages, as made by sencode!, in a client's
+/// encoding
+///
+/// Messages are converted as they're completed, so they can be
+/// written in pieces.
+pub struct Encoder<W: std::io::Write> {
+    writer: W,
+    codec: std::sync::Arc<dyn Codec>,
+    pending: Vec<u8>,
+    requests: Option<std::sync::Arc<crate::latency::Requests>>,
+}
+
+impl<W: std::io::Write> Encoder<W> {
+    pub fn new(writer: W, codec: std::sync::Arc<dyn Codec>) -> Encoder<W> {
+        Encoder { writer, codec, pending: vec![], requests: None }
+    }
+
+    /// Pass messages written to requests, to time the requests they
+    /// respond to.
+    pub fn with_requests(mut self, requests: Option<std::sync::Arc<crate::latency::Requests>>)
+                         -> Encoder<W> {
+        self.requests = requests;
+        self
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.codec.is_msgpack() {
+            match self.requests {
+                None => return self.writer.write(buf),
+                // Messages are usually written whole, and can be
+                // passed along as they are.
+                Some(ref requests) if self.pending.is_empty() && buf.len() >= 4 &&
+                    BigEndian::read_u32(buf) as usize + 4 == buf.len() => {
+                        requests.sent(buf);
+                        self.writer.write_all(buf)?;
+                        return Ok(buf.len());
+                    },
+                _ => {},
+            }
+        }
+        self.pending.extend_from_slice(buf);
+        while self.pending.len() >= 4 {
+            let want = BigEndian::read_u32(&self.pending) as usize + 4;
+            if self.pending.len() < want {
+                break;
+            }
+            let rest = self.pending.split_off(want);
+            let sized = std::mem::replace(&mut self.pending, rest);
+            if let Some(ref requests) = self.requests {
+                requests.sent(&sized);
+            }
+            let framed = self.codec.frame(sized).map_err(std::io::Error::other)?;
+            self.writer.write_all(&framed)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// How much input ZeoIters read at a time, by default
+pub const READ_BUFFER_SIZE: usize = 1 << 16;
+
 pub struct ZeoIter<T: std::io::Read> {
     reader: T,
-    buf: [u8; 1<<16],
+    buf: Vec<u8>,
     input: Vec<u8>,
+    codec: std::sync::Arc<dyn Codec>,
+    methods: std::sync::Arc<Methods>,
+    // Messages read, including heartbeats
+    received: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    // Where requests' arrival is noted, to time them
+    requests: Option<std::sync::Arc<crate::latency::Requests>>,
 }
 
 static HEARTBEAT_PREFIX: [u8; 2] = [147, 255];
@@ -56,7 +413,46 @@ static HEARTBEAT_PREFIX: [u8; 2] = [147, 255];
 impl<T: std::io::Read> ZeoIter<T> {
 
     pub fn new(reader: T) -> ZeoIter<T> {
-        ZeoIter { reader: reader, buf: [0u8; 1<<16], input: vec![] }
+        ZeoIter { reader: reader, buf: vec![0u8; READ_BUFFER_SIZE], input: vec![],
+                  codec: std::sync::Arc::new(Msgpack), methods: Default::default(),
+                  received: Default::default(), requests: None }
+    }
+
+    /// A count of the messages read, including heartbeats, to tell
+    /// whether the client is still sending
+    pub fn received(&self) -> std::sync::Arc<std::sync::atomic::AtomicU64> {
+        self.received.clone()
+    }
+
+    /// Note requests' arrival in requests, so their latency can be
+    /// recorded when they're responded to.
+    pub fn set_requests(&mut self, requests: std::sync::Arc<crate::latency::Requests>) {
+        self.requests = Some(requests);
+    }
+
+    /// Parse requests for methods, rather than the protocol's.
+    pub fn set_methods(&mut self, methods: std::sync::Arc<Methods>) {
+        self.methods = methods;
+    }
+
+    /// The methods requests are parsed for
+    pub fn methods(&self) -> std::sync::Arc<Methods> {
+        self.methods.clone()
+    }
+
+    /// Decode messages with codec, rather than as msgpack.
+    pub fn set_codec(&mut self, codec: std::sync::Arc<dyn Codec>) {
+        self.codec = codec;
+    }
+
+    /// The codec messages are decoded with
+    pub fn codec(&self) -> std::sync::Arc<dyn Codec> {
+        self.codec.clone()
+    }
+
+    /// Read up to size bytes at a time.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.buf = vec![0u8; size.max(1)];
     }
 
     fn read_want(&mut self, want: usize) -> Result<bool> {
@@ -83,6 +479,14 @@ impl<T: std::io::Read> ZeoIter<T> {
         )
     }
 
+    /// Continue reading messages from another reader, keeping any
+    /// input that's been read but not yet parsed.
+    pub fn with_reader<U: std::io::Read>(self, reader: U) -> ZeoIter<U> {
+        ZeoIter { reader, buf: self.buf, input: self.input, codec: self.codec,
+                  methods: self.methods, received: self.received,
+                  requests: self.requests }
+    }
+
     pub fn next_vec(&mut self) -> Result<Vec<u8>> {
         let want = self.advance()?;
         let mut data = self.input.split_off(want as usize);
@@ -97,18 +501,26 @@ impl<T: std::io::Read> ZeoIter<T> {
         }
         let mut data = self.input.split_off(want as usize);
         std::mem::swap(&mut data, &mut self.input);
+        self.received.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
-        if data[4..6] == HEARTBEAT_PREFIX {
+        let message = self.codec.decode(data.split_off(4)).context("decoding message")?;
+        if message.starts_with(&HEARTBEAT_PREFIX) {
             return self.next()    // skip heartbeats
         }
-        //println!("Read vec {:?}", &data[4..]);
-        let mut reader = std::io::Cursor::new(data.split_off(4));
-        parse_message(&mut reader)
+        if let Some(ref requests) = self.requests {
+            if let Ok((id, method)) = pre_parse(&mut &message[..]) {
+                if id > 0 {
+                    requests.received(id, method);
+                }
+            }
+        }
+        let mut reader = std::io::Cursor::new(message);
+        self.methods.parse(&mut reader)
     }
 
 }
 
-fn pre_parse(mut reader: &mut dyn std::io::Read)
+pub(crate) fn pre_parse(mut reader: &mut dyn std::io::Read)
              -> Result<(i64, String)> {
     let array_size =
         rmp::decode::read_array_size(&mut reader).context("get mess size")?;
@@ -120,58 +532,318 @@ fn pre_parse(mut reader: &mut dyn std::io::Read)
     Ok((id, method))
 }
 
-fn parse_message(mut reader: &mut dyn std::io::Read) -> Result<Zeo> {
-    let (id, method) = pre_parse(&mut reader)?;
-
-    Ok(match method.as_ref() {
-        "loadBefore" => {
-            let (oid, before): (ByteBuf, ByteBuf) =
-                decode!(&mut reader, "decoding loadBefore oid")?;
-            let oid = util::read8(&mut (&*oid)).context("loadBefore oid")?;
-            let before =
-                util::read8(&mut (&*before))
-                .context("loadBefore before")?;
-            Zeo::LoadBefore(id, oid, before)
-        },
-        "ping" => Zeo::Ping(id),
-        "tpc_begin" => {
-            let (txn, user, desc, ext, _, _): (
-                u64, ByteBuf, ByteBuf, ByteBuf, Option<ByteBuf>, ByteBuf) =
-                decode!(&mut reader, "decoding tpc_begin")?;
-            Zeo::TpcBegin(txn, user.to_vec(), desc.to_vec(), ext.to_vec())
-        },
-        "storea" => {
-            let (oid, committed, data, txn): (ByteBuf, ByteBuf, ByteBuf, u64) =
-                decode!(&mut reader, "decoding storea")?;
-            let oid = util::read8(&mut (&*oid)).context("storea oid")?;
-            let committed =
-                util::read8(&mut (&*committed))
-                .context("storea committed")?;
-            Zeo::Storea(oid, committed, data.to_vec(), txn)
-        },
-        "vote" => {
-            let (txn,): (u64,) = decode!(&mut reader, "decoding vote")?;
-            Zeo::Vote(id, txn)
-        },
-        "tpc_finish" => {
-            let (txn,): (u64,) = decode!(&mut reader, "decoding tpc_finish")?;
-            Zeo::TpcFinish(id, txn)
-        },
-        "tpc_abort" => {
-            let (txn,): (u64,) = decode!(&mut reader, "decoding tpc_abort")?;
-            Zeo::TpcAbort(id, txn)
-        },
-        "new_oids" => Zeo::NewOids(id),
-        "get_info" => Zeo::GetInfo(id),
-        "register" => {
-            let (storage, read_only): (String, bool) =
-                decode!(&mut reader, "decoding register")?;
-            Zeo::Register(id, storage, read_only)
-        },
-        _ => return Err(anyhow!("bad method {}", method))?
-    })
+/// Reads a method's arguments, making the message for a request with
+/// the given id
+pub type Parser = fn(i64, &mut dyn std::io::Read) -> Result<Zeo>;
+
+/// Handles calls to an extension method, given the client's storage
+/// and the msgpack-encoded arguments, returning the msgpack-encoded
+/// result
+pub type Handler = std::sync::Arc<
+        dyn Fn(&crate::storage::FileStorage<crate::writer::Client>, &[u8]) -> Result<Vec<u8>>
+        + Send + Sync>;
+
+#[derive(Clone)]
+enum Method {
+    Parser(Parser),
+    Extension(Handler),
 }
 
+/// The methods clients can call, by name
+///
+/// The protocol's methods are parsed into messages for the reader
+/// and writer.  Extension methods, added by embedders, are parsed
+/// into Extension messages, which the reader passes to their
+/// handlers.  Calls to other methods are parsed into Unknown
+/// messages, which get error responses.
+///
+/// Calls with arguments that can't be parsed are parsed into
+/// Malformed messages, which also get error responses, so the
+/// connection can carry on.  Asynchronous calls, which have no id to
+/// respond to, fail instead.
+#[derive(Clone)]
+pub struct Methods {
+    methods: std::collections::HashMap<String, Method>,
+}
+
+impl Default for Methods {
+    fn default() -> Methods {
+        let mut methods = Methods { methods: std::collections::HashMap::new() };
+        standard(&mut methods);
+        methods
+    }
+}
+
+impl Methods {
+
+    /// Add a method, or replace one, parsing its arguments with parser.
+    pub fn add(&mut self, name: &str, parser: Parser) {
+        self.methods.insert(name.to_string(), Method::Parser(parser));
+    }
+
+    /// Add an extension method, or replace a method with one.
+    pub fn add_extension(&mut self, name: &str, handler: Handler) {
+        self.methods.insert(name.to_string(), Method::Extension(handler));
+    }
+
+    /// The handler for an extension method
+    pub fn extension(&self, name: &str) -> Option<&Handler> {
+        match self.methods.get(name) {
+            Some(Method::Extension(handler)) => Some(handler),
+            _ => None,
+        }
+    }
+
+    /// The names of the extension methods, sorted
+    pub fn extensions(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.methods.iter()
+            .filter(| (_, method) | matches!(method, Method::Extension(_)))
+            .map(| (name, _) | name.as_ref())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Parse a request.
+    pub fn parse(&self, mut reader: &mut dyn std::io::Read) -> Result<Zeo> {
+        let (id, method) = pre_parse(&mut reader)?;
+        match self.methods.get(&method) {
+            Some(Method::Parser(parser)) => match parser(id, reader) {
+                Err(err) if id > 0 => Ok(Zeo::Malformed(id, method, format!("{:#}", err))),
+                result => result,
+            },
+            Some(Method::Extension(_)) => {
+                let mut args = vec![];
+                reader.read_to_end(&mut args).context("reading arguments")?;
+                Ok(Zeo::Extension(id, method, args))
+            },
+            None => Ok(Zeo::Unknown(id, method)),
+        }
+    }
+}
+
+// The methods of the protocol, described in protocol.rst
+fn standard(methods: &mut Methods) {
+    methods.add("loadBefore", | id, mut reader | {
+        let (oid, before): (ByteBuf, ByteBuf) =
+            decode!(&mut reader, "decoding loadBefore oid")?;
+        let oid = util::read8(&mut (&*oid)).context("loadBefore oid")?;
+        let before =
+            util::read8(&mut (&*before))
+            .context("loadBefore before")?;
+        Ok(Zeo::LoadBefore(id, oid, before))
+    });
+    methods.add("loadBefores", | id, mut reader | {
+        let (oids, before): (Vec<ByteBuf>, ByteBuf) =
+            decode!(&mut reader, "decoding loadBefores")?;
+        let oids = oids.iter()
+            .map(| oid | util::read8(&mut (&**oid)).context("loadBefores oid"))
+            .collect::<Result<Vec<util::Oid>>>()?;
+        let before = util::read8(&mut (&*before)).context("loadBefores before")?;
+        Ok(Zeo::LoadBefores(id, oids, before))
+    });
+    methods.add("load_before_range", | id, mut reader | {
+        let (oid, before, offset, size): (ByteBuf, ByteBuf, u64, u64) =
+            decode!(&mut reader, "decoding load_before_range")?;
+        let oid = util::read8(&mut (&*oid)).context("load_before_range oid")?;
+        let before = util::read8(&mut (&*before)).context("load_before_range before")?;
+        Ok(Zeo::LoadBeforeRange(id, oid, before, offset, size))
+    });
+    methods.add("loadSerial", | id, mut reader | {
+        let (oid, serial): (ByteBuf, ByteBuf) =
+            decode!(&mut reader, "decoding loadSerial")?;
+        let oid = util::read8(&mut (&*oid)).context("loadSerial oid")?;
+        let serial = util::read8(&mut (&*serial)).context("loadSerial serial")?;
+        Ok(Zeo::LoadSerial(id, oid, serial))
+    });
+    methods.add("sendBlob", | id, mut reader | {
+        let (oid, serial): (ByteBuf, ByteBuf) = decode!(&mut reader, "decoding sendBlob")?;
+        let oid = util::read8(&mut (&*oid)).context("sendBlob oid")?;
+        let serial = util::read8(&mut (&*serial)).context("sendBlob serial")?;
+        Ok(Zeo::SendBlob(id, oid, serial))
+    });
+    methods.add("history", | id, mut reader | {
+        let (oid, size): (ByteBuf, u64) = decode!(&mut reader, "decoding history")?;
+        let oid = util::read8(&mut (&*oid)).context("history oid")?;
+        Ok(Zeo::History(id, oid, size))
+    });
+    methods.add("undoLog", | id, mut reader | {
+        let (first, last): (i64, i64) = decode!(&mut reader, "decoding undoLog")?;
+        Ok(Zeo::UndoLog(id, first, last))
+    });
+    methods.add("undoInfo", | id, mut reader | {
+        let (first, last, spec) = decode!(&mut reader, "decoding undoInfo")?;
+        Ok(Zeo::UndoInfo(id, first, last, spec))
+    });
+    methods.add("ping", | id, _ | Ok(Zeo::Ping(id)));
+    methods.add("checkpoint", | id, _ | Ok(Zeo::Checkpoint(id)));
+    methods.add("record_iternext", | id, mut reader | {
+        let (next,): (Option<ByteBuf>,) =
+            decode!(&mut reader, "decoding record_iternext")?;
+        let next = match next {
+            Some(oid) => Some(util::read8(&mut (&*oid))
+                              .context("record_iternext oid")?),
+            None => None,
+        };
+        Ok(Zeo::RecordIternext(id, next))
+    });
+    methods.add("iterator_start", | id, mut reader | {
+        let (start, stop): (Option<ByteBuf>, Option<ByteBuf>) =
+            decode!(&mut reader, "decoding iterator_start")?;
+        let start = match start {
+            Some(tid) => Some(util::read8(&mut (&*tid)).context("iterator_start start")?),
+            None => None,
+        };
+        let stop = match stop {
+            Some(tid) => Some(util::read8(&mut (&*tid)).context("iterator_start stop")?),
+            None => None,
+        };
+        Ok(Zeo::IteratorStart(id, start, stop))
+    });
+    methods.add("iterator_next", | id, mut reader | {
+        let (iid,): (u64,) = decode!(&mut reader, "decoding iterator_next")?;
+        Ok(Zeo::IteratorNext(id, iid))
+    });
+    methods.add("iterator_record_start", | id, mut reader | {
+        let (iid, tid): (u64, ByteBuf) =
+            decode!(&mut reader, "decoding iterator_record_start")?;
+        let tid = util::read8(&mut (&*tid)).context("iterator_record_start tid")?;
+        Ok(Zeo::IteratorRecordStart(id, iid, tid))
+    });
+    methods.add("iterator_record_next", | id, mut reader | {
+        let (iid,): (u64,) = decode!(&mut reader, "decoding iterator_record_next")?;
+        Ok(Zeo::IteratorRecordNext(id, iid))
+    });
+    methods.add("iterator_gc", | _, mut reader | {
+        let (iids,): (Vec<u64>,) = decode!(&mut reader, "decoding iterator_gc")?;
+        Ok(Zeo::IteratorGc(iids))
+    });
+    methods.add("set_read_view", | id, mut reader | {
+        let (tid,): (Option<ByteBuf>,) =
+            decode!(&mut reader, "decoding set_read_view")?;
+        let tid = match tid {
+            Some(tid) => Some(util::read8(&mut (&*tid))
+                              .context("set_read_view tid")?),
+            None => None,
+        };
+        Ok(Zeo::SetReadView(id, tid))
+    });
+    methods.add("hot_objects", | id, mut reader | {
+        let (count,): (u64,) = decode!(&mut reader, "decoding hot_objects")?;
+        Ok(Zeo::HotObjects(id, count))
+    });
+    methods.add("pack", | id, mut reader | {
+        let (time, wait): (f64, bool) = decode!(&mut reader, "decoding pack")?;
+        Ok(Zeo::Pack(id, time, wait))
+    });
+    methods.add("backup", | id, mut reader | {
+        let (path,): (String,) = decode!(&mut reader, "decoding backup")?;
+        Ok(Zeo::Backup(id, path))
+    });
+    methods.add("incremental_backup", | id, mut reader | {
+        let (dir,): (String,) = decode!(&mut reader, "decoding incremental_backup")?;
+        Ok(Zeo::IncrementalBackup(id, dir))
+    });
+    methods.add("defer_fsync", | id, mut reader | {
+        let (defer,): (bool,) = decode!(&mut reader, "decoding defer_fsync")?;
+        Ok(Zeo::DeferFsync(id, defer))
+    });
+    methods.add("tpc_begin", | _, mut reader | {
+        let (txn, user, desc, ext, tid, _): (
+            u64, ByteBuf, ByteBuf, ByteBuf, Option<ByteBuf>, ByteBuf) =
+            decode!(&mut reader, "decoding tpc_begin")?;
+        let tid = match tid {
+            Some(tid) => Some(util::read8(&mut (&*tid)).context("tpc_begin tid")?),
+            None => None,
+        };
+        Ok(Zeo::TpcBegin(txn, user.to_vec(), desc.to_vec(), ext.to_vec(), tid))
+    });
+    methods.add("storea", | _, mut reader | {
+        let (oid, committed, data, txn): (ByteBuf, ByteBuf, ByteBuf, u64) =
+            decode!(&mut reader, "decoding storea")?;
+        let oid = util::read8(&mut (&*oid)).context("storea oid")?;
+        let committed =
+            util::read8(&mut (&*committed))
+            .context("storea committed")?;
+        Ok(Zeo::Storea(oid, committed, data.to_vec(), txn))
+    });
+    methods.add("storea_start", | _, mut reader | {
+        let (oid, committed, size, txn): (ByteBuf, ByteBuf, u64, u64) =
+            decode!(&mut reader, "decoding storea_start")?;
+        let oid = util::read8(&mut (&*oid)).context("storea_start oid")?;
+        let committed = util::read8(&mut (&*committed)).context("storea_start committed")?;
+        Ok(Zeo::StoreaStart(oid, committed, size, txn))
+    });
+    methods.add("storea_chunk", | _, mut reader | {
+        let (data, txn): (ByteBuf, u64) = decode!(&mut reader, "decoding storea_chunk")?;
+        Ok(Zeo::StoreaChunk(data.to_vec(), txn))
+    });
+    methods.add("storeBlobStart", | _, _ | Ok(Zeo::StoreBlobStart));
+    methods.add("storeBlobChunk", | _, mut reader | {
+        let (chunk,): (ByteBuf,) = decode!(&mut reader, "decoding storeBlobChunk")?;
+        Ok(Zeo::StoreBlobChunk(chunk.to_vec()))
+    });
+    methods.add("storeBlobEnd", | _, mut reader | {
+        let (oid, serial, data, txn): (ByteBuf, ByteBuf, ByteBuf, u64) =
+            decode!(&mut reader, "decoding storeBlobEnd")?;
+        let oid = util::read8(&mut (&*oid)).context("storeBlobEnd oid")?;
+        let serial = util::read8(&mut (&*serial)).context("storeBlobEnd serial")?;
+        Ok(Zeo::StoreBlobEnd(oid, serial, data.to_vec(), txn))
+    });
+    methods.add("storeBlobShared", | _, mut reader | {
+        let (oid, serial, data, filename, txn): (ByteBuf, ByteBuf, ByteBuf, String, u64) =
+            decode!(&mut reader, "decoding storeBlobShared")?;
+        let oid = util::read8(&mut (&*oid)).context("storeBlobShared oid")?;
+        let serial = util::read8(&mut (&*serial)).context("storeBlobShared serial")?;
+        Ok(Zeo::StoreBlobShared(oid, serial, data.to_vec(), filename, txn))
+    });
+    methods.add("restorea", | _, mut reader | {
+        let (oid, serial, data, prev_txn, txn): (
+            ByteBuf, ByteBuf, Option<ByteBuf>, Option<ByteBuf>, u64) =
+            decode!(&mut reader, "decoding restorea")?;
+        let oid = util::read8(&mut (&*oid)).context("restorea oid")?;
+        let serial = util::read8(&mut (&*serial)).context("restorea serial")?;
+        let prev_txn = match prev_txn {
+            Some(tid) => Some(util::read8(&mut (&*tid)).context("restorea prev_txn")?),
+            None => None,
+        };
+        Ok(Zeo::Restorea(oid, serial, data.map(| data | data.to_vec()), prev_txn, txn))
+    });
+    methods.add("vote", | id, mut reader | {
+        let (txn,): (u64,) = decode!(&mut reader, "decoding vote")?;
+        Ok(Zeo::Vote(id, txn))
+    });
+    methods.add("tpc_finish", | id, mut reader | {
+        let (txn,): (u64,) = decode!(&mut reader, "decoding tpc_finish")?;
+        Ok(Zeo::TpcFinish(id, txn))
+    });
+    methods.add("tpc_abort", | id, mut reader | {
+        let (txn,): (u64,) = decode!(&mut reader, "decoding tpc_abort")?;
+        Ok(Zeo::TpcAbort(id, txn))
+    });
+    methods.add("new_oids", | id, _ | Ok(Zeo::NewOids(id)));
+    methods.add("new_oid", | id, _ | Ok(Zeo::NewOid(id)));
+    methods.add("lastTransaction", | id, _ | Ok(Zeo::LastTransaction(id)));
+    methods.add("getInvalidations", | id, mut reader | {
+        let (tid,): (ByteBuf,) = decode!(&mut reader, "decoding getInvalidations")?;
+        let tid = util::read8(&mut (&*tid)).context("getInvalidations tid")?;
+        Ok(Zeo::GetInvalidations(id, tid))
+    });
+    methods.add("get_info", | id, _ | Ok(Zeo::GetInfo(id)));
+    methods.add("getExtensionMethods", | id, _ | Ok(Zeo::GetExtensionMethods(id)));
+    methods.add("server_status", | id, _ | Ok(Zeo::ServerStatus(id)));
+    methods.add("auth_challenge", | id, _ | Ok(Zeo::AuthChallenge(id)));
+    methods.add("authenticate", | id, mut reader | {
+        let (user, response): (String, ByteBuf) =
+            decode!(&mut reader, "decoding authenticate")?;
+        Ok(Zeo::Authenticate(id, user, response.to_vec()))
+    });
+    methods.add("register", | id, mut reader | {
+        let (storage, read_only): (String, bool) =
+            decode!(&mut reader, "decoding register")?;
+        Ok(Zeo::Register(id, storage, read_only))
+    });
+}
 
 // ======================================================================
 
@@ -194,6 +866,11 @@ mod tests {
             &[0, 0, 0, 34, 147, 2, 170, 108, 111, 97, 100, 66, 101,
               102, 111, 114, 101, 146, 196, 8, 0, 0, 0, 0, 0, 0, 0, 0,
               196, 8, 1, 1, 1, 1, 1, 1, 1, 1]);
+        // (3, 'loadSerial', (b"\0\0\0\0\0\0\0\0", b"\1\1\1\1\1\1\1\1"))
+        buf.extend_from_slice(
+            &[0, 0, 0, 34, 147, 3, 170, 108, 111, 97, 100, 83, 101,
+              114, 105, 97, 108, 146, 196, 8, 0, 0, 0, 0, 0, 0, 0, 0,
+              196, 8, 1, 1, 1, 1, 1, 1, 1, 1]);
         let reader = std::io::Cursor::new(buf);
 
         let mut it = ZeoIter::new(reader);
@@ -211,6 +888,46 @@ mod tests {
             },
             _ => panic!("bad match")
         }
+        match it.next().unwrap() {
+            Zeo::LoadSerial(3, oid, serial) => {
+                assert_eq!(oid, [0u8; 8]);
+                assert_eq!(serial, [1u8; 8]);
+            },
+            _ => panic!("bad match")
+        }
+    }
+
+    #[test]
+    fn methods() {
+        let mut methods = Methods::default();
+        let parse = | methods: &Methods, message: Vec<u8> |
+            methods.parse(&mut &message[4..]).unwrap();
+
+        assert_eq!(parse(&methods, sencode!((1, "ping", ())).unwrap()), Zeo::Ping(1));
+        assert_eq!(parse(&methods, sencode!((2, "frobnicate", (1,))).unwrap()),
+                   Zeo::Unknown(2, "frobnicate".to_string()));
+
+        // Bad arguments, for requests with ids to respond to:
+        match parse(&methods, sencode!((3, "loadBefore", (1,))).unwrap()) {
+            Zeo::Malformed(3, method, err) => {
+                assert_eq!(&method, "loadBefore");
+                assert!(err.starts_with("decoding loadBefore"), "{}", err);
+            },
+            zeo => panic!("bad match {:?}", zeo),
+        }
+        let message = sencode!((0, "storea", (1,))).unwrap();
+        assert!(methods.parse(&mut &message[4..]).is_err());
+
+        methods.add("frobnicate", | id, _ | Ok(Zeo::Checkpoint(id)));
+        assert_eq!(parse(&methods, sencode!((3, "frobnicate", (1,))).unwrap()),
+                   Zeo::Checkpoint(3));
+
+        methods.add_extension("frobnicate", std::sync::Arc::new(| _, args | Ok(args.to_vec())));
+        assert_eq!(parse(&methods, sencode!((4, "frobnicate", (1,))).unwrap()),
+                   Zeo::Extension(4, "frobnicate".to_string(), vec![0x91, 1]));
+        assert!(methods.extension("frobnicate").is_some());
+        assert!(methods.extension("ping").is_none());
+        assert_eq!(methods.extensions(), vec!["frobnicate"]);
     }
 
     #[test]
@@ -224,4 +941,34 @@ mod tests {
         assert_eq!(v, vec![0, 0, 0, 5, 147, 1, 161, 82, 42]);
     }
 
+    #[test]
+    fn zstd_codec() {
+        let codec = codec(b"M5+zstd");
+        assert!(!codec.is_msgpack());
+
+        // Small messages are only flagged:
+        let small = sencode!((1u64, "R", 42)).unwrap();
+        let framed = codec.frame(small.clone()).unwrap();
+        assert_eq!(&framed[4..], &[&[0u8] as &[u8], &small[4..]].concat()[..]);
+        assert_eq!(codec.decode(framed[4..].to_vec()).unwrap(), &small[4..]);
+
+        // Large ones are compressed:
+        let data = vec![b'x'; COMPRESSION_THRESHOLD * 2];
+        let large = sencode!((2u64, "R", bytes(&data))).unwrap();
+        let framed = codec.frame(large.clone()).unwrap();
+        assert_eq!(framed[4], 1);
+        assert!(framed.len() < large.len() / 10);
+        assert_eq!(BigEndian::read_u32(&framed) as usize + 4, framed.len());
+        assert_eq!(codec.decode(framed[4..].to_vec()).unwrap(), &large[4..]);
+
+        // Pickle clients can ask for compression too:
+        let codec = super::codec(b"Z5+zstd");
+        let framed = codec.frame(large.clone()).unwrap();
+        assert_eq!(framed[4], 1);
+        assert_eq!(codec.decode(framed[4..].to_vec()).unwrap(), &large[4..]);
+
+        assert!(codec.decode(vec![2, 0]).is_err());
+        assert!(codec.decode(vec![]).is_err());
+    }
+
 }
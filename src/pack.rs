@@ -0,0 +1,488 @@
+// Removing old revisions from data files
+//
+// Packing keeps each object's revision as of the pack time, and later
+// revisions, and drops earlier revisions, and transactions left
+// without any.  Object data is opaque, so unreachable objects are
+// only removed if the storage has a ReferencesExtractor, which finds
+// the objects a record's data refers to.  Otherwise, that's left to
+// external garbage collectors.
+use std::io::prelude::*;
+
+use anyhow::{anyhow, Context, Result};
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::blobs;
+use crate::dictionaries;
+use crate::index;
+use crate::records;
+use crate::scan;
+use crate::storage;
+use crate::util;
+
+pub const PACK_SUFFIX: &str = ".pack";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Packed {
+    // Transactions and data records removed
+    pub transactions: u64,
+    pub records: u64,
+    // Unreachable objects removed
+    pub objects: u64,
+    pub old_size: u64,
+    pub new_size: u64,
+    // Blob files of removed revisions, and their bytes
+    pub blobs: u64,
+    pub blob_bytes: u64,
+}
+
+/// Finds the objects an object's data refers to, for removing
+/// unreachable objects when packing.  Applications know how their
+/// data are encoded, e.g. as ZODB pickles, and the server doesn't.
+pub trait ReferencesExtractor: Send + Sync {
+    fn references(&self, data: &[u8]) -> Result<Vec<util::Oid>>;
+}
+
+// The objects reachable from the root object, z64, through the
+// revisions current as of the pack time, or through later records.
+// Objects with later records are reachable too, as they were just
+// changed.
+fn reachable<R: Read + Seek>(reader: &mut R, version: u32,
+                             dictionaries: &dictionaries::Dictionaries,
+                             current: &std::collections::HashMap<util::Oid, u64>,
+                             later: &[(u64, records::DataHeader)],
+                             references: &dyn ReferencesExtractor)
+                             -> Result<std::collections::HashSet<util::Oid>> {
+    let read_references = | reader: &mut R, pos: u64, header: &records::DataHeader | {
+        let data = scan::read_data(reader, pos, header, version, dictionaries)?;
+        references.references(&data)
+            .with_context(|| format!("finding references of {}", util::hex(&header.id)))
+    };
+    let mut found = vec![util::Z64];
+    for (pos, header) in later {
+        found.push(header.id);
+        found.extend(read_references(reader, *pos, header)?);
+    }
+    let mut reachable = std::collections::HashSet::new();
+    while let Some(oid) = found.pop() {
+        if reachable.insert(oid) {
+            if let Some(&pos) = current.get(&oid) {
+                util::seek(reader, pos)?;
+                let header = records::DataHeader::read(reader, version)?;
+                found.extend(read_references(reader, pos, &header)?);
+            }
+        }
+    }
+    Ok(reachable)
+}
+
+// Read committed transactions, before end, in file order.
+fn committed<R: Read + Seek>(reader: &mut R, end: u64, version: u32,
+                             mut f: impl FnMut(&mut R, scan::TransactionRecord) -> Result<bool>)
+                             -> Result<()> {
+    let mut pos = records::HEADER_SIZE;
+    while pos < end {
+        let record = scan::read_transaction(reader, pos, version)?;
+        if record.header.length == 0 || record.end() > end {
+            return Err(anyhow!("Bad transaction length {} at {}",
+                               record.header.length, pos));
+        }
+        pos = record.end();
+        if record.committed && ! f(reader, record)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Limits how fast packs read data files, so scheduled packs don't
+/// starve clients of disk bandwidth
+///
+/// Packs read the file twice, and each pass is paced separately.
+pub struct Throttle {
+    // Bytes per second, or None for no limit
+    rate: Option<u64>,
+    start: std::time::Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub fn new(rate: Option<u64>) -> Throttle {
+        Throttle { rate: rate.filter(| rate | *rate > 0),
+                   start: std::time::Instant::now(), bytes: 0 }
+    }
+
+    /// Start pacing anew.
+    fn restart(&mut self) {
+        self.start = std::time::Instant::now();
+        self.bytes = 0;
+    }
+
+    /// Account for bytes read, sleeping if reading got ahead of the
+    /// rate.
+    pub fn pace(&mut self, bytes: u64) {
+        if let Some(rate) = self.rate {
+            self.bytes += bytes;
+            let due = std::time::Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+            if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+                std::thread::sleep(ahead);
+            }
+        }
+    }
+}
+
+/// Copy the committed transactions before end in the data file at
+/// path to out, a new file, leaving out revisions replaced at or
+/// before pack_tid.  Given references, revisions at or before
+/// pack_tid of objects that aren't reachable are left out too.  Reads
+/// are paced by throttle.
+///
+/// Previous pointers and record offsets are updated to reflect new
+/// record positions.  Returns the new file's index and what was
+/// removed, leaving out positioned at its end.
+pub fn copy(path: &str, end: u64, pack_tid: &util::Tid, out: &mut std::fs::File,
+            references: Option<&dyn ReferencesExtractor>, throttle: &mut Throttle)
+            -> Result<(index::Index, Packed)> {
+    let mut reader = std::io::BufReader::new(
+        std::fs::File::open(path).context("opening data file")?);
+    let header = records::FileHeader::read(&mut reader).context("reading file header")?;
+
+    // Find the revisions current as of the pack time, and, to find
+    // reachable objects, later records:
+    let mut current = std::collections::HashMap::<util::Oid, u64>::new();
+    let mut later = vec![];
+    let mut count = 0u64;
+    throttle.restart();
+    committed(&mut reader, end, header.version, | reader, record | {
+        throttle.pace(record.header.length);
+        if &record.tid() > pack_tid {
+            if references.is_none() {
+                return Ok(false);
+            }
+            later.extend(record.data_headers(reader)?);
+            return Ok(true);
+        }
+        for (pos, header) in record.data_headers(reader)? {
+            current.insert(header.id, pos);
+        }
+        count += 1;
+        if count.is_multiple_of(100_000) {
+            tracing::info!("{}: pack found current revisions in {} transactions", path, count);
+        }
+        Ok(true)
+    })?;
+
+    let mut packed = Packed { old_size: end, ..Default::default() };
+    if let Some(references) = references {
+        let dictionaries = dictionaries::Dictionaries::load(path)
+            .context("loading dictionaries")?;
+        let reachable = reachable(&mut reader, header.version, &dictionaries,
+                                  &current, &later, references)?;
+        let objects = current.len();
+        current.retain(| oid, _ | reachable.contains(oid));
+        packed.objects = (objects - current.len()) as u64;
+        tracing::info!("{}: pack found {} unreachable objects", path, packed.objects);
+    }
+
+    let mut out = std::io::BufWriter::new(out);
+    header.write(&mut out)?;
+    let mut new_index = index::Index::new();
+    let mut pos = records::HEADER_SIZE;
+    let mut count = 0u64;
+    throttle.restart();
+    committed(&mut reader, end, header.version, | reader, record | {
+        throttle.pace(record.header.length);
+        let headers = record.data_headers(reader)?;
+        let kept: Vec<&(u64, records::DataHeader)> =
+            if &record.tid() > pack_tid {
+                headers.iter().collect()
+            }
+            else {
+                headers.iter().filter(| (dpos, dh) | current.get(&dh.id) == Some(dpos))
+                    .collect()
+            };
+        packed.records += (headers.len() - kept.len()) as u64;
+        if kept.is_empty() {
+            packed.transactions += 1;
+            return Ok(true);
+        }
+        let mut transaction_header = records::TransactionHeader {
+            length: 0, // set below
+            checksum: 0, id: record.tid(), ndata: kept.len() as u32,
+            luser: record.header.luser, ldesc: record.header.ldesc, lext: record.header.lext,
+        };
+        let mut buf = vec![];
+        buf.write_all(storage::TRANSACTION_MARKER)?;
+        transaction_header.write(&mut buf, header.version)?;
+        buf.write_all(&record.user)?;
+        buf.write_all(&record.desc)?;
+        buf.write_all(&record.ext)?;
+        // Transactions that had checksums get new ones, as records
+        // may have been left out:
+        let mut checksum = records::TransactionChecksum::of_record(&buf, header.version);
+        let data_header_size = records::data_header_size(header.version);
+        for (dpos, dh) in kept {
+            checksum.add(&dh.id, dh.checksum);
+            let offset = buf.len() as u64;
+            let previous = new_index.get(&dh.id).cloned().unwrap_or(0);
+            records::DataHeader { previous, offset, ..*dh }.write(&mut buf, header.version)?;
+            util::seek(reader, dpos + data_header_size)?;
+            buf.extend(util::read_sized(reader, dh.length as usize)
+                       .context("reading data record")?);
+            new_index.insert(dh.id, pos + offset);
+        }
+        let length = buf.len() as u64 + 8;
+        buf.write_u64::<BigEndian>(length)?;
+        transaction_header.length = length;
+        if record.header.checksum != 0 {
+            transaction_header.checksum = checksum.value();
+        }
+        transaction_header.write(&mut &mut buf[4..], header.version)?;
+        out.write_all(&buf).context("writing transaction")?;
+        pos += length;
+        count += 1;
+        if count.is_multiple_of(100_000) {
+            tracing::info!("{}: pack copied {} transactions", path, count);
+        }
+        Ok(true)
+    })?;
+    out.flush().context("writing packed file")?;
+    packed.new_size = pos;
+    Ok((new_index, packed))
+}
+
+/// Copy the records between start and end in file, committed or not,
+/// to the end of out, where start is copied to.
+///
+/// Previous pointers to records after start are moved with them, and
+/// those to earlier records, which must be the records current at
+/// start, are pointed at the records for the same objects in index.
+pub fn append(file: &std::fs::File, version: u32, start: u64, end: u64,
+              out: &mut std::fs::File, index: &index::Index)
+              -> Result<()> {
+    let mut reader = std::io::BufReader::new(file.try_clone()?);
+    let new_start = out.seek(std::io::SeekFrom::End(0))?;
+    let mut pos = start;
+    while pos < end {
+        let record = scan::read_transaction(&mut reader, pos, version)?;
+        if record.header.length == 0 || record.end() > end {
+            return Err(anyhow!("Bad transaction length {} at {}",
+                               record.header.length, pos));
+        }
+        let headers = record.data_headers(&mut reader)?;
+        util::seek(&mut reader, pos)?;
+        let mut buf = util::read_sized(&mut reader, record.header.length as usize)
+            .context("reading transaction")?;
+        for (dpos, dh) in headers {
+            let previous = if dh.previous == 0 {
+                0
+            }
+            else if dh.previous >= start {
+                dh.previous - start + new_start
+            }
+            else {
+                *index.get(&dh.id).ok_or_else(
+                    || anyhow!("Record at {} refers to an unknown revision", dpos))?
+            };
+            util::write_u64(
+                &mut &mut buf[(dpos - pos + records::data_previous_offset(version)) as usize..],
+                previous)?;
+        }
+        out.write_all(&buf).context("writing transaction")?;
+        pos = record.end();
+    }
+    Ok(())
+}
+
+/// Blob files whose revisions a data file doesn't have
+#[derive(Debug, Default, PartialEq)]
+pub struct BlobGarbage {
+    pub files: Vec<std::path::PathBuf>,
+    pub bytes: u64,
+}
+
+impl BlobGarbage {
+    /// Remove the files.
+    pub fn remove(&self) -> std::io::Result<()> {
+        for path in self.files.iter() {
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Find the blob files of transactions up to and including last
+/// whose revisions aren't in the committed transactions before end in
+/// file, because they were packed away, or their transactions weren't
+/// committed.
+///
+/// Later blobs, which may be those of transactions being committed,
+/// are left alone.
+pub fn blob_garbage(blobs: &blobs::BlobDir, file: &std::fs::File, version: u32, end: u64,
+                    last: &util::Tid)
+                    -> Result<BlobGarbage> {
+    let mut unused: std::collections::HashMap<(util::Oid, util::Tid), u64> =
+        blobs.files().context("listing blob files")?.into_iter()
+        .filter(| (_, tid, _) | tid <= last)
+        .map(| (oid, tid, size) | ((oid, tid), size))
+        .collect();
+    if ! unused.is_empty() {
+        let mut reader = std::io::BufReader::new(file.try_clone()?);
+        committed(&mut reader, end, version, | reader, record | {
+            if &record.tid() > last {
+                return Ok(false);
+            }
+            for (_, header) in record.data_headers(reader)? {
+                unused.remove(&(header.id, header.tid));
+            }
+            Ok(true)
+        })?;
+    }
+    let mut unused: Vec<((util::Oid, util::Tid), u64)> = unused.into_iter().collect();
+    unused.sort();
+    Ok(BlobGarbage {
+        bytes: unused.iter().map(| (_, size) | size).sum(),
+        files: unused.iter().map(| ((oid, tid), _) | blobs.blob_path(oid, tid)).collect(),
+    })
+}
+
+/// Find, and unless dry_run is true, remove, the blob files in
+/// blob_dir whose revisions aren't in the data file at path, such as
+/// those left by packs before blob files were collected, or by
+/// crashes.  Fails if the storage is in use.
+pub fn collect_blobs(path: &str, blob_dir: &str, dry_run: bool) -> Result<BlobGarbage> {
+    let file = storage::lock_data_path(path).context("locking data file")?;
+    let header = records::FileHeader::read(&mut &file).context("reading file header")?;
+    if ! std::path::Path::new(blob_dir).is_dir() {
+        return Err(anyhow!("{} isn't a directory", blob_dir));
+    }
+    let blobs = blobs::BlobDir::open(blob_dir, false).context("opening blob directory")?;
+    let end = file.metadata()?.len();
+    let garbage = blob_garbage(&blobs, &file, header.version, end, &[0xff; 8])?;
+    if ! dry_run {
+        garbage.remove().context("removing blob files")?;
+    }
+    Ok(garbage)
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::fsck;
+    use crate::storage::testing;
+
+    #[test]
+    fn works() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        testing::make_sample(
+            &path,
+            vec![vec![(util::p64(0), b"000")],
+                 vec![(util::p64(0), b"111"), (util::p64(1), b"aaa")],
+                 vec![(util::p64(1), b"bbb")],
+                 vec![(util::p64(0), b"222")],
+            ]).unwrap();
+        let records: Vec<scan::TransactionRecord> = scan::TransactionIterator::open(&path)
+            .unwrap().map(| r | r.unwrap()).collect();
+        let end = std::fs::metadata(&path).unwrap().len();
+
+        // Copy all but the last transaction, as if it was committed
+        // during the pack, as of the third transaction.  That drops
+        // the first transaction and the second's record for object 1.
+        let new_path = path.clone() + PACK_SUFFIX;
+        let mut out = std::fs::OpenOptions::new()
+            .read(true).write(true).create_new(true).open(&new_path).unwrap();
+        let (index, packed) = copy(&path, records[3].pos, &records[2].tid(), &mut out,
+                                   None, &mut Throttle::new(None)).unwrap();
+        assert_eq!((packed.transactions, packed.records), (1, 2));
+        assert_eq!(packed.old_size, records[3].pos);
+        assert_eq!(packed.new_size, std::fs::metadata(&new_path).unwrap().len());
+
+        // The last transaction's record refers to one that was moved:
+        let original = std::fs::File::open(&path).unwrap();
+        append(&original, records::FORMAT_VERSION, records[3].pos, end, &mut out, &index)
+            .unwrap();
+        drop(out);
+
+        let report = fsck::check(&new_path).unwrap();
+        assert_eq!(report.problems, vec![]);
+        assert_eq!((report.transactions, report.records), (3, 3));
+
+        let mut file = std::fs::File::open(&new_path).unwrap();
+        let last = scan::TransactionIterator::open(&new_path).unwrap()
+            .last().unwrap().unwrap();
+        let revisions = | file: &mut std::fs::File, mut pos: u64 | {
+            let mut revisions = vec![];
+            while pos != 0 {
+                util::seek(file, pos).unwrap();
+                let header = records::DataHeader::read(file, records::FORMAT_VERSION).unwrap();
+                revisions.push(
+                    scan::read_data(file, pos, &header, records::FORMAT_VERSION,
+                                    &Default::default()).unwrap());
+                pos = header.previous;
+            }
+            revisions
+        };
+        assert_eq!(revisions(&mut file, last.data_pos()),
+                   vec![b"222".to_vec(), b"111".to_vec()]);
+        assert_eq!(revisions(&mut file, index[&util::p64(1)]), vec![b"bbb".to_vec()]);
+    }
+
+    // Data are lists of oids, for testing
+    struct Oids;
+
+    impl ReferencesExtractor for Oids {
+        fn references(&self, data: &[u8]) -> Result<Vec<util::Oid>> {
+            Ok(data.chunks(8).map(| chunk | chunk.try_into().unwrap()).collect())
+        }
+    }
+
+    #[test]
+    fn garbage() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        let (o1, o2, o4) = (util::p64(1), util::p64(2), util::p64(4));
+        testing::make_sample(
+            &path,
+            vec![vec![(util::p64(0), &o1), (o1, b""), (o2, b""), (util::p64(3), b"")],
+                 vec![(util::p64(0), &o4), (o4, b"")],
+                 // After the pack time:
+                 vec![(util::p64(5), &o2)],
+            ]).unwrap();
+        let records: Vec<scan::TransactionRecord> = scan::TransactionIterator::open(&path)
+            .unwrap().map(| r | r.unwrap()).collect();
+        let end = std::fs::metadata(&path).unwrap().len();
+
+        // Object 0 no longer refers to object 1, and nothing refers to
+        // object 3, but a later record refers to object 2:
+        let mut out = tempfile::tempfile().unwrap();
+        let (index, packed) = copy(&path, end, &records[1].tid(), &mut out, Some(&Oids),
+                                   &mut Throttle::new(None)).unwrap();
+        assert_eq!((packed.objects, packed.records, packed.transactions), (2, 3, 0));
+        let mut oids: Vec<util::Oid> = index.keys().cloned().collect();
+        oids.sort();
+        assert_eq!(oids, [0, 2, 4, 5].map(util::p64));
+    }
+
+    #[test]
+    fn throttling() {
+        // 1000 bytes at 10000 bytes per second take a tenth of a second:
+        let start = std::time::Instant::now();
+        let mut throttle = Throttle::new(Some(10_000));
+        for _ in 0..10 {
+            throttle.pace(100);
+        }
+        assert!(start.elapsed() >= std::time::Duration::from_millis(100));
+
+        // Without a rate, there's no waiting:
+        let start = std::time::Instant::now();
+        let mut throttle = Throttle::new(None);
+        throttle.pace(1 << 40);
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
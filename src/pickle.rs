@@ -0,0 +1,534 @@
+// Translation between pickle and msgpack, for clients that encode
+// messages with pickle.
+//
+// Only the plain data ZEO messages are made of is supported: None,
+// booleans, integers, floats, strings, bytes, tuples, lists, sets
+// and dicts.  Pickles that refer to classes or functions are
+// rejected.  Messages from clients are converted to msgpack, and
+// messages to clients from msgpack, so the rest of the server only
+// deals with msgpack.
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use anyhow::{anyhow, Context, Result};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// The pickle protocol used for messages to clients, the one ZEO uses
+pub const PROTOCOL: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Nil,
+    Bool(bool),
+    Int(i128),
+    Float(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+/// Convert a pickle to msgpack.
+pub fn to_msgpack(pickle: &[u8]) -> Result<Vec<u8>> {
+    let value = load(pickle).context("reading pickle")?;
+    let mut out = vec![];
+    write_msgpack(&mut out, &value)?;
+    Ok(out)
+}
+
+/// Convert msgpack to a pickle.
+pub fn from_msgpack(msgpack: &[u8]) -> Result<Vec<u8>> {
+    let mut input = msgpack;
+    let value = read_msgpack(&mut input).context("reading msgpack")?;
+    if ! input.is_empty() {
+        return Err(anyhow!("{} bytes of msgpack left over", input.len()));
+    }
+    let mut out = vec![0x80, PROTOCOL];
+    dump(&mut out, &value)?;
+    out.push(b'.');
+    Ok(out)
+}
+
+// ======================================================================
+// Pickle
+
+// Values are shared while loading, because containers are memoized
+// before their items are added.
+type Shared = Rc<RefCell<Value>>;
+
+enum Item {
+    Mark,
+    Value(Shared),
+}
+
+fn shared(value: Value) -> Item {
+    Item::Value(Rc::new(RefCell::new(value)))
+}
+
+struct Loader<'a> {
+    input: &'a [u8],
+    stack: Vec<Item>,
+    memo: std::collections::HashMap<u32, Shared>,
+}
+
+impl<'a> Loader<'a> {
+
+    fn read(&mut self, size: usize) -> Result<&'a [u8]> {
+        if self.input.len() < size {
+            return Err(anyhow!("pickle data was truncated"));
+        }
+        let (data, rest) = self.input.split_at(size);
+        self.input = rest;
+        Ok(data)
+    }
+
+    fn string(&mut self, size: usize) -> Result<Item> {
+        let data = self.read(size)?;
+        Ok(shared(Value::Str(
+            std::str::from_utf8(data).context("decoding string")?.to_string())))
+    }
+
+    fn bytes(&mut self, size: usize) -> Result<Item> {
+        Ok(shared(Value::Bytes(self.read(size)?.to_vec())))
+    }
+
+    fn long(&mut self, size: usize) -> Result<Item> {
+        if size > 16 {
+            return Err(anyhow!("integer too large"));
+        }
+        let data = self.read(size)?;
+        let negative = data.last().is_some_and(| b | b & 0x80 != 0);
+        let mut le = [if negative { 0xff } else { 0 }; 16];
+        le[..size].copy_from_slice(data);
+        Ok(shared(Value::Int(i128::from_le_bytes(le))))
+    }
+
+    fn pop(&mut self) -> Result<Value> {
+        match self.stack.pop() {
+            Some(Item::Value(value)) => Ok(value.borrow().clone()),
+            _ => Err(anyhow!("pickle stack underflow")),
+        }
+    }
+
+    fn top(&self) -> Result<Shared> {
+        match self.stack.last() {
+            Some(Item::Value(value)) => Ok(value.clone()),
+            _ => Err(anyhow!("pickle stack underflow")),
+        }
+    }
+
+    // Pop the items above the topmost mark, and the mark.
+    fn pop_mark(&mut self) -> Result<Vec<Value>> {
+        let mark = self.stack.iter().rposition(| item | matches!(item, Item::Mark))
+            .ok_or_else(|| anyhow!("pickle mark not found"))?;
+        let items = self.stack.split_off(mark + 1);
+        self.stack.pop();
+        Ok(items.into_iter().map(| item | match item {
+            Item::Value(value) => value.borrow().clone(),
+            Item::Mark => unreachable!(),
+        }).collect())
+    }
+
+    fn tuple(&mut self, size: usize) -> Result<Item> {
+        let mut items = (0..size).map(| _ | self.pop()).collect::<Result<Vec<Value>>>()?;
+        items.reverse();
+        Ok(shared(Value::Array(items)))
+    }
+
+    // Add items to the list or set on top of the stack.
+    fn extend(&mut self, items: Vec<Value>) -> Result<()> {
+        match *self.top()?.borrow_mut() {
+            Value::Array(ref mut array) => array.extend(items),
+            _ => return Err(anyhow!("appending to a non-list")),
+        }
+        Ok(())
+    }
+
+    // Add key-value pairs to the dict on top of the stack.
+    fn set_items(&mut self, items: Vec<Value>) -> Result<()> {
+        if ! items.len().is_multiple_of(2) {
+            return Err(anyhow!("odd number of dict items"));
+        }
+        let mut items = items.into_iter();
+        match *self.top()?.borrow_mut() {
+            Value::Map(ref mut map) =>
+                while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                    map.push((key, value));
+                },
+            _ => return Err(anyhow!("setting items of a non-dict")),
+        }
+        Ok(())
+    }
+
+    fn put(&mut self, key: u32) -> Result<()> {
+        let value = self.top()?;
+        self.memo.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&mut self, key: u32) -> Result<Item> {
+        Ok(Item::Value(self.memo.get(&key)
+                       .ok_or_else(|| anyhow!("memo key {} not found", key))?
+                       .clone()))
+    }
+
+    fn run(&mut self) -> Result<Value> {
+        loop {
+            let op = self.read(1)?[0];
+            let item = match op {
+                0x80 => { // PROTO
+                    let protocol = self.read(1)?[0];
+                    if protocol > 5 {
+                        return Err(anyhow!("unsupported pickle protocol {}", protocol));
+                    }
+                    continue;
+                },
+                0x95 => { self.read(8)?; continue }, // FRAME
+                b'.' => { // STOP
+                    let value = self.pop()?;
+                    if ! self.stack.is_empty() {
+                        return Err(anyhow!("values left on the pickle stack"));
+                    }
+                    return Ok(value);
+                },
+                b'N' => shared(Value::Nil),
+                0x88 => shared(Value::Bool(true)),
+                0x89 => shared(Value::Bool(false)),
+                b'J' => shared(Value::Int(self.input.read_i32::<LittleEndian>()
+                                          .context("reading int")? as i128)),
+                b'K' => shared(Value::Int(self.read(1)?[0] as i128)),
+                b'M' => shared(Value::Int(self.input.read_u16::<LittleEndian>()
+                                          .context("reading int")? as i128)),
+                0x8a => { let size = self.read(1)?[0]; self.long(size as usize)? },
+                0x8b => {
+                    let size = self.input.read_u32::<LittleEndian>().context("reading size")?;
+                    self.long(size as usize)?
+                },
+                b'G' => shared(Value::Float(self.input.read_f64::<BigEndian>()
+                                            .context("reading float")?)),
+                0x8c => { let size = self.read(1)?[0]; self.string(size as usize)? },
+                b'X' => {
+                    let size = self.input.read_u32::<LittleEndian>().context("reading size")?;
+                    self.string(size as usize)?
+                },
+                0x8d => {
+                    let size = self.input.read_u64::<LittleEndian>().context("reading size")?;
+                    self.string(size as usize)?
+                },
+                b'C' => { let size = self.read(1)?[0]; self.bytes(size as usize)? },
+                b'B' => {
+                    let size = self.input.read_u32::<LittleEndian>().context("reading size")?;
+                    self.bytes(size as usize)?
+                },
+                0x8e => {
+                    let size = self.input.read_u64::<LittleEndian>().context("reading size")?;
+                    self.bytes(size as usize)?
+                },
+                b'(' => Item::Mark,
+                b')' => shared(Value::Array(vec![])),
+                0x85 => self.tuple(1)?,
+                0x86 => self.tuple(2)?,
+                0x87 => self.tuple(3)?,
+                b't' => shared(Value::Array(self.pop_mark()?)),
+                b']' | 0x8f => shared(Value::Array(vec![])), // EMPTY_LIST, EMPTY_SET
+                b'l' | 0x91 => shared(Value::Array(self.pop_mark()?)), // LIST, FROZENSET
+                b'a' => { let item = self.pop()?; self.extend(vec![item])?; continue },
+                b'e' | 0x90 => { // APPENDS, ADDITEMS
+                    let items = self.pop_mark()?;
+                    self.extend(items)?;
+                    continue
+                },
+                b'}' => shared(Value::Map(vec![])),
+                b'd' => {
+                    let items = self.pop_mark()?;
+                    self.stack.push(shared(Value::Map(vec![])));
+                    self.set_items(items)?;
+                    continue
+                },
+                b's' => {
+                    let value = self.pop()?;
+                    let key = self.pop()?;
+                    self.set_items(vec![key, value])?;
+                    continue
+                },
+                b'u' => { let items = self.pop_mark()?; self.set_items(items)?; continue },
+                b'q' => { let key = self.read(1)?[0]; self.put(key as u32)?; continue },
+                b'r' => {
+                    let key = self.input.read_u32::<LittleEndian>().context("reading memo key")?;
+                    self.put(key)?;
+                    continue
+                },
+                0x94 => { let key = self.memo.len() as u32; self.put(key)?; continue },
+                b'h' => { let key = self.read(1)?[0]; self.get(key as u32)? },
+                b'j' => {
+                    let key = self.input.read_u32::<LittleEndian>().context("reading memo key")?;
+                    self.get(key)?
+                },
+                op => return Err(anyhow!("unsupported pickle opcode 0x{:02x}", op)),
+            };
+            self.stack.push(item);
+        }
+    }
+}
+
+fn load(pickle: &[u8]) -> Result<Value> {
+    Loader { input: pickle, stack: vec![], memo: Default::default() }.run()
+}
+
+fn dump(out: &mut Vec<u8>, value: &Value) -> Result<()> {
+    match value {
+        Value::Nil => out.push(b'N'),
+        Value::Bool(true) => out.push(0x88),
+        Value::Bool(false) => out.push(0x89),
+        Value::Int(i) => {
+            if (0..0x100).contains(i) {
+                out.extend_from_slice(&[b'K', *i as u8]);
+            }
+            else if (0..0x10000).contains(i) {
+                out.push(b'M');
+                out.write_u16::<LittleEndian>(*i as u16)?;
+            }
+            else if (i32::MIN as i128..=i32::MAX as i128).contains(i) {
+                out.push(b'J');
+                out.write_i32::<LittleEndian>(*i as i32)?;
+            }
+            else {
+                // The shortest two's complement that keeps the sign
+                let bytes = i.to_le_bytes();
+                let mut size = 16;
+                while size > 1 && (
+                    (bytes[size - 1] == 0 && bytes[size - 2] & 0x80 == 0) ||
+                    (bytes[size - 1] == 0xff && bytes[size - 2] & 0x80 != 0)) {
+                    size -= 1;
+                }
+                out.extend_from_slice(&[0x8a, size as u8]);
+                out.extend_from_slice(&bytes[..size]);
+            }
+        },
+        Value::Float(f) => {
+            out.push(b'G');
+            out.write_f64::<BigEndian>(*f)?;
+        },
+        Value::Str(s) => {
+            out.push(b'X');
+            out.write_u32::<LittleEndian>(s.len() as u32)?;
+            out.extend_from_slice(s.as_bytes());
+        },
+        Value::Bytes(b) => {
+            if b.len() < 0x100 {
+                out.extend_from_slice(&[b'C', b.len() as u8]);
+            }
+            else {
+                out.push(b'B');
+                out.write_u32::<LittleEndian>(b.len() as u32)?;
+            }
+            out.extend_from_slice(b);
+        },
+        // Messages and their arguments are tuples.
+        Value::Array(items) => {
+            match items.len() {
+                0 => out.push(b')'),
+                1..=3 => {
+                    for item in items {
+                        dump(out, item)?;
+                    }
+                    out.push(0x84 + items.len() as u8);
+                },
+                _ => {
+                    out.push(b'(');
+                    for item in items {
+                        dump(out, item)?;
+                    }
+                    out.push(b't');
+                },
+            }
+        },
+        Value::Map(items) => {
+            out.push(b'}');
+            if ! items.is_empty() {
+                out.push(b'(');
+                for (key, value) in items {
+                    dump(out, key)?;
+                    dump(out, value)?;
+                }
+                out.push(b'u');
+            }
+        },
+    }
+    Ok(())
+}
+
+// ======================================================================
+// msgpack
+
+fn read_msgpack(input: &mut &[u8]) -> Result<Value> {
+    fn read<'a>(input: &mut &'a [u8], size: usize) -> Result<&'a [u8]> {
+        if input.len() < size {
+            return Err(anyhow!("msgpack data was truncated"));
+        }
+        let (data, rest) = input.split_at(size);
+        *input = rest;
+        Ok(data)
+    }
+    fn array(input: &mut &[u8], size: usize) -> Result<Value> {
+        Ok(Value::Array((0..size).map(| _ | read_msgpack(input)).collect::<Result<_>>()?))
+    }
+    fn map(input: &mut &[u8], size: usize) -> Result<Value> {
+        Ok(Value::Map((0..size)
+                      .map(| _ | Ok((read_msgpack(input)?, read_msgpack(input)?)))
+                      .collect::<Result<_>>()?))
+    }
+    fn string(input: &mut &[u8], size: usize) -> Result<Value> {
+        Ok(Value::Str(std::str::from_utf8(read(input, size)?)
+                      .context("decoding string")?.to_string()))
+    }
+    fn bytes(input: &mut &[u8], size: usize) -> Result<Value> {
+        Ok(Value::Bytes(read(input, size)?.to_vec()))
+    }
+
+    let marker = input.read_u8().context("reading marker")?;
+    Ok(match marker {
+        0x00..=0x7f => Value::Int(marker as i128),
+        0x80..=0x8f => map(input, (marker & 0x0f) as usize)?,
+        0x90..=0x9f => array(input, (marker & 0x0f) as usize)?,
+        0xa0..=0xbf => string(input, (marker & 0x1f) as usize)?,
+        0xc0 => Value::Nil,
+        0xc2 => Value::Bool(false),
+        0xc3 => Value::Bool(true),
+        0xc4 => { let size = input.read_u8()?; bytes(input, size as usize)? },
+        0xc5 => { let size = input.read_u16::<BigEndian>()?; bytes(input, size as usize)? },
+        0xc6 => { let size = input.read_u32::<BigEndian>()?; bytes(input, size as usize)? },
+        0xca => Value::Float(input.read_f32::<BigEndian>()? as f64),
+        0xcb => Value::Float(input.read_f64::<BigEndian>()?),
+        0xcc => Value::Int(input.read_u8()? as i128),
+        0xcd => Value::Int(input.read_u16::<BigEndian>()? as i128),
+        0xce => Value::Int(input.read_u32::<BigEndian>()? as i128),
+        0xcf => Value::Int(input.read_u64::<BigEndian>()? as i128),
+        0xd0 => Value::Int(input.read_i8()? as i128),
+        0xd1 => Value::Int(input.read_i16::<BigEndian>()? as i128),
+        0xd2 => Value::Int(input.read_i32::<BigEndian>()? as i128),
+        0xd3 => Value::Int(input.read_i64::<BigEndian>()? as i128),
+        0xd9 => { let size = input.read_u8()?; string(input, size as usize)? },
+        0xda => { let size = input.read_u16::<BigEndian>()?; string(input, size as usize)? },
+        0xdb => { let size = input.read_u32::<BigEndian>()?; string(input, size as usize)? },
+        0xdc => { let size = input.read_u16::<BigEndian>()?; array(input, size as usize)? },
+        0xdd => { let size = input.read_u32::<BigEndian>()?; array(input, size as usize)? },
+        0xde => { let size = input.read_u16::<BigEndian>()?; map(input, size as usize)? },
+        0xdf => { let size = input.read_u32::<BigEndian>()?; map(input, size as usize)? },
+        0xe0..=0xff => Value::Int((marker as i8) as i128),
+        marker => return Err(anyhow!("unsupported msgpack marker 0x{:02x}", marker)),
+    })
+}
+
+fn write_msgpack(out: &mut Vec<u8>, value: &Value) -> Result<()> {
+    match value {
+        Value::Nil => rmp::encode::write_nil(out).map_err(| e | anyhow!("{:?}", e))?,
+        Value::Bool(b) => rmp::encode::write_bool(out, *b).map_err(| e | anyhow!("{:?}", e))?,
+        Value::Int(i) if *i >= 0 => {
+            let i = u64::try_from(*i).map_err(|_| anyhow!("integer too large"))?;
+            rmp::encode::write_uint(out, i).map_err(| e | anyhow!("{:?}", e))?;
+        },
+        Value::Int(i) => {
+            let i = i64::try_from(*i).map_err(|_| anyhow!("integer too small"))?;
+            rmp::encode::write_sint(out, i).map_err(| e | anyhow!("{:?}", e))?;
+        },
+        Value::Float(f) => rmp::encode::write_f64(out, *f).map_err(| e | anyhow!("{:?}", e))?,
+        Value::Str(s) => rmp::encode::write_str(out, s).map_err(| e | anyhow!("{:?}", e))?,
+        Value::Bytes(b) => rmp::encode::write_bin(out, b).map_err(| e | anyhow!("{:?}", e))?,
+        Value::Array(items) => {
+            rmp::encode::write_array_len(out, items.len() as u32)
+                .map_err(| e | anyhow!("{:?}", e))?;
+            for item in items {
+                write_msgpack(out, item)?;
+            }
+        },
+        Value::Map(items) => {
+            rmp::encode::write_map_len(out, items.len() as u32)
+                .map_err(| e | anyhow!("{:?}", e))?;
+            for (key, value) in items {
+                write_msgpack(out, key)?;
+                write_msgpack(out, value)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn python_pickles() {
+        // pickle.dumps((1, 'loadBefore', (b'\0'*8, b'\xff'*8)), 3)
+        assert_eq!(
+            load(b"\x80\x03K\x01X\n\x00\x00\x00loadBeforeq\x00\
+                   C\x08\x00\x00\x00\x00\x00\x00\x00\x00q\x01\
+                   C\x08\xff\xff\xff\xff\xff\xff\xff\xffq\x02\x86q\x03\x87q\x04.")
+                .unwrap(),
+            Value::Array(vec![
+                Value::Int(1), Value::Str("loadBefore".to_string()),
+                Value::Array(vec![Value::Bytes(vec![0; 8]), Value::Bytes(vec![255; 8])])]));
+
+        // pickle.dumps((2, 'tpc_begin',
+        //               (1, 'user', 'desc', {'x': [1, -2, 300, 2**40]}, None, ' ')), 3)
+        assert_eq!(
+            load(b"\x80\x03K\x02X\t\x00\x00\x00tpc_beginq\x00(K\x01\
+                   X\x04\x00\x00\x00userq\x01X\x04\x00\x00\x00descq\x02}q\x03\
+                   X\x01\x00\x00\x00xq\x04]q\x05(K\x01J\xfe\xff\xff\xffM,\x01\
+                   \x8a\x06\x00\x00\x00\x00\x00\x01esNX\x01\x00\x00\x00 q\x06\
+                   tq\x07\x87q\x08.")
+                .unwrap(),
+            Value::Array(vec![
+                Value::Int(2), Value::Str("tpc_begin".to_string()),
+                Value::Array(vec![
+                    Value::Int(1), Value::Str("user".to_string()),
+                    Value::Str("desc".to_string()),
+                    Value::Map(vec![(Value::Str("x".to_string()), Value::Array(vec![
+                        Value::Int(1), Value::Int(-2), Value::Int(300),
+                        Value::Int(1 << 40)]))]),
+                    Value::Nil, Value::Str(" ".to_string())])]));
+
+        // A shared list, memoized before it's filled:
+        // l = [1]; pickle.dumps((3, 'x', (l, l, 1.5, True, 70000, -70000)), 3)
+        let one = Value::Array(vec![Value::Int(1)]);
+        assert_eq!(
+            load(b"\x80\x03K\x03X\x01\x00\x00\x00xq\x00(]q\x01K\x01ah\x01\
+                   G?\xf8\x00\x00\x00\x00\x00\x00\x88Jp\x11\x01\x00J\x90\xee\xfe\xff\
+                   tq\x02\x87q\x03.")
+                .unwrap(),
+            Value::Array(vec![
+                Value::Int(3), Value::Str("x".to_string()),
+                Value::Array(vec![one.clone(), one, Value::Float(1.5), Value::Bool(true),
+                                  Value::Int(70000), Value::Int(-70000)])]));
+
+        // References to classes aren't data:
+        assert!(load(b"\x80\x03cbuiltins\nset\n.").is_err());
+        assert!(load(b"\x80\x03K\x01").is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = Value::Array(vec![
+            Value::Int(42), Value::Str("R".to_string()),
+            Value::Array(vec![
+                Value::Nil, Value::Bool(false), Value::Int(-1), Value::Int(-129),
+                Value::Int(65535), Value::Int(1 << 33), Value::Int(-(1 << 40)),
+                Value::Int(u64::MAX as i128), Value::Float(-2.5),
+                Value::Bytes(vec![7; 300]), Value::Array(vec![]),
+                Value::Map(vec![(Value::Bytes(b"k".to_vec()), Value::Str("v".to_string()))]),
+                Value::Map(vec![]),
+            ])]);
+        let mut msgpack = vec![];
+        write_msgpack(&mut msgpack, &value).unwrap();
+        let pickle = from_msgpack(&msgpack).unwrap();
+        assert_eq!(load(&pickle).unwrap(), value);
+        assert_eq!(to_msgpack(&pickle).unwrap(), msgpack);
+
+        assert!(from_msgpack(&[0xc0, 0xc0]).is_err());
+        assert!(from_msgpack(&[0x92, 0xc0]).is_err());
+    }
+}
@@ -36,39 +36,51 @@ impl FileFactory for TmpFileFactory {
     }
 }
 
-pub type TmpFilePointer<'store> = PooledFilePointer<'store, TmpFileFactory>;
+pub type TmpFilePointer = PooledFilePointer<TmpFileFactory>;
 
 #[derive(Debug)]
 pub struct FilePool<F: FileFactory> {
     capacity: usize, // Doesn't change
-    files: std::sync::Mutex<Vec<std::fs::File>>,
+    // Files, and the generation they were made in
+    files: std::sync::Mutex<(Vec<std::fs::File>, u64)>,
     factory: F, // Doesn't change
 }
 
 impl<F: FileFactory> FilePool<F> {
-    pub fn new(factory: F, capacity: usize) -> FilePool<F> {
-        FilePool { capacity: capacity, factory: factory,
-                   files: std::sync::Mutex::new(vec![]) }
+    pub fn new(factory: F, capacity: usize) -> std::sync::Arc<FilePool<F>> {
+        std::sync::Arc::new(
+            FilePool { capacity: capacity, factory: factory,
+                       files: std::sync::Mutex::new((vec![], 0)) })
     }
 
-    pub fn get<'pool>(&'pool self) -> std::io::Result<PooledFilePointer<'pool, F>> {
-        let mut files = self.files.lock().unwrap();
+    // Pointers share ownership of the pool, so they (and
+    // transactions using them) don't borrow the storage.
+    pub fn get(self: &std::sync::Arc<Self>) -> std::io::Result<PooledFilePointer<F>> {
+        let (ref mut files, generation) = *self.files.lock().unwrap();
         let file = match files.pop() {
             Some(filerc) => filerc,
             None         => self.factory.new()?,
         };
-        Ok(PooledFilePointer {file: file, pool: self})
+        Ok(PooledFilePointer {file: file, pool: self.clone(), generation})
     }
 
-    pub fn put(&self, filerc: std::fs::File) {
-        let mut files = self.files.lock().unwrap();
-        if files.len() < self.capacity {
+    fn put(&self, filerc: std::fs::File, generation: u64) {
+        let (ref mut files, current) = *self.files.lock().unwrap();
+        if files.len() < self.capacity && generation == current {
             files.push(filerc);
         }
     }
 
+    /// Close pooled files, and files in use when they're returned,
+    /// e.g. because the file they were opened for was replaced.
+    pub fn clear(&self) {
+        let (ref mut files, ref mut generation) = *self.files.lock().unwrap();
+        files.clear();
+        *generation += 1;
+    }
+
     pub fn len(&self) -> usize {
-        self.files.lock().unwrap().len()
+        self.files.lock().unwrap().0.len()
     }
 }
 
@@ -76,12 +88,13 @@ unsafe impl<F: FileFactory> std::marker::Sync for FilePool<F> {}
 unsafe impl<F: FileFactory> std::marker::Send for FilePool<F> {}
 
 #[derive(Debug)]
-pub struct PooledFilePointer<'pool, F: FileFactory + 'pool> {
+pub struct PooledFilePointer<F: FileFactory> {
     file: std::fs::File,
-    pool: &'pool FilePool<F>,
+    pool: std::sync::Arc<FilePool<F>>,
+    generation: u64,
 }
 
-impl<'pool, F: FileFactory + 'pool> std::ops::Deref for PooledFilePointer<'pool, F> {
+impl<F: FileFactory> std::ops::Deref for PooledFilePointer<F> {
     type Target = std::fs::File;
 
     fn deref<'fptr>(&'fptr self) -> &'fptr std::fs::File {
@@ -89,9 +102,9 @@ impl<'pool, F: FileFactory + 'pool> std::ops::Deref for PooledFilePointer<'pool,
     }
 }
 
-impl<'pool, F: FileFactory + 'pool> Drop for PooledFilePointer<'pool, F> {
+impl<F: FileFactory> Drop for PooledFilePointer<F> {
     fn drop(&mut self) {
-        self.pool.put(self.file.try_clone().expect(r#"Cloning file"#));
+        self.pool.put(self.file.try_clone().expect(r#"Cloning file"#), self.generation);
     }
 }
 
@@ -115,8 +128,7 @@ mod tests {
             tmp_dir.path().join("data").to_str().unwrap());
         { std::fs::File::create(&path).unwrap().write_all(sample).unwrap(); }
         
-        let pool = sync::Arc::new(
-            FilePool::new(ReadFileFactory { path: path }, 2));
+        let pool = FilePool::new(ReadFileFactory { path: path }, 2);
         let (t, r) = sync::mpsc::channel();
 
         let count = 8;
@@ -139,4 +151,24 @@ mod tests {
         }
 
     }
+
+    #[test]
+    fn clear() {
+        let tmp_dir = util::test::dir();
+        let path = String::from(tmp_dir.path().join("data").to_str().unwrap());
+        std::fs::File::create(&path).unwrap();
+        let pool = FilePool::new(ReadFileFactory { path }, 2);
+        drop(pool.get().unwrap());
+        let p = pool.get().unwrap();
+        drop(pool.get().unwrap());
+        assert_eq!(pool.len(), 1);
+
+        // Files, in the pool or in use, aren't reused after clearing:
+        pool.clear();
+        assert_eq!(pool.len(), 0);
+        drop(p);
+        assert_eq!(pool.len(), 0);
+        drop(pool.get().unwrap());
+        assert_eq!(pool.len(), 1);
+    }
 }
@@ -0,0 +1,163 @@
+// HAProxy PROXY protocol headers
+//
+// Load balancers that speak the PROXY protocol send a header with
+// the real client address before the client's data.  See
+// https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// Longest possible v1 header, including the CRLF
+const V1_MAX: usize = 107;
+
+/// Read a version 1 or 2 PROXY header.
+///
+/// Returns the client address, or None if the header doesn't have
+/// one, as for health checks from the proxy itself.  No more than
+/// the header is read, so the stream is left at the client's data.
+pub fn read_header<R: Read>(reader: &mut R) -> Result<Option<SocketAddr>> {
+    let mut start = [0u8; 6];
+    reader.read_exact(&mut start).context("reading PROXY header")?;
+    if &start == b"PROXY " {
+        read_v1(reader)
+    }
+    else if start[..] == V2_SIGNATURE[..6] {
+        read_v2(reader)
+    }
+    else {
+        Err(anyhow!("Expected a PROXY header"))
+    }
+}
+
+fn read_v1<R: Read>(reader: &mut R) -> Result<Option<SocketAddr>> {
+    let mut line = b"PROXY ".to_vec();
+    while ! line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX {
+            return Err(anyhow!("PROXY header is too long"));
+        }
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte).context("reading PROXY header")?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[.. line.len() - 2])
+        .context("PROXY header isn't ASCII")?;
+    let bad = || anyhow!("Bad PROXY header {:?}", line);
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", source, _, port, _] | ["PROXY", "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(| _ | bad())?;
+            if ip.is_ipv4() != (parts[1] == "TCP4") {
+                return Err(bad());
+            }
+            Ok(Some(SocketAddr::new(ip, port.parse().map_err(| _ | bad())?)))
+        },
+        _ => Err(bad()),
+    }
+}
+
+fn read_v2<R: Read>(reader: &mut R) -> Result<Option<SocketAddr>> {
+    let mut header = [0u8; 10];
+    reader.read_exact(&mut header).context("reading PROXY header")?;
+    if header[..6] != V2_SIGNATURE[6..] {
+        return Err(anyhow!("Bad PROXY header signature"));
+    }
+    let (version, command, family) = (header[6] >> 4, header[6] & 0xf, header[7]);
+    if version != 2 {
+        return Err(anyhow!("Unsupported PROXY protocol version {}", version));
+    }
+    let mut addresses = vec![0u8; BigEndian::read_u16(&header[8..]) as usize];
+    reader.read_exact(&mut addresses).context("reading PROXY addresses")?;
+    if command == 0 {
+        // LOCAL, e.g. a health check
+        return Ok(None);
+    }
+    if command != 1 {
+        return Err(anyhow!("Unsupported PROXY command {}", command));
+    }
+    let short = || anyhow!("PROXY addresses are too short");
+    match family >> 4 {
+        1 => {
+            if addresses.len() < 12 {
+                return Err(short());
+            }
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), BigEndian::read_u16(&addresses[8..]))))
+        },
+        2 => {
+            if addresses.len() < 36 {
+                return Err(short());
+            }
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)),
+                                    BigEndian::read_u16(&addresses[32..]))))
+        },
+        // Unspecified, or unix sockets
+        _ => Ok(None),
+    }
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn v1() {
+        let mut data: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.2 5678 8080\r\nM5";
+        assert_eq!(read_header(&mut data).unwrap(),
+                   Some("192.0.2.1:5678".parse().unwrap()));
+        assert_eq!(data, b"M5");
+
+        let mut data: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 5678 8080\r\n";
+        assert_eq!(read_header(&mut data).unwrap(),
+                   Some("[2001:db8::1]:5678".parse().unwrap()));
+
+        let mut data: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut data).unwrap(), None);
+
+        for bad in [&b"PROXY TCP4 2001:db8::1 192.0.2.2 1 2\r\n"[..],
+                    b"PROXY TCP4 192.0.2.1\r\n",
+                    b"\0\0\0\x02M5"] {
+            assert!(read_header(&mut &bad[..]).is_err());
+        }
+        let long = [&b"PROXY "[..], &[b'x'; 200][..]].concat();
+        assert!(read_header(&mut &long[..]).is_err());
+    }
+
+    #[test]
+    fn v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 0, 2, 1, 198, 51, 100, 2,
+                                 0x16, 0x2e, 0x1f, 0x90]);
+        data.extend_from_slice(b"M5");
+        let mut reader = &data[..];
+        assert_eq!(read_header(&mut reader).unwrap(),
+                   Some("192.0.2.1:5678".parse().unwrap()));
+        assert_eq!(reader, b"M5");
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        let mut addresses = [0u8; 36];
+        addresses[..16].copy_from_slice(
+            &"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addresses[32] = 0x16; addresses[33] = 0x2e;
+        data.extend_from_slice(&addresses);
+        assert_eq!(read_header(&mut &data[..]).unwrap(),
+                   Some("[2001:db8::1]:5678".parse().unwrap()));
+
+        // LOCAL
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x20, 0x00, 0, 0, b'M']);
+        let mut reader = &data[..];
+        assert_eq!(read_header(&mut reader).unwrap(), None);
+        assert_eq!(reader, b"M");
+    }
+}
@@ -0,0 +1,765 @@
+// Serve client connections with a fixed pool of worker threads
+//
+// A poller thread waits, using epoll, for input on any connection,
+// and queues connections with input, or with messages for their
+// writers (invalidations, lock notifications and so on), for the
+// workers.  A worker handles what's available without waiting for
+// more, and moves on, so mostly-idle clients don't need threads of
+// their own.
+//
+// Connections may use TLS (see tls.rs), in which case input is
+// decrypted, and output encrypted, by the workers.
+//
+// Responses are written in blocking mode, so a client that stops
+// reading its responses ties up a worker until the write times out.
+//
+// Connections are queued by storage and the queues take turns, so a
+// busy storage, with lots of clients, can't starve other storages of
+// workers.
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{Context, Result};
+
+use crate::msg;
+use crate::reader;
+use crate::registry;
+use crate::storage;
+use crate::tls;
+use crate::writer;
+
+pub const DEFAULT_WORKERS: usize = 16;
+
+// How many epoll events to handle at once
+const EVENTS: usize = 256;
+
+/// Buffer sizes for a connection, in bytes, e.g. bigger for clients
+/// across wide-area networks.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffers {
+    /// Input read at a time
+    pub read: usize,
+    /// Output collected before it's written, or 0 to write responses
+    /// one at a time
+    pub write: usize,
+    /// Operating-system socket buffers, for sending and receiving,
+    /// or 0 for the system default
+    pub socket: usize,
+}
+
+impl Default for Buffers {
+    fn default() -> Buffers {
+        Buffers { read: msg::READ_BUFFER_SIZE, write: writer::WRITE_BUFFER_SIZE, socket: 0 }
+    }
+}
+
+pub struct Reactor {
+    epoll: i32,
+    connections: std::sync::Mutex<std::collections::HashMap<u64, std::sync::Arc<Connection>>>,
+    next_token: std::sync::atomic::AtomicU64,
+    ready: std::sync::Mutex<Ready<std::sync::Arc<Connection>>>,
+    available: std::sync::Condvar,
+    idle_timeout: Option<std::time::Duration>,
+}
+
+// Queues of things to work on, by storage, taking turns
+struct Ready<T> {
+    queues: std::collections::HashMap<String, std::collections::VecDeque<T>>,
+    // Storages with non-empty queues, in the order they get turns
+    turns: std::collections::VecDeque<String>,
+}
+
+impl<T> Ready<T> {
+
+    fn new() -> Ready<T> {
+        Ready { queues: std::collections::HashMap::new(),
+                turns: std::collections::VecDeque::new() }
+    }
+
+    fn push(&mut self, storage: &str, item: T) {
+        let queue = self.queues.entry(storage.to_string()).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(storage.to_string());
+        }
+        queue.push_back(item);
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let storage = self.turns.pop_front()?;
+        let queue = self.queues.get_mut(&storage)?;
+        let item = queue.pop_front();
+        if queue.is_empty() {
+            self.queues.remove(&storage);
+        }
+        else {
+            self.turns.push_back(storage);
+        }
+        item
+    }
+}
+
+struct Connection {
+    token: u64,
+    // The path of the storage the client registered for, for fair
+    // scheduling
+    storage: std::sync::OnceLock<String>,
+    stream: tls::Stream,
+    // Whether the connection is queued for a worker
+    scheduled: std::sync::atomic::AtomicBool,
+    // Set by the poller when there's input to read
+    readable: std::sync::atomic::AtomicBool,
+    // Set by the poller when the client has been idle too long
+    idle: std::sync::atomic::AtomicBool,
+    last_input: std::sync::Mutex<std::time::Instant>,
+    // None until the client registers, and after the connection closes
+    state: std::sync::Mutex<Option<State>>,
+}
+
+struct State {
+    fs: registry::Storage,
+    client: writer::Client,
+    requests: msg::ZeoIter<Input>,
+    read: reader::State,
+    send: std::sync::mpsc::Sender<msg::Zeo>,
+    receive: std::sync::mpsc::Receiver<msg::Zeo>,
+    session: writer::Session,
+    // Output not yet written
+    output: Vec<u8>,
+    write_buffer: usize,
+    _slot: Option<registry::Slot>,
+}
+
+// Reads whatever input is available, failing with WouldBlock rather
+// than waiting for more.
+struct Input(tls::Stream);
+
+impl std::io::Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read_available(buf)
+    }
+}
+
+impl Reactor {
+
+    /// Start a poller thread and the given number of worker threads.
+    ///
+    /// With an idle timeout, clients that send nothing for that long
+    /// are disconnected.
+    pub fn new(workers: usize, idle_timeout: Option<std::time::Duration>)
+               -> Result<std::sync::Arc<Reactor>> {
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll < 0 {
+            return Err(std::io::Error::last_os_error()).context("creating epoll");
+        }
+        let reactor = std::sync::Arc::new(Reactor {
+            epoll,
+            connections: std::sync::Mutex::new(std::collections::HashMap::new()),
+            next_token: std::sync::atomic::AtomicU64::new(0),
+            ready: std::sync::Mutex::new(Ready::new()),
+            available: std::sync::Condvar::new(),
+            idle_timeout,
+        });
+        let poller = reactor.clone();
+        std::thread::spawn(move || poller.poll());
+        for _ in 0 .. workers.max(1) {
+            let worker = reactor.clone();
+            std::thread::spawn(move || worker.work());
+        }
+        Ok(reactor)
+    }
+
+    /// Register a client connection and hand it to the workers.
+    ///
+    /// This returns once the client has registered (or failed to),
+    /// so registration, including authentication, uses the calling
+    /// thread.  Give the stream a read timeout to limit how long that
+    /// can take.  The admission slot, if any, is held until the
+    /// connection closes.
+    pub fn serve(self: &std::sync::Arc<Self>, registry: &registry::Registry,
+                 name: String, read_only: bool, stream: tls::Stream,
+                 buffers: Buffers, slot: Option<registry::Slot>)
+                 -> Result<()> {
+        if buffers.socket > 0 {
+            set_socket_buffers(stream.tcp(), buffers.socket)?;
+        }
+        let connection = std::sync::Arc::new(Connection {
+            token: self.next_token.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+            storage: std::sync::OnceLock::new(),
+            stream: stream.try_clone()?,
+            scheduled: std::sync::atomic::AtomicBool::new(false),
+            readable: std::sync::atomic::AtomicBool::new(true),
+            idle: std::sync::atomic::AtomicBool::new(false),
+            last_input: std::sync::Mutex::new(std::time::Instant::now()),
+            state: std::sync::Mutex::new(None),
+        });
+        let reactor = std::sync::Arc::downgrade(self);
+        let woken = std::sync::Arc::downgrade(&connection);
+        let wake = std::sync::Arc::new(move || {
+            if let (Some(reactor), Some(connection)) = (reactor.upgrade(), woken.upgrade()) {
+                reactor.schedule(&connection);
+            }
+        });
+
+        let mut output = stream.try_clone()?;
+        let identity = stream.identity().map(str::to_string);
+        let registry::Registered { fs, client, requests, send, receive } =
+            match registry::register(registry, name, read_only, identity.as_deref(), stream,
+                                     &mut output, Some(wake))? {
+                Some(registered) => registered,
+                None => return Ok(()),
+            };
+        if let Some(timeout) = self.idle_timeout {
+            connection.stream.tcp().set_write_timeout(Some(timeout))?;
+        }
+        let _ = connection.storage.set(fs.path().to_string());
+        let mut requests = requests.with_reader(Input(connection.stream.try_clone()?));
+        requests.set_buffer_size(buffers.read);
+        let session = writer::Session::new(fs.clone(), client.clone());
+        let read = reader::State {
+            methods: requests.methods(), read_only: client.read_only(), ..Default::default()
+        };
+        *connection.state.lock().unwrap() = Some(State {
+            fs, client, requests, read, send, receive, session,
+            output: vec![], write_buffer: buffers.write, _slot: slot,
+        });
+
+        self.connections.lock().unwrap().insert(connection.token, connection.clone());
+        if let Err(err) = self.control(libc::EPOLL_CTL_ADD, &connection) {
+            self.connections.lock().unwrap().remove(&connection.token);
+            if let Some(state) = connection.state.lock().unwrap().take() {
+                state.fs.remove_client(
+                    state.client, storage::DisconnectReason::ProtocolError(
+                        format!("{:#}", err)));
+            }
+            return Err(err);
+        }
+        // Registration may have read requests past the register call.
+        self.schedule(&connection);
+        Ok(())
+    }
+
+    /// The number of connections being served
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Watch (or keep watching) a connection for input.  Connections
+    // are watched one-shot, so a connection isn't reported again
+    // while a worker is reading from it.
+    fn control(&self, op: i32, connection: &Connection) -> Result<()> {
+        let mut event = libc::epoll_event {
+            events: (libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLONESHOT) as u32,
+            u64: connection.token,
+        };
+        if unsafe { libc::epoll_ctl(self.epoll, op, connection.stream.tcp().as_raw_fd(),
+                                    &mut event) } < 0 {
+            return Err(std::io::Error::last_os_error()).context("watching connection");
+        }
+        Ok(())
+    }
+
+    fn schedule(&self, connection: &std::sync::Arc<Connection>) {
+        if ! connection.scheduled.swap(true, std::sync::atomic::Ordering::SeqCst) {
+            let storage = connection.storage.get().map(| s | s.as_str()).unwrap_or("");
+            self.ready.lock().unwrap().push(storage, connection.clone());
+            self.available.notify_one();
+        }
+    }
+
+    fn poll(&self) {
+        let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; EVENTS];
+        // Check for idle connections a few times per timeout.
+        let check = self.idle_timeout.map(
+            | timeout | (timeout / 4).clamp(std::time::Duration::from_millis(10),
+                                             std::time::Duration::from_secs(1)));
+        let wait = check.map(| check | check.as_millis() as i32).unwrap_or(-1);
+        let mut checked = std::time::Instant::now();
+        loop {
+            let n = unsafe {
+                libc::epoll_wait(self.epoll, events.as_mut_ptr(), EVENTS as i32, wait)
+            };
+            if n < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    tracing::error!("Polling failed: {}", err);
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                }
+                continue;
+            }
+            for event in &events[.. n as usize] {
+                let token = event.u64;
+                let connection = self.connections.lock().unwrap().get(&token).cloned();
+                if let Some(connection) = connection {
+                    connection.readable.store(true, std::sync::atomic::Ordering::SeqCst);
+                    self.schedule(&connection);
+                }
+            }
+            if let (Some(timeout), Some(check)) = (self.idle_timeout, check) {
+                if checked.elapsed() >= check {
+                    checked = std::time::Instant::now();
+                    let connections: Vec<std::sync::Arc<Connection>> =
+                        self.connections.lock().unwrap().values().cloned().collect();
+                    for connection in connections {
+                        if connection.last_input.lock().unwrap().elapsed() > timeout {
+                            connection.idle.store(true, std::sync::atomic::Ordering::SeqCst);
+                            self.schedule(&connection);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn work(&self) {
+        loop {
+            let connection = {
+                let mut ready = self.ready.lock().unwrap();
+                loop {
+                    match ready.pop() {
+                        Some(connection) => break connection,
+                        None => ready = self.available.wait(ready).unwrap(),
+                    }
+                }
+            };
+            // A panic handling a connection closes the connection,
+            // rather than taking down the worker.
+            let run = std::panic::catch_unwind(
+                std::panic::AssertUnwindSafe(|| self.run(&connection)));
+            if let Err(panic) = run {
+                let message = registry::panic_message(panic.as_ref());
+                tracing::error!("Worker panicked: {}", message);
+                let state = connection.state.lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner).take();
+                if let Some(state) = state {
+                    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(
+                        || self.close(&connection, state,
+                                      storage::DisconnectReason::Failed(message))));
+                }
+            }
+        }
+    }
+
+    // Handle a connection's input and writer messages, closing it if
+    // it's done.
+    fn run(&self, connection: &Connection) {
+        // Cleared first, so messages sent while we work get the
+        // connection scheduled again.
+        connection.scheduled.store(false, std::sync::atomic::Ordering::SeqCst);
+        let mut state = connection.state.lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let reason = match *state {
+            Some(ref mut state) => {
+                let _connection = state.client.span();
+                self.step(connection, state)
+            },
+            None => return,
+        };
+        if let Some(reason) = reason {
+            if let Some(state) = state.take() {
+                self.close(connection, state, reason);
+            }
+        }
+    }
+
+    // Returns a reason if the connection should be closed.
+    fn step(&self, connection: &Connection, state: &mut State)
+            -> Option<storage::DisconnectReason> {
+        use std::sync::atomic::Ordering::SeqCst;
+        if connection.idle.swap(false, SeqCst) &&
+            connection.last_input.lock().unwrap().elapsed() >
+            self.idle_timeout.unwrap_or(std::time::Duration::MAX)
+        {
+            return Some(storage::DisconnectReason::Idle);
+        }
+        if connection.readable.swap(false, SeqCst) {
+            loop {
+                let message = match state.requests.next() {
+                    Ok(message) => message,
+                    Err(err) if would_block(&err) => break,
+                    Err(err) => return Some(
+                        storage::DisconnectReason::ProtocolError(format!("{:#}", err))),
+                };
+                *connection.last_input.lock().unwrap() = std::time::Instant::now();
+                match reader::handle(&state.fs, &mut state.read, message, &state.send,
+                                     state.client.connection()) {
+                    Ok(true) => {},
+                    Ok(false) => return Some(storage::DisconnectReason::Closed),
+                    Err(err) => return Some(
+                        storage::DisconnectReason::ProtocolError(format!("{:#}", err))),
+                }
+                // Respond as we go, rather than queueing responses
+                // to everything the client has sent.
+                if let Some(reason) = self.drain(connection, state) {
+                    return Some(reason);
+                }
+            }
+            if let Err(err) = self.control(libc::EPOLL_CTL_MOD, connection) {
+                return Some(storage::DisconnectReason::ProtocolError(format!("{:#}", err)));
+            }
+        }
+        self.drain(connection, state)
+            .or_else(|| flush(connection, state).err()
+                     .map(| _ | storage::DisconnectReason::SendFailed))
+    }
+
+    // Handle messages for the client's writer, writing output when
+    // there's a buffer-full.
+    fn drain(&self, connection: &Connection, state: &mut State)
+             -> Option<storage::DisconnectReason> {
+        while let Ok(zeo) = state.receive.try_recv() {
+            let reason = match state.session.handle(zeo, &mut state.output) {
+                Ok(true) => None,
+                Ok(false) => Some(storage::DisconnectReason::Closed),
+                Err(_) => Some(storage::DisconnectReason::SendFailed),
+            };
+            if reason.is_some() {
+                let _ = flush(connection, state);
+                return reason;
+            }
+            if state.output.len() >= state.write_buffer &&
+                flush(connection, state).is_err()
+            {
+                return Some(storage::DisconnectReason::SendFailed);
+            }
+        }
+        None
+    }
+
+    fn close(&self, connection: &Connection, mut state: State,
+             reason: storage::DisconnectReason) {
+        state.fs.remove_client(state.client.clone(), reason);
+        // Let the client know why, if there's a reason to.
+        while let Ok(zeo) = state.receive.try_recv() {
+            if ! state.session.handle(zeo, &mut state.output).unwrap_or(false) {
+                break;
+            }
+        }
+        let _ = flush(connection, &mut state);
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        unsafe {
+            libc::epoll_ctl(self.epoll, libc::EPOLL_CTL_DEL,
+                            connection.stream.tcp().as_raw_fd(), &mut event);
+        }
+        self.connections.lock().unwrap().remove(&connection.token);
+        connection.stream.close();
+        // Dropping the state aborts pending transactions and gives
+        // up the admission slot.
+    }
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.epoll); }
+    }
+}
+
+// Write collected output.
+fn flush(connection: &Connection, state: &mut State) -> std::io::Result<()> {
+    if ! state.output.is_empty() {
+        (&connection.stream).write_all(&state.output)?;
+        state.output.clear();
+        state.output.shrink_to(state.write_buffer);
+    }
+    Ok(())
+}
+
+fn set_socket_buffers(stream: &std::net::TcpStream, size: usize) -> Result<()> {
+    let size = size as libc::c_int;
+    for option in [libc::SO_RCVBUF, libc::SO_SNDBUF] {
+        if unsafe {
+            libc::setsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, option,
+                             &size as *const libc::c_int as *const libc::c_void,
+                             std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+        } < 0 {
+            return Err(std::io::Error::last_os_error()).context("setting socket buffers");
+        }
+    }
+    Ok(())
+}
+
+fn would_block(err: &anyhow::Error) -> bool {
+    err.chain().any(
+        | cause | cause.downcast_ref::<std::io::Error>()
+            .is_some_and(| err | err.kind() == std::io::ErrorKind::WouldBlock))
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::msgmacros::*;
+    use crate::util;
+
+    fn start(registry: &std::sync::Arc<registry::Registry>,
+             reactor: &std::sync::Arc<Reactor>,
+             listener: &std::net::TcpListener,
+             name: String, buffers: Buffers)
+             -> (std::net::TcpStream, msg::ZeoIter<std::net::TcpStream>) {
+        let address = listener.local_addr().unwrap();
+        let registry = registry.clone();
+        let reactor = reactor.clone();
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+        std::thread::spawn(move || {
+            reactor.serve(&registry, name, false, tls::Stream::Plain(server_stream), buffers,
+                          None)
+                .unwrap()
+        });
+        let mut responses = msg::ZeoIter::new(stream.try_clone().unwrap());
+        assert_eq!(responses.next_vec().unwrap(), b"M5".to_vec());
+        stream.write_all(&msg::size_vec(b"M5".to_vec())).unwrap();
+        stream.write_all(&sencode!((1, "register", ("1", false))).unwrap()).unwrap();
+        responses.next_vec().unwrap();
+        (stream, responses)
+    }
+
+    fn wait_for(test: impl Fn() -> bool) {
+        let start = std::time::Instant::now();
+        while ! test() {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5));
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn storages_take_turns() {
+        let mut ready = Ready::new();
+        for i in 0 .. 4 {
+            ready.push("busy", i);
+        }
+        ready.push("quiet", 10);
+        ready.push("", 20);
+        ready.push("quiet", 11);
+        let mut order = vec![];
+        while let Some(i) = ready.pop() {
+            order.push(i);
+            if i == 0 {
+                ready.push("quiet", 12);
+            }
+        }
+        assert_eq!(order, vec![0, 10, 20, 1, 11, 2, 12, 3]);
+        assert!(ready.queues.is_empty() && ready.turns.is_empty());
+    }
+
+    #[test]
+    fn many_connections_few_threads() {
+        let tmpdir = util::test::dir();
+        let mut registry = registry::Registry::new();
+        let fs = registry.open("1", &util::test::test_path(&tmpdir, "data.fs"),
+                               storage::Limits::default(), Default::default()).unwrap();
+        let registry = std::sync::Arc::new(registry);
+        let reactor = Reactor::new(2, None).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut clients: Vec<_> = (0 .. 20).map(
+            | i | start(&registry, &reactor, &listener, i.to_string(),
+                        // Some connections write responses one at a time.
+                        Buffers { read: 7, write: i % 2 * 100, socket: 4096 }))
+            .collect();
+        wait_for(|| reactor.len() == 20);
+
+        // Pipelined requests, from all clients, are answered in order:
+        for (stream, _) in clients.iter_mut() {
+            for id in 2 .. 5 {
+                stream.write_all(&sencode!((id, "ping", ())).unwrap()).unwrap();
+            }
+        }
+        for (_, responses) in clients.iter_mut() {
+            for id in 2 .. 5 {
+                let (rid, flag, _): (i64, String, ()) =
+                    decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+                assert_eq!((rid, &flag as &str), (id, "R"));
+            }
+        }
+
+        // A commit by one client is seen by the others.
+        let (stream, responses) = &mut clients[0];
+        stream.write_all(&sencode!(
+            (0, "tpc_begin", (1, msg::bytes(b""), msg::bytes(b""), msg::bytes(b""),
+                              msg::NIL, msg::bytes(b" ")))).unwrap()).unwrap();
+        stream.write_all(&sencode!(
+            (0, "storea", (msg::bytes(&util::Z64), msg::bytes(&util::Z64),
+                           msg::bytes(b"data"), 1))).unwrap()).unwrap();
+        stream.write_all(&sencode!((5, "vote", (1,))).unwrap()).unwrap();
+        responses.next_vec().unwrap();
+        stream.write_all(&sencode!((6, "tpc_finish", (1,))).unwrap()).unwrap();
+        let (_, method, _): (i64, String, (Vec<(serde::bytes::ByteBuf, serde::bytes::ByteBuf)>,)) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!(&method, "serialnos");
+        for (_, responses) in clients[1 ..].iter_mut() {
+            let (_, method, _): (i64, String, (serde::bytes::ByteBuf, Vec<serde::bytes::ByteBuf>)) =
+                decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+            assert_eq!(&method, "invalidateTransaction");
+        }
+
+        // Closed connections are cleaned up.
+        clients.truncate(10);
+        wait_for(|| reactor.len() == 10 && fs.client_count() == 10);
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let tmpdir = util::test::dir();
+        let mut registry = registry::Registry::new();
+        let fs = registry.open("1", &util::test::test_path(&tmpdir, "data.fs"),
+                               storage::Limits::default(), Default::default()).unwrap();
+        let registry = std::sync::Arc::new(registry);
+        let reactor = Reactor::new(1, Some(std::time::Duration::from_millis(100))).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let (mut stream, mut responses) = start(&registry, &reactor, &listener, "test".to_string(),
+                                           Buffers::default());
+        wait_for(|| reactor.len() == 1);
+        for _ in 0 .. 3 {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            stream.write_all(&sencode!((2, "ping", ())).unwrap()).unwrap();
+            responses.next_vec().unwrap();
+        }
+        let (id, method, (reason,)): (i64, String, (String,)) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &method as &str, &reason as &str),
+                   (0, "disconnected", "idle too long"));
+        wait_for(|| reactor.is_empty() && fs.client_count() == 0);
+    }
+
+    // Make a certificate, signed by issuer, if given, or a
+    // certificate authority, if not.
+    fn certificate(name: &str, issuer: Option<&(rcgen::Certificate, rcgen::KeyPair)>)
+                   -> (rcgen::Certificate, rcgen::KeyPair) {
+        let mut params = rcgen::CertificateParams::new(vec![name.to_string()]).unwrap();
+        params.distinguished_name.push(rcgen::DnType::CommonName, name);
+        let key = rcgen::KeyPair::generate().unwrap();
+        let certificate = match issuer {
+            Some((issuer, issuer_key)) => {
+                params.extended_key_usages = vec![
+                    rcgen::ExtendedKeyUsagePurpose::ServerAuth,
+                    rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+                params.signed_by(&key, issuer, issuer_key).unwrap()
+            },
+            None => {
+                params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+                params.self_signed(&key).unwrap()
+            },
+        };
+        (certificate, key)
+    }
+
+    // Read a response from a client stream.
+    fn response(stream: &mut impl Read) -> std::io::Result<Vec<u8>> {
+        let mut size = [0u8; 4];
+        stream.read_exact(&mut size)?;
+        let mut data = vec![0u8; u32::from_be_bytes(size) as usize];
+        stream.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn tls_client_certificates() {
+        let tmpdir = util::test::dir();
+        let ca = certificate("Test CA", None);
+        let server = certificate("localhost", Some(&ca));
+        let (server_path, key_path, ca_path) = (
+            util::test::test_path(&tmpdir, "server.pem"),
+            util::test::test_path(&tmpdir, "server.key"),
+            util::test::test_path(&tmpdir, "ca.pem"));
+        std::fs::write(&server_path, server.0.pem()).unwrap();
+        std::fs::write(&key_path, server.1.serialize_pem()).unwrap();
+        std::fs::write(&ca_path, ca.0.pem()).unwrap();
+        let config = tls::server_config(&server_path, &key_path, Some(&ca_path)).unwrap();
+
+        let mut registry = registry::Registry::new();
+        let one = registry.open("1", &util::test::test_path(&tmpdir, "1.fs"),
+                                storage::Limits::default(), Default::default()).unwrap();
+        let two = registry.open("2", &util::test::test_path(&tmpdir, "2.fs"),
+                                storage::Limits::default(), Default::default()).unwrap();
+        let mut permissions = crate::auth::Permissions::new();
+        permissions.grant("bob", "1", crate::auth::Access::Write);
+        permissions.grant("bob", "2", crate::auth::Access::Read);
+        registry.set_permissions(permissions);
+        let registry = std::sync::Arc::new(registry);
+        let reactor = Reactor::new(2, None).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        // Connect with a certificate, if given, and register for a
+        // storage, returning the stream, the register response and
+        // how serving went.
+        let connect = | client: Option<&(rcgen::Certificate, rcgen::KeyPair)>, storage: &str | {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add(ca.0.der().clone()).unwrap();
+            let builder = rustls::ClientConfig::builder().with_root_certificates(roots);
+            let client_config = match client {
+                Some((certificate, key)) => builder.with_client_auth_cert(
+                    vec![certificate.der().clone()],
+                    rustls::pki_types::PrivateKeyDer::try_from(key.serialize_der()).unwrap())
+                    .unwrap(),
+                None => builder.with_no_client_auth(),
+            };
+            let socket = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (server_socket, _) = listener.accept().unwrap();
+            let (registry, reactor, config) = (registry.clone(), reactor.clone(), config.clone());
+            let serving = std::thread::spawn(move || -> Result<()> {
+                let stream = tls::accept(server_socket, &config)?;
+                reactor.serve(&registry, "test".to_string(), false, stream,
+                              Buffers::default(), None)
+            });
+            let mut stream = rustls::StreamOwned::new(
+                rustls::ClientConnection::new(std::sync::Arc::new(client_config),
+                                              "localhost".try_into().unwrap()).unwrap(),
+                socket);
+            let registered = response(&mut stream).and_then(| handshake | {
+                assert_eq!(handshake, b"M5".to_vec());
+                stream.write_all(&msg::size_vec(b"M5".to_vec()))?;
+                stream.write_all(&sencode!((1, "register", (storage, false))).unwrap())?;
+                response(&mut stream)
+            });
+            (stream, registered, serving.join().unwrap())
+        };
+
+        // Bob may write to 1:
+        let bob = certificate("bob", Some(&ca));
+        let (mut stream, registered, served) = connect(Some(&bob), "1");
+        served.unwrap();
+        let (id, flag, _): (i64, String, serde::bytes::ByteBuf) =
+            decode!(&mut &registered.unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &flag as &str), (1, "R"));
+        stream.write_all(&sencode!((2, "ping", ())).unwrap()).unwrap();
+        let (id, flag, _): (i64, String, ()) =
+            decode!(&mut &response(&mut stream).unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &flag as &str), (2, "R"));
+        wait_for(|| one.client_count() == 1);
+        assert!(! one.clients()[0].read_only());
+
+        // but only read 2:
+        let (_stream2, registered, served) = connect(Some(&bob), "2");
+        served.unwrap();
+        registered.unwrap();
+        wait_for(|| two.client_count() == 1);
+        assert!(two.clients()[0].read_only());
+
+        // Carol's certificate is valid, but she has no permissions:
+        let carol = certificate("carol", Some(&ca));
+        let (_, registered, served) = connect(Some(&carol), "1");
+        served.unwrap();
+        let (id, flag, (name, _)): (i64, String, (String, (String, String))) =
+            decode!(&mut &registered.unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &flag as &str, &name as &str), (1, "E", "ZEO.Exceptions.AuthError"));
+
+        // Clients without certificates, or with certificates we
+        // don't trust, are refused:
+        let (_, registered, served) = connect(None, "1");
+        assert!(registered.is_err() && served.is_err());
+        let eve = certificate("bob", Some(&certificate("Eve's CA", None)));
+        let (_, registered, served) = connect(Some(&eve), "1");
+        assert!(registered.is_err() && served.is_err());
+
+        // Closed connections are cleaned up:
+        drop(stream);
+        wait_for(|| one.client_count() == 0);
+    }
+}
@@ -2,11 +2,18 @@
 
 use anyhow::{anyhow, Context, Result};
 
+use crate::auth;
+use crate::errors;
+use crate::iterators;
 use crate::storage;
+use crate::util;
 use crate::writer;
 use crate::msg;
 use crate::msgmacros::*;
 
+// Size of the chunks blobs are sent in, as ZEO sends them
+pub const BLOB_CHUNK_SIZE: usize = 59000;
+
 macro_rules! respond {
     ($sender: expr, $id: expr, $data: expr) => (
         $sender.send(msg::Zeo::Raw(response!($id, $data))).context("send response")?
@@ -21,6 +28,123 @@ macro_rules! error {
     )
 }
 
+// Send an error response for a failed request
+macro_rules! report {
+    ($sender: expr, $connection: expr, $id: expr, $err: expr) => (
+        $sender
+            .send(msg::Zeo::Raw(
+                errors::POSError::from_error($err).response($connection, $id)?))
+            .context("send error response")?
+    )
+}
+
+/// A register call's request id, storage name and read-only flag
+pub type Registration = (i64, String, bool);
+
+/// Read the client handshake and register call.
+///
+/// Returns the registration, or None if the client disconnected.
+pub fn register<R: std::io::Read>(it: &mut msg::ZeoIter<R>)
+                                  -> Result<Option<Registration>> {
+    handshake(it)?;
+    read_register(it)
+}
+
+/// Read the client handshake, authentication and register call.
+///
+/// Responses to authentication calls are passed to send, encoded as
+/// the client's protocol says.  Clients
+/// that fail to authenticate, or that register without
+/// authenticating, get an AuthError, and None is returned, as for a
+/// client that disconnected.  Otherwise, the user is returned with
+/// the register call.
+pub fn register_authenticated<R: std::io::Read>(
+    it: &mut msg::ZeoIter<R>,
+    authenticator: &dyn auth::Authenticator,
+    send: &mut dyn FnMut(Vec<u8>) -> Result<()>,
+    connection: u64)
+    -> Result<Option<(String, Registration)>> {
+
+    handshake(it)?;
+    let codec = it.codec();
+    let send = &mut | data | send(codec.frame(data)?);
+    let mut challenge: Vec<u8> = vec![];
+    let user = loop {
+        match it.next()? {
+            msg::Zeo::AuthChallenge(id) => {
+                challenge = authenticator.challenge();
+                send(response!(id, msg::bytes(&challenge)))?;
+            },
+            msg::Zeo::Authenticate(id, user, response) => {
+                if authenticator.authenticate(&user, &challenge, &response) {
+                    tracing::info!("[{}] authenticated as {}", connection, user);
+                    send(response!(id, true))?;
+                    break user;
+                }
+                send(errors::POSError::Auth(format!("Authentication failed for {}", user))
+                     .response(connection, id)?)?;
+                return Ok(None);
+            },
+            msg::Zeo::Register(id, _, _) => {
+                send(errors::POSError::Auth("Authentication required".to_string())
+                     .response(connection, id)?)?;
+                return Ok(None);
+            },
+            msg::Zeo::End => return Ok(None),
+            _ => return Err(anyhow!("bad method")),
+        }
+    };
+    Ok(read_register(it)?.map(| registered | (user, registered)))
+}
+
+/// The outcome of a client's reply to the server's protocol
+#[derive(Debug, PartialEq)]
+pub enum Handshake {
+    /// The client chose a protocol we speak
+    Accepted(util::Bytes),
+    /// The client chose a protocol we don't speak, such as a
+    /// pickle-based protocol from a client older than ZEO 5
+    Unsupported(util::Bytes),
+}
+
+/// Decide whether to accept the protocol a client replied with.
+pub fn negotiate(protocol: &[u8]) -> Handshake {
+    if msg::CLIENT_PROTOCOLS.contains(&protocol) {
+        Handshake::Accepted(protocol.to_vec())
+    }
+    else {
+        Handshake::Unsupported(protocol.to_vec())
+    }
+}
+
+/// Read the client's protocol, failing if it isn't supported.
+///
+/// Following messages are decoded as the protocol says.
+pub fn handshake<R: std::io::Read>(it: &mut msg::ZeoIter<R>) -> Result<util::Bytes> {
+    match negotiate(&it.next_vec()?) {
+        Handshake::Accepted(protocol) => {
+            it.set_codec(msg::codec(&protocol));
+            Ok(protocol)
+        },
+        Handshake::Unsupported(protocol) => {
+            let supported: Vec<String> = msg::CLIENT_PROTOCOLS.iter()
+                .map(| p | String::from_utf8_lossy(p).to_string()).collect();
+            Err(anyhow!("Unsupported protocol {:?}, supported protocols are {}",
+                        String::from_utf8_lossy(&protocol), supported.join(", ")))
+        },
+    }
+}
+
+fn read_register<R: std::io::Read>(it: &mut msg::ZeoIter<R>)
+                                   -> Result<Option<Registration>> {
+    match it.next()? {
+        msg::Zeo::Register(id, storage, read_only) =>
+            Ok(Some((id, storage, read_only))),
+        msg::Zeo::End => Ok(None),
+        _ => Err(anyhow!("bad method")),
+    }
+}
+
 pub fn reader<R: std::io::Read>(
     fs: std::sync::Arc<storage::FileStorage<writer::Client>>,
     reader: R,
@@ -28,81 +152,417 @@ pub fn reader<R: std::io::Read>(
     -> Result<()> {
 
     let mut it = msg::ZeoIter::new(reader);
+    let connection = writer::new_connection();
 
-    // handshake
-    if it.next_vec()? != b"M5".to_vec() {
-        return Err(anyhow!("Bad handshake"))?
+    // handshake and register(storage_id, read_only)
+    match register(&mut it)? {
+        Some((id, storage, read_only)) => {
+            if &storage != "1" {
+                error!(sender, id,
+                       ("builtins.ValueError",
+                        ("Invalid storage", writer::correlation_id(connection, id))))
+            }
+            respond!(sender, id, msg::bytes(&fs.last_transaction()));
+            serve(fs, it, sender, connection, read_only)
+        },
+        None => {
+            sender.send(msg::Zeo::End);
+            Ok(())
+        },
     }
+}
 
-    // register(storage_id, read_only)
-    loop {
-        match it.next()? {
-            msg::Zeo::Register(id, storage, read_only) => {
-                if &storage != "1" {
-                    error!(sender, id,
-                           ("builtins.ValueError", ("Invalid storage",)))
-                }
-                respond!(sender, id, msg::bytes(&fs.last_transaction()));
-                break;          // onward
-            },
-            msg::Zeo::End => {
-                sender.send(msg::Zeo::End);
-                return Ok(())
-            },
-            _ => return Err(anyhow!("bad method"))?
-        }
-    }
+/// Handle requests from a registered client.
+///
+/// The connection id is used in error responses and log lines.
+/// Read-only connections can't make the server write, as with
+/// checkpoints.
+pub fn serve<R: std::io::Read>(
+    fs: std::sync::Arc<storage::FileStorage<writer::Client>>,
+    mut it: msg::ZeoIter<R>,
+    sender: std::sync::mpsc::Sender<msg::Zeo>,
+    connection: u64,
+    read_only: bool)
+    -> Result<()> {
+
+    let mut state = State { methods: it.methods(), read_only, ..Default::default() };
 
     // Main loop. We spend most of our time here.
-    loop {
-        let message = it.next()?;
-        match message {
-            msg::Zeo::LoadBefore(id, oid, before) => {
-                use storage::LoadBeforeResult::*;
-                match fs.load_before(&oid, &before)? {
-                    Loaded(data, tid, Some(end)) => {
-                        respond!(
-                            sender, id,
-                            (msg::bytes(&data), msg::bytes(&tid), msg::bytes(&end)));
-                    },
-                    Loaded(data, tid, None) => {
-                        respond!(
-                            sender, id,
-                            (msg::bytes(&data), msg::bytes(&tid), msg::NIL));
-                    },
-                    NoneBefore => {
-                        respond!(sender, id, msg::NIL);
-                    },
-                    PosKeyError => {
-                        error!(sender, id,
-                               ("ZODB.POSException.POSKeyError",
-                                (msg::bytes(&oid),)));
-                    },
+    while handle(&fs, &mut state, it.next()?, &sender, connection)? {}
+    Ok(())
+}
+
+/// A connection's read-side state
+#[derive(Default)]
+pub struct State {
+    // Set with set_read_view, loads see the database before this.
+    pub view: Option<util::Tid>,
+    pub iterators: iterators::Iterators,
+    // The methods clients can call, for handling extension methods
+    pub methods: std::sync::Arc<msg::Methods>,
+    // Whether the connection is read-only
+    pub read_only: bool,
+}
+
+/// Handle a request from a registered client, sending responses and
+/// transaction messages to the client's writer.
+///
+/// State has the connection's read view, set by set_read_view, its
+/// iterators, the methods it can call and whether it's read-only.
+/// Returns false when the client has disconnected.
+pub fn handle(
+    fs: &storage::FileStorage<writer::Client>,
+    state: &mut State,
+    message: msg::Zeo,
+    sender: &std::sync::mpsc::Sender<msg::Zeo>,
+    connection: u64)
+    -> Result<bool> {
+
+    let _request = message.id().map(crate::log::request);
+    match message {
+        msg::Zeo::LoadBefore(id, oid, before) => {
+            use storage::LoadBeforeResult::*;
+            let result = match state.view {
+                Some(tid) => fs.read_view(tid).load_before(&oid, &before),
+                None => fs.load_before(&oid, &before),
+            };
+            let result = match result {
+                Ok(result) => result,
+                Err(err) => {
+                    report!(sender, connection, id, err);
+                    return Ok(true);
+                },
+            };
+            match result {
+                Loaded(data, tid, Some(end)) => {
+                    respond!(
+                        sender, id,
+                        (msg::bytes(&data), msg::bytes(&tid), msg::bytes(&end)));
+                },
+                Loaded(data, tid, None) => {
+                    respond!(
+                        sender, id,
+                        (msg::bytes(&data), msg::bytes(&tid), msg::NIL));
+                },
+                NoneBefore => {
+                    respond!(sender, id, msg::NIL);
+                },
+                PosKeyError => {
+                    report!(sender, connection, id,
+                            errors::POSError::Key(oid).into());
+                },
+            }
+        },
+        msg::Zeo::LoadBefores(id, oids, before) => {
+            use storage::LoadBeforeResult::*;
+            let results = match state.view {
+                Some(tid) => fs.read_view(tid).load_befores(&oids, &before),
+                None => fs.load_befores(&oids, &before),
+            };
+            match results {
+                Ok(results) => {
+                    let results: Vec<_> = results.iter().map(| result | match result {
+                        Loaded(data, tid, end) => Some(
+                            (msg::bytes(data), msg::bytes(tid),
+                             end.as_ref().map(| end | msg::bytes(end)))),
+                        NoneBefore | PosKeyError => None,
+                    }).collect();
+                    respond!(sender, id, results);
+                },
+                Err(err) => report!(sender, connection, id, err),
+            }
+        },
+        msg::Zeo::LoadBeforeRange(id, oid, before, offset, size) => {
+            use storage::LoadBeforeResult::*;
+            let result = match state.view {
+                Some(tid) => fs.read_view(tid).load_before_range(&oid, &before, offset, size),
+                None => fs.load_before_range(&oid, &before, offset, size),
+            };
+            match result {
+                Ok((Loaded(data, tid, end), length)) => respond!(
+                    sender, id,
+                    (msg::bytes(&data), msg::bytes(&tid),
+                     end.as_ref().map(| end | msg::bytes(end)), length)),
+                Ok((NoneBefore, _)) => respond!(sender, id, msg::NIL),
+                Ok((PosKeyError, _)) => report!(sender, connection, id,
+                                                errors::POSError::Key(oid).into()),
+                Err(err) => report!(sender, connection, id, err),
+            }
+        },
+        msg::Zeo::LoadSerial(id, oid, serial) => {
+            match fs.load_serial(&oid, &serial) {
+                Ok(Some(data)) => respond!(sender, id, msg::bytes(&data)),
+                Ok(None) => report!(sender, connection, id,
+                                    errors::POSError::Key(oid).into()),
+                Err(err) => report!(sender, connection, id, err),
+            }
+        },
+        msg::Zeo::SendBlob(id, oid, serial) => {
+            // The blob is sent as asynchronous receiveBlob messages,
+            // a chunk at a time, before the response.
+            let file = match fs.load_blob(&oid, &serial) {
+                Ok(file) => file,
+                Err(err) => {
+                    report!(sender, connection, id, err);
+                    return Ok(true);
+                },
+            };
+            let (oid_bytes, serial_bytes) = (msg::bytes(&oid), msg::bytes(&serial));
+            sender.send(msg::Zeo::Raw(
+                message!(0, "receiveBlobStart", (&oid_bytes, &serial_bytes))))
+                .context("send blob")?;
+            let sent = crate::blobs::BlobDir::read_chunks(file, BLOB_CHUNK_SIZE, | chunk | {
+                sender.send(msg::Zeo::Raw(
+                    message!(0, "receiveBlobChunk",
+                             (&oid_bytes, &serial_bytes, msg::bytes(chunk)))))
+                    .context("send blob")
+            });
+            if let Err(err) = sent {
+                // The client has part of the blob, and no way to tell.
+                return Err(err.context("sending blob"));
+            }
+            sender.send(msg::Zeo::Raw(
+                message!(0, "receiveBlobStop", (&oid_bytes, &serial_bytes))))
+                .context("send blob")?;
+            respond!(sender, id, msg::NIL);
+        },
+        msg::Zeo::History(id, oid, size) => {
+            match fs.history(&oid, size as usize) {
+                Ok(Some(revisions)) => {
+                    use msg::InfoValue::*;
+                    let revisions: Vec<std::collections::BTreeMap<&str, msg::InfoValue>> =
+                        revisions.into_iter().map(| revision | [
+                            ("tid", Bytes(revision.tid.to_vec())),
+                            ("time", Float(crate::tid::tid_time(&revision.tid))),
+                            ("user_name", Bytes(revision.user)),
+                            ("description", Bytes(revision.description)),
+                            ("size", Int(revision.size)),
+                        ].into_iter().collect()).collect();
+                    respond!(sender, id, revisions)
+                },
+                Ok(None) => report!(sender, connection, id,
+                                    errors::POSError::Key(oid).into()),
+                Err(err) => report!(sender, connection, id, err),
+            }
+        },
+        msg::Zeo::RecordIternext(id, next) => {
+            match fs.record_iternext(next.as_ref()) {
+                Ok(Some(record)) => respond!(
+                    sender, id,
+                    (msg::bytes(&record.oid), msg::bytes(&record.tid), msg::bytes(&record.data),
+                     record.next.as_ref().map(| oid | msg::bytes(oid)))),
+                Ok(None) => respond!(sender, id, msg::NIL),
+                Err(err) => report!(sender, connection, id, err),
+            }
+        },
+        msg::Zeo::IteratorStart(id, start, stop) => {
+            match fs.transactions() {
+                Ok(it) => respond!(sender, id,
+                                   state.iterators.start(it, fs.dictionaries(), start, stop)),
+                Err(err) => report!(sender, connection, id, err),
+            }
+        },
+        msg::Zeo::IteratorNext(id, iid) => {
+            match state.iterators.next_transaction(iid) {
+                Ok(Some(info)) => respond!(
+                    sender, id,
+                    (msg::bytes(&info.tid), " ", msg::bytes(&info.user),
+                     msg::bytes(&info.description), msg::bytes(&info.extension))),
+                Ok(None) => respond!(sender, id, msg::NIL),
+                Err(err) => report!(sender, connection, id, err),
+            }
+        },
+        msg::Zeo::IteratorRecordStart(id, iid, tid) => {
+            match state.iterators.start_records(iid, &tid) {
+                Ok(records) => respond!(sender, id, records),
+                Err(err) => report!(sender, connection, id, err),
+            }
+        },
+        msg::Zeo::IteratorRecordNext(id, iid) => {
+            match state.iterators.next_record(iid) {
+                Ok(Some(record)) => respond!(
+                    sender, id,
+                    (msg::bytes(&record.oid), msg::bytes(&record.tid),
+                     msg::bytes(&record.data), msg::NIL)),
+                Ok(None) => respond!(sender, id, msg::NIL),
+                Err(err) => report!(sender, connection, id, err),
+            }
+        },
+        msg::Zeo::IteratorGc(iids) => state.iterators.gc(&iids),
+        msg::Zeo::UndoLog(id, first, last) => {
+            undo_log(fs, sender, connection, id, first, last, None)?
+        },
+        msg::Zeo::UndoInfo(id, first, last, spec) => {
+            undo_log(fs, sender, connection, id, first, last, spec)?
+        },
+        msg::Zeo::SetReadView(id, tid) => {
+            state.view = tid;
+            respond!(sender, id, msg::NIL);
+        },
+        msg::Zeo::Ping(id) => {
+            respond!(sender, id, msg::NIL);
+        },
+        msg::Zeo::Checkpoint(id) => {
+            if state.read_only {
+                report!(sender, connection, id, errors::POSError::ReadOnly.into());
+                return Ok(true);
+            }
+            match fs.checkpoint() {
+                Ok(count) => respond!(sender, id, count),
+                Err(err) => report!(sender, connection, id, err),
+            }
+        },
+        msg::Zeo::HotObjects(id, count) => {
+            // Only reads sampled load counts, so read-only clients
+            // can warm caches with it.
+            let hot = fs.hot_objects(count as usize);
+            let hot: Vec<(serde::bytes::Bytes, u64)> = hot.iter()
+                .map(| (oid, loads) | (msg::bytes(oid), *loads)).collect();
+            respond!(sender, id, hot)
+        },
+        msg::Zeo::NewOids(id) => {
+            let oids = fs.new_oids();
+            let oids: Vec<serde::bytes::Bytes> =
+                oids.iter().map(| oid | msg::bytes(oid)).collect();
+            respond!(sender, id, oids)
+        },
+        msg::Zeo::NewOid(id) => {
+            respond!(sender, id, msg::bytes(&fs.new_oid()))
+        },
+        msg::Zeo::LastTransaction(id) => {
+            respond!(sender, id, msg::bytes(&fs.last_transaction()))
+        },
+        msg::Zeo::GetInvalidations(id, tid) => {
+            match fs.invalidations_since(&tid, fs.invalidation_queue_size()) {
+                Ok(Some((last, oids))) => respond!(
+                    sender, id,
+                    (msg::bytes(&last),
+                     oids.iter().map(| oid | msg::bytes(oid)).collect::<Vec<_>>())),
+                Ok(None) => respond!(sender, id, msg::NIL),
+                Err(err) => report!(sender, connection, id, err),
+            }
+        },
+        msg::Zeo::GetExtensionMethods(id) => {
+            let methods: std::collections::BTreeMap<&str, Option<u32>> =
+                crate::info::EXTENSION_METHODS.iter().copied()
+                .chain(state.methods.extensions())
+                .map(| m | (m, msg::NIL)).collect();
+            respond!(sender, id, methods)
+        },
+        msg::Zeo::ServerStatus(id) => {
+            respond!(sender, id, crate::info::storage_status(fs))
+        },
+        msg::Zeo::GetInfo(id) => {
+            let mut info = crate::info::server_info();
+            info.insert("name".to_string(), msg::InfoValue::Str(fs.path().to_string()));
+            info.insert("length".to_string(), msg::InfoValue::Int(fs.len() as u64));
+            info.insert("size".to_string(), msg::InfoValue::Int(fs.size()));
+            if let Some(dir) = fs.blob_dir() {
+                if let Some(msg::InfoValue::List(capabilities)) = info.get_mut("capabilities") {
+                    capabilities.push(msg::InfoValue::Str("blobs".to_string()));
+                    if dir.is_shared() {
+                        capabilities.push(msg::InfoValue::Str("shared_blobs".to_string()));
+                    }
                 }
-            },
-            msg::Zeo::Ping(id) => {
-                respond!(sender, id, msg::NIL);
-            },
-            msg::Zeo::NewOids(id) => {
-                let oids = fs.new_oids();
-                let oids: Vec<serde::bytes::Bytes> =
-                    oids.iter().map(| oid | msg::bytes(oid)).collect();
-                respond!(sender, id, oids)
-            },
-            msg::Zeo::GetInfo(id) => { // TODO, don't punt :)
-                respond!(sender, id, std::collections::BTreeMap::<String, i64>::new())
-            },
-            msg::Zeo::TpcBegin(_, _, _, _) | msg::Zeo::Storea(_, _, _, _) |
-            msg::Zeo::Vote(_, _) | msg::Zeo::TpcFinish(_, _) |  msg::Zeo::TpcAbort(_, _)
-                =>
-                sender
-                .send(message)
-                .context("send error")?, // Forward these
-            msg::Zeo::End => {
-                sender.send(msg::Zeo::End);
-                return Ok(())
-            },
-            _ => return Err(anyhow!("bad method"))
-        }            
+            }
+            respond!(sender, id, info)
+        },
+        msg::Zeo::TpcBegin(_, _, _, _, _) | msg::Zeo::Storea(_, _, _, _) |
+        msg::Zeo::StoreaStart(_, _, _, _) | msg::Zeo::StoreaChunk(_, _) |
+        msg::Zeo::StoreBlobStart | msg::Zeo::StoreBlobChunk(_) |
+        msg::Zeo::StoreBlobEnd(_, _, _, _) | msg::Zeo::StoreBlobShared(_, _, _, _, _) |
+        msg::Zeo::Restorea(_, _, _, _, _) |
+        msg::Zeo::Vote(_, _) | msg::Zeo::TpcFinish(_, _) |  msg::Zeo::TpcAbort(_, _) |
+        msg::Zeo::DeferFsync(_, _) | msg::Zeo::Pack(_, _, _) | msg::Zeo::Backup(_, _) |
+        msg::Zeo::IncrementalBackup(_, _)
+            =>
+            sender
+            .send(message)
+            .context("send error")?, // Forward these
+        msg::Zeo::Extension(id, method, args) => {
+            // The connection's methods may lack it, like unknown methods.
+            let handler = match state.methods.extension(&method) {
+                Some(handler) => handler,
+                None => {
+                    let cid = writer::correlation_id(connection, id);
+                    tracing::warn!("[{}] Unknown extension method {}", cid, method);
+                    if id > 0 {
+                        error!(sender, id, ("builtins.AttributeError", (method, cid)))
+                    }
+                    return Ok(true);
+                },
+            };
+            match handler(fs, &args) {
+                Ok(result) => sender.send(msg::Zeo::Raw(msg::raw_response(id, &result)?))
+                    .context("send response")?,
+                Err(err) => report!(sender, connection, id, err),
+            }
+        },
+        msg::Zeo::Unknown(id, method) => {
+            let cid = writer::correlation_id(connection, id);
+            tracing::warn!("[{}] Unknown method {}", cid, method);
+            if id > 0 {
+                error!(sender, id, ("builtins.AttributeError", (method, cid)))
+            }
+        },
+        msg::Zeo::Malformed(id, method, err) => {
+            let cid = writer::correlation_id(connection, id);
+            tracing::warn!("[{}] Malformed {} request: {}", cid, method, err);
+            error!(sender, id, ("builtins.ValueError", (err, cid)))
+        },
+        msg::Zeo::End => {
+            sender.send(msg::Zeo::End);
+            return Ok(false)
+        },
+        _ => return Err(anyhow!("bad method"))
+    }
+    Ok(true)
+}
+
+type Description = std::collections::BTreeMap<String, msg::InfoValue>;
+
+fn describe(info: &storage::TransactionInfo) -> Description {
+    use msg::InfoValue::*;
+    [("id", Bytes(info.tid.to_vec())),
+     ("time", Float(crate::tid::tid_time(&info.tid))),
+     ("user_name", Bytes(info.user.clone())),
+     ("description", Bytes(info.description.clone()))]
+        .into_iter().map(| (name, value) | (name.to_string(), value)).collect()
+}
+
+// Clients may send strings for bytes, so compare them as bytes.
+fn same(a: &msg::InfoValue, b: &msg::InfoValue) -> bool {
+    use msg::InfoValue::*;
+    match (a, b) {
+        (Str(a), Bytes(b)) | (Bytes(b), Str(a)) => a.as_bytes() == &b[..],
+        _ => a == b,
+    }
+}
+
+// Respond with descriptions of transactions matching spec, from first
+// to last, where a negative last is a count, as with ZODB's undoLog.
+fn undo_log(
+    fs: &storage::FileStorage<writer::Client>,
+    sender: &std::sync::mpsc::Sender<msg::Zeo>,
+    connection: u64,
+    id: i64, first: i64, last: i64, spec: Option<Description>)
+    -> Result<()> {
+
+    let first = first.max(0);
+    // Clients choose last, so the count can be as large as they like.
+    let last = if last < 0 { first.saturating_sub(last) } else { last };
+    let spec = spec.unwrap_or_default();
+    let matches = | info: &storage::TransactionInfo | {
+        let description = describe(info);
+        spec.iter().all(| (name, value) |
+                        description.get(name).is_some_and(| v | same(v, value)))
+    };
+    match fs.undo_log(first as usize, last as usize, matches) {
+        Ok(transactions) => {
+            let descriptions: Vec<Description> = transactions.iter().map(describe).collect();
+            respond!(sender, id, descriptions)
+        },
+        Err(err) => report!(sender, connection, id, err),
     }
+    Ok(())
 }
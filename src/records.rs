@@ -3,33 +3,100 @@ use std::io::prelude::*;
 
 use byteorder::{ByteOrder, BigEndian, ReadBytesExt, WriteBytesExt};
 
+use crate::dictionaries;
 use crate::index;
 use crate::util;
 
-pub static HEADER_MARKER: &'static [u8] = b"fs2 ";
+// File markers, by format version.  Version 2 data headers have
+// checksums, version 3 data headers flag compressed data, and
+// version 4 lengths and offsets are 64-bit.  Older servers don't
+// know the new markers, so they refuse files they'd misread.
+pub static HEADER_MARKER: &[u8] = b"fs5 ";
+pub static V3_HEADER_MARKER: &[u8] = b"fs4 ";
+pub static V2_HEADER_MARKER: &[u8] = b"fs3 ";
+pub static V1_HEADER_MARKER: &[u8] = b"fs2 ";
+pub const FORMAT_VERSION: u32 = 4;
 
 pub struct FileHeader {
+    pub version: u32,
     alignment: u64,
     previous: String,
 }
 pub const HEADER_SIZE: u64 = 4096;
 
+/// Read a data file's marker, returning its format version.
+pub fn read_version(reader: &mut dyn std::io::Read) -> std::io::Result<u32> {
+    let marker = util::read4(reader)?;
+    if marker == HEADER_MARKER { Ok(FORMAT_VERSION) }
+    else if marker == V3_HEADER_MARKER { Ok(3) }
+    else if marker == V2_HEADER_MARKER { Ok(2) }
+    else if marker == V1_HEADER_MARKER { Ok(1) }
+    else { Err(util::io_error("bad magic")) }
+}
+
+/// The marker for a format version
+pub fn marker(version: u32) -> &'static [u8] {
+    match version {
+        1 => V1_HEADER_MARKER,
+        2 => V2_HEADER_MARKER,
+        3 => V3_HEADER_MARKER,
+        _ => HEADER_MARKER,
+    }
+}
+
+/// Whether records in files of a format version have checksums
+pub fn checksums(version: u32) -> bool {
+    version >= 2
+}
+
+/// Whether data records in files of a format version can be
+/// compressed
+pub fn compression(version: u32) -> bool {
+    version >= 3
+}
+
+/// Whether data-record lengths, and their offsets in transactions,
+/// are 64-bit in files of a format version, with transaction
+/// checksums in fields of their own, so records aren't limited to 4
+/// GiB
+pub fn wide(version: u32) -> bool {
+    version >= 4
+}
+
 impl FileHeader {
 
     pub fn new() -> FileHeader {
-        FileHeader { alignment: 1 << 32, previous: String::new() }
+        FileHeader { version: FORMAT_VERSION, alignment: 1 << 32, previous: String::new() }
+    }
+
+    /// Whether data records written to the file have checksums
+    pub fn checksums(&self) -> bool {
+        checksums(self.version)
+    }
+
+    /// A header in the current format, linked to the same previous file.
+    pub fn upgraded(&self) -> FileHeader {
+        self.with_version(FORMAT_VERSION)
+    }
+
+    /// A header in a format version, linked to the same previous file.
+    pub fn with_version(&self, version: u32) -> FileHeader {
+        FileHeader { version, previous: self.previous.clone(), ..FileHeader::new() }
     }
 
     pub fn read<T>(mut reader: &mut T) -> std::io::Result<FileHeader>
         where T: std::io::Read + std::io::Seek
     {
-        util::check_magic(&mut reader, HEADER_MARKER);
-        util::io_assert(reader.read_u64::<BigEndian>()? == 4096,
-                  "Bad header length")?;
+        let version = read_version(reader)?;
+        let header_length = reader.read_u64::<BigEndian>()?;
+        util::io_assert(
+            header_length != HEADER_SIZE.swap_bytes(),
+            "Legacy little-endian data file, convert it with byteserver convert")?;
+        util::io_assert(header_length == 4096, "Bad header length")?;
         let alignment = reader.read_u64::<BigEndian>()?;
         let h = match String::from_utf8(util::read_sized16(&mut reader)?) {
             Ok(previous) =>
-                FileHeader { alignment: alignment, previous: previous },
+                FileHeader { version, alignment: alignment, previous: previous },
             _ => return Err(util::io_error("Bad previous utf8")),
         };
         util::io_assert(reader.seek(std::io::SeekFrom::Start(4088))? == 4088,
@@ -42,7 +109,7 @@ impl FileHeader {
     pub fn write<T>(&self, writer: &mut T) -> std::io::Result<()>
         where T: std::io::Write + std::io::Seek
     {
-        writer.write_all(&HEADER_MARKER)?;
+        writer.write_all(marker(self.version))?;
         writer.write_u64::<BigEndian>(4096)?;
         writer.write_u64::<BigEndian>(self.alignment)?;
         writer.write_u16::<BigEndian>(self.previous.len() as u16)?;
@@ -61,35 +128,138 @@ impl FileHeader {
 #[derive(PartialEq, Debug)]
 pub struct TransactionHeader {
     pub length: u64,
+    // The transaction's checksum, or 0 if it wasn't computed, as in
+    // version 1 files.  It's the high half of the 64-bit length field
+    // in versions 2 and 3, so their transactions are limited to 4 GiB,
+    // and has a field of its own, after the header, in later versions.
+    pub checksum: u32,
     pub id: util::Tid,
     pub ndata: u32,
     pub luser: u16,
     pub ldesc: u16,
     pub lext: u32,
 }
-pub const TRANSACTION_HEADER_LENGTH: u64 = 28;
+
+/// The size of a transaction header, after the record marker, in
+/// files of a format version
+pub fn transaction_header_length(version: u32) -> u64 {
+    if wide(version) { 32 } else { 28 }
+}
+
+/// Where the transaction checksum is, relative to the record marker,
+/// in files of a format version
+pub fn transaction_checksum_offset(version: u32) -> u64 {
+    if wide(version) { 32 } else { 4 }
+}
+
+// Where what the transaction checksum starts with is: the tid,
+// record count and metadata lengths, which end where version 4
+// checksums are, followed by the metadata.
+pub const TRANSACTION_CHECKSUMMED_OFFSET: u64 = 12;
+const TRANSACTION_CHECKSUMMED_END: u64 = 32;
+
+// Whether checksums share fields with transaction lengths and
+// data-record offsets, as in versions 2 and 3
+fn checksums_share_fields(version: u32) -> bool {
+    checksums(version) && ! wide(version)
+}
+
+/// Split a transaction record's length field into its checksum and
+/// the length.  Version 1 files have no checksums, and version 4
+/// files have a field for them, so both use the whole field for the
+/// length, and the checksum returned is 0.
+pub fn split_length(raw: u64, version: u32) -> (u32, u64) {
+    if checksums_share_fields(version) { ((raw >> 32) as u32, raw & u32::MAX as u64) }
+    else { (0, raw) }
+}
+
+/// The most bytes a transaction record can have in files of a format
+/// version
+pub fn max_transaction_length(version: u32) -> u64 {
+    if checksums_share_fields(version) { u32::MAX as u64 } else { u64::MAX }
+}
+
+/// A transaction's checksum is a CRC-32 of its tid, record count and
+/// metadata, followed by its data records' oids and checksums.  With
+/// data checksums, it covers everything in the transaction but
+/// previous pointers and offsets, which change when files are packed.
+pub struct TransactionChecksum(u32);
+
+impl TransactionChecksum {
+
+    /// Start with the transaction's header, after the length, and
+    /// metadata.
+    pub fn new(header: &[u8]) -> TransactionChecksum {
+        TransactionChecksum(util::crc32(0, header))
+    }
+
+    /// Start with a transaction record's header, from its marker, and
+    /// metadata, in a file of a format version, leaving out the
+    /// length and checksum.
+    pub fn of_record(record: &[u8], version: u32) -> TransactionChecksum {
+        let lengths = &record[TRANSACTION_CHECKSUMMED_OFFSET as usize ..
+                              TRANSACTION_CHECKSUMMED_END as usize];
+        let metadata = &record[4 + transaction_header_length(version) as usize ..];
+        TransactionChecksum(util::crc32(util::crc32(0, lengths), metadata))
+    }
+
+    pub fn add(&mut self, oid: &util::Oid, checksum: u32) {
+        self.0 = util::crc32(util::crc32(self.0, oid), &checksum.to_be_bytes());
+    }
+
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+}
 
 impl TransactionHeader {
 
     fn new(tid: util::Tid) -> TransactionHeader {
         TransactionHeader {
-            length: 0, id: tid, luser: 0, ldesc: 0, lext: 0, ndata: 0 }
+            length: 0, checksum: 0, id: tid, luser: 0, ldesc: 0, lext: 0, ndata: 0 }
     }
 
-    pub fn read(mut reader: &mut dyn std::io::Read)
+    pub fn read(mut reader: &mut dyn std::io::Read, version: u32)
                 -> std::io::Result<TransactionHeader> {
-        let length = reader.read_u64::<BigEndian>()?;
+        let (checksum, length) = split_length(reader.read_u64::<BigEndian>()?, version);
         let mut h = TransactionHeader::new(util::read8(&mut reader)?);
         h.length = length;
+        h.checksum = checksum;
         h.ndata = reader.read_u32::<BigEndian>()?;
         h.luser = reader.read_u16::<BigEndian>()?;
         h.ldesc = reader.read_u16::<BigEndian>()?;
         h.lext = reader.read_u32::<BigEndian>()?;
+        if wide(version) {
+            h.checksum = reader.read_u32::<BigEndian>()?;
+        }
         Ok(h)
     }
 
-    pub fn update_index<T>(&self, mut reader: &mut T, index: &mut index::Index,
-                           mut last_oid: util::Oid)
+    /// Write the header, after the record marker, in a format
+    /// version.  Version 1 headers have no checksum.
+    pub fn write(&self, writer: &mut dyn std::io::Write, version: u32)
+                 -> std::io::Result<()> {
+        util::io_assert(self.length <= max_transaction_length(version),
+                        "Transaction is too large for the file format")?;
+        if checksums_share_fields(version) {
+            writer.write_u64::<BigEndian>((self.checksum as u64) << 32 | self.length)?;
+        }
+        else {
+            writer.write_u64::<BigEndian>(self.length)?;
+        }
+        writer.write_all(&self.id)?;
+        writer.write_u32::<BigEndian>(self.ndata)?;
+        writer.write_u16::<BigEndian>(self.luser)?;
+        writer.write_u16::<BigEndian>(self.ldesc)?;
+        writer.write_u32::<BigEndian>(self.lext)?;
+        if wide(version) {
+            writer.write_u32::<BigEndian>(self.checksum)?;
+        }
+        Ok(())
+    }
+
+    pub fn update_index<T>(&self, mut reader: &mut T, version: u32,
+                           index: &mut index::Index, mut last_oid: util::Oid)
                            -> std::io::Result<util::Oid>
         where T: std::io::Read + std::io::Seek {
         let mut pos =
@@ -98,13 +268,13 @@ impl TransactionHeader {
                     self.luser as i64 + self.ldesc as i64 + self.lext as i64))?;
 
         for i in 0 .. self.ndata {
-            let ldata = reader.read_u32::<BigEndian>()?;
+            let (_, ldata) = read_data_length(reader, version)?;
             let oid = util::read8(&mut reader)?;
             index.insert(oid, pos);
             if oid > last_oid {
                 last_oid = oid;
             }
-            pos += DATA_HEADER_SIZE + ldata as u64;
+            pos += data_header_size(version) + ldata;
             if i + 1 < self.ndata {
                 util::seek(&mut reader, pos)?;
             }
@@ -117,35 +287,223 @@ impl TransactionHeader {
 
 #[derive(PartialEq, Debug)]
 pub struct DataHeader {
-    pub length: u32,
+    pub length: u64,
     pub id: util::Oid,
     pub tid: util::Tid,
     pub previous: u64,
+    // CRC-32 of the data, or 0 if it wasn't computed, as in version
+    // 1 files, where this is the high half of the 64-bit offset.
+    pub checksum: u32,
     pub offset: u64,
+    // Whether the data are compressed, in which case length and the
+    // checksum are of the compressed data
+    pub compressed: bool,
+}
+
+/// The size of a data record's header in files of a format version.
+/// Version 4 headers are bigger, as their length and offset fields
+/// are 64-bit.
+pub fn data_header_size(version: u32) -> u64 {
+    if wide(version) { 44 } else { 36 }
+}
+
+// Where a data-header field is, given where it is before version 4,
+// whose length field is 4 bytes bigger
+fn after_length(offset: u64, version: u32) -> u64 {
+    if wide(version) { offset + 4 } else { offset }
+}
+
+pub fn data_tid_offset(version: u32) -> u64 {
+    after_length(12, version)
+}
+
+pub fn data_previous_offset(version: u32) -> u64 {
+    after_length(20, version)
+}
+
+pub fn data_checksum_offset(version: u32) -> u64 {
+    after_length(28, version)
+}
+
+// The high bit of a data record's length field is set if its data
+// are compressed, in files that can have compressed data.
+fn compressed_flag(version: u32) -> u64 {
+    if wide(version) { 1 << 63 } else { 1 << 31 }
+}
+
+/// Split a data record's length field into whether its data are
+/// compressed, and their length.  Files before version 3 have no
+/// compressed data, and use the whole field for the length.
+pub fn split_data_length(raw: u64, version: u32) -> (bool, u64) {
+    if compression(version) {
+        let flag = compressed_flag(version);
+        (raw & flag != 0, raw & ! flag)
+    }
+    else { (false, raw) }
+}
+
+/// Read a data record's length field, which is 64-bit in version 4
+/// files, and 32-bit in earlier ones, and split it.
+pub fn read_data_length(reader: &mut dyn std::io::Read, version: u32)
+                        -> std::io::Result<(bool, u64)> {
+    let raw = if wide(version) { reader.read_u64::<BigEndian>()? }
+              else { reader.read_u32::<BigEndian>()? as u64 };
+    Ok(split_data_length(raw, version))
+}
+
+/// The most bytes of data a data record can have in files of a
+/// format version
+pub fn max_data_length(version: u32) -> u64 {
+    if compression(version) { compressed_flag(version) - 1 } else { u32::MAX as u64 }
+}
+
+// Compression level: zstd's default, which is fast, and does about
+// as well as gzip's best
+pub const COMPRESSION_LEVEL: i32 = 3;
+
+// Smaller data are rarely made smaller enough to be worth it, unless
+// there's a dictionary.
+const MIN_COMPRESSED_SIZE: usize = 64;
+const MIN_DICTIONARY_COMPRESSED_SIZE: usize = 16;
+
+/// Compress data, with the newest of a data file's dictionaries, if
+/// it has any, if that makes them smaller.
+pub fn compress(data: &[u8], dictionaries: &dictionaries::Dictionaries)
+                -> std::io::Result<Option<Vec<u8>>> {
+    let compressed = match dictionaries.encoder() {
+        Some(dictionary) if data.len() >= MIN_DICTIONARY_COMPRESSED_SIZE =>
+            zstd::bulk::Compressor::with_prepared_dictionary(dictionary)?.compress(data)?,
+        None if data.len() >= MIN_COMPRESSED_SIZE =>
+            zstd::bulk::compress(data, COMPRESSION_LEVEL)?,
+        _ => return Ok(None),
+    };
+    Ok(if compressed.len() < data.len() { Some(compressed) } else { None })
+}
+
+/// Decompress data, with the dictionary they were compressed with,
+/// if any.
+pub fn decompress(data: &[u8], dictionaries: &dictionaries::Dictionaries)
+                  -> std::io::Result<Vec<u8>> {
+    match zstd::zstd_safe::get_dict_id_from_frame(data) {
+        Some(id) => {
+            let mut decoder = zstd::stream::Decoder::with_prepared_dictionary(
+                data, dictionaries.decoder(id.get())?)?;
+            let mut decompressed = vec![];
+            decoder.read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        },
+        None => zstd::stream::decode_all(data),
+    }
+}
+
+/// The most bytes of a zstd frame's header, which is all
+/// decompressed_size needs
+pub const FRAME_HEADER_SIZE: usize = 18;
+
+/// The size of compressed data, once decompressed, from the start
+/// of their zstd frame, which records it.
+pub fn decompressed_size(frame: &[u8]) -> std::io::Result<u64> {
+    match zstd::zstd_safe::get_frame_content_size(frame) {
+        Ok(Some(size)) => Ok(size),
+        _ => Err(util::io_error("Compressed data don't record their size")),
+    }
+}
+
+/// A record's data, as read from the file, decompressed if they're
+/// compressed.
+pub fn decode(compressed: bool, data: Vec<u8>, dictionaries: &dictionaries::Dictionaries)
+              -> std::io::Result<Vec<u8>> {
+    if compressed { decompress(&data, dictionaries) } else { Ok(data) }
+}
+
+/// Whether data match a record's checksum, if it has one
+pub fn checksum_ok(checksum: u32, data: &[u8]) -> bool {
+    checksum == 0 || util::crc32(0, data) == checksum
+}
+
+/// The checksum of the next length bytes read, which are read a
+/// chunk at a time, as records can be big.
+pub fn read_checksum(reader: &mut dyn std::io::Read, length: u64) -> std::io::Result<u32> {
+    let mut buf = vec![0u8; std::cmp::min(length, 1 << 16) as usize];
+    let mut crc = 0;
+    let mut left = length as usize;
+    while left > 0 {
+        let size = std::cmp::min(left, buf.len());
+        reader.read_exact(&mut buf[.. size])?;
+        crc = util::crc32(crc, &buf[.. size]);
+        left -= size;
+    }
+    Ok(crc)
 }
-pub const DATA_HEADER_SIZE: u64 = 36;
-pub const DATA_TID_OFFSET: u64 = 12;
-pub const DATA_PREVIOUS_OFFSET: u64 = 20;
 
 impl DataHeader {
 
     fn new(tid: util::Tid) -> TransactionHeader {
         TransactionHeader {
-            length: 0, id: tid, luser: 0, ldesc: 0, lext: 0, ndata: 0 }
+            length: 0, checksum: 0, id: tid, luser: 0, ldesc: 0, lext: 0, ndata: 0 }
     }
 
-    pub fn read(reader: &mut dyn std::io::Read) -> std::io::Result<DataHeader> {
+    pub fn read(reader: &mut dyn std::io::Read, version: u32)
+                -> std::io::Result<DataHeader> {
         // assume reader is unbuffered
-        let mut buf = [0u8; DATA_HEADER_SIZE as usize];
-        reader.read_exact(&mut buf)?;
+        let mut buf = [0u8; 44];
+        let buf = &mut buf[.. data_header_size(version) as usize];
+        reader.read_exact(buf)?;
+        let (compressed, length) = read_data_length(&mut &buf[..], version)?;
+        // The oid, tid, previous pointer, checksum and offset
+        let fields = &buf[after_length(4, version) as usize ..];
+        let (checksum, offset) =
+            if wide(version) {
+                (BigEndian::read_u32(&fields[24..]), BigEndian::read_u64(&fields[28..]))
+            }
+            else if checksums(version) {
+                (BigEndian::read_u32(&fields[24..]), BigEndian::read_u32(&fields[28..]) as u64)
+            }
+            else {
+                (0, BigEndian::read_u64(&fields[24..]))
+            };
         Ok(DataHeader {
-            length: BigEndian::read_u32(&buf[0..4]),
-            id: util::read8(&mut &buf[4..])?,
-            tid: util::read8(&mut &buf[12..])?,
-            previous: BigEndian::read_u64(&buf[20..]),
-            offset: BigEndian::read_u64(&buf[28..]),
+            length, compressed, checksum, offset,
+            id: util::read8(&mut &fields[0..])?,
+            tid: util::read8(&mut &fields[8..])?,
+            previous: BigEndian::read_u64(&fields[16..]),
         })
     }
+
+    /// Write the header in a format version.  Version 1 headers have
+    /// no checksum.
+    pub fn write(&self, writer: &mut dyn std::io::Write, version: u32)
+                 -> std::io::Result<()> {
+        util::io_assert(self.length <= max_data_length(version),
+                        "Object data are too large for the file format")?;
+        util::io_assert(! self.compressed || compression(version),
+                        "The file format can't have compressed data")?;
+        let length =
+            if self.compressed { self.length | compressed_flag(version) } else { self.length };
+        if wide(version) {
+            writer.write_u64::<BigEndian>(length)?;
+        }
+        else {
+            writer.write_u32::<BigEndian>(length as u32)?;
+        }
+        writer.write_all(&self.id)?;
+        writer.write_all(&self.tid)?;
+        writer.write_u64::<BigEndian>(self.previous)?;
+        if wide(version) {
+            writer.write_u32::<BigEndian>(self.checksum)?;
+            writer.write_u64::<BigEndian>(self.offset)?;
+        }
+        else if checksums(version) {
+            util::io_assert(self.offset <= u32::MAX as u64,
+                            "Data record offset is too large for the file format")?;
+            writer.write_u32::<BigEndian>(self.checksum)?;
+            writer.write_u32::<BigEndian>(self.offset as u32)?;
+        }
+        else {
+            writer.write_u64::<BigEndian>(self.offset)?;
+        }
+        Ok(())
+    }
 }
 
 
@@ -159,12 +517,16 @@ mod tests {
     pub use super::*;
 
     fn file_header_sample(previous: &[u8]) -> Vec<u8> {
+        file_header_sample_with(HEADER_MARKER, previous)
+    }
+
+    fn file_header_sample_with(marker: &[u8], previous: &[u8]) -> Vec<u8> {
         let mut sample = vec![0u8; 0];
-        sample.extend_from_slice(&HEADER_MARKER);
+        sample.extend_from_slice(marker);
         sample.extend_from_slice(&[0, 0, 0, 0, 0, 0, 16, 0]); // 4096
         sample.extend_from_slice(&[0, 0, 0, 0, 64, 0, 0, 0]); // 1<<30
         sample.extend_from_slice(&vec![0u8, previous.len() as u8][..]);
-        sample.extend_from_slice(&previous);
+        sample.extend_from_slice(previous);
         sample.extend_from_slice(&vec![0; 4066 - previous.len()]);
         sample.extend_from_slice(&[0, 0, 0, 0, 0, 0, 16, 0]); // 4096
         sample
@@ -181,6 +543,31 @@ mod tests {
         let h = FileHeader::read(&mut reader).unwrap();
         assert_eq!(h.previous, "previous");
         assert_eq!(h.alignment, 1<<30);
+        assert!(h.checksums());
+
+        // Version 1 files are read, and written back in version 1:
+        let mut reader = std::io::Cursor::new(file_header_sample_with(V1_HEADER_MARKER, b""));
+        let h = FileHeader::read(&mut reader).unwrap();
+        assert_eq!(h.version, 1);
+        assert!(! h.checksums());
+        let mut writer = std::io::Cursor::new(vec![0u8; 0]);
+        h.write(&mut writer).unwrap();
+        assert_eq!(writer.into_inner(), file_header_sample_with(V1_HEADER_MARKER, b""));
+        assert_eq!(h.upgraded().version, FORMAT_VERSION);
+
+        // As are version 2 and 3 files:
+        for (marker, version) in [(V2_HEADER_MARKER, 2), (V3_HEADER_MARKER, 3)] {
+            let mut reader = std::io::Cursor::new(file_header_sample_with(marker, b""));
+            let h = FileHeader::read(&mut reader).unwrap();
+            assert_eq!(h.version, version);
+            assert!(h.checksums());
+            let mut writer = std::io::Cursor::new(vec![0u8; 0]);
+            h.write(&mut writer).unwrap();
+            assert_eq!(writer.into_inner(), file_header_sample_with(marker, b""));
+        }
+
+        let mut reader = std::io::Cursor::new(file_header_sample_with(b"fs9 ", b""));
+        assert!(FileHeader::read(&mut reader).is_err());
     }
 
     #[test]
@@ -188,6 +575,7 @@ mod tests {
         
         let mut writer = std::io::Cursor::new(vec![0u8; 0]);
         let h = FileHeader {
+            version: FORMAT_VERSION,
             previous: String::new(),
             alignment: 1<<30,
         };
@@ -196,6 +584,7 @@ mod tests {
         
         let mut writer = std::io::Cursor::new(vec![0u8; 0]);
         let h = FileHeader {
+            version: FORMAT_VERSION,
             previous: String::from("previous"),
             alignment: 1<<30,
         };
@@ -211,7 +600,7 @@ mod tests {
         let mut cursor = std::io::Cursor::new(Vec::new());
 
         // Write out some sample data:
-        util::write_u64(&mut cursor, 9999).unwrap();
+        util::write_u64(&mut cursor, (7 << 32) + 9999).unwrap(); // checksum, length
         cursor.write_all(&util::p64(1234567890)).unwrap();
         util::write_u32(&mut cursor, 2).unwrap();
         util::write_u16(&mut cursor, 11).unwrap();
@@ -219,13 +608,181 @@ mod tests {
         util::write_u32(&mut cursor, 33).unwrap();
         util::seek(&mut cursor, 0).unwrap();
 
-        let h = TransactionHeader::read(&mut cursor).unwrap();
+        // In versions 2 and 3, the checksum is the high half of the
+        // length field:
+        let h = TransactionHeader::read(&mut cursor, 3).unwrap();
         assert_eq!(
             h,
             TransactionHeader {
-                length: 9999, id: util::p64(1234567890), ndata: 2,
+                length: 9999, checksum: 7, id: util::p64(1234567890), ndata: 2,
                 luser: 11, ldesc: 22, lext: 33,
             });
+        assert_eq!(max_transaction_length(3), u32::MAX as u64);
+
+        // In version 4, the length is 64-bit, and the checksum follows
+        // the header:
+        util::write_u32(&mut cursor, 8).unwrap();
+        util::seek(&mut cursor, 0).unwrap();
+        let h = TransactionHeader::read(&mut cursor, FORMAT_VERSION).unwrap();
+        assert_eq!((h.length, h.checksum), ((7 << 32) + 9999, 8));
+        assert_eq!(max_transaction_length(FORMAT_VERSION), u64::MAX);
+
+        // Version 1 lengths are 64-bit, without checksums:
+        util::seek(&mut cursor, 0).unwrap();
+        let h = TransactionHeader::read(&mut cursor, 1).unwrap();
+        assert_eq!((h.length, h.checksum), ((7 << 32) + 9999, 0));
+        assert_eq!(max_transaction_length(1), u64::MAX);
+    }
+
+    #[test]
+    fn write_transaction_header() {
+        for version in 1 ..= FORMAT_VERSION {
+            let h = TransactionHeader {
+                length: 9999, checksum: if checksums(version) { 7 } else { 0 },
+                id: util::p64(1234567890), ndata: 2, luser: 11, ldesc: 22, lext: 33,
+            };
+            let mut buf = vec![];
+            h.write(&mut buf, version).unwrap();
+            assert_eq!(buf.len() as u64, transaction_header_length(version));
+            assert_eq!(TransactionHeader::read(&mut &buf[..], version).unwrap(), h);
+        }
+        // Lengths aren't truncated to make room for checksums:
+        let h = TransactionHeader {
+            length: 1 << 32, checksum: 7, id: util::p64(1), ndata: 1, luser: 0, ldesc: 0,
+            lext: 0,
+        };
+        assert!(h.write(&mut vec![], 3).is_err());
+        h.write(&mut vec![], FORMAT_VERSION).unwrap();
+    }
+
+    #[test]
+    fn transaction_checksums_leave_out_lengths_and_checksums() {
+        let h = TransactionHeader {
+            length: 99, checksum: 7, id: util::p64(1), ndata: 2, luser: 4, ldesc: 0, lext: 0,
+        };
+        let expected = TransactionChecksum::new(
+            &[&util::p64(1)[..], &[0, 0, 0, 2, 0, 4, 0, 0, 0, 0, 0, 0], b"user"].concat());
+        for version in 2 ..= FORMAT_VERSION {
+            let mut record = b"TTTT".to_vec();
+            h.write(&mut record, version).unwrap();
+            record.extend_from_slice(b"user");
+            assert_eq!(TransactionChecksum::of_record(&record, version).value(),
+                       expected.value());
+        }
+    }
+
+    #[test]
+    fn read_data_header() {
+        let mut buf = vec![0u8; 0];
+        util::write_u64(&mut buf, 3).unwrap();
+        buf.extend_from_slice(&util::p64(1));
+        buf.extend_from_slice(&util::p64(2));
+        util::write_u64(&mut buf, 99).unwrap();
+        util::write_u32(&mut buf, util::crc32(0, b"abc")).unwrap();
+        util::write_u64(&mut buf, 1 << 32).unwrap();
+        let h = DataHeader::read(&mut &buf[..], FORMAT_VERSION).unwrap();
+        assert_eq!(
+            h,
+            DataHeader {
+                length: 3, id: util::p64(1), tid: util::p64(2), previous: 99,
+                checksum: 0x352441c2, offset: 1 << 32, compressed: false,
+            });
+
+        // Version 4 lengths are 64-bit, with the high bit flagging
+        // compressed data:
+        buf[0] = 0x80;
+        buf[3] = 1;
+        let h = DataHeader::read(&mut &buf[..], FORMAT_VERSION).unwrap();
+        assert_eq!((h.length, h.compressed), ((1 << 32) + 3, true));
+        assert_eq!(max_data_length(FORMAT_VERSION), (1 << 63) - 1);
+
+        // Earlier versions have 32-bit lengths:
+        let mut buf = vec![0u8; 0];
+        util::write_u32(&mut buf, 3).unwrap();
+        buf.extend_from_slice(&util::p64(1));
+        buf.extend_from_slice(&util::p64(2));
+        util::write_u64(&mut buf, 99).unwrap();
+        util::write_u32(&mut buf, util::crc32(0, b"abc")).unwrap();
+        util::write_u32(&mut buf, 42).unwrap();
+        let h = DataHeader::read(&mut &buf[..], 3).unwrap();
+        assert_eq!(
+            h,
+            DataHeader {
+                length: 3, id: util::p64(1), tid: util::p64(2), previous: 99,
+                checksum: 0x352441c2, offset: 42, compressed: false,
+            });
+        assert!(checksum_ok(h.checksum, b"abc"));
+        assert!(! checksum_ok(h.checksum, b"abd"));
+        assert!(checksum_ok(0, b"abd"));
+
+        // Version 1 offsets are 64-bit, without checksums:
+        let h = DataHeader::read(&mut &buf[..], 1).unwrap();
+        assert_eq!((h.checksum, h.offset), (0, (0x352441c2 << 32) + 42));
+
+        // The high bit of the length flags compressed data in
+        // version 3:
+        buf[0] = 0x80;
+        let h = DataHeader::read(&mut &buf[..], 3).unwrap();
+        assert_eq!((h.length, h.compressed), (3, true));
+        assert_eq!(max_data_length(3), (1 << 31) - 1);
+        // but is part of the length in files before version 3:
+        let h = DataHeader::read(&mut &buf[..], 2).unwrap();
+        assert_eq!((h.length, h.compressed), ((1 << 31) + 3, false));
+        assert_eq!(max_data_length(2), u32::MAX as u64);
+    }
+
+    #[test]
+    fn write_data_header() {
+        for version in 1 ..= FORMAT_VERSION {
+            let h = DataHeader {
+                length: 3, id: util::p64(1), tid: util::p64(2), previous: 99,
+                checksum: if checksums(version) { 7 } else { 0 }, offset: 42,
+                compressed: super::compression(version),
+            };
+            let mut buf = vec![];
+            h.write(&mut buf, version).unwrap();
+            assert_eq!(buf.len() as u64, data_header_size(version));
+            assert_eq!(DataHeader::read(&mut &buf[..], version).unwrap(), h);
+            assert_eq!(&buf[data_tid_offset(version) as usize ..][.. 8], &util::p64(2));
+            assert_eq!(&buf[data_previous_offset(version) as usize ..][.. 8], &util::p64(99));
+            if checksums(version) {
+                assert_eq!(&buf[data_checksum_offset(version) as usize ..][.. 4], &[0, 0, 0, 7]);
+            }
+        }
+
+        // Lengths aren't truncated, and versions that can't have
+        // compressed data don't get them:
+        let h = DataHeader {
+            length: 1 << 31, id: util::p64(1), tid: util::p64(2), previous: 0,
+            checksum: 0, offset: 42, compressed: true,
+        };
+        assert!(h.write(&mut vec![], 3).is_err());
+        assert!(DataHeader { length: 3, ..h }.write(&mut vec![], 2).is_err());
+        let mut buf = vec![];
+        h.write(&mut buf, FORMAT_VERSION).unwrap();
+        assert_eq!(DataHeader::read(&mut &buf[..], FORMAT_VERSION).unwrap(), h);
+    }
+
+    #[test]
+    fn compression() {
+        let none = dictionaries::Dictionaries::default();
+        let data = b"Hello, world. ".repeat(100);
+        let compressed = compress(&data, &none).unwrap().unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompressed_size(&compressed[.. FRAME_HEADER_SIZE]).unwrap(),
+                   data.len() as u64);
+        assert_eq!(decode(true, compressed, &none).unwrap(), data);
+        assert_eq!(decode(false, data.clone(), &none).unwrap(), data);
+        // Data that don't get smaller are left alone:
+        assert_eq!(compress(b"x", &none).unwrap(), None);
+        assert!(decompress(b"not compressed", &none).is_err());
+    }
+
+    #[test]
+    fn crc32() {
+        assert_eq!(util::crc32(0, b"123456789"), 0xcbf43926);
+        assert_eq!(util::crc32(util::crc32(0, b"1234"), b"56789"), 0xcbf43926);
+        assert_eq!(util::crc32(0, b""), 0);
     }
     
 }
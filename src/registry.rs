@@ -0,0 +1,764 @@
+// Named storages served by one process
+//
+// Each storage has its own file pools, locks and limits, so load on
+// one storage doesn't use up another's resources.
+use std::io::prelude::*;
+
+use anyhow::{Context, Result};
+
+use crate::auth;
+use crate::errors;
+use crate::msg;
+use crate::msgmacros::*;
+use crate::reader;
+use crate::storage;
+use crate::writer;
+
+pub type Storage = std::sync::Arc<storage::FileStorage<writer::Client>>;
+
+#[derive(Default)]
+pub struct Registry {
+    storages: std::collections::BTreeMap<String, Storage>,
+    authenticator: Option<Box<dyn auth::Authenticator>>,
+    permissions: Option<auth::Permissions>,
+    read_only: bool,
+    methods: std::sync::Arc<msg::Methods>,
+}
+
+impl Registry {
+
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Open the data file at path and serve it as name.
+    pub fn open(&mut self, name: &str, path: &str, limits: storage::Limits,
+                pool_sizes: storage::PoolSizes)
+                -> Result<Storage> {
+        if self.storages.contains_key(name) {
+            return Err(anyhow::anyhow!("Duplicate storage name {}", name));
+        }
+        let fs = std::sync::Arc::new(
+            storage::FileStorage::options()
+                .path(path).pool_sizes(pool_sizes).open()
+                .with_context(|| format!("opening {}", path))?
+                .with_limits(limits));
+        storage::start_deferred_syncer(&fs);
+        storage::start_watchdog(&fs);
+        self.storages.insert(String::from(name), fs.clone());
+        Ok(fs)
+    }
+
+    /// Let clients call an extension method, handled by handler.
+    ///
+    /// Extension methods are listed by getExtensionMethods.
+    pub fn add_extension(&mut self, name: &str, handler: msg::Handler) {
+        std::sync::Arc::make_mut(&mut self.methods).add_extension(name, handler);
+    }
+
+    /// Require clients to authenticate before registering.
+    pub fn set_authenticator(&mut self, authenticator: Box<dyn auth::Authenticator>) {
+        self.authenticator = Some(authenticator);
+    }
+
+    /// Limit the storages users may use, and which they may write
+    /// to.  Users are identified by their client certificates or by
+    /// authenticating.
+    pub fn set_permissions(&mut self, permissions: auth::Permissions) {
+        self.permissions = Some(permissions);
+    }
+
+    /// Make all connections read-only, whether or not clients
+    /// register as read-only.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn get(&self, name: &str) -> Option<Storage> {
+        self.storages.get(name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.storages.keys().cloned().collect()
+    }
+}
+
+/// Send clients of all of the registry's storages heartbeats every
+/// interval, so that idle connections aren't dropped by firewalls and
+/// NAT, until the registry is dropped.
+pub fn start_heartbeats(registry: &std::sync::Arc<Registry>, interval: std::time::Duration) {
+    every(registry, interval, | fs | fs.heartbeat());
+}
+
+/// Send clients of all of the registry's storages info messages,
+/// with their storages' object counts, sizes and last transactions,
+/// every interval, so client-side monitoring stays fresh on
+/// connections that don't commit, until the registry is dropped.
+pub fn start_info(registry: &std::sync::Arc<Registry>, interval: std::time::Duration) {
+    every(registry, interval, | fs | fs.push_info());
+}
+
+// Call f with each of the registry's storages every interval, in a
+// thread of its own, until the registry is dropped.
+fn every(registry: &std::sync::Arc<Registry>, interval: std::time::Duration,
+         f: fn(&storage::FileStorage<writer::Client>)) {
+    let registry = std::sync::Arc::downgrade(registry);
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(interval);
+            match registry.upgrade() {
+                Some(registry) => for fs in registry.storages.values() {
+                    f(fs);
+                },
+                None => break,
+            }
+        }
+    });
+}
+
+/// Limit on simultaneous connections, shared by listeners
+pub struct Admission {
+    max: usize,
+    count: std::sync::Mutex<usize>,
+    freed: std::sync::Condvar,
+}
+
+/// A connection's place under an admission limit, given up when dropped
+pub struct Slot {
+    admission: std::sync::Arc<Admission>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.admission.count.lock().unwrap() -= 1;
+        self.admission.freed.notify_one();
+    }
+}
+
+impl Admission {
+
+    pub fn new(max: usize) -> std::sync::Arc<Admission> {
+        std::sync::Arc::new(Admission {
+            max, count: std::sync::Mutex::new(0), freed: std::sync::Condvar::new(),
+        })
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Get a slot, if the limit hasn't been reached.
+    pub fn try_admit(self: &std::sync::Arc<Self>) -> Option<Slot> {
+        let mut count = self.count.lock().unwrap();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(Slot { admission: self.clone() })
+    }
+
+    /// Get a slot, waiting for one if the limit has been reached.
+    pub fn admit(self: &std::sync::Arc<Self>) -> Slot {
+        let mut count = self.count.lock().unwrap();
+        while *count >= self.max {
+            count = self.freed.wait(count).unwrap();
+        }
+        *count += 1;
+        Slot { admission: self.clone() }
+    }
+}
+
+/// Turn a client away, with a StorageError response to its first
+/// request.
+pub fn reject<R, W>(reason: &str, reader: R, mut writer: W) -> Result<()>
+where R: Read, W: Write {
+    writer.write_all(&msg::size_vec(msg::PROTOCOL.to_vec()))
+        .context("writing handshake")?;
+    let mut it = msg::ZeoIter::new(reader);
+    reader::handshake(&mut it)?;
+    let id = match it.next()? {
+        msg::Zeo::Register(id, _, _) | msg::Zeo::AuthChallenge(id) |
+        msg::Zeo::Authenticate(id, _, _) => id,
+        _ => return Ok(()),
+    };
+    writer.write_all(&it.codec().frame(
+        errors::POSError::Storage(reason.to_string())
+            .response(writer::new_connection(), id)?)?)
+        .context("send error response")
+}
+
+// Whether an error is from a read timing out, for connections with
+// read timeouts.
+fn timed_out(err: &anyhow::Error) -> bool {
+    err.chain().any(
+        | cause | match cause.downcast_ref::<std::io::Error>() {
+            Some(err) => matches!(err.kind(), std::io::ErrorKind::WouldBlock |
+                                  std::io::ErrorKind::TimedOut),
+            None => false,
+        })
+}
+
+/// Describe a panic, from its payload.
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "panicked".to_string(),
+        },
+    }
+}
+
+/// A client connection that has registered to use a storage.
+pub struct Registered<R: Read> {
+    pub fs: Storage,
+    pub client: writer::Client,
+    /// Requests following the register call
+    pub requests: msg::ZeoIter<R>,
+    /// For sending messages to the client's writer
+    pub send: std::sync::mpsc::Sender<msg::Zeo>,
+    pub receive: std::sync::mpsc::Receiver<msg::Zeo>,
+}
+
+/// Do the handshake, authentication and register call for a client
+/// connection.
+///
+/// Clients identified by their TLS certificates, with identity,
+/// don't authenticate.  The client is added to the storage it
+/// registers for.  If wake is given, it's called when messages are
+/// sent to the client's writer.  None is returned if the client
+/// disconnected or failed to register.
+pub fn register<R, W>(registry: &Registry, name: String, read_only: bool,
+                      identity: Option<&str>, reader: R, writer: &mut W,
+                      wake: Option<std::sync::Arc<dyn Fn() + Send + Sync>>)
+                      -> Result<Option<Registered<R>>>
+where R: Read, W: Write {
+
+    writer.write_all(&msg::size_vec(msg::PROTOCOL.to_vec()))
+        .context("writing handshake")?;
+
+    let (send, receive) = std::sync::mpsc::channel();
+    let mut client = writer::Client::new(name.clone(), send.clone());
+    if let Some(wake) = wake {
+        client = client.with_wake(wake);
+    }
+    let connection = client.connection();
+    match identity {
+        Some(identity) => tracing::info!("[{}] {}: connected as {}", connection, name, identity),
+        None => tracing::info!("[{}] {}: connected", connection, name),
+    }
+
+    let mut it = msg::ZeoIter::new(reader);
+    it.set_methods(registry.methods.clone());
+    let registered = match (identity, &registry.authenticator) {
+        (None, Some(authenticator)) => reader::register_authenticated(
+            &mut it, authenticator.as_ref(),
+            &mut | data | writer.write_all(&data).context("send response"),
+            connection)?
+            .map(| (user, registered) | (Some(user), registered)),
+        _ => reader::register(&mut it)?
+            .map(| registered | (identity.map(str::to_string), registered)),
+    };
+    let (user, (id, storage_name, registered_read_only)) = match registered {
+        Some(registered) => registered,
+        None => return Ok(None),
+    };
+    // Responses from here on are encoded as the client's protocol says.
+    let writer = &mut msg::Encoder::new(writer, it.codec());
+    let fs = match registry.get(&storage_name) {
+        Some(fs) => fs,
+        None => {
+            let cid = writer::correlation_id(connection, id);
+            tracing::warn!("[{}] Invalid storage {}", cid, storage_name);
+            writer.write_all(&error_response!(
+                id, ("builtins.ValueError", ("Invalid storage", cid))))
+                .context("send error response")?;
+            return Ok(None);
+        },
+    };
+    let permitted_read_only = match registry.permissions {
+        Some(ref permissions) => {
+            match permissions.access(user.as_deref(), &storage_name) {
+                Some(access) => access < auth::Access::Write,
+                None => {
+                    let message = format!("{} may not use storage {}",
+                                          user.as_deref().unwrap_or("Anonymous user"),
+                                          storage_name);
+                    writer.write_all(&errors::POSError::Auth(message).response(connection, id)?)
+                        .context("send error response")?;
+                    return Ok(None);
+                },
+            }
+        },
+        None => false,
+    };
+
+    let requests = std::sync::Arc::new(
+        crate::latency::Requests::new(connection, fs.latencies().clone()));
+    it.set_requests(requests.clone());
+    let client = client
+        .with_codec(it.codec())
+        .with_received(it.received())
+        .with_requests(requests)
+        .with_read_only(read_only || registered_read_only || permitted_read_only ||
+                        registry.read_only);
+    if let Err(err) = fs.try_add_client(client.clone()) {
+        writer::report(writer, connection, id, err)?;
+        return Ok(None);
+    }
+    writer.write_all(&response!(id, msg::bytes(&fs.last_transaction())))
+        .context("send response")?;
+    Ok(Some(Registered { fs, client, requests: it, send, receive }))
+}
+
+/// Serve a client connection.
+///
+/// To disconnect idle clients, give the reader a read timeout.
+/// Heartbeats from the client count as activity.
+///
+/// The client's register call selects the storage.  A writer thread
+/// is started for the connection and requests are read in the
+/// calling thread until the client disconnects.  If read_only is
+/// true, the connection is read-only, however the client registers.
+///
+/// This uses two threads per connection.  To serve many connections
+/// with a fixed number of threads, use a reactor::Reactor.
+pub fn connect<R, W>(registry: &Registry, name: String, read_only: bool,
+                     reader: R, mut writer: W)
+                     -> Result<()>
+where R: Read, W: Write + Send + 'static {
+
+    let Registered { fs, client, requests, send, receive } =
+        match register(registry, name, read_only, None, reader, &mut writer, None)? {
+            Some(registered) => registered,
+            None => return Ok(()),
+        };
+    let connection = client.connection();
+
+    let write_fs = fs.clone();
+    let write_client = client.clone();
+    let _connection = client.span();
+    let writer_thread = std::thread::spawn(
+        move || writer::run(write_fs, writer, receive, write_client));
+
+    // However serving ends, even with a panic, the client is removed
+    // and its writer told to finish, which aborts its transactions.
+    let read_send = send.clone();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(
+        || reader::serve(fs.clone(), requests, read_send, connection,
+                         client.read_only())));
+    let (result, reason) = match result {
+        Ok(Ok(_)) => (Ok(()), storage::DisconnectReason::Closed),
+        Ok(Err(err)) => {
+            let reason = if timed_out(&err) { storage::DisconnectReason::Idle }
+            else { storage::DisconnectReason::ProtocolError(format!("{:#}", err)) };
+            (Err(err), reason)
+        },
+        Err(panic) => {
+            let message = panic_message(panic.as_ref());
+            (Err(anyhow::anyhow!("reader panicked: {}", message)),
+             storage::DisconnectReason::Failed(message))
+        },
+    };
+    fs.remove_client(client, reason);
+    if result.is_err() {
+        // Let the writer finish up
+        let _ = send.send(msg::Zeo::End);
+    }
+    drop(send);
+    let written = writer_thread.join()
+        .map_err(| _ | anyhow::anyhow!("writer thread panicked"))?;
+    result.and(written)
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::util;
+
+    #[test]
+    fn open_and_get() {
+        let tmpdir = util::test::dir();
+        let mut registry = Registry::new();
+        registry.open("1", &util::test::test_path(&tmpdir, "one.fs"),
+                      storage::Limits::default(), Default::default()).unwrap();
+        registry.open("two", &util::test::test_path(&tmpdir, "two.fs"),
+                      storage::Limits { read_only: true, ..Default::default() },
+                      storage::PoolSizes { tmps: 2 })
+            .unwrap();
+        assert!(registry.open("two", &util::test::test_path(&tmpdir, "x.fs"),
+                              storage::Limits::default(), Default::default())
+                .is_err());
+
+        assert_eq!(registry.names(), vec!["1", "two"]);
+        assert!(! registry.get("1").unwrap().limits().read_only);
+        assert!(registry.get("two").unwrap().limits().read_only);
+        assert!(registry.get("three").is_none());
+    }
+
+    // Start a connection, returning a writer for requests and an
+    // iterator for responses.
+    fn start(registry: &std::sync::Arc<Registry>)
+             -> (pipe::PipeWriter, msg::ZeoIter<pipe::PipeReader>) {
+        let (reader, mut client_writer) = pipe::pipe();
+        let (client_reader, writer) = pipe::pipe();
+        let registry = registry.clone();
+        std::thread::spawn(
+            move || connect(&registry, "test".to_string(), false, reader, writer));
+        let mut responses = msg::ZeoIter::new(client_reader);
+        assert_eq!(responses.next_vec().unwrap(), b"M5".to_vec());
+        client_writer.write_all(&msg::size_vec(b"M5".to_vec())).unwrap();
+        (client_writer, responses)
+    }
+
+    #[test]
+    fn admission() {
+        let admission = Admission::new(2);
+        let one = admission.try_admit().unwrap();
+        let two = admission.admit();
+        assert!(admission.try_admit().is_none());
+
+        // Waiting callers get slots as they're freed:
+        let waiting = admission.clone();
+        let thread = std::thread::spawn(move || waiting.admit());
+        drop(one);
+        let three = thread.join().unwrap();
+        assert!(admission.try_admit().is_none());
+        drop(two);
+        drop(three);
+        assert!(admission.try_admit().is_some());
+    }
+
+    #[test]
+    fn rejection() {
+        let (reader, mut client_writer) = pipe::pipe();
+        let (client_reader, writer) = pipe::pipe();
+        let thread = std::thread::spawn(
+            move || reject("Too many connections", reader, writer));
+        let mut responses = msg::ZeoIter::new(client_reader);
+        assert_eq!(responses.next_vec().unwrap(), b"M5".to_vec());
+        client_writer.write_all(&msg::size_vec(b"M5".to_vec())).unwrap();
+        client_writer.write_all(&sencode!((1, "register", ("1", false))).unwrap())
+            .unwrap();
+        let (id, code, (name, (message, _))): (i64, String, (String, (String, String))) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &code as &str, &name as &str, &message as &str),
+                   (1, "E", "ZODB.POSException.StorageError", "Too many connections"));
+        thread.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn pickle() {
+        let tmpdir = util::test::dir();
+        let mut registry = Registry::new();
+        let fs = registry.open("1", &util::test::test_path(&tmpdir, "one.fs"),
+                               storage::Limits::default(), Default::default()).unwrap();
+        let registry = std::sync::Arc::new(registry);
+        let (reader, mut client_writer) = pipe::pipe();
+        let (client_reader, writer) = pipe::pipe();
+        std::thread::spawn(
+            move || connect(&registry, "test".to_string(), false, reader, writer));
+        let mut responses = msg::ZeoIter::new(client_reader);
+        assert_eq!(responses.next_vec().unwrap(), b"M5".to_vec());
+        client_writer.write_all(&msg::size_vec(b"Z5".to_vec())).unwrap();
+
+        // Requests and responses are pickles:
+        let mut requests = msg::Encoder::new(client_writer, msg::codec(b"Z5"));
+        let mut response = || {
+            let data = responses.next_vec().unwrap();
+            assert_eq!(&data[..2], b"\x80\x03");
+            crate::pickle::to_msgpack(&data).unwrap()
+        };
+        requests.write_all(&sencode!((1, "register", ("1", false))).unwrap()).unwrap();
+        let (id, code, tid): (i64, String, serde::bytes::ByteBuf) =
+            decode!(&mut &response()[..], "decoding").unwrap();
+        assert_eq!((id, &code as &str, tid.to_vec()), (1, "R", fs.last_transaction().to_vec()));
+
+        requests.write_all(&sencode!((2, "lastTransaction", ())).unwrap()).unwrap();
+        let (id, code, tid): (i64, String, serde::bytes::ByteBuf) =
+            decode!(&mut &response()[..], "decoding").unwrap();
+        assert_eq!((id, &code as &str, tid.to_vec()), (2, "R", fs.last_transaction().to_vec()));
+    }
+
+    #[test]
+    fn extensions() {
+        let tmpdir = util::test::dir();
+        let mut registry = Registry::new();
+        registry.open("1", &util::test::test_path(&tmpdir, "one.fs"),
+                      storage::Limits::default(), Default::default()).unwrap();
+        registry.add_extension("length", std::sync::Arc::new(| fs, args | {
+            let (scale,): (u64,) = decode!(&mut &args[..], "decoding length")?;
+            let mut buf = vec![];
+            rmp::encode::write_uint(&mut buf, fs.len() as u64 * scale)
+                .map_err(| e | anyhow::anyhow!("{:?}", e))?;
+            Ok(buf)
+        }));
+        let registry = std::sync::Arc::new(registry);
+        let (mut requests, mut responses) = start(&registry);
+        requests.write_all(&sencode!((1, "register", ("1", false))).unwrap()).unwrap();
+        responses.next_vec().unwrap();
+
+        requests.write_all(&sencode!((2, "length", (3,))).unwrap()).unwrap();
+        let (id, code, length): (i64, String, u64) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &code as &str, length), (2, "R", 0));
+
+        // Handler errors are reported, as are calls to unknown methods:
+        requests.write_all(&sencode!((3, "length", ("x",))).unwrap()).unwrap();
+        let (id, code, (name, _)): (i64, String, (String, (String, String))) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &code as &str, &name as &str),
+                   (3, "E", "ZODB.POSException.StorageError"));
+        requests.write_all(&sencode!((4, "nonsense", ())).unwrap()).unwrap();
+        let (id, code, (name, (method, _))): (i64, String, (String, (String, String))) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &code as &str, &name as &str, &method as &str),
+                   (4, "E", "builtins.AttributeError", "nonsense"));
+
+        // Requests with bad arguments get errors too:
+        requests.write_all(&sencode!((5, "loadBefore", ("x",))).unwrap()).unwrap();
+        let (id, code, (name, (message, _))): (i64, String, (String, (String, String))) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &code as &str, &name as &str), (5, "E", "builtins.ValueError"));
+        assert!(message.starts_with("decoding loadBefore"), "{}", message);
+
+        // and the connection carries on:
+        requests.write_all(&sencode!((6, "getExtensionMethods", ())).unwrap()).unwrap();
+        let (id, _, methods): (i64, String, std::collections::BTreeMap<String, Option<u32>>) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!(id, 6);
+        assert!(methods.contains_key("length"));
+        assert!(methods.contains_key("checkpoint"));
+    }
+
+    #[test]
+    fn heartbeats() {
+        let tmpdir = util::test::dir();
+        let mut registry = Registry::new();
+        let fs = registry.open("1", &util::test::test_path(&tmpdir, "one.fs"),
+                               storage::Limits::default(), Default::default()).unwrap();
+        let registry = std::sync::Arc::new(registry);
+        let (mut requests, mut responses) = start(&registry);
+        requests.write_all(&sencode!((1, "register", ("1", false))).unwrap()).unwrap();
+        responses.next_vec().unwrap();
+        let heartbeat = | responses: &mut msg::ZeoIter<pipe::PipeReader> | {
+            let (id, method, ()): (i64, String, ()) =
+                decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+            assert_eq!((id, &method as &str), (-1, "heartbeat"));
+        };
+        let missed = || match &crate::info::storage_status(&fs)["missed-heartbeats"] {
+            msg::InfoValue::List(missed) => missed.clone(),
+            _ => panic!("bad status"),
+        };
+
+        // The client registered since the last heartbeat, so it
+        // hasn't missed any:
+        fs.heartbeat();
+        heartbeat(&mut responses);
+        assert_eq!(missed(), vec![]);
+
+        // Then it goes quiet:
+        fs.heartbeat();
+        heartbeat(&mut responses);
+        fs.heartbeat();
+        heartbeat(&mut responses);
+        let connection = fs.clients()[0].connection();
+        assert_eq!(missed(), vec![msg::InfoValue::List(vec![
+            msg::InfoValue::Int(connection), msg::InfoValue::Str("test".to_string()),
+            msg::InfoValue::Int(2)])]);
+
+        // Client heartbeats count:
+        requests.write_all(&sencode!((-1, "heartbeat", ())).unwrap()).unwrap();
+        while missed().len() == 1 {
+            fs.heartbeat();
+            heartbeat(&mut responses);
+        }
+        assert_eq!(fs.clients()[0].missed_heartbeats(), 0);
+
+        // Heartbeats are sent at intervals, once started:
+        start_heartbeats(&registry, std::time::Duration::from_millis(10));
+        heartbeat(&mut responses);
+    }
+
+    #[test]
+    fn info() {
+        let tmpdir = util::test::dir();
+        let mut registry = Registry::new();
+        let fs = registry.open("1", &util::test::test_path(&tmpdir, "one.fs"),
+                               storage::Limits::default(), Default::default()).unwrap();
+        let registry = std::sync::Arc::new(registry);
+        let (mut requests, mut responses) = start(&registry);
+        requests.write_all(&sencode!((1, "register", ("1", false))).unwrap()).unwrap();
+        responses.next_vec().unwrap();
+        let info = | responses: &mut msg::ZeoIter<pipe::PipeReader> | {
+            let (id, method, (info,)): (i64, String, (std::collections::BTreeMap<String, msg::InfoValue>,)) =
+                decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+            assert_eq!((id, &method as &str), (0, "info"));
+            info
+        };
+
+        fs.push_info();
+        let sent = info(&mut responses);
+        assert_eq!(sent["length"], msg::InfoValue::Int(fs.len() as u64));
+        assert_eq!(sent["size"], msg::InfoValue::Int(fs.size()));
+        assert_eq!(sent["last_tid"], msg::InfoValue::Bytes(fs.last_transaction().to_vec()));
+
+        // Info is sent at intervals, once started:
+        start_info(&registry, std::time::Duration::from_millis(10));
+        info(&mut responses);
+    }
+
+    #[test]
+    fn latencies() {
+        let tmpdir = util::test::dir();
+        let mut registry = Registry::new();
+        let fs = registry.open("1", &util::test::test_path(&tmpdir, "one.fs"),
+                               storage::Limits::default(), Default::default()).unwrap();
+        let registry = std::sync::Arc::new(registry);
+        let (mut requests, mut responses) = start(&registry);
+        requests.write_all(&sencode!((1, "register", ("1", false))).unwrap()).unwrap();
+        responses.next_vec().unwrap();
+
+        // Responses are timed, by method:
+        requests.write_all(&sencode!((2, "lastTransaction", ())).unwrap()).unwrap();
+        requests.write_all(&sencode!((3, "lastTransaction", ())).unwrap()).unwrap();
+        requests.write_all(&sencode!((4, "ping", ())).unwrap()).unwrap();
+        for _ in 0..3 {
+            responses.next_vec().unwrap();
+        }
+        let histograms = fs.latencies().histograms();
+        assert_eq!(histograms.keys().cloned().collect::<Vec<String>>(),
+                   vec!["lastTransaction", "ping"]);
+        assert_eq!(histograms["lastTransaction"].count, 2);
+        assert_eq!(histograms["ping"].buckets.iter().sum::<u64>(), 1);
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let tmpdir = util::test::dir();
+        let mut registry = Registry::new();
+        let fs = registry.open("1", &util::test::test_path(&tmpdir, "one.fs"),
+                               storage::Limits::default(), Default::default()).unwrap();
+        let registry = std::sync::Arc::new(registry);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(std::time::Duration::from_millis(100))).unwrap();
+            let reader = stream.try_clone().unwrap();
+            connect(&registry, "test".to_string(), false, reader, stream)
+        });
+
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let mut responses = msg::ZeoIter::new(stream.try_clone().unwrap());
+        assert_eq!(responses.next_vec().unwrap(), b"M5".to_vec());
+        stream.write_all(&msg::size_vec(b"M5".to_vec())).unwrap();
+        stream.write_all(&sencode!((1, "register", ("1", false))).unwrap()).unwrap();
+        responses.next_vec().unwrap();
+        assert_eq!(fs.client_count(), 1);
+
+        // Activity keeps the connection open:
+        for _ in 0 .. 3 {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            stream.write_all(&sencode!((2, "ping", ())).unwrap()).unwrap();
+            responses.next_vec().unwrap();
+        }
+
+        // But then we go quiet:
+        let (id, method, (reason,)): (i64, String, (String,)) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &method as &str, &reason as &str),
+                   (0, "disconnected", "idle too long"));
+        assert!(server.join().unwrap().is_err());
+        assert_eq!(fs.client_count(), 0);
+    }
+
+    #[test]
+    fn teardown() {
+        let tmpdir = util::test::dir();
+        let mut registry = Registry::new();
+        let fs = registry.open("1", &util::test::test_path(&tmpdir, "one.fs"),
+                               storage::Limits::default(), Default::default()).unwrap();
+        let registry = std::sync::Arc::new(registry);
+
+        let vote = | requests: &mut pipe::PipeWriter, id: i64 | {
+            requests.write_all(&sencode!(
+                (0, "tpc_begin", (id, msg::bytes(b""), msg::bytes(b""), msg::bytes(b""),
+                                  msg::NIL, msg::bytes(b" ")))).unwrap()).unwrap();
+            requests.write_all(&sencode!(
+                (0, "storea", (msg::bytes(&util::Z64), msg::bytes(&util::Z64),
+                               msg::bytes(b"data"), id))).unwrap()).unwrap();
+            requests.write_all(&sencode!((id, "vote", (id,))).unwrap()).unwrap();
+        };
+
+        // A client votes, locking the object, then breaks the protocol:
+        let (mut requests, mut responses) = start(&registry);
+        requests.write_all(&sencode!((1, "register", ("1", false))).unwrap()).unwrap();
+        responses.next_vec().unwrap();
+        vote(&mut requests, 2);
+        responses.next_vec().unwrap();
+        requests.write_all(&sencode!((3, "vote")).unwrap()).unwrap();
+        let (id, method, (reason,)): (i64, String, (String,)) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &method as &str), (0, "disconnected"));
+        assert!(reason.starts_with("protocol error"));
+        assert_eq!(fs.client_count(), 0);
+
+        // Its transaction was aborted, so another client can commit.
+        let (mut requests, mut responses) = start(&registry);
+        requests.write_all(&sencode!((1, "register", ("1", false))).unwrap()).unwrap();
+        responses.next_vec().unwrap();
+        vote(&mut requests, 2);
+        let (id, flag, _): (i64, String, Vec<std::collections::BTreeMap<String, serde::bytes::ByteBuf>>) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &flag as &str), (2, "R"));
+    }
+
+    #[test]
+    fn authentication() {
+        let tmpdir = util::test::dir();
+        let mut registry = Registry::new();
+        registry.open("1", &util::test::test_path(&tmpdir, "one.fs"),
+                      storage::Limits::default(), Default::default()).unwrap();
+        let mut passwords = crate::auth::Passwords::new();
+        passwords.add("bob", "secret");
+        registry.set_authenticator(Box::new(passwords));
+        let registry = std::sync::Arc::new(registry);
+
+        // Registering without authenticating fails:
+        let (mut writer, mut responses) = start(&registry);
+        writer.write_all(&sencode!((1, "register", ("1", false))).unwrap()).unwrap();
+        let (id, code, (name, _)): (i64, String, (String, (String, String))) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &code as &str, &name as &str),
+                   (1, "E", "ZEO.Exceptions.AuthError"));
+
+        // So does a bad password:
+        let (mut writer, mut responses) = start(&registry);
+        writer.write_all(&sencode!((1, "authenticate", ("bob", msg::bytes(b"guess"))))
+                         .unwrap()).unwrap();
+        let (_, code, (name, _)): (i64, String, (String, (String, String))) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!((&code as &str, &name as &str), ("E", "ZEO.Exceptions.AuthError"));
+
+        // A good one lets the client register:
+        let (mut writer, mut responses) = start(&registry);
+        writer.write_all(&sencode!((1, "auth_challenge", ())).unwrap()).unwrap();
+        let (id, code, challenge): (i64, String, serde::bytes::ByteBuf) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &code as &str, challenge.len()), (1, "R", 0));
+        writer.write_all(&sencode!((2, "authenticate", ("bob", msg::bytes(b"secret"))))
+                         .unwrap()).unwrap();
+        let (id, code, ok): (i64, String, bool) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &code as &str, ok), (2, "R", true));
+        writer.write_all(&sencode!((3, "register", ("1", false))).unwrap()).unwrap();
+        let (id, code, _): (i64, String, serde::bytes::ByteBuf) =
+            decode!(&mut &responses.next_vec().unwrap()[..], "decoding").unwrap();
+        assert_eq!((id, &code as &str), (3, "R"));
+    }
+}
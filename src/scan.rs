@@ -0,0 +1,232 @@
+// Sequential scanning of data files, for offline tools
+use std::io::prelude::*;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::dictionaries;
+use crate::records;
+use crate::storage;
+use crate::transaction;
+use crate::util;
+
+#[derive(Debug)]
+pub struct TransactionRecord {
+    pub pos: u64,
+    // false for padding (uncommitted) records
+    pub committed: bool,
+    pub header: records::TransactionHeader,
+    pub user: util::Bytes,
+    pub desc: util::Bytes,
+    pub ext: util::Bytes,
+    // The data file's format version
+    pub version: u32,
+}
+
+impl TransactionRecord {
+    pub fn tid(&self) -> util::Tid {
+        self.header.id
+    }
+
+    // Position of the first data record
+    pub fn data_pos(&self) -> u64 {
+        self.pos + 4 + records::transaction_header_length(self.version) +
+            self.user.len() as u64 + self.desc.len() as u64 +
+            self.ext.len() as u64
+    }
+
+    pub fn end(&self) -> u64 {
+        self.pos + self.header.length
+    }
+
+    /// Read the transaction's data-record headers, with their positions.
+    pub fn data_headers<R: Read + Seek>(&self, reader: &mut R)
+                                        -> Result<Vec<(u64, records::DataHeader)>> {
+        let mut pos = self.data_pos();
+        let mut headers = vec![];
+        for _ in 0 .. self.header.ndata {
+            util::seek(reader, pos)?;
+            let header = records::DataHeader::read(reader, self.version)
+                .context("reading data header")?;
+            let next = pos + records::data_header_size(self.version) + header.length;
+            headers.push((pos, header));
+            pos = next;
+        }
+        Ok(headers)
+    }
+
+    /// The transaction's checksum, computed from its header and data
+    /// headers, without reading its data, to compare with the one it
+    /// was written with, or another copy's.  Data are checked by
+    /// their own checksums.
+    pub fn checksum<R: Read + Seek>(&self, reader: &mut R) -> Result<u32> {
+        let mut checksum = records::TransactionChecksum::new(&[
+            &self.header.id[..], &self.header.ndata.to_be_bytes(),
+            &self.header.luser.to_be_bytes(), &self.header.ldesc.to_be_bytes(),
+            &self.header.lext.to_be_bytes(), &self.user, &self.desc, &self.ext,
+        ].concat());
+        for (_, header) in self.data_headers(reader)? {
+            checksum.add(&header.id, header.checksum);
+        }
+        Ok(checksum.value())
+    }
+}
+
+/// Read the data for a data record at pos, in a file of a format
+/// version, decompressed, with the file's dictionaries, if they're
+/// compressed.
+pub fn read_data<R: Read + Seek>(reader: &mut R, pos: u64,
+                                 header: &records::DataHeader, version: u32,
+                                 dictionaries: &dictionaries::Dictionaries)
+                                 -> Result<util::Bytes> {
+    util::seek(reader, pos + records::data_header_size(version))?;
+    let data = util::read_sized(reader, header.length as usize)
+        .context("reading record data")?;
+    records::decode(header.compressed, data, dictionaries).context("decompressing record data")
+}
+
+/// Read the transaction record at pos, in a file of a format version.
+pub fn read_transaction<R: Read + Seek>(reader: &mut R, pos: u64, version: u32)
+                                        -> Result<TransactionRecord> {
+    util::seek(reader, pos)?;
+    let marker = util::read4(reader)?;
+    let committed = if marker == storage::TRANSACTION_MARKER {
+        true
+    }
+    else if marker == transaction::PADDING_MARKER {
+        false
+    }
+    else {
+        return Err(anyhow!("Bad record marker {:?} at {}", marker, pos));
+    };
+    let header = records::TransactionHeader::read(reader, version)
+        .context("reading transaction header")?;
+    let user = util::read_sized(reader, header.luser as usize)?;
+    let desc = util::read_sized(reader, header.ldesc as usize)?;
+    let ext = util::read_sized(reader, header.lext as usize)?;
+    Ok(TransactionRecord { pos, committed, header, user, desc, ext, version })
+}
+
+pub struct TransactionIterator {
+    reader: std::io::BufReader<std::fs::File>,
+    pos: u64,
+    size: u64,
+    padding: bool,
+    version: u32,
+}
+
+impl TransactionIterator {
+
+    pub fn open(path: &str) -> Result<TransactionIterator> {
+        let file = std::fs::File::open(path).context("opening data file")?;
+        let size = file.metadata()?.len();
+        TransactionIterator::new(file, size)
+    }
+
+    /// Iterate over the transactions in file before size, which
+    /// needn't be the file's size, to leave out transactions being
+    /// written.
+    pub fn new(mut file: std::fs::File, size: u64) -> Result<TransactionIterator> {
+        util::seek(&mut file, 0)?;
+        let header = records::FileHeader::read(&mut file).context("reading file header")?;
+        Ok(TransactionIterator {
+            reader: std::io::BufReader::new(file),
+            pos: records::HEADER_SIZE, size, padding: false, version: header.version,
+        })
+    }
+
+    /// Start at the transaction at pos, rather than the first.
+    pub fn with_start(mut self, pos: u64) -> TransactionIterator {
+        self.pos = pos;
+        self
+    }
+
+    /// Include padding records
+    pub fn with_padding(mut self) -> TransactionIterator {
+        self.padding = true;
+        self
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The data file's format version
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn reader(&mut self) -> &mut std::io::BufReader<std::fs::File> {
+        &mut self.reader
+    }
+
+    fn read(&mut self) -> Result<TransactionRecord> {
+        let record = read_transaction(&mut self.reader, self.pos, self.version)?;
+        if record.header.length == 0 || record.end() > self.size {
+            return Err(anyhow!("Bad transaction length {} at {}",
+                               record.header.length, self.pos));
+        }
+        self.pos = record.end();
+        Ok(record)
+    }
+}
+
+impl std::iter::Iterator for TransactionIterator {
+
+    type Item = Result<TransactionRecord>;
+
+    fn next(&mut self) -> Option<Result<TransactionRecord>> {
+        while self.pos < self.size {
+            match self.read() {
+                Ok(record) => {
+                    if record.committed || self.padding {
+                        return Some(Ok(record));
+                    }
+                },
+                Err(err) => {
+                    self.pos = self.size; // Don't try to go on
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::storage::testing;
+
+    #[test]
+    fn iterate() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        testing::make_sample(
+            &path,
+            vec![vec![(util::p64(0), b"000")],
+                 vec![(util::p64(0), b"111"), (util::p64(1), b"aaa")],
+            ]).unwrap();
+
+        let mut it = TransactionIterator::open(&path).unwrap();
+        let first = it.next().unwrap().unwrap();
+        assert_eq!(first.pos, records::HEADER_SIZE);
+        assert!(first.committed);
+        let second = it.next().unwrap().unwrap();
+        assert_eq!(second.pos, first.end());
+        assert!(second.tid() > first.tid());
+        assert!(it.next().is_none());
+
+        let headers = second.data_headers(it.reader()).unwrap();
+        assert_eq!(headers.iter().map(| h | h.1.id).collect::<Vec<util::Oid>>(),
+                   vec![util::p64(0), util::p64(1)]);
+        assert_eq!(headers[0].1.previous, first.data_pos());
+        assert_eq!(headers[1].1.previous, 0);
+
+        assert_ne!(second.header.checksum, 0);
+        assert_eq!(second.checksum(it.reader()).unwrap(), second.header.checksum);
+        assert_ne!(first.checksum(it.reader()).unwrap(), second.header.checksum);
+    }
+}
@@ -0,0 +1,178 @@
+// Offline data-file statistics
+use anyhow::Result;
+
+use crate::records;
+use crate::scan;
+use crate::tid;
+use crate::util;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+    pub size: u64,
+    pub objects: u64,
+    pub transactions: u64,
+    pub records: u64,
+    pub first_tid: Option<util::Tid>,
+    pub last_tid: Option<util::Tid>,
+    // Bytes in committed transaction records
+    pub transaction_bytes: u64,
+    // Bytes in padding (uncommitted) records
+    pub padding_bytes: u64,
+    // Bytes in data records that aren't current
+    pub old_record_bytes: u64,
+}
+
+impl Stats {
+    pub fn average_transaction_size(&self) -> u64 {
+        self.transaction_bytes.checked_div(self.transactions).unwrap_or(0)
+    }
+
+    /// Rough estimate of what a pack that removed all non-current
+    /// records would save.
+    pub fn pack_savings(&self) -> u64 {
+        self.padding_bytes + self.old_record_bytes
+    }
+}
+
+fn tid_or_none(tid: &Option<util::Tid>) -> String {
+    tid.as_ref().map(tid::tid_string).unwrap_or_else(|| String::from("none"))
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "File size: {}", self.size)?;
+        writeln!(f, "Objects: {}", self.objects)?;
+        writeln!(f, "Transactions: {}", self.transactions)?;
+        writeln!(f, "Data records: {}", self.records)?;
+        writeln!(f, "Oldest transaction: {}", tid_or_none(&self.first_tid))?;
+        writeln!(f, "Newest transaction: {}", tid_or_none(&self.last_tid))?;
+        writeln!(f, "Average transaction size: {}",
+                 self.average_transaction_size())?;
+        write!(f, "Estimated pack savings: {}", self.pack_savings())
+    }
+}
+
+pub fn file_stats(path: &str) -> Result<Stats> {
+    let mut it = scan::TransactionIterator::open(path)?.with_padding();
+    let mut stats = Stats { size: it.size(), ..Default::default() };
+
+    // oid -> size of the current record
+    let mut current = std::collections::HashMap::<util::Oid, u64>::new();
+    while let Some(record) = it.next() {
+        let record = record?;
+        if ! record.committed {
+            stats.padding_bytes += record.header.length;
+            continue;
+        }
+        stats.transactions += 1;
+        stats.transaction_bytes += record.header.length;
+        if stats.first_tid.is_none() {
+            stats.first_tid = Some(record.tid());
+        }
+        stats.last_tid = Some(record.tid());
+        for (_, header) in record.data_headers(it.reader())? {
+            stats.records += 1;
+            let size = records::data_header_size(record.version) + header.length;
+            if let Some(old) = current.insert(header.id, size) {
+                stats.old_record_bytes += old;
+            }
+        }
+    }
+    stats.objects = current.len() as u64;
+    Ok(stats)
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ObjectSpace {
+    pub oid: util::Oid,
+    pub revisions: u64,
+    // Bytes in the current data record
+    pub current_bytes: u64,
+    // Bytes in all data records, including the current one
+    pub total_bytes: u64,
+}
+
+impl std::fmt::Display for ObjectSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} total={} current={} revisions={}",
+               util::hex(&self.oid), self.total_bytes, self.current_bytes,
+               self.revisions)
+    }
+}
+
+/// Account for the space used by each object, adding up records as
+/// they're scanned.  Returns the count objects using the most space,
+/// biggest first.
+pub fn space_by_oid(path: &str, count: usize) -> Result<Vec<ObjectSpace>> {
+    let mut it = scan::TransactionIterator::open(path)?;
+    let mut space = std::collections::HashMap::<util::Oid, ObjectSpace>::new();
+    while let Some(record) = it.next() {
+        let record = record?;
+        for (_, header) in record.data_headers(it.reader())? {
+            let size = records::data_header_size(record.version) + header.length;
+            let object = space.entry(header.id).or_insert_with(
+                || ObjectSpace { oid: header.id, ..Default::default() });
+            object.revisions += 1;
+            object.current_bytes = size;
+            object.total_bytes += size;
+        }
+    }
+    let mut space: Vec<ObjectSpace> = space.into_values().collect();
+    space.sort_by(| a, b | b.total_bytes.cmp(&a.total_bytes)
+                  .then(a.oid.cmp(&b.oid)));
+    space.truncate(count);
+    Ok(space)
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::storage::testing;
+
+    #[test]
+    fn works() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        testing::make_sample(
+            &path,
+            vec![vec![(util::p64(0), b"000")],
+                 vec![(util::p64(0), b"111"), (util::p64(1), b"aaa")],
+            ]).unwrap();
+
+        let stats = file_stats(&path).unwrap();
+        assert_eq!(stats.size, std::fs::metadata(&path).unwrap().len());
+        assert_eq!(stats.objects, 2);
+        assert_eq!(stats.transactions, 2);
+        assert_eq!(stats.records, 3);
+        assert!(stats.first_tid < stats.last_tid);
+        assert_eq!(stats.transaction_bytes, stats.size - records::HEADER_SIZE);
+        assert_eq!(stats.average_transaction_size(),
+                   stats.transaction_bytes / 2);
+        assert_eq!(stats.pack_savings(),
+                   records::data_header_size(records::FORMAT_VERSION) + 3);
+    }
+
+    #[test]
+    fn space() {
+        let tmpdir = util::test::dir();
+        let path = util::test::test_path(&tmpdir, "data.fs");
+        testing::make_sample(
+            &path,
+            vec![vec![(util::p64(0), b"000")],
+                 vec![(util::p64(0), b"1"), (util::p64(1), b"aaaaaa")],
+                 vec![(util::p64(2), b"x")],
+            ]).unwrap();
+
+        let h = records::data_header_size(records::FORMAT_VERSION);
+        assert_eq!(space_by_oid(&path, 2).unwrap(), vec![
+            ObjectSpace { oid: util::p64(0), revisions: 2,
+                          current_bytes: h + 1, total_bytes: 2 * h + 4 },
+            ObjectSpace { oid: util::p64(1), revisions: 1,
+                          current_bytes: h + 6, total_bytes: h + 6 },
+        ]);
+        assert_eq!(space_by_oid(&path, 10).unwrap().len(), 3);
+    }
+}
@@ -5,18 +5,45 @@ use std::io::prelude::*;
 use anyhow::{Context, Result};
 use byteorder::{ByteOrder, BigEndian, ReadBytesExt};
 
+use crate::appender;
+use crate::backup;
+use crate::blobs;
+use crate::cache;
+use crate::cdc;
+use crate::chains;
+use crate::dictionaries;
 use crate::errors;
+use crate::fsck;
 use crate::index;
 use crate::lock;
+use crate::mapped;
+use crate::pack;
 use crate::pool;
 use crate::records;
+use crate::scan;
 use crate::tid;
 use crate::transaction;
 
 use crate::util;
 
-const INDEX_SUFFIX: &'static str = ".index";
-const TRANSACTION_MARKER: &'static [u8] = b"TTTT";
+pub const INDEX_SUFFIX: &'static str = ".index";
+pub const TRANSACTION_MARKER: &'static [u8] = b"TTTT";
+
+// Number of objects whose revision chains are cached for historical
+// loads
+pub const CHAIN_CACHE_SIZE: usize = 10_000;
+
+// Number of recent transactions whose invalidations are kept in
+// memory for clients catching up after reconnecting
+pub const INVALIDATION_QUEUE_SIZE: usize = 100;
+
+// How long a vote waits for room in a full commit queue before
+// failing
+pub const VOTE_QUEUE_WAIT: std::time::Duration = std::time::Duration::from_secs(1);
+
+// How often deferred commits are fsynced
+pub const DEFERRED_FSYNC_INTERVAL: std::time::Duration =
+    std::time::Duration::from_millis(10);
 
 #[derive(Debug)]
 pub enum LoadBeforeResult {
@@ -25,6 +52,49 @@ pub enum LoadBeforeResult {
     PosKeyError,
 }
 
+/// A revision of an object, with its transaction's metadata
+#[derive(Debug, PartialEq)]
+pub struct Revision {
+    pub tid: util::Tid,
+    pub size: u64,
+    pub user: util::Bytes,
+    pub description: util::Bytes,
+}
+
+/// An object's current record, and the oid of the next object, if
+/// any, for walking the whole database
+#[derive(Debug, PartialEq)]
+pub struct CurrentRecord {
+    pub oid: util::Oid,
+    pub tid: util::Tid,
+    pub data: util::Bytes,
+    pub next: Option<util::Oid>,
+}
+
+/// Activity counts and commit-queue state, for monitoring
+#[derive(Debug, PartialEq)]
+pub struct Status {
+    // Since the storage was opened
+    pub commits: u64,
+    pub aborts: u64,
+    pub conflicts: u64,
+    // Transactions waiting for locks
+    pub lock_waits: usize,
+    pub clients: usize,
+    // Voted transactions waiting to be committed
+    pub voted: usize,
+    pub last_tid: util::Tid,
+}
+
+/// A committed transaction's metadata
+#[derive(Debug, PartialEq)]
+pub struct TransactionInfo {
+    pub tid: util::Tid,
+    pub user: util::Bytes,
+    pub description: util::Bytes,
+    pub extension: util::Bytes,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Conflict {
     pub oid: util::Oid,
@@ -33,21 +103,333 @@ pub struct Conflict {
     pub data: util::Bytes,
 }
 
+/// Capacities of a storage's file pools: the most open files kept
+/// for buffering transaction data before votes.  More files are
+/// opened when needed, but aren't kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolSizes {
+    pub tmps: usize,
+}
+
+impl Default for PoolSizes {
+    fn default() -> PoolSizes {
+        PoolSizes { tmps: 22 }
+    }
+}
+
+/// How to open a storage, made with FileStorage::options(), like:
+///
+/// ```ignore
+/// let fs: FileStorage<C> = FileStorage::options()
+///     .path("data.fs").tmp_pool(22).create(false).open()?;
+/// ```
+pub struct OpenOptions<C: Client> {
+    path: Option<String>,
+    pool_sizes: PoolSizes,
+    read_only: bool,
+    create: bool,
+    tmp_dir: Option<String>,
+    client: std::marker::PhantomData<fn() -> C>,
+}
+
+impl<C: Client> OpenOptions<C> {
+
+    /// The data file.  Required.
+    pub fn path<P: Into<String>>(mut self, path: P) -> OpenOptions<C> {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// The most temporary files kept for buffering transaction data,
+    /// 22 by default
+    pub fn tmp_pool(mut self, size: usize) -> OpenOptions<C> {
+        self.pool_sizes.tmps = size;
+        self
+    }
+
+    pub fn pool_sizes(mut self, pool_sizes: PoolSizes) -> OpenOptions<C> {
+        self.pool_sizes = pool_sizes;
+        self
+    }
+
+    /// Open the data file read-only, so votes fail with
+    /// ReadOnlyError.  A read-only data file isn't created.
+    pub fn read_only(mut self, read_only: bool) -> OpenOptions<C> {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Whether to create the data file if it doesn't exist, which is
+    /// the default.
+    pub fn create(mut self, create: bool) -> OpenOptions<C> {
+        self.create = create;
+        self
+    }
+
+    /// Where temporary files are made, rather than the data file's
+    /// path with .tmp added.  It's created if necessary.
+    pub fn tmp_dir<P: Into<String>>(mut self, tmp_dir: P) -> OpenOptions<C> {
+        self.tmp_dir = Some(tmp_dir.into());
+        self
+    }
+
+    pub fn open(&self) -> std::io::Result<FileStorage<C>> {
+        let path = self.path.clone().ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::InvalidInput, "No data file path"))?;
+        let mut file =
+            std::fs::OpenOptions::new()
+            .read(true).write(! self.read_only).create(self.create && ! self.read_only)
+            .open(&path)?;
+        // Another process writing the file would corrupt it.
+        lock_data_file(&file, &path)?;
+        let size = file.metadata()?.len();
+        if size == 0 {
+            util::io_assert(! self.read_only, &format!("{} is empty", path))?;
+            let header = records::FileHeader::new();
+            header.write(&mut file)?;
+            FileStorage::new(path, file, &header, index::Index::new(), util::Z64, util::Z64,
+                             records::HEADER_SIZE, 0, self)
+        }
+        else {
+            let header = records::FileHeader::read(&mut file)?;
+            let index_path = path.clone() + INDEX_SUFFIX;
+            // An index file in an older format is rewritten in the
+            // current one.
+            let old_index = index::index_version(&index_path)
+                .is_ok_and(| v | v < index::INDEX_VERSION);
+            let (index, last_tid, last_oid, replayed, complete) =
+                FileStorage::<C>::load_index(&index_path, &mut file, size,
+                                             header.version)?;
+            if complete < size {
+                tracing::warn!("{}: discarding {} bytes at {}, left by a transaction \
+                                that was being voted when the server stopped",
+                               path, size - complete, complete);
+                if ! self.read_only {
+                    file.set_len(complete)?;
+                }
+            }
+            let fs = FileStorage::new(path, file, &header, index, last_tid, last_oid,
+                                      complete, replayed, self)?;
+            if old_index && ! self.read_only {
+                fs.checkpoint().map_err(| err | util::io_error(&format!("{:#}", err)))?;
+            }
+            Ok(fs)
+        }
+    }
+}
+
+/// Take the exclusive lock storages hold on their data files, failing
+/// if another storage, or offline tool, has the file.
+///
+/// The lock is held until the file is closed.
+pub fn lock_data_file(file: &std::fs::File, path: &str) -> std::io::Result<()> {
+    file.try_lock().map_err(| err | match err {
+        std::fs::TryLockError::WouldBlock => std::io::Error::new(
+            std::io::ErrorKind::WouldBlock,
+            format!("{} is locked by another process, such as another server", path)),
+        std::fs::TryLockError::Error(err) => err,
+    })
+}
+
+/// Lock the data file at path, for offline tools that rewrite or
+/// truncate it, so they fail, rather than corrupt the file, if a
+/// server has it open.
+///
+/// The lock is held until the returned file is dropped.
+pub fn lock_data_path(path: &str) -> std::io::Result<std::fs::File> {
+    let file = std::fs::File::open(path)?;
+    lock_data_file(&file, path)?;
+    Ok(file)
+}
+
+/// Per-storage limits, for storages shared by several tenants.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    // Maximum data-file size, in bytes
+    pub max_size: Option<u64>,
+    // Maximum number of connected clients
+    pub max_clients: Option<usize>,
+    pub read_only: bool,
+    // Warn when usage reaches this percentage of a limit
+    pub warning_percent: Option<u64>,
+    // Abort voted transactions that haven't finished after this long
+    pub finish_timeout: Option<std::time::Duration>,
+    // Maximum number of voted transactions waiting to finish
+    pub max_voted: Option<usize>,
+    // Maximum bytes a transaction can add to the data file
+    pub max_transaction_size: Option<u64>,
+    // Maximum number of objects a transaction can save
+    pub max_transaction_records: Option<u64>,
+    // Minimum free space on the data file's file system, in bytes
+    pub min_free_space: Option<u64>,
+}
+
+/// When to pack a storage automatically, see start_packer
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackSchedule {
+    // Time between packs, the first an interval after startup
+    pub interval: std::time::Duration,
+    // How much history packs keep
+    pub keep: std::time::Duration,
+    // Maximum bytes per second packs read, or None for no limit
+    pub rate: Option<u64>,
+}
+
+/// Advance notice that a limit is being approached.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LimitWarning {
+    Size { size: u64, max_size: u64 },
+    Clients { clients: usize, max_clients: usize },
+}
+
+impl std::fmt::Display for LimitWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LimitWarning::Size { size, max_size } =>
+                write!(f, "data file size {} is approaching the limit of {} bytes",
+                       size, max_size),
+            LimitWarning::Clients { clients, max_clients } =>
+                write!(f, "{} clients are connected, and the limit is {}",
+                       clients, max_clients),
+        }
+    }
+}
+
+pub type WarningHook = Box<dyn Fn(&LimitWarning) + Send + Sync>;
+
 pub struct FileStorage<C: Client> {
     path: String,
     voted: std::sync::Mutex<std::collections::VecDeque<Voted<C>>>,
-    file: std::sync::Mutex<std::fs::File>,
-    index: std::sync::Mutex<index::Index>,
-    readers: pool::FilePool<pool::ReadFileFactory>,
-    tmps: pool::FilePool<pool::TmpFileFactory>,
-    last_tid: std::sync::Mutex<util::Tid>,
-    committed_tid: std::sync::Mutex<util::Tid>,
+    // Notified when voted transactions leave the queue
+    voted_left: std::sync::Condvar,
+    // Makes all writes to the data file
+    appender: appender::Appender,
+    // Shared with snapshots, and copied on write if there are any
+    index: std::sync::Mutex<std::sync::Arc<index::Index>>,
+    // Shared by reads, which use positional reads, so they don't
+    // need their own file positions
+    reader: std::sync::Mutex<std::sync::Arc<std::fs::File>>,
+    // The data file's format version, which determines how records
+    // are read and written, e.g. whether they have checksums
+    version: u32,
+    tmps: std::sync::Arc<pool::FilePool<pool::TmpFileFactory>>,
+    // Tids, as big-endian integers, so they're read without locking
+    last_tid: std::sync::atomic::AtomicU64,
+    committed_tid: std::sync::atomic::AtomicU64,
     locker: std::sync::Mutex<lock::LockManager>,
     clients: std::sync::Mutex<Vec<C>>,
     last_oid: std::sync::Mutex<u64>,
+    // End of the part of the file covered by the in-memory index
+    index_end: std::sync::Mutex<u64>,
+    // Transactions committed since the index was last saved
+    unsaved_transactions: std::sync::Mutex<u64>,
+    // Index changes since the index was last saved, if they're journaled
+    journal: std::sync::Mutex<Option<index::Journal>>,
+    // Whether new transactions compress the data they save
+    compress: std::sync::atomic::AtomicBool,
+    // Zstd dictionaries, loaded when the storage is opened, for
+    // compressing and decompressing data
+    dictionaries: std::sync::Arc<dictionaries::Dictionaries>,
+    limits: Limits,
+    // Whether usage is over the warning threshold
+    size_warned: std::sync::atomic::AtomicBool,
+    clients_warned: std::sync::atomic::AtomicBool,
+    warning_hook: Option<WarningHook>,
+    // Load sampling: one in access_sample_rate loads is counted, if
+    // the rate isn't 0.
+    access_sample_rate: std::sync::atomic::AtomicU32,
+    loads: std::sync::atomic::AtomicU64,
+    access_counts: std::sync::Mutex<std::collections::HashMap<util::Oid, u64>>,
+    // Request latencies, recorded by client connections
+    latencies: std::sync::Arc<crate::latency::Latencies>,
+    // Deferred commits, and their committers, waiting for an fsync
+    deferred: std::sync::Mutex<Vec<(util::Tid, C)>>,
+    // Revision chains walked by historical loads
+    chains: std::sync::Mutex<chains::ChainCache>,
+    // Maps of the data file, if loads read from them
+    maps: std::sync::Mutex<Option<mapped::Maps>>,
+    // Recently loaded data, if they're cached
+    records: std::sync::Mutex<Option<cache::RecordCache>>,
+    // Every revision's position, if they're indexed
+    revisions: std::sync::Mutex<Option<chains::RevisionIndex>>,
+    // Recently committed transactions' oids
+    invalidations: std::sync::Mutex<Invalidations>,
+    // Where blob files are kept, if the storage has blobs
+    blobs: std::sync::OnceLock<blobs::BlobDir>,
+    // Where clients can write backups, if anywhere
+    backup_dir: std::sync::OnceLock<String>,
+    // Finds references in object data, to remove unreachable objects
+    // when packing
+    references: std::sync::Mutex<Option<std::sync::Arc<dyn pack::ReferencesExtractor>>>,
+    // Where committed changes are sent, if anywhere
+    changes: std::sync::Mutex<Option<std::sync::mpsc::Sender<cdc::Commit>>>,
+    // Held to read while using record positions, and to write while
+    // a pack moves records
+    moving: std::sync::RwLock<()>,
+    packing: std::sync::atomic::AtomicBool,
+    commits: std::sync::atomic::AtomicU64,
+    aborts: std::sync::atomic::AtomicU64,
+    conflicts: std::sync::atomic::AtomicU64,
     // TODO header: FileHeader,
 }
 
+// What loads read records with
+enum Source {
+    Mapped(std::sync::Arc<mapped::Mapping>),
+    File(std::sync::Arc<std::fs::File>),
+}
+
+impl Source {
+    fn reader(&self) -> mapped::Reader<'_> {
+        match self {
+            Source::Mapped(mapping) => mapping.reader(),
+            Source::File(file) => mapped::Reader::file(file),
+        }
+    }
+}
+
+// A bounded queue of recently committed transactions and the oids
+// they changed, oldest first.  Every transaction committed after
+// since is in the queue.
+struct Invalidations {
+    since: util::Tid,
+    recent: std::collections::VecDeque<(util::Tid, Vec<util::Oid>)>,
+    size: usize,
+}
+
+impl Invalidations {
+
+    fn add(&mut self, tid: util::Tid, oids: Vec<util::Oid>) {
+        self.recent.push_back((tid, oids));
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.recent.len() > self.size {
+            if let Some((tid, _)) = self.recent.pop_front() {
+                self.since = tid;
+            }
+        }
+    }
+
+    // Like FileStorage::invalidations_since, or None if the queue
+    // doesn't go back to tid
+    fn since(&self, tid: &util::Tid, max_transactions: usize)
+             -> Option<Option<(util::Tid, Vec<util::Oid>)>> {
+        if tid < &self.since {
+            return None;
+        }
+        let last = self.recent.back().map(| (tid, _) | *tid).unwrap_or(self.since);
+        let after = self.recent.iter().filter(| (t, _) | t > tid);
+        if after.clone().count() > max_transactions {
+            return Some(None);
+        }
+        let oids: std::collections::BTreeSet<util::Oid> =
+            after.flat_map(| (_, oids) | oids.iter().cloned()).collect();
+        Some(Some((last, oids.into_iter().collect())))
+    }
+}
+
 pub struct Voted<C: Client> {
     id: util::Tid,
     pos: u64,
@@ -55,53 +437,179 @@ pub struct Voted<C: Client> {
     length: u64,
     index: index::Index,
     finished: Option<C>,
+    // Finished without an fsync
+    deferred: bool,
+    voted_at: std::time::Instant,
+    // Blob files moved into place, to be removed if it's aborted
+    blobs: Vec<std::path::PathBuf>,
+}
+
+/// Why a client was removed from a storage
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisconnectReason {
+    // The client closed its connection
+    Closed,
+    // Sending to the client failed
+    SendFailed,
+    // Removed on purpose, e.g. by an administrator
+    Kicked,
+    // The server is shutting down
+    Shutdown,
+    // Nothing was received from the client for too long
+    Idle,
+    ProtocolError(String),
+    // Serving the client failed, e.g. because of a bug
+    Failed(String),
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DisconnectReason::Closed => write!(f, "connection closed"),
+            DisconnectReason::SendFailed => write!(f, "sending to the client failed"),
+            DisconnectReason::Kicked => write!(f, "disconnected by the server"),
+            DisconnectReason::Shutdown => write!(f, "server shutting down"),
+            DisconnectReason::Idle => write!(f, "idle too long"),
+            DisconnectReason::ProtocolError(message) =>
+                write!(f, "protocol error: {}", message),
+            DisconnectReason::Failed(message) => write!(f, "server error: {}", message),
+        }
+    }
 }
 
 pub trait Client: PartialEq + Send + Clone + std::fmt::Debug {
-    fn finished(&self, tid: &util::Tid, len: u64, size: u64) -> Result<()>;
+    // A transaction committed by the client is done, with the new
+    // object count and file size, and the oids it stored.
+    fn finished(&self, tid: &util::Tid, len: u64, size: u64, oids: &[util::Oid])
+                -> Result<()>;
     fn invalidate(&self, tid: &util::Tid, oids: &Vec<util::Oid>) -> Result<()>;
-    fn close(&self);
+    // A transaction finished with tpc_finish_deferred is on disk
+    fn durable(&self, tid: &util::Tid) -> Result<()>;
+    // The client was removed from the storage
+    fn close(&self, reason: &DisconnectReason);
+    // Time for a heartbeat, for clients whose connections have them
+    fn heartbeat(&self) -> Result<()> {
+        Ok(())
+    }
+    // The storage's object count, file size and last transaction, sent
+    // periodically for client-side monitoring
+    fn info(&self, len: u64, size: u64, last_tid: &util::Tid) -> Result<()> {
+        Ok(())
+    }
 }
 
 impl<C: Client> FileStorage<C> {
 
-    fn new(path: String, file: std::fs::File, index: index::Index,
-           last_tid: util::Tid, last_oid: util::Oid)
+    #[allow(clippy::too_many_arguments)]
+    fn new(path: String, file: std::fs::File, header: &records::FileHeader,
+           index: index::Index, last_tid: util::Tid, last_oid: util::Oid,
+           index_end: u64, unsaved_transactions: u64, options: &OpenOptions<C>)
            -> std::io::Result<FileStorage<C>> {
         let last_oid = BigEndian::read_u64(&last_oid);
+        let tmp_dir = options.tmp_dir.clone().unwrap_or_else(|| path.clone() + ".tmp");
+        let dictionaries = dictionaries::Dictionaries::load(&path)?;
         Ok(FileStorage {
-            readers: pool::FilePool::new(
-                pool::ReadFileFactory { path: path.clone() }, 9),
+            reader: std::sync::Mutex::new(std::sync::Arc::new(file.try_clone()?)),
+            version: header.version,
             tmps: pool::FilePool::new(
-                pool::TmpFileFactory::base(path.clone() + ".tmp")?,
-                22),
+                pool::TmpFileFactory::base(tmp_dir)?,
+                options.pool_sizes.tmps),
             path: path,
-            file: std::sync::Mutex::new(file),
-            index: std::sync::Mutex::new(index),
-            committed_tid: std::sync::Mutex::new(last_tid),
-            last_tid: std::sync::Mutex::new(last_tid),
+            appender: appender::Appender::new(file),
+            index: std::sync::Mutex::new(std::sync::Arc::new(index)),
+            committed_tid: std::sync::atomic::AtomicU64::new(BigEndian::read_u64(&last_tid)),
+            last_tid: std::sync::atomic::AtomicU64::new(BigEndian::read_u64(&last_tid)),
             locker: std::sync::Mutex::new(lock::LockManager::new()),
             voted: std::sync::Mutex::new(std::collections::VecDeque::new()),
+            voted_left: std::sync::Condvar::new(),
             clients: std::sync::Mutex::new(Vec::new()),
             last_oid: std::sync::Mutex::new(last_oid),
+            index_end: std::sync::Mutex::new(index_end),
+            unsaved_transactions: std::sync::Mutex::new(unsaved_transactions),
+            journal: std::sync::Mutex::new(None),
+            compress: std::sync::atomic::AtomicBool::new(false),
+            dictionaries: std::sync::Arc::new(dictionaries),
+            limits: Limits { read_only: options.read_only, ..Default::default() },
+            size_warned: std::sync::atomic::AtomicBool::new(false),
+            clients_warned: std::sync::atomic::AtomicBool::new(false),
+            warning_hook: None,
+            access_sample_rate: std::sync::atomic::AtomicU32::new(0),
+            loads: std::sync::atomic::AtomicU64::new(0),
+            access_counts: std::sync::Mutex::new(std::collections::HashMap::new()),
+            latencies: Default::default(),
+            deferred: std::sync::Mutex::new(Vec::new()),
+            chains: std::sync::Mutex::new(chains::ChainCache::new(CHAIN_CACHE_SIZE)),
+            maps: std::sync::Mutex::new(None),
+            records: std::sync::Mutex::new(None),
+            revisions: std::sync::Mutex::new(None),
+            invalidations: std::sync::Mutex::new(Invalidations {
+                since: last_tid,
+                recent: std::collections::VecDeque::new(),
+                size: INVALIDATION_QUEUE_SIZE,
+            }),
+            blobs: std::sync::OnceLock::new(),
+            backup_dir: std::sync::OnceLock::new(),
+            references: std::sync::Mutex::new(None),
+            changes: std::sync::Mutex::new(None),
+            moving: std::sync::RwLock::new(()),
+            packing: std::sync::atomic::AtomicBool::new(false),
+            commits: std::sync::atomic::AtomicU64::new(0),
+            aborts: std::sync::atomic::AtomicU64::new(0),
+            conflicts: std::sync::atomic::AtomicU64::new(0),
         })
     }
 
+    /// Options for opening a storage
+    pub fn options() -> OpenOptions<C> {
+        OpenOptions { path: None, pool_sizes: PoolSizes::default(), read_only: false,
+                      create: true, tmp_dir: None, client: std::marker::PhantomData }
+    }
+
+    /// Open the data file at path, creating it if necessary, with
+    /// the default options.
     pub fn open(path: String) -> std::io::Result<FileStorage<C>> {
-        let mut file =
-            std::fs::OpenOptions::new()
-            .read(true).write(true).create(true)
-            .open(&path)?;
-        let size = file.metadata()?.len();
-        if size == 0 {
-            records::FileHeader::new().write(&mut file)?;
-            FileStorage::new(path, file, index::Index::new(), util::Z64, util::Z64)
+        FileStorage::options().path(path).open()
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> FileStorage<C> {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Handle limit warnings with hook, rather than printing them.
+    ///
+    /// Hooks are called with storage locks held, so mustn't use the
+    /// storage.
+    pub fn with_warning_hook(mut self, hook: WarningHook) -> FileStorage<C> {
+        self.warning_hook = Some(hook);
+        self
+    }
+
+    // Warn when usage first reaches the warning percentage of max,
+    // and again if it drops back below and then reaches it again.
+    fn check_soft_limit(&self, warned: &std::sync::atomic::AtomicBool,
+                        used: u64, max: u64, warning: LimitWarning) {
+        if let Some(percent) = self.limits.warning_percent {
+            let over = used * 100 >= max * percent;
+            if warned.swap(over, std::sync::atomic::Ordering::Relaxed) != over &&
+                over {
+                    match self.warning_hook {
+                        Some(ref hook) => hook(&warning),
+                        None => tracing::warn!("{}: {}", self.path, warning),
+                    }
+                }
         }
-        else {
-            records::FileHeader::read(&mut file); // TODO use header info
-            let (index, last_tid, last_oid) = FileStorage::<C>::load_index(
-                &(path.clone() + INDEX_SUFFIX), &mut file, size)?;
-            FileStorage::new(path, file, index, last_tid, last_oid)
+    }
+
+    fn check_client_limit(&self, clients: usize) {
+        if let Some(max_clients) = self.limits.max_clients {
+            self.check_soft_limit(
+                &self.clients_warned, clients as u64, max_clients as u64,
+                LimitWarning::Clients { clients, max_clients });
         }
     }
 
@@ -109,53 +617,164 @@ impl<C: Client> FileStorage<C> {
         self.clients.lock().unwrap().push(client);
     }
 
-    pub fn remove_client(&self, client: C) {
+    /// Add a client, unless the storage already has its maximum
+    /// number of clients.
+    pub fn try_add_client(&self, client: C) -> Result<()> {
         let mut clients = self.clients.lock().unwrap();
+        if self.limits.max_clients.is_some_and(| max | clients.len() >= max) {
+            return Err(errors::POSError::Storage("Too many clients".into()))?;
+        }
+        clients.push(client);
+        self.check_client_limit(clients.len());
+        Ok(())
+    }
+
+    pub fn remove_client(&self, client: C, reason: DisconnectReason) {
+        let mut clients = self.clients.lock().unwrap();
+        let count = clients.len();
         clients.retain(| c | c != &client);
+        if clients.len() < count {
+            client.close(&reason);
+        }
+        self.check_client_limit(clients.len());
+    }
+
+    /// The connected clients
+    pub fn clients(&self) -> Vec<C> {
+        self.clients.lock().unwrap().clone()
+    }
+
+    /// Send heartbeats to clients, removing those that can't be sent to.
+    pub fn heartbeat(&self) {
+        self.send_to_clients(| client | client.heartbeat());
+    }
+
+    /// Send clients the storage's object count, size and last
+    /// transaction, removing those that can't be sent to.
+    pub fn push_info(&self) {
+        let (len, size, last_tid) = (self.len() as u64, self.size(), self.last_transaction());
+        self.send_to_clients(| client | client.info(len, size, &last_tid));
+    }
+
+    fn send_to_clients(&self, send: impl Fn(&C) -> Result<()>) {
+        let mut clients = self.clients.lock().unwrap();
+        let failed: Vec<C> = clients.iter()
+            .filter(| client | send(client).is_err())
+            .cloned()
+            .collect();
+        if ! failed.is_empty() {
+            clients.retain(| c | ! failed.contains(c));
+            for client in failed.iter() {
+                client.close(&DisconnectReason::SendFailed);
+            }
+            self.check_client_limit(clients.len());
+        }
+    }
+
+    /// Remove all clients, e.g. when shutting down.
+    pub fn remove_clients(&self, reason: DisconnectReason) {
+        let mut clients = self.clients.lock().unwrap();
+        for client in clients.drain(..) {
+            client.close(&reason);
+        }
+        self.check_client_limit(0);
     }
 
     pub fn client_count(&self) -> usize {
         self.clients.lock().unwrap().len()
     }
 
-    fn load_index(path: &str, mut file: &std::fs::File, size: u64)
-                  -> std::io::Result<(index::Index, util::Tid, util::Oid)> {
-
-        let (mut index, segment_size, mut end) =
-            if std::path::Path::new(&path).exists() {
-                let (index, segment_size, start, end) =
-                    index::load_index(path)?;
-                util::io_assert(size >= segment_size, "Index bad segment length")?;
-                file.seek(std::io::SeekFrom::Start(records::HEADER_SIZE + 12))?;
-                util::io_assert(util::read8(&mut file)? == start, "Index bad start")?;
-                file.seek(std::io::SeekFrom::Start(segment_size - 8))?;
-                util::io_assert(util::read8(&mut file)? == end, "Index bad end")?;
-                (index, segment_size, end)
+    // Load the index file at path, if there is one, and apply its
+    // journal, if there is one, checking that they match the data
+    // file, returning the index, the size of the data it covers, the
+    // last tid in them and the number of journal entries applied.
+    fn load_saved_index(path: &str, mut file: &std::fs::File, size: u64)
+                        -> std::io::Result<Option<(index::Index, u64, util::Tid, u64)>> {
+        let saved = std::path::Path::new(&path).exists();
+        if ! saved && ! std::path::Path::new(&index::journal_path(path)).exists() {
+            return Ok(None);
+        }
+        let (mut index, mut segment_size, start, mut end) =
+            if saved {
+                index::load_index(path)?
             }
             else {
-                (index::Index::new(), records::HEADER_SIZE, util::Z64)
+                (index::Index::new(), records::HEADER_SIZE, util::Z64, util::Z64)
+            };
+        let journaled = index::replay_journal(path, &mut index, &mut segment_size, &mut end)?;
+        util::io_assert(size >= segment_size, "Index bad segment length")?;
+        if saved {
+            file.seek(std::io::SeekFrom::Start(records::HEADER_SIZE + 12))?;
+            util::io_assert(util::read8(&mut file)? == start, "Index bad start")?;
+        }
+        util::io_assert(committed_tid_before(file, segment_size)? == end,
+                        "Index bad end")?;
+        Ok(Some((index, segment_size, end, journaled)))
+    }
+
+    // Load the index, and update it from transactions after the part
+    // of the file it covers, returning it, the last tid and oid, the
+    // number of transactions added, and the end of the complete
+    // records.
+    fn load_index(path: &str, file: &std::fs::File, size: u64, version: u32)
+                  -> std::io::Result<(index::Index, util::Tid, util::Oid, u64, u64)> {
+
+        // An index that can't be read, or doesn't match the data
+        // file, e.g. because the data file was restored from a
+        // backup, is ignored, and the index is rebuilt from the data
+        // file.  It's replaced at the next checkpoint.
+        let (mut index, segment_size, mut end, journaled) =
+            match FileStorage::<C>::load_saved_index(path, file, size) {
+                Ok(Some(saved)) => saved,
+                Ok(None) => (index::Index::new(), records::HEADER_SIZE, util::Z64, 0),
+                Err(err) => {
+                    tracing::warn!("{}: ignoring index ({}), rebuilding it from the data file",
+                                   path, err);
+                    (index::Index::new(), records::HEADER_SIZE, util::Z64, 0)
+                },
             };
 
         let mut last_oid = util::Z64;
+        // Journaled transactions aren't in the index file either.
+        let mut replayed = journaled;
+        let mut complete = size;
         if segment_size < size {
             // Read newer records into index
             let mut reader = std::io::BufReader::new(file.try_clone()?);
             let mut pos = segment_size;
             util::seek(&mut reader, pos)?;
             while pos < size {
+                // A transaction being voted when the server stopped
+                // may be incomplete.  It's uncommitted, and discarded
+                // along with anything after it.
+                if size - pos < 12 {
+                    complete = pos;
+                    break;
+                }
                 let marker = util::read4(&mut reader)?;
                 let length = match &marker {
                     m if m == TRANSACTION_MARKER => {
                         let header =
-                            records::TransactionHeader::read(&mut reader)?;
+                            records::TransactionHeader::read(&mut reader, version)?;
                         last_oid = header.update_index(
-                            &mut reader, &mut index, last_oid)?;
+                            &mut reader, version, &mut index, last_oid)?;
                         assert!(header.id > end);
                         end = header.id;
+                        replayed += 1;
                         header.length
                     },
                     m if m == transaction::PADDING_MARKER => {
-                        reader.read_u64::<BigEndian>()?
+                        let (_, length) =
+                            records::split_length(reader.read_u64::<BigEndian>()?, version);
+                        let incomplete = length > size - pos || length == size - pos && {
+                            util::seek(&mut reader, size - 8)?;
+                            util::read_u64(&mut reader)? != length
+                        };
+                        if incomplete {
+                            complete = pos;
+                            break;
+                        }
+                        length
                     },
                     _ => {
                         util::io_assert(
@@ -165,16 +784,30 @@ impl<C: Client> FileStorage<C> {
                 };
                 pos += length;
                 util::seek(&mut reader, pos - 8)?;
-                assert_eq!(util::read_u64(&mut reader)?, length);
+                util::io_assert(util::read_u64(&mut reader)? == length,
+                                &format!("Bad record length {} at {}", length, pos - length))?;
+            }
+        }
+        // The largest oid may have been saved in the index file:
+        if let Some(oid) = index.keys().next_back() {
+            if *oid > last_oid {
+                last_oid = *oid;
             }
         }
-        Ok((index, end, last_oid))
+        Ok((index, end, last_oid, replayed, complete))
     }
 
     fn new_tid(&self) -> util::Tid {
-        let mut last_tid = self.last_tid.lock().unwrap();
-        *last_tid = tid::later_than(tid::now_tid(), *last_tid);
-        *last_tid
+        use std::sync::atomic::Ordering;
+        let mut last = self.last_tid.load(Ordering::Acquire);
+        loop {
+            let tid = tid::later_than(tid::now_tid(), util::p64(last));
+            match self.last_tid.compare_exchange_weak(
+                last, BigEndian::read_u64(&tid), Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return tid,
+                Err(current) => last = current,
+            }
+        }
     }
 
     fn lookup_pos(&self, oid: &util::Oid) -> Option<u64> {
@@ -182,42 +815,535 @@ impl<C: Client> FileStorage<C> {
         index.get(oid).map(| pos | *pos)
     }
 
+    /// Latencies of client requests, by method
+    pub fn latencies(&self) -> &std::sync::Arc<crate::latency::Latencies> {
+        &self.latencies
+    }
+
+    /// Count one in every rate loads, by oid, to find frequently
+    /// loaded objects.  A rate of 0 turns sampling off and discards
+    /// the counts.
+    pub fn set_access_sampling(&self, rate: u32) {
+        use std::sync::atomic::Ordering;
+        self.access_sample_rate.store(rate, Ordering::Relaxed);
+        if rate == 0 {
+            self.access_counts.lock().unwrap().clear();
+        }
+    }
+
+    /// Keep blobs in a directory with ZODB's bushy layout, so clients
+    /// can store and load them.  Storages don't have blobs otherwise.
+    ///
+    /// If the directory is shared, clients on the same file system
+    /// can store blobs they wrote to it.
+    pub fn set_blob_dir(&self, path: &str, shared: bool) -> std::io::Result<()> {
+        let dir = blobs::BlobDir::open(path, shared)?;
+        self.blobs.set(dir).map_err(| _ | util::io_error("The blob directory is already set"))
+    }
+
+    pub fn blob_dir(&self) -> Option<&blobs::BlobDir> {
+        self.blobs.get()
+    }
+
+    /// Let clients back the storage up to files in a directory,
+    /// which is created if needed.  Clients can't write backups
+    /// otherwise.
+    pub fn set_backup_dir(&self, path: &str) -> std::io::Result<()> {
+        std::fs::create_dir_all(path)?;
+        self.backup_dir.set(path.to_string())
+            .map_err(| _ | util::io_error("The backup directory is already set"))
+    }
+
+    /// Where a backup path given by a client is.  Paths are relative
+    /// to the backup directory, and can't leave it.
+    pub fn backup_path(&self, path: &str) -> Result<String> {
+        let dir = self.backup_dir.get()
+            .ok_or_else(|| anyhow::anyhow!("The storage has no backup directory"))?;
+        let inside = ! path.is_empty() &&
+            std::path::Path::new(path).components()
+            .all(| c | matches!(c, std::path::Component::Normal(_)));
+        if ! inside {
+            return Err(anyhow::anyhow!("Backup path {:?} isn't in the backup directory", path));
+        }
+        Ok(format!("{}/{}", dir, path))
+    }
+
+    /// Open an object's blob as committed by the transaction with id
+    /// serial.
+    pub fn load_blob(&self, oid: &util::Oid, serial: &util::Tid) -> Result<std::fs::File> {
+        let dir = self.blobs.get().ok_or_else(|| errors::POSError::Storage(
+            "Blobs aren't supported by this storage".to_string()))?;
+        match dir.load(oid, serial) {
+            Ok(file) => Ok(file),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound =>
+                Err(errors::POSError::Key(*oid))?,
+            Err(err) => Err(anyhow::Error::new(err).context("opening blob")),
+        }
+    }
+
+    /// Remove unreachable objects when packing, finding the objects
+    /// object data refer to with references.  Without an extractor,
+    /// packing keeps all objects.
+    pub fn set_references_extractor(
+        &self, references: Option<std::sync::Arc<dyn pack::ReferencesExtractor>>) {
+        *self.references.lock().unwrap() = references;
+    }
+
+    /// Send committed changes to sinks, replacing any sinks set
+    /// before.  Sinks are called in the order given, in a separate
+    /// thread.
+    pub fn set_change_sinks(&self, sinks: Vec<Box<dyn cdc::Sink>>) {
+        *self.changes.lock().unwrap() =
+            if sinks.is_empty() { None } else { Some(cdc::start(sinks)) };
+    }
+
+    fn sample_access(&self, oid: &util::Oid) {
+        use std::sync::atomic::Ordering;
+        let rate = self.access_sample_rate.load(Ordering::Relaxed) as u64;
+        if rate > 0 && self.loads.fetch_add(1, Ordering::Relaxed).is_multiple_of(rate) {
+            *self.access_counts.lock().unwrap().entry(*oid).or_insert(0) += 1;
+        }
+    }
+
+    /// Return up to count of the most frequently loaded objects, with
+    /// their estimated numbers of loads, most loaded first.
+    pub fn hot_objects(&self, count: usize) -> Vec<(util::Oid, u64)> {
+        use std::sync::atomic::Ordering;
+        let rate = self.access_sample_rate.load(Ordering::Relaxed) as u64;
+        let mut hot: Vec<(util::Oid, u64)> =
+            self.access_counts.lock().unwrap().iter()
+            .map(| (oid, n) | (*oid, n * rate))
+            .collect();
+        hot.sort_by(| a, b | b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot.truncate(count);
+        hot
+    }
+
     pub fn load_before(&self, oid: &util::Oid, tid: &util::Tid)
                        -> Result<LoadBeforeResult> {
+        Ok(self.load_before_range(oid, tid, 0, u64::MAX)?.0)
+    }
+
+    /// Load up to size bytes of an object's data, starting at
+    /// offset, as load_before would, with the length of all of the
+    /// data, or 0 if nothing was loaded.
+    ///
+    /// Clients load big objects a piece at a time this way, so
+    /// they needn't be held in memory whole.
+    pub fn load_before_range(&self, oid: &util::Oid, tid: &util::Tid, offset: u64, size: u64)
+                             -> Result<(LoadBeforeResult, u64)> {
+        if offset == 0 {
+            self.sample_access(oid);
+        }
+        let _moving = self.moving.read().unwrap();
         match self.lookup_pos(oid) {
             Some(pos) => {
-                let p = self.readers.get().context("getting reader")?;
-                let mut file = p.try_clone()?;
-                file.seek(std::io::SeekFrom::Start(pos))
-                    .context("seeking to object record")?;
-                let mut header =
-                    records::DataHeader::read(&mut &file)
-                    .context("Reading object header")?;
-                let mut next: Option<util::Tid> = None;
-                while &header.tid >= tid {
-                    if header.previous == 0 {
-                        return Ok(LoadBeforeResult::NoneBefore);
-                    }
-                    next = Some(header.tid);
-                    file.seek(std::io::SeekFrom::Start(header.previous))
-                        .context("seeking to previous")?;
-                    header =
-                        records::DataHeader::read(&mut &file)
-                        .context("reading previous header")?;
+                let source = self.source()?;
+                let file = source.reader();
+                match self.find_revision(file, oid, pos, | t | t < tid)? {
+                    Some((link, next)) if link.compressed => {
+                        // Compressed data are decompressed whole.
+                        let data = self.read_cached_revision(file, oid, &link)?;
+                        let length = data.len() as u64;
+                        let offset = std::cmp::min(offset, length) as usize;
+                        let end = offset + std::cmp::min(size, length - offset as u64) as usize;
+                        Ok((LoadBeforeResult::Loaded(data[offset .. end].to_vec(), link.tid, next),
+                            length))
+                    },
+                    Some((link, next)) => Ok((LoadBeforeResult::Loaded(
+                        if offset == 0 && size >= link.length {
+                            self.read_cached_revision(file, oid, &link)?
+                        }
+                        else {
+                            self.read_revision_range(file, &link, offset, size)?
+                        },
+                        link.tid, next), link.length)),
+                    None => Ok((LoadBeforeResult::NoneBefore, 0)),
                 }
-                Ok(LoadBeforeResult::Loaded(
-                    util::read_sized(&mut &file, header.length as usize)
-                        .context("Reading object data")?,
-                    header.tid, next))
             },
-            None => Ok(LoadBeforeResult::PosKeyError),
+            None => Ok((LoadBeforeResult::PosKeyError, 0)),
+        }
+    }
+
+    /// Load several objects as load_before would, with one index
+    /// lookup and one reader for all of them, for clients warming
+    /// their caches.
+    pub fn load_befores(&self, oids: &[util::Oid], tid: &util::Tid)
+                        -> Result<Vec<LoadBeforeResult>> {
+        let _moving = self.moving.read().unwrap();
+        let positions: Vec<Option<u64>> = {
+            let index = self.index.lock().unwrap();
+            oids.iter().map(| oid | index.get(oid).cloned()).collect()
+        };
+        let source = self.source()?;
+        let file = source.reader();
+        oids.iter().zip(positions).map(| (oid, pos) | {
+            self.sample_access(oid);
+            match pos {
+                Some(pos) => match self.find_revision(file, oid, pos, | t | t < tid)? {
+                    Some((link, next)) => Ok(LoadBeforeResult::Loaded(
+                        self.read_cached_revision(file, oid, &link)?, link.tid, next)),
+                    None => Ok(LoadBeforeResult::NoneBefore),
+                },
+                None => Ok(LoadBeforeResult::PosKeyError),
+            }
+        }).collect()
+    }
+
+    /// Load the revision of an object committed by the transaction
+    /// with id serial, if there is one.
+    pub fn load_serial(&self, oid: &util::Oid, serial: &util::Tid)
+                       -> Result<Option<util::Bytes>> {
+        let _moving = self.moving.read().unwrap();
+        match self.lookup_pos(oid) {
+            Some(pos) => {
+                let source = self.source()?;
+                let file = source.reader();
+                match self.find_revision(file, oid, pos, | t | t <= serial)? {
+                    Some((link, _)) if &link.tid == serial =>
+                        Ok(Some(self.read_cached_revision(file, oid, &link)?)),
+                    _ => Ok(None),
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Load the current record of the first object with an oid at
+    /// or after start, or of the first object, if start is None.
+    /// Returns None if there are no such objects.
+    pub fn record_iternext(&self, start: Option<&util::Oid>) -> Result<Option<CurrentRecord>> {
+        let _moving = self.moving.read().unwrap();
+        let (oid, pos, next) = {
+            let index = self.index.lock().unwrap();
+            let mut records = index.range(*start.unwrap_or(&util::Z64)..);
+            match records.next() {
+                Some((oid, pos)) => (*oid, *pos, records.next().map(| (oid, _) | *oid)),
+                None => return Ok(None),
+            }
+        };
+        let source = self.source()?;
+        let file = source.reader();
+        let (link, _) = self.find_revision(file, &oid, pos, | _ | true)?
+            .context("reading current record")?;
+        let data = self.read_revision(file, &link)?;
+        Ok(Some(CurrentRecord { oid, tid: link.tid, data, next }))
+    }
+
+    /// Describe up to size of an object's revisions, newest first.
+    /// Returns None if there's no such object.
+    pub fn history(&self, oid: &util::Oid, size: usize) -> Result<Option<Vec<Revision>>> {
+        let _moving = self.moving.read().unwrap();
+        let mut pos = match self.lookup_pos(oid) {
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+        let reader = self.reader();
+        let mut file = mapped::Reader::file(&reader);
+        let mut revisions = vec![];
+        while revisions.len() < size {
+            util::seek(&mut file, pos)?;
+            let header = records::DataHeader::read(&mut file, self.version)
+                .context("Reading object header")?;
+            // Compressed data record their size, which is what's reported:
+            let size = if header.compressed {
+                records::decompressed_size(&util::read_sized(
+                    &mut file,
+                    std::cmp::min(header.length as usize, records::FRAME_HEADER_SIZE))?)?
+            }
+            else {
+                header.length
+            };
+            // Data records know their offsets in their transactions:
+            let transaction =
+                scan::read_transaction(&mut file, pos - header.offset, self.version)?;
+            revisions.push(Revision {
+                tid: header.tid, size,
+                user: transaction.user, description: transaction.desc,
+            });
+            if header.previous == 0 {
+                break;
+            }
+            pos = header.previous;
+        }
+        Ok(Some(revisions))
+    }
+
+    /// Describe committed transactions for which matches returns
+    /// true, newest first, skipping the first first of them and
+    /// stopping before the last.
+    pub fn undo_log(&self, first: usize, last: usize,
+                    matches: impl Fn(&TransactionInfo) -> bool)
+                    -> Result<Vec<TransactionInfo>> {
+        let _moving = self.moving.read().unwrap();
+        let reader = self.reader();
+        let mut file = mapped::Reader::file(&reader);
+        let mut pos = self.size();
+        let mut count = 0;
+        let mut transactions = vec![];
+        // Records end with their lengths, so we can walk back from the end.
+        while pos > records::HEADER_SIZE && count < last {
+            util::seek(&mut file, pos - 8)?;
+            let length = util::read_u64(&mut file)?;
+            if length == 0 || length > pos - records::HEADER_SIZE {
+                return Err(anyhow::anyhow!("Bad record length {} before {}", length, pos));
+            }
+            pos -= length;
+            let record = scan::read_transaction(&mut file, pos, self.version)?;
+            if ! record.committed {
+                continue;
+            }
+            let info = TransactionInfo {
+                tid: record.tid(), user: record.user,
+                description: record.desc, extension: record.ext,
+            };
+            if matches(&info) {
+                if count >= first {
+                    transactions.push(info);
+                }
+                count += 1;
+            }
+        }
+        Ok(transactions)
+    }
+
+    /// Index every revision, so historical loads needn't walk
+    /// revision chains, or stop.  Building the index reads the whole
+    /// data file.
+    pub fn set_revision_index(&self, on: bool) -> Result<()> {
+        if on {
+            self.build_revision_index()
+        }
+        else {
+            *self.revisions.lock().unwrap() = None;
+            Ok(())
+        }
+    }
+
+    /// The number of revisions indexed, if they are
+    pub fn indexed_revisions(&self) -> Option<usize> {
+        self.revisions.lock().unwrap().as_ref().map(| revisions | revisions.revisions())
+    }
+
+    fn build_revision_index(&self) -> Result<()> {
+        // Most of the file is read without holding up commits, and
+        // the rest with commits held up.
+        let _moving = self.moving.read().unwrap();
+        let mut revisions = chains::RevisionIndex::default();
+        let end = *self.index_end.lock().unwrap();
+        self.index_revisions(&mut revisions, records::HEADER_SIZE, end)?;
+        let _voted = self.voted.lock().unwrap();
+        let index_end = *self.index_end.lock().unwrap();
+        self.index_revisions(&mut revisions, end, index_end)?;
+        *self.revisions.lock().unwrap() = Some(revisions);
+        Ok(())
+    }
+
+    fn index_revisions(&self, revisions: &mut chains::RevisionIndex, start: u64, end: u64)
+                       -> Result<()> {
+        let file = std::fs::File::open(&self.path).context("opening data file")?;
+        let mut it = scan::TransactionIterator::new(file, end)?.with_start(start);
+        while let Some(record) = it.next() {
+            let record = record?;
+            for (pos, header) in record.data_headers(it.reader())? {
+                revisions.add(header.id, header.tid, pos);
+            }
+        }
+        Ok(())
+    }
+
+    // Find an object's newest revision whose tid is found, given its
+    // current record at pos, returning it and the tid of the
+    // revision after it, if any.  If found is true of a revision, it
+    // must be true of earlier ones.
+    //
+    // Revisions are looked up in the revision index, if there is
+    // one, and otherwise walked, newest first.
+    fn find_revision(&self, file: mapped::Reader<'_>, oid: &util::Oid, pos: u64,
+                     found: impl Fn(&util::Tid) -> bool)
+                     -> Result<Option<(chains::Link, Option<util::Tid>)>> {
+        let mut file = file;
+        let indexed = self.revisions.lock().unwrap().as_ref()
+            .and_then(| revisions | revisions.get(oid, pos))
+            .map(| revisions | {
+                let n = revisions.partition_point(| (tid, _) | found(tid));
+                (n.checked_sub(1).map(| i | revisions[i].1), revisions.get(n).map(| r | r.0))
+            });
+        if let Some((at, next)) = indexed {
+            return match at {
+                Some(at) => {
+                    util::seek(&mut file, at)?;
+                    let header = records::DataHeader::read(&mut file, self.version)
+                        .context("Reading object header")?;
+                    Ok(Some((chains::Link {
+                        tid: header.tid, pos: at, length: header.length,
+                        previous: header.previous, checksum: header.checksum,
+                        compressed: header.compressed }, next)))
+                },
+                None => Ok(None),
+            };
+        }
+
+        // Links walked before, if any
+        let mut links = self.chains.lock().unwrap().get(oid, pos)
+            .cloned().unwrap_or_default();
+        let known = links.len();
+        let mut next: Option<util::Tid> = None;
+        let mut i = 0;
+        let link = loop {
+            if i == links.len() {
+                let at = if i == 0 { pos } else { links[i - 1].previous };
+                file.seek(std::io::SeekFrom::Start(at))
+                    .context("seeking to object record")?;
+                let header = records::DataHeader::read(&mut file, self.version)
+                    .context("Reading object header")?;
+                links.push(chains::Link {
+                    tid: header.tid, pos: at, length: header.length,
+                    previous: header.previous, checksum: header.checksum,
+                    compressed: header.compressed });
+            }
+            let link = links[i];
+            if found(&link.tid) {
+                break Some(link);
+            }
+            if link.previous == 0 {
+                break None;
+            }
+            next = Some(link.tid);
+            i += 1;
+        };
+        // Current records' headers are only worth keeping if their
+        // data are cached.
+        let keep = if self.records.lock().unwrap().is_some() { known } else { known.max(1) };
+        if links.len() > keep {
+            self.chains.lock().unwrap().put(*oid, pos, links);
+        }
+        Ok(link.map(| link | (link, next)))
+    }
+
+    fn reader(&self) -> std::sync::Arc<std::fs::File> {
+        self.reader.lock().unwrap().clone()
+    }
+
+    /// Read records for loads from a memory map of the data file,
+    /// rather than with the shared read handle.
+    pub fn set_mapped_reads(&self, on: bool) {
+        *self.maps.lock().unwrap() =
+            if on { Some(mapped::Maps::new(&self.path, mapped::REMAP_SLACK)) } else { None };
+    }
+
+    pub fn mapped_reads(&self) -> bool {
+        self.maps.lock().unwrap().is_some()
+    }
+
+    /// Compress the data of records saved by transactions begun from
+    /// now on, when that makes them smaller, with the newest of the
+    /// data file's dictionaries, if it has any.  Compressed records
+    /// are read whether this is on or not.  Data files written before
+    /// records could be compressed must be upgraded, with compact,
+    /// first.
+    pub fn set_compression(&self, on: bool) -> std::io::Result<()> {
+        util::io_assert(! on || records::compression(self.version),
+                        "Compression needs a data file in the current format; \
+                         upgrade it with byteserver compact")?;
+        self.compress.store(on, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn compression(&self) -> bool {
+        self.compress.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// The data file's zstd dictionaries, as of when the storage was
+    /// opened
+    pub fn dictionaries(&self) -> std::sync::Arc<dictionaries::Dictionaries> {
+        self.dictionaries.clone()
+    }
+
+    /// Allocate disk space for the data file this many bytes at a
+    /// time, ahead of appends, or, if it's 0, as data are appended.
+    pub fn set_preallocation(&self, bytes: u64) {
+        self.appender.set_preallocation(bytes);
+    }
+
+    pub fn preallocation(&self) -> u64 {
+        self.appender.preallocation()
+    }
+
+    // Get what loads read records with: a map of the data file, if
+    // reads are mapped, or the shared read handle.  Callers must hold the
+    // moving lock.
+    fn source(&self) -> Result<Source> {
+        let end = self.size();
+        match *self.maps.lock().unwrap() {
+            Some(ref mut maps) =>
+                Ok(Source::Mapped(maps.get(end).context("mapping data file")?)),
+            None => Ok(Source::File(self.reader())),
+        }
+    }
+
+    /// Cache up to size bytes of recently loaded object data, so hot
+    /// objects are loaded without reading the data file.  A size of
+    /// 0 turns caching off.
+    pub fn set_record_cache_size(&self, size: u64) {
+        *self.records.lock().unwrap() =
+            if size > 0 { Some(cache::RecordCache::new(size)) } else { None };
+    }
+
+    /// How the record cache is doing, if there is one
+    pub fn record_cache_stats(&self) -> Option<cache::CacheStats> {
+        self.records.lock().unwrap().as_ref().map(| records | records.stats())
+    }
+
+    // Read a revision's data, from the record cache if it has them,
+    // adding them to it if not.
+    fn read_cached_revision(&self, file: mapped::Reader<'_>, oid: &util::Oid,
+                            link: &chains::Link) -> Result<util::Bytes> {
+        if let Some(ref mut records) = *self.records.lock().unwrap() {
+            if let Some(data) = records.get(oid, &link.tid) {
+                return Ok(data.clone());
+            }
+        }
+        let data = self.read_revision(file, link)?;
+        if let Some(ref mut records) = *self.records.lock().unwrap() {
+            records.put(*oid, link.tid, data.clone());
+        }
+        Ok(data)
+    }
+
+    // Read a revision's data, checking them against the record's
+    // checksum, if it has one, and decompressing them, if they're
+    // compressed.
+    fn read_revision(&self, file: mapped::Reader<'_>, link: &chains::Link)
+                     -> Result<util::Bytes> {
+        let data = self.read_revision_range(file, link, 0, u64::MAX)?;
+        if ! records::checksum_ok(link.checksum, &data) {
+            return Err(errors::POSError::Corrupted(format!(
+                "Data record at {} (tid {}) doesn't match its checksum",
+                link.pos, util::hex(&link.tid))))?;
         }
+        records::decode(link.compressed, data, &self.dictionaries).map_err(
+            | err | errors::POSError::Corrupted(format!(
+                "Data record at {} (tid {}) can't be decompressed: {}",
+                link.pos, util::hex(&link.tid), err)).into())
+    }
+
+    fn read_revision_range(&self, mut file: mapped::Reader<'_>, link: &chains::Link,
+                           offset: u64, size: u64) -> Result<util::Bytes> {
+        let offset = std::cmp::min(offset, link.length);
+        let size = std::cmp::min(size, link.length - offset);
+        file.seek(std::io::SeekFrom::Start(link.pos + records::data_header_size(self.version) + offset))
+            .context("seeking to object data")?;
+        util::read_sized(&mut file, size as usize).context("Reading object data")
     }
 
     pub fn lock(&self,
                 transaction: &transaction::Transaction,
                 locked: Box<dyn Fn(util::Tid)>)
                 -> Result<()> {
+        if self.limits.read_only {
+            return Err(errors::POSError::ReadOnly)?;
+        }
         let (tid, oids) = transaction.lock_data()?;
         let mut locker = self.locker.lock().unwrap();
         locker.lock(tid, oids, locked);
@@ -225,23 +1351,78 @@ impl<C: Client> FileStorage<C> {
     }
 
     pub fn new_oids(&self) -> Vec<util::Oid> {
+        self.allocate_oids(100).map(util::p64).collect()
+    }
+
+    pub fn new_oid(&self) -> util::Oid {
+        util::p64(self.allocate_oids(1).start)
+    }
+
+    fn allocate_oids(&self, count: u64) -> std::ops::Range<u64> {
         let mut last_oid = self.last_oid.lock().unwrap();
-        let result: Vec<util::Oid> =
-            (*last_oid + 1 .. *last_oid + 101).map(| oid | util::p64(oid)).collect();
-        *last_oid += 100;
-        result
+        let start = *last_oid + 1;
+        *last_oid += count;
+        start .. start + count
     }
 
     pub fn tpc_begin(&self, user: &[u8], desc: &[u8], ext: &[u8])
-                 -> std::io::Result<transaction::Transaction> {
-        Ok(transaction::Transaction::begin(
-                self.tmps.get()?,
-                self.new_tid(), user, desc, ext)?)
+                 -> Result<transaction::Transaction> {
+        // Space errors are StorageErrors, for clients:
+        self.check_space(self.size(), 0)?;
+        let mut trans = transaction::Transaction::begin(
+            self.tmps.get()?,
+            self.new_tid(), user, desc, ext, self.version)?;
+        trans.set_limits(self.limits.max_transaction_size,
+                         self.limits.max_transaction_records);
+        trans.set_compression(self.compress.load(std::sync::atomic::Ordering::Relaxed));
+        trans.set_dictionaries(self.dictionaries.clone());
+        Ok(trans)
+    }
+
+    /// Begin a transaction to be committed with the given id, to copy
+    /// transactions from another storage.  The id must be later than
+    /// that of the last transaction when the transaction is voted.
+    pub fn tpc_begin_restore(&self, tid: &util::Tid, user: &[u8], desc: &[u8], ext: &[u8])
+                             -> Result<transaction::Transaction> {
+        let mut trans = self.tpc_begin(user, desc, ext)?;
+        trans.tid = Some(*tid);
+        Ok(trans)
+    }
+
+    /// Save a revision of an object, as committed elsewhere, in a
+    /// transaction begun with tpc_begin_restore.
+    ///
+    /// Serial must be the transaction's id.  Revisions aren't checked
+    /// for conflicts.  There are no back-pointer records, so if data
+    /// is None, the data prev_txn wrote for the object are copied.
+    pub fn restore(&self, trans: &mut transaction::Transaction,
+                   oid: util::Oid, serial: &util::Tid,
+                   data: Option<&[u8]>, prev_txn: Option<&util::Tid>)
+                   -> Result<()> {
+        if trans.tid.as_ref() != Some(serial) {
+            return Err(errors::POSError::StorageTransaction(
+                "Restored serial doesn't match the transaction id".to_string()))?;
+        }
+        match (data, prev_txn) {
+            (Some(data), _) => trans.restore(oid, data)?,
+            (None, Some(prev_txn)) => {
+                let data = self.load_serial(&oid, prev_txn)?
+                    .ok_or(errors::POSError::Key(oid))?;
+                trans.restore(oid, &data)?
+            },
+            (None, None) => return Err(errors::POSError::Storage(
+                "Restoring object deletions isn't supported".to_string()))?,
+        }
+        Ok(())
     }
 
     pub fn stage(&self, trans: &mut transaction::Transaction)
              -> Result<Vec<Conflict>> {
 
+        // Previous pointers are set from positions, which mustn't move
+        // before the transaction is written.
+        let _moving = self.moving.read().unwrap();
+
         // Check for conflicts
         let oid_serials = {
             let mut oid_serials: Vec<(util::Oid, util::Tid)> = vec![];
@@ -260,12 +1441,19 @@ impl<C: Client> FileStorage<C> {
                 .collect::<Vec<(util::Oid, util::Tid, Option<u64>)>>()
         };
         let mut conflicts: Vec<Conflict> = vec![];
-        let p = self.readers.get().context("getting reader")?;
-        let mut file = p.try_clone()?;
+        let reader = self.reader();
+        let mut file = mapped::Reader::file(&reader);
         for (oid, serial, posop) in oid_serial_pos {
+            if trans.restored(&oid) {
+                if let Some(pos) = posop {
+                    trans.set_previous(&oid, pos)?;
+                }
+                continue;
+            }
             match posop {
                 Some(pos) => {
-                    file.seek(std::io::SeekFrom::Start(pos+12))
+                    file.seek(std::io::SeekFrom::Start(
+                        pos + records::data_tid_offset(self.version)))
                         .context("Seeking to serial")?;
                     let committed =
                         util::read8(&mut file).context("Reading serial")?;
@@ -289,16 +1477,62 @@ impl<C: Client> FileStorage<C> {
         if conflicts.len() == 0 {
             trans.pack().context("trans pack")?;
             let mut voted = self.voted.lock().unwrap();
-            let mut file = self.file.lock().unwrap();
-            let tid = self.new_tid();
-            let pos = file.seek(std::io::SeekFrom::End(0)).context("seek end")?;
-            let (index, length) =
-                trans.stage(tid, &mut file).context("trans stage")?;
+            if let Some(max_voted) = self.limits.max_voted {
+                // Wait a bit for earlier transactions to finish, so
+                // a stuck one can't grow the queue without bound.
+                voted = self.voted_left.wait_timeout_while(
+                    voted, VOTE_QUEUE_WAIT, | voted | voted.len() >= max_voted)
+                    .unwrap().0;
+                if voted.len() >= max_voted {
+                    return Err(errors::POSError::Transient(
+                        format!("{} voted transactions are waiting to finish",
+                                voted.len())))?;
+                }
+            }
+            let tid = match trans.tid {
+                Some(tid) => {
+                    let last = voted.back().map(| v | v.tid)
+                        .unwrap_or_else(|| self.last_transaction());
+                    if tid <= last {
+                        return Err(errors::POSError::StorageTransaction(
+                            format!("Restored transaction {} isn't after the last \
+                                     transaction, {}",
+                                    tid::tid_string(&tid), tid::tid_string(&last))))?;
+                    }
+                    self.last_tid.fetch_max(BigEndian::read_u64(&tid),
+                                            std::sync::atomic::Ordering::AcqRel);
+                    tid
+                },
+                None => self.new_tid(),
+            };
+            // Stages are serialized by the voted lock, so the
+            // transaction is appended here:
+            let pos = self.appender.end().context("getting data file size")?;
+            self.check_space(pos, trans.staged_size())?;
+            let blobs = self.store_blobs(trans, &tid)?;
+            let staged = trans.stage(tid, | data, length | {
+                self.appender.append(data.try_clone()?, length).map(| _ | ())
+            });
+            let (index, length) = match staged.context("trans stage") {
+                Ok(staged) => staged,
+                Err(err) => {
+                    remove_files(&blobs);
+                    return Err(err);
+                },
+            };
+            if let Some(max_size) = self.limits.max_size {
+                let size = pos + length;
+                self.check_soft_limit(&self.size_warned, size, max_size,
+                                      LimitWarning::Size { size, max_size });
+            }
             voted.push_back(
                 Voted { id: trans.id, pos: pos, tid: tid, index: index,
-                        finished: None, length: length });
+                        finished: None, deferred: false, length: length,
+                        voted_at: std::time::Instant::now(), blobs });
         }
         else {
+            self.conflicts.fetch_add(conflicts.len() as u64,
+                                     std::sync::atomic::Ordering::Relaxed);
             trans.unlocked()?;
             self.locker.lock().unwrap().release(&trans.id);
         }
@@ -306,23 +1540,91 @@ impl<C: Client> FileStorage<C> {
         Ok(conflicts)
     }
 
+    // Check that adding needed bytes to a data file of the given
+    // size wouldn't exceed the maximum size, or leave less than the
+    // minimum free space.
+    fn check_space(&self, size: u64, needed: u64) -> std::result::Result<(), errors::POSError> {
+        if let Some(max_size) = self.limits.max_size {
+            if size + needed > max_size || size >= max_size {
+                return Err(errors::POSError::Storage(
+                    format!("Storage is full ({} bytes)", max_size)));
+            }
+        }
+        if let Some(min_free_space) = self.limits.min_free_space {
+            let free = util::free_space(&self.reader()).map_err(
+                | err | errors::POSError::Storage(format!("Checking free space: {}", err)))?;
+            if free < min_free_space.saturating_add(needed) {
+                return Err(errors::POSError::Storage(
+                    format!("Disk is nearly full ({} bytes free, the minimum is {})",
+                            free, min_free_space)));
+            }
+        }
+        Ok(())
+    }
+
+    // Move a transaction's uploaded blobs into place, returning
+    // their paths.
+    fn store_blobs(&self, trans: &mut transaction::Transaction, tid: &util::Tid)
+                   -> Result<Vec<std::path::PathBuf>> {
+        let uploaded = trans.take_blobs();
+        if uploaded.is_empty() {
+            return Ok(vec![]);
+        }
+        let dir = match self.blobs.get() {
+            Some(dir) => dir,
+            None => {
+                remove_files(uploaded.values());
+                return Err(errors::POSError::Storage(
+                    "Blobs aren't supported by this storage".to_string()))?;
+            },
+        };
+        let mut stored = vec![];
+        for (oid, path) in uploaded.iter() {
+            match dir.store(oid, tid, path) {
+                Ok(path) => stored.push(path),
+                Err(err) => {
+                    remove_files(&stored);
+                    remove_files(uploaded.values());
+                    return Err(anyhow::Error::new(err).context("storing blob"));
+                },
+            }
+        }
+        Ok(stored)
+    }
+
     pub fn tpc_finish(&self, id: &util::Tid, finished: C) -> Result<()> {
+        self.finish(id, finished, false)
+    }
+
+    /// Finish a transaction without waiting for an fsync.
+    ///
+    /// The transaction is committed and visible right away, but a
+    /// system crash before the next fsync, at most
+    /// DEFERRED_FSYNC_INTERVAL later (plus the time the fsync takes),
+    /// loses it.  The committer's durable method is called once it's
+    /// on disk.
+    pub fn tpc_finish_deferred(&self, id: &util::Tid, finished: C) -> Result<()> {
+        self.finish(id, finished, true)
+    }
+
+    fn finish(&self, id: &util::Tid, finished: C, deferred: bool) -> Result<()> {
         let mut voted = self.voted.lock().unwrap();
 
+        if ! voted.iter().any(| v | v.id == *id) {
+            return Err(errors::POSError::StorageTransaction(
+                "Transaction isn't voted, or was aborted".to_string()))?;
+        }
         for v in voted.iter_mut() {
             if v.id == *id {
                 v.finished = Some(finished);
+                v.deferred = deferred;
 
                 // Update the transaction maker right away, so if we
                 // restart, the transaction will be there.  We don't
                 // update the index and notify clients until earlier
                 // voted transactions have finished.
-                let mut file = self.file.lock().unwrap();
-                file.seek(std::io::SeekFrom::Start(v.pos))
-                    .context("seeking tpc_finish")?;
-                file.write_all(TRANSACTION_MARKER)
+                self.appender.write(v.pos, TRANSACTION_MARKER, ! deferred)
                     .context("writing trans marker tpc_finish")?;
-                file.sync_all().context("fsync")?;
                 break;
             }
         }
@@ -341,16 +1643,44 @@ impl<C: Client> FileStorage<C> {
                 if let Some(ref finished) = v.finished {
                     let len = {
                         let mut index = self.index.lock().unwrap();
+                        let index = std::sync::Arc::make_mut(&mut index);
                         for (k, pos) in v.index.iter() {
                             index.insert(k.clone(), *pos + v.pos);
                         };
                         index.len() as u64
                     };
+                    if let Some(ref mut revisions) = *self.revisions.lock().unwrap() {
+                        for (oid, pos) in v.index.iter() {
+                            revisions.add(*oid, v.tid, *pos + v.pos);
+                        }
+                    }
 
                     let oids: Vec<util::Oid> = v.index.keys()
                         .map(| oid | oid.clone())
                         .collect();
-                    *self.committed_tid.lock().unwrap() = v.tid;
+                    self.committed_tid.store(BigEndian::read_u64(&v.tid),
+                                             std::sync::atomic::Ordering::Release);
+                    self.invalidations.lock().unwrap().add(v.tid, oids.clone());
+                    self.commits.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    if let Some(ref changes) = *self.changes.lock().unwrap() {
+                        changes.send(cdc::Commit { tid: v.tid, oids: oids.clone() });
+                    }
+                    {
+                        let mut index_end = self.index_end.lock().unwrap();
+                        let mut journal = self.journal.lock().unwrap();
+                        if let Some(ref mut j) = *journal {
+                            let changes = v.index.iter().map(| (oid, pos) | (*oid, *pos + v.pos));
+                            if let Err(err) = j.append(*index_end, v.pos + v.length, &v.tid,
+                                                       changes) {
+                                tracing::error!("{}: couldn't journal index changes, \
+                                                 saving the whole index at checkpoints instead: {}",
+                                                self.path, err);
+                                *journal = None;
+                            }
+                        }
+                        *index_end = v.pos + v.length;
+                    }
+                    *self.unsaved_transactions.lock().unwrap() += 1;
                     let mut clients = self.clients.lock().unwrap();
                     let mut clients_to_remove: Vec<C> = vec![];
 
@@ -361,11 +1691,21 @@ impl<C: Client> FileStorage<C> {
                             }
                         }
                     }
-                    if finished.finished(&v.tid, len, v.pos + v.length)
+                    if finished.finished(&v.tid, len, v.pos + v.length, &oids)
                         .is_err() {
                             clients_to_remove.push(finished.clone());
-                        };
+                        }
+                    else if v.deferred {
+                        self.deferred.lock().unwrap().push(
+                            (v.tid, finished.clone()));
+                    }
                     clients.retain(| c | ! clients_to_remove.contains(&c));
+                    for client in clients_to_remove.iter() {
+                        client.close(&DisconnectReason::SendFailed);
+                    }
+                    if ! clients_to_remove.is_empty() {
+                        self.check_client_limit(clients.len());
+                    }
                     self.locker.lock().unwrap().release(&v.id);
                 }
                 else {
@@ -374,16 +1714,19 @@ impl<C: Client> FileStorage<C> {
             }
             voted.pop_front();
         }
+        self.voted_left.notify_all();
     }
 
 
     pub fn tpc_abort(&self, id: &util::Tid) {
+        self.aborts.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         let mut voted = self.voted.lock().unwrap();
         let l = voted.len();
         voted.retain(
             | v | {
                 if &v.id == id {
                     self.locker.lock().unwrap().release(id);
+                    remove_files(&v.blobs);
                     false
                 }
                 else {
@@ -398,22 +1741,782 @@ impl<C: Client> FileStorage<C> {
         self.handle_finished_at_voted_head(voted);
     }
 
+    /// Abort voted transactions at the head of the commit queue that
+    /// have waited longer than timeout for tpc_finish, presumably
+    /// because their clients went away, so they don't hold up other
+    /// commits.
+    ///
+    /// Returns the number of transactions aborted.
+    pub fn abort_stalled(&self, timeout: std::time::Duration) -> usize {
+        let mut voted = self.voted.lock().unwrap();
+        let mut aborted = 0;
+        while let Some(v) = voted.front() {
+            let waited = v.voted_at.elapsed();
+            if v.finished.is_some() || waited < timeout {
+                break;
+            }
+            tracing::warn!("{}: aborting transaction {}, which voted {:.1}s ago \
+                          and hasn't finished",
+                               self.path, tid::tid_string(&v.tid), waited.as_secs_f64());
+            self.locker.lock().unwrap().release(&v.id);
+            remove_files(&v.blobs);
+            voted.pop_front();
+            aborted += 1;
+            self.aborts.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        self.handle_finished_at_voted_head(voted);
+        aborted
+    }
+
+    /// Count commits, aborts and conflicts since the storage was
+    /// opened, and describe the commit queue.
+    pub fn status(&self) -> Status {
+        use std::sync::atomic::Ordering;
+        let (voted, last_tid) = {
+            let voted = self.voted.lock().unwrap();
+            (voted.len(), self.last_transaction())
+        };
+        Status {
+            commits: self.commits.load(Ordering::Relaxed),
+            aborts: self.aborts.load(Ordering::Relaxed),
+            conflicts: self.conflicts.load(Ordering::Relaxed),
+            lock_waits: self.locker.lock().unwrap().waiting(),
+            clients: self.client_count(),
+            voted,
+            last_tid,
+        }
+    }
+
+    /// The number of voted transactions waiting to be committed, and
+    /// how long the first has waited, to tell whether commits are
+    /// stuck.
+    pub fn commit_queue(&self) -> (usize, Option<std::time::Duration>) {
+        let voted = self.voted.lock().unwrap();
+        (voted.len(), voted.front().map(| v | v.voted_at.elapsed()))
+    }
+
+    /// Fsync deferred commits and tell their committers.
+    ///
+    /// Returns the number of commits made durable.
+    pub fn sync_deferred(&self) -> Result<usize> {
+        // Markers for these were written before they were added.
+        let deferred = std::mem::take(&mut *self.deferred.lock().unwrap());
+        if deferred.is_empty() {
+            return Ok(0);
+        }
+        // Sync the read handle, rather than waiting for the appender,
+        // so commits can go on meanwhile.
+        self.reader().sync_all().context("fsync")?;
+        for (tid, client) in deferred.iter() {
+            client.durable(tid); // A failed client is cleaned up elsewhere.
+        }
+        Ok(deferred.len())
+    }
+
+    /// Set how many recent transactions' invalidations are kept in
+    /// memory, INVALIDATION_QUEUE_SIZE by default.
+    pub fn set_invalidation_queue_size(&self, size: usize) {
+        let mut invalidations = self.invalidations.lock().unwrap();
+        invalidations.size = size;
+        invalidations.trim();
+    }
+
+    pub fn invalidation_queue_size(&self) -> usize {
+        self.invalidations.lock().unwrap().size
+    }
+
+    /// Find the objects modified by transactions committed after tid.
+    ///
+    /// Recent transactions are found in memory.  Otherwise, the
+    /// data file is read back from the end, so the answer doesn't
+    /// depend on how long the server has been running.
+    ///
+    /// Returns the last committed tid and the modified oids, or None
+    /// if more than max_transactions were committed after tid, in
+    /// which case clients should flush their caches.
+    pub fn invalidations_since(&self, tid: &util::Tid, max_transactions: usize)
+                               -> Result<Option<(util::Tid, Vec<util::Oid>)>> {
+        let _moving = self.moving.read().unwrap();
+        let (last, mut pos) = {
+            let _voted = self.voted.lock().unwrap();
+            if let Some(found) =
+                self.invalidations.lock().unwrap().since(tid, max_transactions) {
+                    return Ok(found);
+                }
+            (self.last_transaction(), *self.index_end.lock().unwrap())
+        };
+        let reader = self.reader();
+        let mut file = mapped::Reader::file(&reader);
+        let mut oids = std::collections::BTreeSet::new();
+        let mut count = 0;
+        while pos > records::HEADER_SIZE {
+            file.seek(std::io::SeekFrom::Start(pos - 8))
+                .context("seeking to transaction length")?;
+            let length = util::read_u64(&mut file)
+                .context("reading transaction length")?;
+            let record = scan::read_transaction(&mut file, pos - length, self.version)?;
+            pos = record.pos;
+            if ! record.committed {
+                continue;
+            }
+            if &record.tid() <= tid {
+                break;
+            }
+            if count == max_transactions {
+                return Ok(None);
+            }
+            count += 1;
+            for (_, header) in record.data_headers(&mut file)? {
+                oids.insert(header.id);
+            }
+        }
+        Ok(Some((last, oids.into_iter().collect())))
+    }
+
+    /// Remove revisions of objects replaced at or before pack_tid.
+    ///
+    /// Committed data are copied to a new file without commits being
+    /// blocked.  Then commits and loads pause while data committed
+    /// meanwhile is copied and the new file replaces the old one,
+    /// which is kept with an ".old" suffix.
+    pub fn pack(&self, pack_tid: &util::Tid) -> Result<pack::Packed> {
+        self.pack_at_rate(pack_tid, None)
+    }
+
+    /// Pack, reading the data file at no more than rate bytes per
+    /// second, if given, so clients' reads aren't starved.
+    ///
+    /// Pauses, while data committed meanwhile are copied, aren't
+    /// throttled, as commits wait for them.
+    pub fn pack_at_rate(&self, pack_tid: &util::Tid, rate: Option<u64>)
+                        -> Result<pack::Packed> {
+        use std::sync::atomic::Ordering;
+        if self.limits.read_only {
+            return Err(errors::POSError::ReadOnly)?;
+        }
+        if self.packing.swap(true, Ordering::SeqCst) {
+            return Err(errors::POSError::Storage("Already packing".into()))?;
+        }
+        let result = self.pack_file(pack_tid, &mut pack::Throttle::new(rate));
+        self.packing.store(false, Ordering::SeqCst);
+        result
+    }
+
+    fn pack_file(&self, pack_tid: &util::Tid, throttle: &mut pack::Throttle)
+                 -> Result<pack::Packed> {
+        let new_path = self.path.clone() + pack::PACK_SUFFIX;
+        if std::path::Path::new(&new_path).exists() {
+            std::fs::remove_file(&new_path).context("removing old pack file")?;
+        }
+        let mut out = std::fs::OpenOptions::new()
+            .read(true).write(true).create_new(true).open(&new_path)
+            .context("creating pack file")?;
+        out.try_lock().context("locking pack file")?;
+        let end = self.size();
+        tracing::info!("{}: packing as of {}", self.path, tid::tid_string(pack_tid));
+        let references = self.references.lock().unwrap().clone();
+        let (packed_index, mut packed) =
+            pack::copy(&self.path, end, pack_tid, &mut out, references.as_deref(),
+                       throttle)?;
+        // Blobs of transactions committed by the time the pack
+        // started are collected, if their revisions were removed.
+        let copied = packed.new_size;
+        let last_copied = committed_tid_before(&self.reader(), end)
+            .context("finding the last packed transaction")?;
+
+        let _moving = self.moving.write().unwrap();
+        let mut voted = self.voted.lock().unwrap();
+        // With the voted lock held, nothing is written meanwhile.
+        let file_end = self.appender.end().context("getting data file size")?;
+        pack::append(&self.reader(), self.version, end, file_end, &mut out, &packed_index)?;
+        out.sync_all().context("fsync")?;
+
+        // The index file, and its journal, are out of date.  If we
+        // crash before saving it, it's rebuilt from the data file on
+        // open.
+        index::remove_index(&(self.path.clone() + INDEX_SUFFIX)).context("removing index")?;
+        let reader = out.try_clone().context("cloning pack file")?;
+        let old_path = self.path.clone() + crate::compact::OLD_SUFFIX;
+        std::fs::rename(&self.path, &old_path).context("renaming original")?;
+        if let Err(err) = std::fs::rename(&new_path, &self.path) {
+            std::fs::rename(&old_path, &self.path).context("restoring original")?;
+            return Err(err).context("renaming packed file");
+        }
+
+        let moved = | pos: u64 | pos - end + packed.new_size;
+        {
+            let mut index = self.index.lock().unwrap();
+            // Current records are kept, unless their objects were
+            // unreachable.
+            *index = std::sync::Arc::new(
+                index.iter().filter_map(| (oid, pos) | if *pos < end {
+                    packed_index.get(oid).map(| pos | (*oid, *pos))
+                }
+                else {
+                    Some((*oid, moved(*pos)))
+                }).collect());
+        }
+        let mut index_end = self.index_end.lock().unwrap();
+        *index_end = moved(*index_end);
+        for v in voted.iter_mut() {
+            v.pos = moved(v.pos);
+        }
+        self.appender.replace(out).context("replacing data file")?;
+        *self.reader.lock().unwrap() = std::sync::Arc::new(reader);
+        if let Some(ref mut maps) = *self.maps.lock().unwrap() {
+            maps.clear();
+        }
+        self.chains.lock().unwrap().clear();
+        // Loads walk revision chains until the index is rebuilt.
+        let revisions = self.revisions.lock().unwrap().take().is_some();
+        *self.unsaved_transactions.lock().unwrap() += 1;
+        packed.old_size = file_end;
+        packed.new_size = moved(file_end);
+        drop((index_end, voted, _moving));
+
+        self.save_index(true)?;
+        if revisions {
+            self.build_revision_index().context("rebuilding revision index")?;
+        }
+        if let Some(blobs) = self.blobs.get() {
+            let garbage = pack::blob_garbage(blobs, &self.reader(), self.version, copied,
+                                             &last_copied)?;
+            garbage.remove().context("removing blob files")?;
+            packed.blobs = garbage.files.len() as u64;
+            packed.blob_bytes = garbage.bytes;
+        }
+        tracing::info!("{}: packed, removing {} revisions, {} transactions, \
+                        {} unreachable objects and {} blob files ({} bytes), \
+                        {} bytes -> {} bytes",
+                       self.path, packed.records, packed.transactions, packed.objects,
+                       packed.blobs, packed.blob_bytes, packed.old_size, packed.new_size);
+        Ok(packed)
+    }
+
+    /// Get a view of the storage as it was before tid.
+    pub fn read_view(&self, tid: util::Tid) -> ReadView<'_, C> {
+        ReadView { fs: self, tid }
+    }
+
+    /// Iterate over committed transactions, in commit order, as of
+    /// the last committed transaction.
+    ///
+    /// The iterator has a file of its own, so commits and packs can
+    /// go on while it's used.
+    pub fn transactions(&self) -> Result<scan::TransactionIterator> {
+        let _moving = self.moving.read().unwrap();
+        let end = {
+            let _voted = self.voted.lock().unwrap();
+            *self.index_end.lock().unwrap()
+        };
+        let file = std::fs::File::open(&self.path).context("opening data file")?;
+        scan::TransactionIterator::new(file, end)
+    }
+
+    /// Get a read-only view of the storage as of the last committed
+    /// transaction.
+    ///
+    /// This is cheap: the index is shared until the next commit
+    /// copies it.  Commits can go on while the snapshot is used.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let _moving = self.moving.read().unwrap();
+        let (index, tid, end) = {
+            let _voted = self.voted.lock().unwrap();
+            (self.index.lock().unwrap().clone(), self.last_transaction(),
+             *self.index_end.lock().unwrap())
+        };
+        let file = std::fs::File::open(&self.path).context("opening snapshot")?;
+        Ok(Snapshot { file, index, tid, end, version: self.version,
+                      dictionaries: self.dictionaries.clone() })
+    }
+
+    /// Write a copy of the data file, and its index, to path, e.g. for
+    /// a backup, while commits go on.
+    ///
+    /// The data committed when the backup starts is copied without
+    /// holding up commits, then the data committed meanwhile is
+    /// copied with the voted lock held, so the copy ends with the last
+    /// committed transaction and the saved index matches it.  If the
+    /// storage is packed during the copy, the copy is started over.
+    ///
+    /// Returns the id of the last transaction in the copy.
+    pub fn backup(&self, path: &str) -> Result<util::Tid> {
+        let mut out = std::fs::OpenOptions::new()
+            .write(true).create_new(true).open(path)
+            .context("creating backup")?;
+        let copied = self.copy_all(&mut out)?;
+        save_copy_index(&copied.file, &copied.index, path, copied.end, &copied.tid)?;
+        tracing::info!("{}: backed up to {} as of {}",
+                       self.path, path, tid::tid_string(&copied.tid));
+        Ok(copied.tid)
+    }
+
+    /// Back up the storage to a directory, like repozo, adding a file
+    /// with the data committed since the last backup in the
+    /// directory, or a full copy, if there isn't one, or the data file
+    /// has been packed since.  See the backup module.
+    ///
+    /// Returns the directory's last backup, which is the previous one
+    /// if nothing has been committed since.
+    pub fn incremental_backup(&self, dir: &str) -> Result<backup::Entry> {
+        std::fs::create_dir_all(dir).context("creating backup directory")?;
+        // First, so the backup has the dictionaries of all its records
+        backup::copy_dictionaries(dir, &self.path)?;
+        let entries = backup::entries(dir)?;
+        let mut out = std::fs::File::create(backup::tmp_path(dir))
+            .context("creating backup file")?;
+        let (start, copied) = match entries.last() {
+            Some(last) => match self.copy_committed(
+                &mut out, last.end, | file | backup::matches(file, last))? {
+                Some(copied) if copied.end == last.end => {
+                    std::fs::remove_file(backup::tmp_path(dir))
+                        .context("removing backup file")?;
+                    return Ok(last.clone());
+                },
+                Some(copied) => (last.end, copied),
+                None => {
+                    tracing::info!("{}: changed since the last backup in {}, \
+                                    probably by a pack, so making a full backup",
+                                   self.path, dir);
+                    (0, self.copy_all(&mut out)?)
+                },
+            },
+            None => (0, self.copy_all(&mut out)?),
+        };
+        let entry = backup::add(dir, &entries, start, copied.end, &copied.tid)?;
+        tracing::info!("{}: backed up to {}/{} as of {}",
+                       self.path, dir, entry.name, tid::tid_string(&copied.tid));
+        Ok(entry)
+    }
+
+    // Copy all of the committed data to out, for backups.
+    fn copy_all(&self, out: &mut std::fs::File) -> Result<Copied> {
+        Ok(self.copy_committed(out, 0, | _ | Ok(true))?
+           .expect("copies are only skipped if the data file isn't valid"))
+    }
+
+    // Copy the data committed after start to out, for backups.  The
+    // data committed when the copy starts are copied without holding
+    // up commits, then the data committed meanwhile are copied with
+    // the voted lock held, so the copy ends with the last committed
+    // transaction.  If the storage is packed during the copy, the
+    // copy is started over.  Before copying, the data file is checked
+    // with valid, and if it isn't, nothing is copied and None is
+    // returned.
+    fn copy_committed(&self, out: &mut std::fs::File, start: u64,
+                      valid: impl Fn(&std::fs::File) -> Result<bool>)
+                      -> Result<Option<Copied>> {
+        loop {
+            let (file, middle) = {
+                let _moving = self.moving.read().unwrap();
+                (self.reader(), self.size())
+            };
+            if ! valid(&file)? {
+                return Ok(None);
+            }
+            out.set_len(0).context("truncating backup")?;
+            util::seek(out, 0)?;
+            copy_data(&file, start, middle, out)?;
+
+            let _moving = self.moving.read().unwrap();
+            if ! std::sync::Arc::ptr_eq(&file, &self.reader()) {
+                tracing::info!("{}: packed during backup, starting over", self.path);
+                continue;
+            }
+            let (index, tid, end) = {
+                let _voted = self.voted.lock().unwrap();
+                let (index, tid, end) = (self.index.lock().unwrap().clone(),
+                                         self.last_transaction(), self.size());
+                copy_data(&file, middle, end, out)?;
+                (index, tid, end)
+            };
+            out.sync_all().context("fsync")?;
+            return Ok(Some(Copied { file, index, tid, end }));
+        }
+    }
+
     pub fn last_transaction(&self) -> util::Tid {
-        self.committed_tid.lock().unwrap().clone()
+        util::p64(self.committed_tid.load(std::sync::atomic::Ordering::Acquire))
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The number of objects in the database.
+    pub fn len(&self) -> usize {
+        self.index.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The size of the committed data in the data file.
+    pub fn size(&self) -> u64 {
+        *self.index_end.lock().unwrap()
+    }
+
+    /// Save the index and fsync the data file.
+    ///
+    /// If index changes are journaled, the journal is synced instead,
+    /// unless it's grown bigger than the index file would be.
+    ///
+    /// Returns the number of transactions committed since the index
+    /// was last saved.
+    pub fn checkpoint(&self) -> Result<u64> {
+        self.save_index(false)
+    }
+
+    /// Journal index changes, appending each transaction's changes
+    /// to a file next to the index file, or stop.  Checkpoints then
+    /// only sync the journal, until it's grown bigger than the index
+    /// file, when the whole index is saved and the journal starts
+    /// over.  Turning the journal on saves the whole index.
+    pub fn set_index_journal(&self, on: bool) -> Result<()> {
+        if on {
+            let mut journal = self.journal.lock().unwrap();
+            if journal.is_none() {
+                *journal = Some(index::Journal::create(&(self.path.clone() + INDEX_SUFFIX))
+                                .context("creating index journal")?);
+                drop(journal);
+                self.save_index(true)?;
+            }
+        }
+        else {
+            *self.journal.lock().unwrap() = None;
+        }
+        Ok(())
+    }
+
+    pub fn index_journal(&self) -> bool {
+        self.journal.lock().unwrap().is_some()
+    }
+
+    // Save the whole index, if full is true, or the journal is off or
+    // bigger than the index file, or otherwise sync the journal.
+    fn save_index(&self, full: bool) -> Result<u64> {
+        let _moving = self.moving.read().unwrap();
+        let index_path = self.path.clone() + INDEX_SUFFIX;
+        let (index, end, tid, count, full, journaled) = {
+            // Holding the voted lock keeps commits from updating the
+            // index while we get it.
+            let voted = self.voted.lock().unwrap();
+            let index = self.index.lock().unwrap().clone();
+            let count = *self.unsaved_transactions.lock().unwrap();
+            let mut journal = self.journal.lock().unwrap();
+            let full = full || match *journal {
+                Some(ref journal) => journal.size() > index::saved_size(index.len()),
+                None => true,
+            };
+            // The journal starts over with the transactions committed
+            // after the index we're saving.
+            if full && journal.is_some() {
+                *journal = Some(index::Journal::create(&index_path)
+                                .context("creating index journal")?);
+            }
+            (index, *self.index_end.lock().unwrap(), self.last_transaction(),
+             count, full, journal.is_some())
+        };
+        self.appender.sync().context("fsync")?;
+        if ! full {
+            if let Some(ref journal) = *self.journal.lock().unwrap() {
+                journal.sync().context("syncing index journal")?;
+            }
+        }
+        else if end > records::HEADER_SIZE {
+            let reader = self.reader();
+            let mut file = mapped::Reader::file(&reader);
+            file.seek(std::io::SeekFrom::Start(records::HEADER_SIZE + 12))
+                .context("seeking to first tid")?;
+            let start = util::read8(&mut file).context("reading first tid")?;
+            index::save_index(&index, &index_path, end, &start, &tid)
+                .context("saving index")?;
+        }
+        if full && ! journaled {
+            // A journal left from when changes were journaled is out
+            // of date.
+            let journal_path = index::journal_path(&index_path);
+            if std::path::Path::new(&journal_path).exists() {
+                std::fs::remove_file(&journal_path).context("removing index journal")?;
+            }
+        }
+        *self.unsaved_transactions.lock().unwrap() -= count;
+        Ok(count)
+    }
+
+    /// Save the index, if transactions were committed since it was
+    /// last saved, so the next open doesn't have to read them from
+    /// the data file.
+    ///
+    /// This is done automatically when the storage is dropped, but
+    /// errors are only printed then.
+    pub fn close(&self) -> Result<()> {
+        if *self.unsaved_transactions.lock().unwrap() > 0 {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Check the committed part of the data file, as ``byteserver
+    /// fsck`` does, while the storage is in use: record markers,
+    /// lengths and checksums, data records, previous-pointer chains
+    /// and that the in-memory index matches the file.
+    ///
+    /// Returns a report of the problems found, with their file
+    /// positions.  Commits continue meanwhile, but aren't checked.
+    pub fn verify(&self) -> Result<fsck::Report> {
+        let _moving = self.moving.read().unwrap();
+        let (index, end, tid) = {
+            // Holding the voted lock keeps commits from updating the
+            // index while we get it.
+            let voted = self.voted.lock().unwrap();
+            let index = self.index.lock().unwrap().clone();
+            (index, *self.index_end.lock().unwrap(), self.last_transaction())
+        };
+        let file = std::fs::File::open(&self.path).context("opening data file")?;
+        fsck::check_file(file, end, Some((&index, end, tid)))
+    }
+}
+
+/// A logical view of a storage at a historical point: loads see the
+/// data committed before the view's tid, however the database has
+/// changed since.
+pub struct ReadView<'store, C: Client> {
+    fs: &'store FileStorage<C>,
+    tid: util::Tid,
+}
+
+impl<'store, C: Client> ReadView<'store, C> {
+
+    pub fn tid(&self) -> util::Tid {
+        self.tid
+    }
+
+    /// Load an object as loadBefore(oid, tid) would.
+    pub fn load(&self, oid: &util::Oid) -> Result<LoadBeforeResult> {
+        self.fs.load_before(oid, &self.tid)
+    }
+
+    /// Load an object as of the earlier of the view's tid and before.
+    pub fn load_before(&self, oid: &util::Oid, before: &util::Tid)
+                       -> Result<LoadBeforeResult> {
+        self.fs.load_before(oid, std::cmp::min(before, &self.tid))
+    }
+
+    /// Load part of an object as of the earlier of the view's tid and before.
+    pub fn load_before_range(&self, oid: &util::Oid, before: &util::Tid,
+                             offset: u64, size: u64)
+                             -> Result<(LoadBeforeResult, u64)> {
+        self.fs.load_before_range(oid, std::cmp::min(before, &self.tid), offset, size)
+    }
+
+    /// Load several objects as of the earlier of the view's tid and before.
+    pub fn load_befores(&self, oids: &[util::Oid], before: &util::Tid)
+                        -> Result<Vec<LoadBeforeResult>> {
+        self.fs.load_befores(oids, std::cmp::min(before, &self.tid))
+    }
+}
+
+/// A read-only view of a storage pinned at a committed transaction.
+///
+/// Records before the snapshot's end are never changed by later
+/// commits, so they're read without coordinating with the storage.
+pub struct Snapshot {
+    file: std::fs::File,
+    index: std::sync::Arc<index::Index>,
+    tid: util::Tid,
+    end: u64,
+    version: u32,
+    dictionaries: std::sync::Arc<dictionaries::Dictionaries>,
+}
+
+impl Snapshot {
+
+    pub fn tid(&self) -> util::Tid {
+        self.tid
+    }
+
+    // End of the data covered by the snapshot
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn oids(&self) -> impl Iterator<Item = &util::Oid> {
+        self.index.keys()
+    }
+
+    /// Load an object's data and tid, as of the snapshot.
+    pub fn load(&mut self, oid: &util::Oid)
+                -> Result<Option<(util::Bytes, util::Tid)>> {
+        match self.index.get(oid) {
+            Some(pos) => {
+                util::seek(&mut self.file, *pos)?;
+                let header = records::DataHeader::read(&mut self.file, self.version)
+                    .context("Reading object header")?;
+                let data = util::read_sized(&mut self.file, header.length as usize)
+                    .context("Reading object data")?;
+                Ok(Some((records::decode(header.compressed, data, &self.dictionaries)
+                         .context("Decompressing object data")?, header.tid)))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Write a copy of the data file, as of the snapshot, and its
+    /// index, e.g. for a backup.  Returns the number of bytes copied.
+    pub fn save(&mut self, path: &str) -> Result<u64> {
+        util::seek(&mut self.file, 0)?;
+        let mut out = std::fs::OpenOptions::new()
+            .write(true).create_new(true).open(path)
+            .context("creating snapshot copy")?;
+        let copied = std::io::copy(&mut (&mut self.file).take(self.end), &mut out)
+            .context("copying data file")?;
+        util::io_assert(copied == self.end, "Data file is too short")?;
+        out.sync_all().context("fsync")?;
+        save_copy_index(&self.file, &self.index, path, self.end, &self.tid)?;
+        Ok(copied)
+    }
+}
+
+// Committed data copied by a backup
+struct Copied {
+    file: std::sync::Arc<std::fs::File>,
+    index: std::sync::Arc<index::Index>,
+    tid: util::Tid,
+    end: u64,
+}
+
+// Copy the data between start and end of a data file to out.
+fn copy_data(file: &std::fs::File, start: u64, end: u64, out: &mut std::fs::File)
+             -> Result<()> {
+    let mut reader = mapped::Reader::file(file);
+    util::seek(&mut reader, start)?;
+    let copied = std::io::copy(&mut reader.take(end - start), out)
+        .context("copying data file")?;
+    util::io_assert(copied == end - start, "Data file is too short")?;
+    Ok(())
+}
+
+// Save the index of a copy of a data file, made by a snapshot or
+// backup, that ends at end, unless the copy has no transactions.
+fn save_copy_index(file: &std::fs::File, index: &index::Index,
+                   path: &str, end: u64, tid: &util::Tid) -> Result<()> {
+    if end > records::HEADER_SIZE {
+        let mut reader = mapped::Reader::file(file);
+        util::seek(&mut reader, records::HEADER_SIZE + 12)?;
+        let start = util::read8(&mut reader).context("reading first tid")?;
+        index::save_index(index, &(String::from(path) + INDEX_SUFFIX), end, &start, tid)
+            .context("saving index")?;
+    }
+    Ok(())
+}
+
+// Remove files, such as blobs of transactions that weren't committed,
+// ignoring errors, since the files may already be gone.
+fn remove_files<P: AsRef<std::path::Path>>(paths: impl IntoIterator<Item = P>) {
+    for path in paths {
+        std::fs::remove_file(path);
     }
 }
 
-// TODO save index on drop.
-// impl std::ops::Drop for FileStorage {
-//     fn drop(&mut self) {
-//         let mut file = self.file.lock.unwrap();
-//         let index = self.index.lock().unwrap();
-//         let size = file.seek(std::io::SeekFrom::End(0));
-//         let
-//         index::save_index(&self.index, &(path.clone() + INDEX_SUFFIX))
-//     }
+/// Start a thread to fsync deferred commits every
+/// DEFERRED_FSYNC_INTERVAL.  The thread exits when the storage is
+/// dropped.
+pub fn start_deferred_syncer<C: Client + Sync + 'static>(
+    fs: &std::sync::Arc<FileStorage<C>>) {
+    let fs = std::sync::Arc::downgrade(fs);
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(DEFERRED_FSYNC_INTERVAL);
+            match fs.upgrade() {
+                Some(fs) => if let Err(err) = fs.sync_deferred() {
+                    tracing::error!("Deferred fsync failed: {:#}", err);
+                },
+                None => break,
+            }
+        }
+    });
+}
+
+/// Start a thread to abort transactions stalled between vote and
+/// finish, if the storage has a finish timeout.  The thread exits
+/// when the storage is dropped.
+pub fn start_watchdog<C: Client + Sync + 'static>(fs: &std::sync::Arc<FileStorage<C>>) {
+    let timeout = match fs.limits.finish_timeout {
+        Some(timeout) => timeout,
+        None => return,
+    };
+    let fs = std::sync::Arc::downgrade(fs);
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(timeout / 4);
+            match fs.upgrade() {
+                Some(fs) => { fs.abort_stalled(timeout); },
+                None => break,
+            }
+        }
+    });
+}
+
+/// Start a thread to pack the storage on a schedule, keeping the
+/// schedule's history.  Progress is logged by the packs.  The thread
+/// exits when the storage is dropped.
+pub fn start_packer<C: Client + Sync + 'static>(fs: &std::sync::Arc<FileStorage<C>>,
+                                                schedule: PackSchedule) {
+    let fs = std::sync::Arc::downgrade(fs);
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(schedule.interval);
+            let fs = match fs.upgrade() {
+                Some(fs) => fs,
+                None => break,
+            };
+            let now = tid::tid_time(&tid::now_tid());
+            let pack_tid = tid::time_tid(now - schedule.keep.as_secs_f64());
+            tracing::info!("{}: scheduled pack, keeping {} seconds of history",
+                           fs.path, schedule.keep.as_secs());
+            if let Err(err) = fs.pack_at_rate(&pack_tid, schedule.rate) {
+                tracing::error!("{}: scheduled pack failed: {:#}", fs.path, err);
+            }
+        }
+    });
+}
+
+/// Find the id of the last committed transaction ending at or before
+/// pos, skipping back over padding records.
+pub fn committed_tid_before(mut file: &std::fs::File, mut pos: u64)
+                            -> std::io::Result<util::Tid> {
+    while pos > records::HEADER_SIZE {
+        file.seek(std::io::SeekFrom::Start(pos - 8))?;
+        let length = util::read_u64(&mut file)?;
+        util::io_assert(length > 0 && length <= pos - records::HEADER_SIZE,
+                        "Bad record length")?;
+        pos -= length;
+        file.seek(std::io::SeekFrom::Start(pos))?;
+        if util::read4(&mut file)? == TRANSACTION_MARKER {
+            file.seek(std::io::SeekFrom::Start(pos + 12))?;
+            return util::read8(&mut file);
+        }
+    }
+    Ok(util::Z64)
+}
 
-// }
+impl<C: Client> std::ops::Drop for FileStorage<C> {
+    fn drop(&mut self) {
+        if let Err(err) = self.close() {
+            tracing::error!("{}: saving index on close failed: {:#}", self.path, err);
+        }
+    }
+}
 
 unsafe impl<C: Client> std::marker::Send for FileStorage<C> {}
 unsafe impl<C: Client> std::marker::Sync for FileStorage<C> {}
@@ -425,16 +2528,20 @@ pub mod testing {
     pub const MAXTID: &'static util::Tid = b"\x7f\xff\xff\xff\xff\xff\xff\xff";
 
     #[derive(Debug, PartialEq, Clone)]
-    struct NullClient;
+    pub struct NullClient;
 
     impl Client for NullClient {
-        fn finished(&self, tid: &util::Tid, len: u64, size: u64) -> Result<()> {
+        fn finished(&self, tid: &util::Tid, len: u64, size: u64,
+                    oids: &[util::Oid]) -> Result<()> {
             Ok(())
         }
         fn invalidate(&self, tid: &util::Tid, oids: &Vec<util::Oid>) -> Result<()> {
             Ok(())
         }
-        fn close(&self) {}
+        fn durable(&self, tid: &util::Tid) -> Result<()> {
+            Ok(())
+        }
+        fn close(&self, reason: &DisconnectReason) {}
     }
 
     pub fn make_sample(path: &String, transactions: Vec<Vec<(util::Oid, &[u8])>>)
@@ -471,4 +2578,20 @@ pub mod testing {
         }
         Ok(())
     }
+
+    /// Make a data file look like one written before records had
+    /// checksums: a version 1 file, without them.
+    pub fn make_version_1(path: &str) -> Result<()> {
+        make_version(path, 1)
+    }
+
+    /// Rewrite a data file in an older format version, removing its
+    /// index, which the new record positions would make wrong.
+    pub fn make_version(path: &str, version: u32) -> Result<()> {
+        let new_path = String::from(path) + crate::compact::COMPACT_SUFFIX;
+        crate::compact::rewrite_in(path, &new_path, version)?;
+        index::remove_index(&(String::from(path) + INDEX_SUFFIX))?;
+        std::fs::rename(&new_path, path)?;
+        Ok(())
+    }
 }
@@ -32,6 +32,42 @@ pub fn tm_tid(tm: time::Tm) -> Tid {
 
 pub fn now_tid() -> Tid { tm_tid(time::now_utc()) }
 
+/// Break a tid into (year, month, day, hour, minute, second).
+pub fn tid_parts(tid: &Tid) -> (u32, u32, u32, u32, u32, f64) {
+    let v = BigEndian::read_u64(tid);
+    let seconds = (v & 0xffff_ffff) as f64 * SCONV;
+    let minutes = (v >> 32) as u32;
+    let (days, minute) = (minutes / 1440, minutes % 1440);
+    let (months, day) = (days / 31, days % 31);
+    (months / 12 + 1900, months % 12 + 1, day + 1,
+     minute / 60, minute % 60, seconds)
+}
+
+/// Format a tid as a UTC timestamp, like 2016-01-02 03:04:56.789000
+pub fn tid_string(tid: &Tid) -> String {
+    let (year, month, day, hour, minute, second) = tid_parts(tid);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:09.6}",
+            year, month, day, hour, minute, second)
+}
+
+/// Seconds since the Unix epoch at which a tid was generated
+pub fn tid_time(tid: &Tid) -> f64 {
+    let (year, month, day, hour, minute, second) = tid_parts(tid);
+    let tm = time::Tm {
+        tm_year: year as i32 - 1900, tm_mon: month as i32 - 1, tm_mday: day as i32,
+        tm_hour: hour as i32, tm_min: minute as i32, tm_sec: 0, tm_nsec: 0,
+        tm_wday: 0, tm_yday: 0, tm_isdst: 0, tm_utcoff: 0,
+    };
+    tm.to_timespec().sec as f64 + second
+}
+
+/// The first tid at a time given in seconds since the Unix epoch
+pub fn time_tid(time: f64) -> Tid {
+    let seconds = time.floor();
+    tm_tid(time::at_utc(time::Timespec::new(
+        seconds as i64, ((time - seconds) * 1e9) as i32)))
+}
+
 pub fn next(tid: &Tid) -> Tid {
     let mut next = tid.clone();
     let iold = BigEndian::read_u64(&mut next);
@@ -78,6 +114,22 @@ mod tests {
                    [3, 180, 48, 88, 242, 76, 187, 82]);
     }
 
+    #[test]
+    fn test_tid_string() {
+        assert_eq!(tid_string(&make_tid(2016, 1, 2, 3, 4, 56.789)),
+                   "2016-01-02 03:04:56.789000");
+        assert_eq!(tid_string(&make_tid(1999, 12, 31, 23, 59, 0.0)),
+                   "1999-12-31 23:59:00.000000");
+    }
+
+    #[test]
+    fn test_tid_time() {
+        assert_eq!(tid_time(&make_tid(1970, 1, 2, 0, 0, 0.0)), 86400.0);
+        let time = tid_time(&make_tid(2016, 1, 2, 3, 4, 56.5));
+        assert!((time - 1451703896.5).abs() < 1e-6);
+        assert_eq!(time_tid(1451703896.5), make_tid(2016, 1, 2, 3, 4, 56.5));
+    }
+
     #[test]
     fn test_later_than() {
     
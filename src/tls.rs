@@ -0,0 +1,348 @@
+// TLS connections
+//
+// Listeners can serve TLS, and require clients to present
+// certificates signed by a given certificate authority.  A client
+// certificate's subject common name is the client's identity, which
+// permissions (see auth::Permissions) map to the storages it may use.
+//
+// Streams are shared, like cloned TcpStreams, between the code that
+// reads requests and the code that writes responses, so TLS state is
+// kept behind a lock, which isn't held while waiting for input.
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{anyhow, Context, Result};
+use rustls::pki_types::pem::PemObject;
+
+// TLS data read from a socket at a time
+const READ_SIZE: usize = 1 << 14;
+
+/// Make a server configuration from PEM files with a certificate
+/// chain and a private key.
+///
+/// With a client CA file, of one or more PEM certificates, clients
+/// must present certificates signed by one of them.
+pub fn server_config(certificate: &str, key: &str, client_ca: Option<&str>)
+                     -> Result<std::sync::Arc<rustls::ServerConfig>> {
+    let chain = read_certificates(certificate)?;
+    let key = rustls::pki_types::PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("reading {}", key))?;
+    let builder = rustls::ServerConfig::builder();
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for certificate in read_certificates(path)? {
+                roots.add(certificate).with_context(|| format!("reading {}", path))?;
+            }
+            builder.with_client_cert_verifier(
+                rustls::server::WebPkiClientVerifier::builder(std::sync::Arc::new(roots))
+                    .build()
+                    .with_context(|| format!("using {}", path))?)
+        },
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(chain, key)
+        .with_context(|| format!("using {}", certificate))?;
+    Ok(std::sync::Arc::new(config))
+}
+
+fn read_certificates(path: &str) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>> {
+    let certificates = rustls::pki_types::CertificateDer::pem_file_iter(path)
+        .and_then(| certificates | certificates.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("reading {}", path))?;
+    if certificates.is_empty() {
+        return Err(anyhow!("{} has no certificates", path));
+    }
+    Ok(certificates)
+}
+
+/// A client connection, with or without TLS
+pub enum Stream {
+    Plain(std::net::TcpStream),
+    Tls(std::sync::Arc<Tls>),
+}
+
+pub struct Tls {
+    socket: std::net::TcpStream,
+    identity: Option<String>,
+    session: std::sync::Mutex<Session>,
+}
+
+struct Session {
+    connection: rustls::ServerConnection,
+    // TLS data read, but not yet taken by the connection
+    incoming: Vec<u8>,
+    // Whether the client has closed its end
+    closed: bool,
+}
+
+/// Do the TLS handshake for a connection.
+///
+/// Give the socket a read timeout to limit how long that can take.
+pub fn accept(socket: std::net::TcpStream, config: &std::sync::Arc<rustls::ServerConfig>)
+              -> Result<Stream> {
+    let mut connection = rustls::ServerConnection::new(config.clone())?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut &socket).context("TLS handshake")?;
+    }
+    let identity = connection.peer_certificates()
+        .and_then(| certificates | certificates.first())
+        .and_then(| certificate | common_name(certificate));
+    Ok(Stream::Tls(std::sync::Arc::new(Tls {
+        socket, identity,
+        session: std::sync::Mutex::new(Session { connection, incoming: vec![], closed: false }),
+    })))
+}
+
+impl Stream {
+
+    /// The underlying socket, e.g. for setting options and polling
+    pub fn tcp(&self) -> &std::net::TcpStream {
+        match self {
+            Stream::Plain(socket) => socket,
+            Stream::Tls(tls) => &tls.socket,
+        }
+    }
+
+    pub fn try_clone(&self) -> std::io::Result<Stream> {
+        Ok(match self {
+            Stream::Plain(socket) => Stream::Plain(socket.try_clone()?),
+            Stream::Tls(tls) => Stream::Tls(tls.clone()),
+        })
+    }
+
+    /// The common name of the client's certificate, if it presented one
+    pub fn identity(&self) -> Option<&str> {
+        match self {
+            Stream::Plain(_) => None,
+            Stream::Tls(tls) => tls.identity.as_deref(),
+        }
+    }
+
+    /// Read whatever input is available, failing with WouldBlock
+    /// rather than waiting for more.
+    pub fn read_available(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => recv_available(socket, buf),
+            Stream::Tls(tls) => tls.read(buf, recv_available),
+        }
+    }
+
+    /// Tell a TLS client we're closing, and shut the socket down.
+    pub fn close(&self) {
+        if let Stream::Tls(tls) = self {
+            let mut session = tls.session.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+            session.connection.send_close_notify();
+            let _ = session.send(&tls.socket);
+        }
+        let _ = self.tcp().shutdown(std::net::Shutdown::Both);
+    }
+}
+
+fn recv_available(socket: &std::net::TcpStream, buf: &mut [u8]) -> std::io::Result<usize> {
+    let n = unsafe {
+        libc::recv(socket.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void,
+                   buf.len(), libc::MSG_DONTWAIT)
+    };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+impl Tls {
+
+    // Read decrypted input, getting TLS data from the socket with
+    // fill, without holding the lock, as needed.
+    fn read(&self, buf: &mut [u8],
+            fill: fn(&std::net::TcpStream, &mut [u8]) -> std::io::Result<usize>)
+            -> std::io::Result<usize> {
+        let mut data = vec![0u8; READ_SIZE];
+        loop {
+            {
+                let mut session = self.session.lock().unwrap();
+                session.take(&self.socket)?;
+                match session.connection.reader().read(buf) {
+                    // Clients that go away without saying so are as
+                    // gone as ones that do.
+                    Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(0),
+                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {},
+                    result => return result,
+                }
+                // Processing input can leave us with something to
+                // say, such as alerts and key updates.
+                session.send(&self.socket)?;
+            }
+            let n = fill(&self.socket, &mut data)?;
+            let mut session = self.session.lock().unwrap();
+            session.incoming.extend_from_slice(&data[.. n]);
+            session.closed = n == 0;
+        }
+    }
+}
+
+impl Session {
+
+    // Give the connection as much of the TLS data read as it wants.
+    // Failures are sent to the client, as alerts, before they're
+    // returned.
+    fn take(&mut self, socket: &std::net::TcpStream) -> std::io::Result<()> {
+        while (self.closed || ! self.incoming.is_empty()) && self.connection.wants_read() {
+            let n = self.connection.read_tls(&mut &self.incoming[..])?;
+            self.incoming.drain(.. n);
+            // Reading nothing tells the connection the client closed.
+            self.closed &= n > 0;
+            if let Err(err) = self.connection.process_new_packets() {
+                let _ = self.send(socket);
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err));
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, socket: &std::net::TcpStream) -> std::io::Result<()> {
+        while self.connection.wants_write() {
+            self.connection.write_tls(&mut &*socket)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => (&*socket).read(buf),
+            Stream::Tls(tls) => tls.read(buf, | mut socket, data | socket.read(data)),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => (&*socket).write(buf),
+            Stream::Tls(tls) => {
+                let mut session = tls.session.lock().unwrap();
+                let mut written = 0;
+                while written < buf.len() {
+                    written += session.connection.writer().write(&buf[written ..])?;
+                    session.send(&tls.socket)?;
+                }
+                Ok(written)
+            },
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Plain(socket) => (&*socket).flush(),
+            Stream::Tls(tls) => tls.session.lock().unwrap().send(&tls.socket),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl std::fmt::Debug for Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Stream::Plain(socket) => write!(f, "{:?}", socket),
+            Stream::Tls(tls) => write!(f, "TLS {:?}", tls.socket),
+        }
+    }
+}
+
+/// The subject common name of a DER-encoded certificate, if it has one
+pub fn common_name(certificate: &[u8]) -> Option<String> {
+    let (_, certificate, _) = element(certificate)?;
+    let (_, mut tbs, _) = element(certificate)?;
+    // Skip the optional version, then the serial number, signature
+    // algorithm, issuer and validity, to get to the subject, a
+    // sequence of sets of (attribute type, value) sequences.
+    if tbs.first() == Some(&0xa0) {
+        tbs = element(tbs)?.2;
+    }
+    for _ in 0 .. 4 {
+        tbs = element(tbs)?.2;
+    }
+    let (_, mut subject, _) = element(tbs)?;
+    while ! subject.is_empty() {
+        let (_, mut set, rest) = element(subject)?;
+        subject = rest;
+        while ! set.is_empty() {
+            let (_, attribute, rest) = element(set)?;
+            set = rest;
+            let (_, kind, value) = element(attribute)?;
+            if kind == COMMON_NAME {
+                let (_, value, _) = element(value)?;
+                return String::from_utf8(value.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+// The DER encoding of the common name attribute type, 2.5.4.3
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+// Split a DER element off the front of data, returning its tag, its
+// contents and what follows it.
+fn element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&length, data) = data.split_first()?;
+    let (length, data) = if length < 0x80 {
+        (length as usize, data)
+    }
+    else {
+        let size = (length & 0x7f) as usize;
+        if size == 0 || size > 4 || data.len() < size {
+            return None;
+        }
+        (data[.. size].iter().fold(0usize, | length, b | length << 8 | *b as usize),
+         &data[size ..])
+    };
+    if data.len() < length {
+        return None;
+    }
+    Some((tag, &data[.. length], &data[length ..]))
+}
+
+// ======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn certificate_common_names() {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.distinguished_name.push(rcgen::DnType::OrganizationName, "Example");
+        params.distinguished_name.push(rcgen::DnType::CommonName, "bob");
+        let certificate = params.self_signed(&key).unwrap();
+        assert_eq!(common_name(certificate.der()), Some("bob".to_string()));
+
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let certificate = params.self_signed(&key).unwrap();
+        assert_eq!(common_name(certificate.der()), None);
+
+        assert_eq!(common_name(&certificate.der()[.. 40]), None);
+        assert_eq!(common_name(b""), None);
+    }
+}
@@ -1,85 +1,140 @@
 use std::io::prelude::*;
 
 use anyhow::{anyhow, Context, Result};
-use byteorder::{ByteOrder, BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, WriteBytesExt};
 
+use crate::dictionaries;
+use crate::errors;
 use crate::util;
 use crate::index;
 use crate::pool;
 use crate::records;
 
-static PADDING16: [u8; 16] = [0u8; 16]; 
 pub const PADDING_MARKER: &'static [u8] = b"PPPP";
 
-pub struct TransactionData<'store> {
-    filep: pool::TmpFilePointer<'store>,
+pub struct TransactionData {
+    filep: pool::TmpFilePointer,
     writer: std::io::BufWriter<std::fs::File>,
     length: u64,
     header_length: u64,
     needs_to_be_packed: bool,
+    // Data still to be saved for a record begun with save_start
+    remaining: u64,
 }
 
-impl<'store> TransactionData<'store> {
+impl TransactionData {
     
-    pub fn save_tid(&mut self, tid: util::Tid, count: u32) -> std::io::Result<()> {
+    /// Write the tid, and the record count, to the transaction
+    /// header and data headers, and data and transaction checksums
+    /// if the data file's format version has them.
+    pub fn save_tid(&mut self, tid: util::Tid, count: u32, version: u32)
+                    -> std::io::Result<()> {
+        let checksums = records::checksums(version);
         self.writer.seek(std::io::SeekFrom::Start(12))?;
         self.writer.write_all(&tid)?;
         self.writer.write_u32::<BigEndian>(count)?;
         self.writer.flush()?;
         let mut wpos = self.header_length;
         let mut file = self.filep.try_clone()?;
+        file.seek(std::io::SeekFrom::Start(0))?;
+        let mut transaction_checksum = records::TransactionChecksum::of_record(
+            &util::read_sized(&mut file, self.header_length as usize)?, version);
         while wpos < self.length {
             file.seek(std::io::SeekFrom::Start(wpos))?;
-            let dlen = file.read_u32::<BigEndian>()?;
+            let (_, dlen) = records::read_data_length(&mut file, version)?;
+            let oid = util::read8(&mut file)?;
             file.seek(
-                std::io::SeekFrom::Start(wpos + records::DATA_TID_OFFSET))?;
+                std::io::SeekFrom::Start(wpos + records::data_tid_offset(version)))?;
             file.write_all(&tid)?;
-            wpos += records::DATA_HEADER_SIZE + dlen as u64;
+            if checksums {
+                file.seek(
+                    std::io::SeekFrom::Start(wpos + records::data_header_size(version)))?;
+                let checksum = records::read_checksum(&mut file, dlen)?;
+                file.seek(
+                    std::io::SeekFrom::Start(wpos + records::data_checksum_offset(version)))?;
+                file.write_u32::<BigEndian>(checksum)?;
+                transaction_checksum.add(&oid, checksum);
+            }
+            wpos += records::data_header_size(version) + dlen;
+        }
+        if checksums {
+            file.seek(std::io::SeekFrom::Start(records::transaction_checksum_offset(version)))?;
+            file.write_u32::<BigEndian>(transaction_checksum.value())?;
         }
         Ok(())
     }
 
 }
 
-pub enum TransactionState<'store> {
-    Saving(TransactionData<'store>),
+pub enum TransactionState {
+    Saving(TransactionData),
     Transitioning,
-    Voting(TransactionData<'store>),
+    Voting(TransactionData),
     Voted,
 }
 
-pub struct Transaction<'store> {
+pub struct Transaction {
     pub id: util::Tid,
-    pub state: TransactionState<'store>,
+    pub state: TransactionState,
     index: index::Index,
+    // The id to commit with, when restoring, rather than a new one
+    pub tid: Option<util::Tid>,
+    // Objects restored, rather than stored, which aren't checked for
+    // conflicts
+    restored: std::collections::HashSet<util::Oid>,
+    // Uploaded blob files, moved into place when the transaction is
+    // staged, and removed if it isn't
+    blobs: std::collections::BTreeMap<util::Oid, std::path::PathBuf>,
+    // The most bytes the transaction can add to the data file, and
+    // the most objects it can save, if limited
+    max_size: Option<u64>,
+    max_records: Option<u64>,
+    // Whether saved data are compressed, when that makes them smaller
+    compress: bool,
+    // The data file's, to compress data with, and decompress them
+    dictionaries: std::sync::Arc<dictionaries::Dictionaries>,
+    // The format version of the data file the transaction is for
+    version: u32,
 }
 
-impl<'store, 't> Transaction<'store> {
-
-    pub fn begin(filep: pool::PooledFilePointer<'store, pool::TmpFileFactory>,
-                 id: util::Tid, user: &[u8], desc: &[u8], ext: &[u8])
-                 -> std::io::Result<Transaction<'store>> {
+impl<'t> Transaction {
+
+    /// Begin a transaction, to be written to a data file of a format
+    /// version.
+    pub fn begin(filep: pool::TmpFilePointer,
+                 id: util::Tid, user: &[u8], desc: &[u8], ext: &[u8], version: u32)
+                 -> std::io::Result<Transaction> {
+        // Lengths are written to fixed-size fields, so check rather
+        // than truncate.
+        util::io_assert(user.len() <= u16::MAX as usize &&
+                        desc.len() <= u16::MAX as usize &&
+                        ext.len() <= u32::MAX as usize,
+                        "Transaction meta data is too large")?;
         let mut file = filep.try_clone()?;
         file.seek(std::io::SeekFrom::Start(0))?;
         file.set_len(0)?;
         let mut writer = std::io::BufWriter::new(file);
         writer.write_all(PADDING_MARKER)?;
-        writer.write_all(&PADDING16)?; // tlen, tid
-        writer.write_u32::<BigEndian>(0 as u32)?; // count
-        writer.write_u16::<BigEndian>(user.len() as u16)?;
-        writer.write_u16::<BigEndian>(desc.len() as u16)?;
-        writer.write_u32::<BigEndian>(ext.len() as u32)?;
+        // The length, tid, count and checksum are set later.
+        records::TransactionHeader {
+            length: 0, checksum: 0, id: util::Z64, ndata: 0,
+            luser: user.len() as u16, ldesc: desc.len() as u16, lext: ext.len() as u32,
+        }.write(&mut writer, version)?;
         if user.len() > 0 { writer.write_all(user)? }
         if desc.len() > 0 { writer.write_all(desc)? }
         if  ext.len() > 0 { writer.write_all(ext)? }
-        let length = 4u64 + records::TRANSACTION_HEADER_LENGTH +
+        let length = 4u64 + records::transaction_header_length(version) +
             user.len() as u64 + desc.len() as u64 + ext.len() as u64;
         Ok(Transaction {
             id: id, index: index::Index::new(),
+            tid: None, restored: std::collections::HashSet::new(),
+            blobs: std::collections::BTreeMap::new(),
+            max_size: None, max_records: None, compress: false,
+            dictionaries: Default::default(), version,
             state: TransactionState::Saving(TransactionData {
                 filep: filep, writer: writer,
                 length: length, header_length: length,
-                needs_to_be_packed: false,
+                needs_to_be_packed: false, remaining: 0,
             }),
         })
     }
@@ -87,27 +142,149 @@ impl<'store, 't> Transaction<'store> {
     pub fn save(&mut self, oid: util::Oid, serial: util::Tid, data: &[u8])
                 -> std::io::Result<()> {
         // Save data in the first phase of 2-phase commit.
+        let compressed =
+            if self.compress && records::compression(self.version) {
+                records::compress(data, &self.dictionaries)?
+            }
+            else {
+                None
+            };
+        match compressed {
+            Some(compressed) => {
+                self.start_record(oid, serial, compressed.len() as u64, true)?;
+                self.save_chunk(&compressed)
+            },
+            None => {
+                self.save_start(oid, serial, data.len() as u64)?;
+                self.save_chunk(data)
+            },
+        }
+    }
+
+    /// Compress data passed to save, when that makes them smaller,
+    /// and the data file's format version allows it.  Data saved a
+    /// chunk at a time aren't compressed.
+    pub fn set_compression(&mut self, on: bool) {
+        self.compress = on;
+    }
+
+    /// Compress data with the newest of a data file's dictionaries,
+    /// if it has any.
+    pub fn set_dictionaries(&mut self, dictionaries: std::sync::Arc<dictionaries::Dictionaries>) {
+        self.dictionaries = dictionaries;
+    }
+
+    /// Limit the bytes the transaction can add to the data file, and
+    /// the number of objects it can save.  Saves beyond the limits
+    /// fail with TransactionTooLargeErrors.
+    pub fn set_limits(&mut self, max_size: Option<u64>, max_records: Option<u64>) {
+        self.max_size = max_size;
+        self.max_records = max_records;
+    }
+
+    /// Start saving a record of size bytes, whose data are then
+    /// written with save_chunk, so big records needn't be held in
+    /// memory.
+    pub fn save_start(&mut self, oid: util::Oid, serial: util::Tid, size: u64)
+                      -> std::io::Result<()> {
+        self.start_record(oid, serial, size, false)
+    }
+
+    fn start_record(&mut self, oid: util::Oid, serial: util::Tid, size: u64, compressed: bool)
+                    -> std::io::Result<()> {
         if let TransactionState::Saving(ref mut  tdata) = self.state {
-            tdata.writer.write_u32::<BigEndian>(data.len() as u32)?;
-            tdata.writer.write_all(&oid)?;
-            // read tid now, committed later:
-            tdata.writer.write_all(&serial)?;
-            util::write_u64(&mut tdata.writer, 0)?; // previous
-            util::write_u64(&mut tdata.writer, tdata.length)?; // offset
-            if data.len() > 0 { tdata.writer.write_all(data)? }
+            util::io_assert(tdata.remaining == 0,
+                            "The previous record's data is incomplete")?;
+            let data_header_size = records::data_header_size(self.version);
+            if let Some(max_size) = self.max_size {
+                if tdata.length + data_header_size + size + 8 > max_size {
+                    return Err(std::io::Error::other(errors::POSError::TooLarge(
+                        format!("Transaction is too large, the limit is {} bytes",
+                                max_size))));
+                }
+            }
+            if let Some(max_records) = self.max_records {
+                if ! self.index.contains_key(&oid) && self.index.len() as u64 >= max_records {
+                    return Err(std::io::Error::other(errors::POSError::TooLarge(
+                        format!("Transaction saves too many objects, the limit is {}",
+                                max_records))));
+                }
+            }
+            // The length field's high bit flags compressed data, in
+            // files that can have them.
+            let max_data_length = records::max_data_length(self.version);
+            util::io_assert(size <= max_data_length,
+                            &format!("Object data is too large, the limit is {} GiB",
+                                     (max_data_length + 1) >> 30))?;
+            // Record offsets and transaction lengths are 32-bit,
+            // to make room for checksums, in version 2 and 3 files.
+            util::io_assert(tdata.length + data_header_size + size + 8
+                            <= records::max_transaction_length(self.version),
+                            "Transaction is too large, the limit is 4 GiB")?;
+            // The tid read is written now, and replaced by the
+            // committed one, and the checksum set, when staged.
+            records::DataHeader {
+                length: size, id: oid, tid: serial, previous: 0, checksum: 0,
+                offset: tdata.length, compressed,
+            }.write(&mut tdata.writer, self.version)?;
+            self.restored.remove(&oid);
             if self.index.insert(oid, tdata.length).is_some() {
                 // There was an earlier save for this oid.  We'll want to
                 // pack the data before committing.
                 tdata.needs_to_be_packed = true;
             };
-            tdata.length += records::DATA_HEADER_SIZE + data.len() as u64;
+            tdata.length += data_header_size + size;
+            tdata.remaining = size;
             Ok(())
         }
         else { Err(util::io_error("Invalid trans state")) }
     }
 
+    /// Save data for the record begun with save_start.
+    pub fn save_chunk(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if let TransactionState::Saving(ref mut  tdata) = self.state {
+            util::io_assert(data.len() as u64 <= tdata.remaining,
+                            "More data than the record's size")?;
+            if data.len() > 0 { tdata.writer.write_all(data)? }
+            tdata.remaining -= data.len() as u64;
+            Ok(())
+        }
+        else { Err(util::io_error("Invalid trans state")) }
+    }
+
+    /// Save data to be committed as is, with the transaction's tid.
+    pub fn restore(&mut self, oid: util::Oid, data: &[u8]) -> std::io::Result<()> {
+        let tid = self.tid.ok_or_else(|| util::io_error("Not restoring"))?;
+        self.save(oid, tid, data)?;
+        self.restored.insert(oid);
+        Ok(())
+    }
+
+    /// Save an object's data along with a blob uploaded to path,
+    /// which the transaction then owns.
+    pub fn save_blob(&mut self, oid: util::Oid, serial: util::Tid, data: &[u8],
+                     path: std::path::PathBuf) -> std::io::Result<()> {
+        let saved = self.save(oid, serial, data);
+        if let Some(old) = self.blobs.insert(oid, path) {
+            std::fs::remove_file(old);
+        }
+        saved
+    }
+
+    /// Take the uploaded blobs, to move them into place.
+    pub fn take_blobs(&mut self) -> std::collections::BTreeMap<util::Oid, std::path::PathBuf> {
+        std::mem::take(&mut self.blobs)
+    }
+
+    pub fn restored(&self, oid: &util::Oid) -> bool {
+        self.restored.contains(oid)
+    }
+
     pub fn lock_data(&self) -> Result<(util::Tid, Vec<util::Oid>)> {
-        if let TransactionState::Saving(_) = self.state {
+        if let TransactionState::Saving(ref tdata) = self.state {
+            if tdata.remaining > 0 {
+                return Err(anyhow!("A record's data is incomplete"));
+            }
             let mut oids =
                 self.index.keys().map(| r | r.clone()).collect::<Vec<util::Oid>>();
             oids.reverse();
@@ -116,6 +293,16 @@ impl<'store, 't> Transaction<'store> {
         else { Err(anyhow!("Invalid trans state")) }
     }
 
+    /// The number of bytes staging the transaction will add to the
+    /// data file, once packed.
+    pub fn staged_size(&self) -> u64 {
+        match self.state {
+            TransactionState::Saving(ref data) |
+            TransactionState::Voting(ref data) => data.length + 8,
+            _ => 0,
+        }
+    }
+
     pub fn locked(&mut self) -> Result<()>
     {
         let mut state = TransactionState::Transitioning;
@@ -165,7 +352,7 @@ impl<'store, 't> Transaction<'store> {
         if let TransactionState::Voting(ref mut data) = self.state {
             TransactionSerialIterator::new(
                 data.filep.try_clone()?,
-                &self.index, data.length, data.header_length)
+                &self.index, data.length, data.header_length, self.version)
         }
         else { Err(util::io_error("Invalid trans state")) }
     }
@@ -177,12 +364,11 @@ impl<'store, 't> Transaction<'store> {
             let mut file = data.filep.try_clone()?;
             file.seek(std::io::SeekFrom::Start(*pos))
                  .context("trans seek")?;
-            let dlen =
-                file.read_u32::<BigEndian>()
+            let (compressed, dlen) = records::read_data_length(&mut file, self.version)
                 .context("trans read dlen")?;
             let data = if dlen > 0 {
                 file.seek(
-                    std::io::SeekFrom::Start(pos + records::DATA_HEADER_SIZE))
+                    std::io::SeekFrom::Start(pos + records::data_header_size(self.version)))
                      .context("trans seek data")?;
                 util::read_sized(&mut file, dlen as usize)
                     .context("trans read data")?
@@ -190,7 +376,8 @@ impl<'store, 't> Transaction<'store> {
             else {
                 vec![0u8; 0]
             };
-            Ok(data)
+            Ok(records::decode(compressed, data, &self.dictionaries)
+               .context("trans decompress data")?)
         }          
         else { Err(anyhow!("Invalid trans state")) }
     }
@@ -201,7 +388,7 @@ impl<'store, 't> Transaction<'store> {
                 self.index.get(oid).ok_or(anyhow!("trans index error"))?;
             let mut file = data.filep.try_clone()?;
             file.seek(
-                std::io::SeekFrom::Start(pos + records::DATA_PREVIOUS_OFFSET))
+                std::io::SeekFrom::Start(pos + records::data_previous_offset(self.version)))
                  .context("trans seek prev")?;
             file.write_u64::<BigEndian>(previous)
                 .context("trans write previous")?;
@@ -220,35 +407,29 @@ impl<'store, 't> Transaction<'store> {
                 let mut rpos = data.header_length;
                 let mut wpos = data.header_length;
 
-                let mut buf = [0u8; 12];
+                let data_header_size = records::data_header_size(self.version);
                 while rpos < data.length {
                     file.seek(std::io::SeekFrom::Start(rpos))?;
-                    file.read_exact(&mut buf)?;
-                    let dlen = BigEndian::read_u32(&buf) as u64;
-                    let oid = util::read8(&mut &buf[4..])?;
+                    let header = records::DataHeader::read(&mut file, self.version)?;
+                    let size = data_header_size + header.length;
                     let oid_pos =
-                        self.index.get(&oid)
+                        self.index.get(&header.id)
                         .ok_or(util::io_error("trans index get"))?.clone();
                     if oid_pos == rpos {
                         // We want this one
                         if rpos != wpos {
-                            // We need to move it.
-                            let mut rest = // tid, previous, offset, data
-                                util::read_sized(
-                                    &mut file,
-                                    dlen as usize +
-                                        records::DATA_HEADER_SIZE as usize
-                                        - 12)?;
-                            // update offset:
-                            util::write_u64(&mut &mut rest[16..24], wpos);
+                            // We need to move it, updating its offset.
+                            let record_data = util::read_sized(&mut file, header.length as usize)?;
                             file.seek(std::io::SeekFrom::Start(wpos))?;
-                            file.write_all(&buf)?;
-                            file.write_all(&rest)?;
-                            self.index.insert(oid, wpos);
-                            wpos += dlen + records::DATA_HEADER_SIZE;
+                            let id = header.id;
+                            records::DataHeader { offset: wpos, ..header }
+                                .write(&mut file, self.version)?;
+                            file.write_all(&record_data)?;
+                            self.index.insert(id, wpos);
                         }
+                        wpos += size;
                     }
-                    rpos += dlen + records::DATA_HEADER_SIZE;
+                    rpos += size;
                 }
                 file.set_len(wpos)?;
                 data.length = wpos;
@@ -266,20 +447,21 @@ impl<'store, 't> Transaction<'store> {
         else { Err(util::io_error("Invalid trans state")) }
     }
 
-    pub fn stage(&mut self, tid: util::Tid, mut out: &mut std::fs::File)
+    /// Finish the transaction's record, with its tid, and checksums,
+    /// if its data file's format version has them, and pass it, and
+    /// its length, to append, to be added to the data file.
+    pub fn stage(&mut self, tid: util::Tid,
+                 append: impl FnOnce(&std::fs::File, u64) -> std::io::Result<()>)
                  -> std::io::Result<(index::Index, u64)> {
         let length =
             if let TransactionState::Voting(ref mut data) = self.state {
                 // Update tids in temp file
-                data.save_tid(tid, self.index.len() as u32)?;
-                let mut file = data.filep.try_clone()?;
-                file.seek(std::io::SeekFrom::Start(0))?;
-                
+                data.save_tid(tid, self.index.len() as u32, self.version)?;
                 data.length += 8;
-                assert_eq!(std::io::copy(&mut file, &mut out)?, data.length);
-                
+                append(&data.filep, data.length)?;
+
                 // Truncate to 0 in hopes of avoiding write to disk
-                file.set_len(0)?;
+                data.filep.set_len(0)?;
                 data.length
             }
         else {
@@ -294,7 +476,15 @@ impl<'store, 't> Transaction<'store> {
     }
 }
 
-impl<'store, 't> std::fmt::Debug for Transaction<'store> {
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        for path in self.blobs.values() {
+            std::fs::remove_file(path);
+        }
+    }
+}
+
+impl std::fmt::Debug for Transaction {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "Transaction()") // TODO: more informative :)
     }
@@ -307,6 +497,7 @@ pub struct TransactionSerialIterator<'t> {
     index: &'t index::Index,
     length: u64,
     pos: u64,
+    version: u32,
 }
 
 impl<'t> TransactionSerialIterator<'t> {
@@ -314,24 +505,24 @@ impl<'t> TransactionSerialIterator<'t> {
     fn new(mut file: std::fs::File,
            index: &'t index::Index,
            length: u64,
-           pos: u64) -> std::io::Result<TransactionSerialIterator> {
+           pos: u64,
+           version: u32) -> std::io::Result<TransactionSerialIterator> {
         file.seek(std::io::SeekFrom::Start(pos))?;
         Ok(TransactionSerialIterator {
             reader: std::io::BufReader::new(file),
-            index: index, length: length, pos: pos })
+            index: index, length: length, pos: pos, version })
     }
 
     fn read(&mut self) -> TransactionSerialIteratorItem {
         loop {
-            let dlen = self.reader.read_u32::<BigEndian>()?;
-            let oid = util::read8(&mut self.reader)?;
-            match self.index.get(&oid) {
-                Some(&pos) => {
-                    if &pos != &self.pos {
+            let header = records::DataHeader::read(&mut self.reader, self.version)?;
+            let pos = self.pos;
+            self.pos += records::data_header_size(self.version) + header.length;
+            util::seek(&mut self.reader, self.pos)?;
+            match self.index.get(&header.id) {
+                Some(&oid_pos) => {
+                    if oid_pos != pos {
                         // The object was repeated and this isn't the last
-                        self.reader.seek(
-                            std::io::SeekFrom::Current(24 + dlen as i64))?;
-                        self.pos += records::DATA_HEADER_SIZE + dlen as u64;
                         continue
                     }
                 },
@@ -339,10 +530,7 @@ impl<'t> TransactionSerialIterator<'t> {
                     return Err(util::io_error("index fail in transaction"))
                 }
             }
-            let tid = util::read8(&mut self.reader)?;
-            self.reader.seek(std::io::SeekFrom::Current(16 + dlen as i64))?;
-            self.pos += records::DATA_HEADER_SIZE + dlen as u64;
-            return Ok((oid, tid))
+            return Ok((header.id, header.tid))
         }
     }
 }
@@ -373,6 +561,106 @@ pub mod tests {
     use crate::records;
     use crate::util;
     
+    #[test]
+    fn meta_data_too_large() {
+        let tmpdir = util::test::dir();
+        let pool = pool::FilePool::new(
+            pool::TmpFileFactory::base(
+                String::from(
+                    tmpdir.path().join("tmp").to_str().unwrap())).unwrap(),
+            22);
+        let big = vec![b'x'; 1 << 16];
+        assert!(Transaction::begin(
+            pool.get().unwrap(), util::p64(1), &big, b"", b"", records::FORMAT_VERSION).is_err());
+        assert!(Transaction::begin(
+            pool.get().unwrap(), util::p64(1), b"", &big, b"", records::FORMAT_VERSION).is_err());
+        assert!(Transaction::begin(
+            pool.get().unwrap(), util::p64(1), b"", b"", &big, records::FORMAT_VERSION).is_ok());
+    }
+
+    #[test]
+    fn limits() {
+        let tmpdir = util::test::dir();
+        let pool = pool::FilePool::new(
+            pool::TmpFileFactory::base(
+                String::from(
+                    tmpdir.path().join("tmp").to_str().unwrap())).unwrap(),
+            22);
+        let too_large = | err: std::io::Error | {
+            matches!(err.into_inner().unwrap().downcast::<errors::POSError>().map(| e | *e),
+                     Ok(errors::POSError::TooLarge(_)))
+        };
+
+        let mut trans = Transaction::begin(
+            pool.get().unwrap(), util::p64(1), b"", b"", b"", records::FORMAT_VERSION).unwrap();
+        let size = trans.staged_size() + records::data_header_size(records::FORMAT_VERSION) + 10;
+        trans.set_limits(Some(size), Some(2));
+        assert!(too_large(trans.save(util::p64(0), util::Z64, &[0; 11]).unwrap_err()));
+        trans.save(util::p64(0), util::Z64, &[0; 10]).unwrap();
+        assert_eq!(trans.staged_size(), size);
+
+        let mut trans = Transaction::begin(
+            pool.get().unwrap(), util::p64(1), b"", b"", b"", records::FORMAT_VERSION).unwrap();
+        trans.set_limits(None, Some(2));
+        trans.save(util::p64(0), util::Z64, b"0").unwrap();
+        trans.save(util::p64(1), util::Z64, b"1").unwrap();
+        // Saving an object again doesn't add a record:
+        trans.save(util::p64(1), util::Z64, b"2").unwrap();
+        assert!(too_large(trans.save(util::p64(2), util::Z64, b"3").unwrap_err()));
+    }
+
+    #[test]
+    fn large_records() {
+        let tmpdir = util::test::dir();
+        let pool = pool::FilePool::new(
+            pool::TmpFileFactory::base(
+                String::from(
+                    tmpdir.path().join("tmp").to_str().unwrap())).unwrap(),
+            22);
+
+        // Records of 4 GiB or more fit in the current format:
+        let mut trans = Transaction::begin(
+            pool.get().unwrap(), util::p64(1), b"", b"", b"", records::FORMAT_VERSION).unwrap();
+        trans.save_start(util::p64(0), util::Z64, 5 << 30).unwrap();
+
+        // but not in older versions, where they fail, rather than
+        // having their lengths truncated:
+        for (version, size) in [(3, 1 << 31), (2, 1 << 32)] {
+            let mut trans = Transaction::begin(
+                pool.get().unwrap(), util::p64(1), b"", b"", b"", version).unwrap();
+            assert!(trans.save_start(util::p64(0), util::Z64, size).is_err());
+        }
+    }
+
+    #[test]
+    fn chunked() {
+        let tmpdir = util::test::dir();
+        let pool = pool::FilePool::new(
+            pool::TmpFileFactory::base(
+                String::from(
+                    tmpdir.path().join("tmp").to_str().unwrap())).unwrap(),
+            22);
+        let mut trans = Transaction::begin(
+            pool.get().unwrap(), util::p64(1), b"", b"", b"", records::FORMAT_VERSION).unwrap();
+
+        trans.save_start(util::p64(0), util::p64(2), 30).unwrap();
+        trans.save_chunk(&[1; 10]).unwrap();
+        assert!(trans.lock_data().is_err()); // incomplete
+        assert!(trans.save(util::p64(1), util::p64(2), b"x").is_err());
+        assert!(trans.save_chunk(&[2; 21]).is_err()); // too much
+        trans.save_chunk(&[2; 20]).unwrap();
+        assert!(trans.save_chunk(b"x").is_err());
+        trans.save(util::p64(1), util::p64(2), b"x").unwrap();
+
+        assert_eq!(trans.lock_data().unwrap(),
+                   (util::p64(1), vec![util::p64(1), util::p64(0)]));
+        trans.locked().unwrap();
+        let mut expected = vec![1; 10];
+        expected.extend_from_slice(&[2; 20]);
+        assert_eq!(trans.get_data(&util::p64(0)).unwrap(), expected);
+        assert_eq!(trans.get_data(&util::p64(1)).unwrap(), b"x".to_vec());
+    }
+
     #[test]
     fn works_w_dup() {
         let tmpdir = util::test::dir();
@@ -387,7 +675,8 @@ pub mod tests {
         let tempfile = tempfilep.try_clone().unwrap();
 
         let mut trans = Transaction::begin(
-            tempfilep, util::p64(1234567890), b"user", b"desc", b"{}").unwrap();
+            tempfilep, util::p64(1234567890), b"user", b"desc", b"{}",
+            records::FORMAT_VERSION).unwrap();
 
         trans.save(util::p64(0), util::p64(123456789), &[1; 11]).unwrap();
         trans.save(util::p64(1), util::p64(12345678),  &[2; 22]).unwrap();
@@ -410,46 +699,59 @@ pub mod tests {
 
         let t2 = pool.get().unwrap();
         let mut file = t2.try_clone().unwrap();
-        let (index, tsize) = trans.stage(util::p64(1234567891), &mut file).unwrap();
+        let (index, tsize) = trans.stage(util::p64(1234567891), | mut data, length | {
+            util::seek(&mut data, 0)?;
+            assert_eq!(std::io::copy(&mut data, &mut file)?, length);
+            Ok(())
+        }).unwrap();
 
         // Now, we'll verify the saved data.
         let l = file.seek(std::io::SeekFrom::End(0)).unwrap();
         assert_eq!(tsize, l);
         util::seek(&mut file, 0).unwrap();
         assert_eq!(&util::read4(&mut file).unwrap(), b"PPPP");
-        let th = records::TransactionHeader::read(&mut file).unwrap();
+        let th = records::TransactionHeader::read(&mut file, records::FORMAT_VERSION).unwrap();
         assert_eq!(
             th,
             records::TransactionHeader {
-                length: l, id: util::p64(1234567891), ndata: 2,
+                length: l, checksum: th.checksum, id: util::p64(1234567891), ndata: 2,
                 luser: 4, ldesc: 4, lext: 2 });
         assert_eq!(&util::read4(&mut file).unwrap(), b"user");
         assert_eq!(&util::read4(&mut file).unwrap(), b"desc");
         assert_eq!(&util::read_sized(&mut file, 2).unwrap(), b"{}");
 
-        let dh1 = records::DataHeader::read(&mut file).unwrap();
+        let dh1 = records::DataHeader::read(&mut file, records::FORMAT_VERSION).unwrap();
         assert_eq!(
             dh1,
             records::DataHeader {
                 length: 22, id: util::p64(1), tid: util::p64(1234567891),
-                previous: 0,
-                offset: records::TRANSACTION_HEADER_LENGTH + 14,
+                previous: 0, checksum: util::crc32(0, &[2; 22]),
+                offset: records::transaction_header_length(records::FORMAT_VERSION) + 14,
+                compressed: false,
             });
         assert_eq!(util::read_sized(&mut file, dh1.length as usize).unwrap(),
                    vec![2; 22]);
 
-        let dh0 = records::DataHeader::read(&mut file).unwrap();
+        let dh0 = records::DataHeader::read(&mut file, records::FORMAT_VERSION).unwrap();
         assert_eq!(
             dh0,
             records::DataHeader {
                 length: 33, id: util::p64(0), tid: util::p64(1234567891),
-                previous: 7777,
+                previous: 7777, checksum: util::crc32(0, &[3; 33]),
                 offset:
-                dh1.offset + records::DATA_HEADER_SIZE + dh1.length as u64,
+                dh1.offset + records::data_header_size(records::FORMAT_VERSION) + dh1.length,
+                compressed: false,
             });
         assert_eq!(util::read_sized(&mut file, dh0.length as usize).unwrap(),
                    vec![3; 33]);
 
+        let mut checksum = records::TransactionChecksum::new(
+            &[&util::p64(1234567891)[..], &[0, 0, 0, 2, 0, 4, 0, 4, 0, 0, 0, 2],
+              b"userdesc{}"].concat());
+        checksum.add(&dh1.id, dh1.checksum);
+        checksum.add(&dh0.id, dh0.checksum);
+        assert_eq!(th.checksum, checksum.value());
+
         assert_eq!(util::read_u64(&mut file).unwrap(), l); // Check redundant length
 
         assert_eq!(
@@ -460,6 +762,27 @@ pub mod tests {
                 other
             });
     }
+
+    #[test]
+    fn pack_keeps_records_that_dont_move() {
+        let tmpdir = util::test::dir();
+        let pool = pool::FilePool::new(
+            pool::TmpFileFactory::base(
+                String::from(
+                    tmpdir.path().join("tmp").to_str().unwrap())).unwrap(),
+            22);
+        let mut trans = Transaction::begin(
+            pool.get().unwrap(), util::p64(1), b"", b"", b"", records::FORMAT_VERSION).unwrap();
+        trans.save(util::p64(1), util::Z64, &[1; 11]).unwrap();
+        trans.save(util::p64(0), util::Z64, &[2; 22]).unwrap();
+        trans.save(util::p64(0), util::Z64, &[3; 33]).unwrap();
+        trans.lock_data().unwrap();
+        trans.locked().unwrap();
+        trans.pack().unwrap();
+        assert_eq!(trans.get_data(&util::p64(1)).unwrap(), vec![1; 11]);
+        assert_eq!(trans.get_data(&util::p64(0)).unwrap(), vec![3; 33]);
+        assert_eq!(trans.serials().unwrap().count(), 2);
+    }
     
     #[test]
     fn works_wo_dup() {
@@ -474,8 +797,9 @@ pub mod tests {
         let tempfilep = pool.get().unwrap();
         let tempfile = tempfilep.try_clone().unwrap();
 
+        // A version 1 file, without checksums:
         let mut trans = Transaction::begin(
-            tempfilep, util::p64(1234567890), b"user", b"desc", b"{}").unwrap();
+            tempfilep, util::p64(1234567890), b"user", b"desc", b"{}", 1).unwrap();
 
         trans.save(util::p64(0), util::p64(123456789), &[1; 11]).unwrap();
         trans.save(util::p64(1), util::p64(12345678),  &[2; 22]).unwrap();
@@ -500,7 +824,11 @@ pub mod tests {
         assert_eq!(pool.len(), 0);
         
         let mut file = t2.try_clone().unwrap();
-        let (index, tsize) = trans.stage(util::p64(1234567891), &mut file).unwrap();
+        let (index, tsize) = trans.stage(util::p64(1234567891), | mut data, length | {
+            util::seek(&mut data, 0)?;
+            assert_eq!(std::io::copy(&mut data, &mut file)?, length);
+            Ok(())
+        }).unwrap();
 
         assert_eq!(pool.len(), 1); // The transaction's tmp file ws returned.
 
@@ -509,35 +837,37 @@ pub mod tests {
         assert_eq!(tsize, l);
         util::seek(&mut file, 0).unwrap();
         assert_eq!(&util::read4(&mut file).unwrap(), b"PPPP");
-        let th = records::TransactionHeader::read(&mut file).unwrap();
+        let th = records::TransactionHeader::read(&mut file, 1).unwrap();
         assert_eq!(
             th,
             records::TransactionHeader {
-                length: l, id: util::p64(1234567891), ndata: 2,
+                length: l, checksum: 0, id: util::p64(1234567891), ndata: 2,
                 luser: 4, ldesc: 4, lext: 2 });
         assert_eq!(&util::read4(&mut file).unwrap(), b"user");
         assert_eq!(&util::read4(&mut file).unwrap(), b"desc");
         assert_eq!(&util::read_sized(&mut file, 2).unwrap(), b"{}");
 
-        let dh0 = records::DataHeader::read(&mut file).unwrap();
+        let dh0 = records::DataHeader::read(&mut file, 1).unwrap();
         assert_eq!(
             dh0,
             records::DataHeader {
                 length: 11, id: util::p64(0), tid: util::p64(1234567891),
-                previous: 7777,
-                offset: records::TRANSACTION_HEADER_LENGTH + 14,
+                previous: 7777, checksum: 0,
+                offset: records::transaction_header_length(1) + 14,
+                compressed: false,
             });
         assert_eq!(util::read_sized(&mut file, dh0.length as usize).unwrap(),
                    vec![1; 11]);
 
-        let dh1 = records::DataHeader::read(&mut file).unwrap();
+        let dh1 = records::DataHeader::read(&mut file, 1).unwrap();
         assert_eq!(
             dh1,
             records::DataHeader {
                 length: 22, id: util::p64(1), tid: util::p64(1234567891),
-                previous: 0,
+                previous: 0, checksum: 0,
                 offset:
-                dh0.offset + records::DATA_HEADER_SIZE + dh0.length as u64,
+                dh0.offset + records::data_header_size(1) + dh0.length,
+                compressed: false,
             });
         assert_eq!(util::read_sized(&mut file, dh1.length as usize).unwrap(),
                    vec![2; 22]);
@@ -19,6 +19,10 @@ pub fn p64(i: u64) -> [u8; 8] {
     r
 }
 
+pub fn hex(data: &[u8]) -> String {
+    data.iter().map(| b | format!("{:02x}", b)).collect()
+}
+
 pub fn io_error(message: &str) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::Other, message)
 }
@@ -101,6 +105,55 @@ pub fn seek(s: &mut dyn std::io::Seek, pos: u64) -> std::io::Result<u64> {
     s.seek(std::io::SeekFrom::Start(pos))
 }
 
+/// Allocate disk space for length bytes of a file at offset, without
+/// changing the file's size.
+pub fn preallocate(file: &std::fs::File, offset: u64, length: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE,
+                                offset as libc::off_t, length as libc::off_t) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The bytes available to unprivileged users on the file system a
+/// file is on.
+pub fn free_space(file: &std::fs::File) -> std::io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatvfs(file.as_raw_fd(), &mut stat) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail * stat.f_frsize)
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Update a CRC-32 (IEEE) checksum, starting from 0, with more data.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = ! crc;
+    for b in data {
+        crc = CRC32_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    ! crc
+}
+
 
 // ======================================================================
 
@@ -1,5 +1,8 @@
+use std::io::prelude::*;
+
 use anyhow::{Context, Result};
 
+use crate::errors;
 use crate::storage;
 use crate::transaction;
 use crate::util;
@@ -13,13 +16,6 @@ macro_rules! respond {
     )
 }
 
-macro_rules! error {
-    ($writer: expr, $id: expr, $data: expr) => (
-        $writer.write_all(&error_response!($id, $data))
-            .context("send error response")?
-    )
-}
-
 macro_rules! async_ {
     ($writer: expr, $method: expr, $args: expr) => (
         $writer.write_all(&message!(0, $method, ($args)))
@@ -27,17 +23,137 @@ macro_rules! async_ {
     )
 }
 
+/// How much output is collected before writing it, by default
+pub const WRITE_BUFFER_SIZE: usize = 1 << 16;
+
+static CONNECTIONS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Allocate an id for a connection, used to correlate log lines and
+/// error responses.
+pub fn new_connection() -> u64 {
+    CONNECTIONS.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1
+}
+
+/// Correlation id for a request on a connection, derived from the
+/// request's message id.
+pub fn correlation_id(connection: u64, id: i64) -> String {
+    format!("{}.{}", connection, id)
+}
+
+/// Sends messages to a client's writer, waking the connection, if
+/// it's served by a worker pool, so they get handled.
+#[derive(Clone)]
+pub struct Sender {
+    send: std::sync::mpsc::Sender<msg::Zeo>,
+    wake: Option<std::sync::Arc<dyn Fn() + Send + Sync>>,
+}
+
+impl Sender {
+    pub fn send(&self, zeo: msg::Zeo)
+                -> std::result::Result<(), std::sync::mpsc::SendError<msg::Zeo>> {
+        self.send.send(zeo)?;
+        if let Some(ref wake) = self.wake {
+            wake();
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Sender {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Sender({:?})", self.send)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Client {
     name: String,
-    send: std::sync::mpsc::Sender<msg::Zeo>,
+    connection: u64,
+    send: Sender,
     request_id: i64,
+    read_only: bool,
+    codec: std::sync::Arc<dyn msg::Codec>,
+    heartbeats: std::sync::Arc<Heartbeats>,
+    requests: Option<std::sync::Arc<crate::latency::Requests>>,
+}
+
+// What a client has sent between heartbeats
+#[derive(Debug, Default)]
+struct Heartbeats {
+    // Messages received, counted by the connection's ZeoIter
+    received: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    // Messages received as of the last heartbeat
+    seen: std::sync::atomic::AtomicU64,
+    // Heartbeats in a row with nothing received since the last
+    missed: std::sync::atomic::AtomicU64,
 }
 
 impl Client {
     pub fn new(name: String, send: std::sync::mpsc::Sender<msg::Zeo>)
            -> Client {
-        Client {name: name, send: send, request_id: 0}
+        Client {name: name, connection: new_connection(),
+                send: Sender { send, wake: None }, request_id: 0,
+                read_only: false, codec: std::sync::Arc::new(msg::Msgpack),
+                heartbeats: Default::default(), requests: None}
+    }
+
+    /// Call wake whenever a message is sent to the client's writer.
+    pub fn with_wake(mut self, wake: std::sync::Arc<dyn Fn() + Send + Sync>) -> Client {
+        self.send.wake = Some(wake);
+        self
+    }
+
+    /// Make the client read-only, so its transactions fail with
+    /// ReadOnlyError.
+    pub fn with_read_only(mut self, read_only: bool) -> Client {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Encode messages to the client with codec, rather than as
+    /// msgpack.
+    pub fn with_codec(mut self, codec: std::sync::Arc<dyn msg::Codec>) -> Client {
+        self.codec = codec;
+        self
+    }
+
+    /// Count heartbeats missed by the client, using received, a
+    /// count of the messages from it, from its ZeoIter.
+    pub fn with_received(mut self, received: std::sync::Arc<std::sync::atomic::AtomicU64>)
+                         -> Client {
+        self.heartbeats = std::sync::Arc::new(Heartbeats { received, ..Default::default() });
+        self
+    }
+
+    /// Time responses to requests, which the client's ZeoIter notes
+    /// the arrival of.
+    pub fn with_requests(mut self, requests: std::sync::Arc<crate::latency::Requests>)
+                         -> Client {
+        self.requests = Some(requests);
+        self
+    }
+
+    pub fn connection(&self) -> u64 {
+        self.connection
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// How many heartbeats in a row the client has sent nothing
+    /// before
+    pub fn missed_heartbeats(&self) -> u64 {
+        self.heartbeats.missed.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Log events with the connection id and client name.
+    pub fn span(&self) -> tracing::span::EnteredSpan {
+        crate::log::connection(self.connection, &self.name)
     }
 }
 
@@ -48,24 +164,54 @@ impl PartialEq for Client {
 }
 
 impl crate::storage::Client for Client {
-    fn finished(&self, tid: &util::Tid, len: u64, size: u64) -> Result<()>  {
+    fn finished(&self, tid: &util::Tid, len: u64, size: u64,
+                oids: &[util::Oid]) -> Result<()>  {
         self.send.send(
-            msg::Zeo::Finished(self.request_id, tid.clone(), len, size)
+            msg::Zeo::Finished(self.request_id, tid.clone(), len, size,
+                               oids.to_vec())
         ).context("send finished")
     }
     fn invalidate(&self, tid: &util::Tid, oids: &Vec<util::Oid>) -> Result<()>  {
         self.send.send(msg::Zeo::Invalidate(
             tid.clone(), oids.clone())).context("send invalidate")
     }
-    fn close(&self) {}
+    fn durable(&self, tid: &util::Tid) -> Result<()>  {
+        self.send.send(msg::Zeo::Durable(*tid)).context("send durable")
+    }
+    fn heartbeat(&self) -> Result<()> {
+        use std::sync::atomic::Ordering::Relaxed;
+        let heartbeats = &self.heartbeats;
+        let received = heartbeats.received.load(Relaxed);
+        if heartbeats.seen.swap(received, Relaxed) == received {
+            heartbeats.missed.fetch_add(1, Relaxed);
+        }
+        else {
+            heartbeats.missed.store(0, Relaxed);
+        }
+        self.send.send(msg::Zeo::Heartbeat).context("send heartbeat")
+    }
+    fn info(&self, len: u64, size: u64, last_tid: &util::Tid) -> Result<()> {
+        self.send.send(msg::Zeo::Info(len, size, *last_tid)).context("send info")
+    }
+    fn close(&self, reason: &storage::DisconnectReason) {
+        tracing::info!("[{}] {}: {}", self.connection, self.name, reason);
+        match reason {
+            // Nobody to tell
+            storage::DisconnectReason::Closed |
+            storage::DisconnectReason::SendFailed => {},
+            _ => {
+                self.send.send(msg::Zeo::Close(reason.to_string()));
+            },
+        }
+    }
 }
 
-struct TransactionsHolder<'store> {
+struct TransactionsHolder {
     fs: std::sync::Arc<storage::FileStorage<Client>>,
-    transactions: std::collections::HashMap<u64, transaction::Transaction<'store>>,
+    transactions: std::collections::HashMap<u64, transaction::Transaction>,
 }
 
-impl<'store> Drop for TransactionsHolder<'store> {
+impl Drop for TransactionsHolder {
     fn drop(&mut self) {
         for trans in self.transactions.values() {
             self.fs.tpc_abort(&trans.id);
@@ -73,6 +219,60 @@ impl<'store> Drop for TransactionsHolder<'store> {
     }
 }
 
+// A blob being uploaded with storeBlobStart and storeBlobChunk.  Its
+// file is removed unless storeBlobEnd hands it to a transaction.
+struct Upload {
+    file: std::fs::File,
+    path: Option<std::path::PathBuf>,
+}
+
+impl Upload {
+
+    fn start(fs: &storage::FileStorage<Client>) -> Result<Upload> {
+        let dir = fs.blob_dir().ok_or_else(|| errors::POSError::Storage(
+            "Blobs aren't supported by this storage".to_string()))?;
+        let (file, path) = dir.temporary().context("creating blob file")?;
+        Ok(Upload { file, path: Some(path) })
+    }
+
+    fn take(mut self) -> std::path::PathBuf {
+        self.path.take().unwrap()
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.path {
+            std::fs::remove_file(path);
+        }
+    }
+}
+
+// Abort a transaction whose asynchronous call failed, keeping the
+// error to report when it's voted.
+fn fail(fs: &storage::FileStorage<Client>,
+        transactions: &mut std::collections::HashMap<u64, transaction::Transaction>,
+        failed: &mut std::collections::HashMap<u64, anyhow::Error>,
+        txn: u64, err: anyhow::Error) {
+    if let Some(trans) = transactions.remove(&txn) {
+        fs.tpc_abort(&trans.id);
+    }
+    failed.insert(txn, err);
+}
+
+// Send an error to the client, rather than failing the connection.
+// Errors that aren't POSErrors are sent as StorageErrors.
+pub fn report<W: std::io::Write>(writer: &mut W, connection: u64, id: i64,
+                                 err: anyhow::Error)
+                                 -> Result<()> {
+    writer.write_all(&errors::POSError::from_error(err).response(connection, id)?)
+        .context("send error response")
+}
+
+fn invalid_transaction(txn: u64) -> anyhow::Error {
+    errors::POSError::StorageTransaction(format!("Invalid transaction {}", txn)).into()
+}
+
 pub fn writer<W: std::io::Write>(
     fs: std::sync::Arc<storage::FileStorage<Client>>,
     mut writer: W,
@@ -80,54 +280,229 @@ pub fn writer<W: std::io::Write>(
     client: Client)
     -> Result<()> {
 
-    writer.write_all(&msg::size_vec(b"M5".to_vec()))
+    writer.write_all(&msg::size_vec(msg::PROTOCOL.to_vec()))
         .context("writing handshake")?;
+    run(fs, writer, receiver, client)
+}
 
-    let mut transaction_holder = TransactionsHolder {
-        fs: fs.clone(),
-        transactions: std::collections::HashMap::new(),
-    };
+/// Write responses and manage transactions for a client whose
+/// handshake is done.
+pub fn run<W: std::io::Write>(
+    fs: std::sync::Arc<storage::FileStorage<Client>>,
+    writer: W,
+    receiver: std::sync::mpsc::Receiver<msg::Zeo>,
+    client: Client)
+    -> Result<()> {
+
+    let _connection = client.span();
+    let mut session = Session::new(fs, client);
+    // Output is buffered until there's nothing more to send.
+    let mut writer = std::io::BufWriter::with_capacity(WRITE_BUFFER_SIZE, writer);
+    loop {
+        let zeo = match receiver.try_recv() {
+            Ok(zeo) => zeo,
+            Err(std::sync::mpsc::TryRecvError::Empty) => {
+                writer.flush().context("writing")?;
+                match receiver.recv() {
+                    Ok(zeo) => zeo,
+                    Err(_) => break,
+                }
+            },
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => break,
+        };
+        if ! session.handle(zeo, &mut writer)? {
+            break;
+        }
+    }
+    writer.flush().context("writing")
+}
+
+/// A client's transactions and connection settings, for handling
+/// writer messages one at a time, by a thread of its own or by
+/// whatever worker is serving the connection.
+///
+/// Pending transactions are aborted when the session is dropped.
+pub struct Session {
+    transaction_holder: TransactionsHolder,
+    client: Client,
+    // Whether tpc_finish should return before the data are fsynced
+    defer_fsync: bool,
+    // Errors from asynchronous tpc_begin, storea and restorea calls,
+    // reported by vote.
+    failed: std::collections::HashMap<u64, anyhow::Error>,
+    // The blob being uploaded, or why it couldn't be
+    upload: Option<Result<Upload>>,
+}
+
+impl Session {
+
+    pub fn new(fs: std::sync::Arc<storage::FileStorage<Client>>, client: Client)
+               -> Session {
+        Session {
+            transaction_holder: TransactionsHolder {
+                fs,
+                transactions: std::collections::HashMap::new(),
+            },
+            client,
+            defer_fsync: false,
+            failed: std::collections::HashMap::new(),
+            upload: None,
+        }
+    }
+
+    /// Handle a message, writing any output.
+    ///
+    /// Returns false if the connection should be closed.
+    pub fn handle<W: std::io::Write>(&mut self, zeo: msg::Zeo, writer: &mut W)
+                                     -> Result<bool> {
+        let _request = zeo.id().map(crate::log::request);
+        let writer = &mut msg::Encoder::new(writer, self.client.codec.clone())
+            .with_requests(self.client.requests.clone());
+        let fs = &self.transaction_holder.fs;
+        let transactions = &mut self.transaction_holder.transactions;
+        let client = &self.client;
+        let failed = &mut self.failed;
+        let upload = &mut self.upload;
 
-    let transactions = &mut transaction_holder.transactions;
-    
-    for zeo in receiver.iter() {
         match zeo {
             msg::Zeo::Raw(bytes) => {
                 writer.write_all(&bytes).context("writing raw")?
             },
-            msg::Zeo::TpcBegin(txn, user, desc, ext) => {
-                if ! transactions.contains_key(&txn) {
-                    transactions.insert(
-                        txn,
-                        fs.tpc_begin(&user, &desc, &ext)
-                             .context("writer begin")?);
+            msg::Zeo::TpcBegin(txn, user, desc, ext, tid) => {
+                if client.read_only {
+                    failed.insert(txn, errors::POSError::ReadOnly.into());
+                }
+                else if ! transactions.contains_key(&txn) {
+                    let begun = match tid {
+                        Some(tid) => fs.tpc_begin_restore(&tid, &user, &desc, &ext),
+                        None => fs.tpc_begin(&user, &desc, &ext),
+                    };
+                    match begun {
+                        Ok(trans) => {
+                            transactions.insert(txn, trans);
+                        },
+                        Err(err) => {
+                            failed.insert(txn, err.context("begin"));
+                        },
+                    }
                 }
             },
             msg::Zeo::Storea(oid, serial, data, txn) => {
                 if let Some(trans) = transactions.get_mut(&txn) {
-                    trans.save(oid, serial, &data)
-                        .context("writer save")?;
+                    if let Err(err) = trans.save(oid, serial, &data) {
+                        if let Some(trans) = transactions.remove(&txn) {
+                            fs.tpc_abort(&trans.id);
+                        }
+                        failed.insert(txn, anyhow::Error::new(err).context("save"));
+                    }
+                }
+            },
+            msg::Zeo::StoreaStart(oid, serial, size, txn) => {
+                if let Some(trans) = transactions.get_mut(&txn) {
+                    if let Err(err) = trans.save_start(oid, serial, size) {
+                        if let Some(trans) = transactions.remove(&txn) {
+                            fs.tpc_abort(&trans.id);
+                        }
+                        failed.insert(txn, anyhow::Error::new(err).context("save"));
+                    }
+                }
+            },
+            msg::Zeo::StoreaChunk(data, txn) => {
+                if let Some(trans) = transactions.get_mut(&txn) {
+                    if let Err(err) = trans.save_chunk(&data) {
+                        if let Some(trans) = transactions.remove(&txn) {
+                            fs.tpc_abort(&trans.id);
+                        }
+                        failed.insert(txn, anyhow::Error::new(err).context("save"));
+                    }
+                }
+            },
+            msg::Zeo::StoreBlobStart => {
+                *upload = Some(Upload::start(fs));
+            },
+            msg::Zeo::StoreBlobChunk(data) => {
+                if let Some(Ok(ref mut blob)) = *upload {
+                    if let Err(err) = blob.file.write_all(&data) {
+                        *upload = Some(Err(anyhow::Error::new(err).context("writing blob")));
+                    }
+                }
+            },
+            msg::Zeo::StoreBlobEnd(oid, serial, data, txn) => {
+                let saved = match upload.take() {
+                    Some(Ok(blob)) => match transactions.get_mut(&txn) {
+                        Some(trans) => trans.save_blob(oid, serial, &data, blob.take())
+                            .context("save"),
+                        None => Ok(()),
+                    },
+                    Some(Err(err)) => Err(err),
+                    None => Err(errors::POSError::Storage(
+                        "storeBlobEnd without storeBlobStart".to_string()).into()),
+                };
+                if let Err(err) = saved {
+                    fail(fs, transactions, failed, txn, err);
+                }
+            },
+            msg::Zeo::StoreBlobShared(oid, serial, data, filename, txn) => {
+                // The client wrote the blob to the object's directory
+                // in a blob directory it shares with the server.
+                let saved = match fs.blob_dir().map(| dir | dir.shared_path(&oid, &filename)) {
+                    Some(Err(err)) =>
+                        Err(errors::POSError::Storage(err.to_string()).into()),
+                    Some(Ok(path)) => match transactions.get_mut(&txn) {
+                        Some(trans) => trans.save_blob(oid, serial, &data, path)
+                            .context("save"),
+                        None => Ok(()),
+                    },
+                    None => Err(errors::POSError::Storage(
+                        "Blobs aren't supported by this storage".to_string()).into()),
+                };
+                if let Err(err) = saved {
+                    fail(fs, transactions, failed, txn, err);
+                }
+            },
+            msg::Zeo::Restorea(oid, serial, data, prev_txn, txn) => {
+                if let Some(trans) = transactions.get_mut(&txn) {
+                    if let Err(err) = fs.restore(
+                        trans, oid, &serial, data.as_deref(), prev_txn.as_ref()) {
+                        if let Some(trans) = transactions.remove(&txn) {
+                            fs.tpc_abort(&trans.id);
+                        }
+                        failed.insert(txn, err.context("restore"));
+                    }
                 }
             },
             msg::Zeo::Vote(id, txn) => {
                 if let Some(trans) = transactions.get(&txn) {
                     let send = client.send.clone();
-                    fs.lock(trans, Box::new(
+                    if let Err(err) = fs.lock(trans, Box::new(
                         move | _ | send.send(msg::Zeo::Locked(id, txn))
                             .or::<Result<()>>(Ok(()))
                             .unwrap()
-                    ))?;
+                    )) {
+                        transactions.remove(&txn);
+                        report(writer, client.connection, id, err)?;
+                    }
+                }
+                else if let Some(err) = failed.remove(&txn) {
+                    // An asynchronous begin or store failed.
+                    report(writer, client.connection, id, err)?;
                 }
                 else {
-                    error!(writer, id,
-                           ("ZODB.PosException.StorageTransactionError",
-                            "Invalid transaction"));
+                    report(writer, client.connection, id, invalid_transaction(txn))?;
                 };
             },
             msg::Zeo::Locked(id, txn) => {
-                if let Some(mut trans) = transactions.get_mut(&txn) {
-                    trans.locked()?;
-                    let conflicts = fs.stage(&mut trans)?;
+                if let Some(mut trans) = transactions.remove(&txn) {
+                    let conflicts = match trans.locked()
+                        .and_then(| _ | fs.stage(&mut trans)) {
+                            Ok(conflicts) => conflicts,
+                            Err(err) => {
+                                fs.tpc_abort(&trans.id);
+                                report(writer, client.connection, id, err)?;
+                                return Ok(true);
+                            }
+                        };
+                    transactions.insert(txn, trans);
                     let conflict_maps:
                     Vec<std::collections::BTreeMap<String, serde::bytes::Bytes>> =
                         conflicts.iter()
@@ -152,15 +527,28 @@ pub fn writer<W: std::io::Write>(
                 if let Some(trans) = transactions.remove(&txn) {
                     let mut client = client.clone();
                     client.request_id = id;
-                    fs.tpc_finish(&trans.id, client)?;
+                    let connection = client.connection;
+                    let finished = if self.defer_fsync {
+                        fs.tpc_finish_deferred(&trans.id, client)
+                    }
+                    else {
+                        fs.tpc_finish(&trans.id, client)
+                    };
+                    if let Err(err) = finished {
+                        report(writer, connection, id, err)?;
+                    }
                 }
                 else {
-                    error!(writer, id,
-                           ("ZODB.PosException.StorageTransactionError",
-                            "Invalid transaction"));
+                    report(writer, client.connection, id, invalid_transaction(txn))?;
                 }
             },
-            msg::Zeo::Finished(id, tid, len, size) => {
+            msg::Zeo::Finished(id, tid, len, size, oids) => {
+                // New serials first, so the client can update its
+                // cache before tpc_finish returns.
+                let serials: Vec<(serde::bytes::Bytes, serde::bytes::Bytes)> =
+                    oids.iter().map(| oid | (msg::bytes(oid), msg::bytes(&tid)))
+                    .collect();
+                async_!(writer, "serialnos", (serials,));
                 respond!(writer, id, msg::bytes(&tid));
                 let mut info: std::collections::BTreeMap<String, u64> =
                     std::collections::BTreeMap::new();
@@ -173,16 +561,80 @@ pub fn writer<W: std::io::Write>(
                     oids.iter().map(| oid | msg::bytes(oid)).collect();
                 async_!(writer, "invalidateTransaction", (msg::bytes(&tid), oids));
             },
+            msg::Zeo::Durable(tid) => {
+                async_!(writer, "durable", (msg::bytes(&tid),));
+            },
+            msg::Zeo::Info(len, size, last_tid) => {
+                let mut info: std::collections::BTreeMap<String, msg::InfoValue> =
+                    std::collections::BTreeMap::new();
+                info.insert("length".to_string(), msg::InfoValue::Int(len));
+                info.insert("size".to_string(), msg::InfoValue::Int(size));
+                info.insert("last_tid".to_string(), msg::InfoValue::Bytes(last_tid.to_vec()));
+                async_!(writer, "info", (info,));
+            },
+            msg::Zeo::Heartbeat => {
+                writer.write_all(&message!(-1, "heartbeat", ())).context("send heartbeat")?
+            },
+            msg::Zeo::DeferFsync(id, defer) => {
+                self.defer_fsync = defer;
+                respond!(writer, id, msg::NIL);
+            },
+            msg::Zeo::Pack(id, time, wait) => {
+                let pack_tid = crate::tid::time_tid(time);
+                if client.read_only {
+                    report(writer, client.connection, id, errors::POSError::ReadOnly.into())?;
+                }
+                else if wait {
+                    match fs.pack(&pack_tid) {
+                        Ok(_) => respond!(writer, id, msg::NIL),
+                        Err(err) => report(writer, client.connection, id, err)?,
+                    }
+                }
+                else {
+                    let fs = fs.clone();
+                    std::thread::spawn(move || if let Err(err) = fs.pack(&pack_tid) {
+                        tracing::error!("{}: pack failed: {:#}", fs.path(), err);
+                    });
+                    respond!(writer, id, msg::NIL);
+                }
+            },
+            msg::Zeo::Backup(id, path) => {
+                if client.read_only {
+                    report(writer, client.connection, id, errors::POSError::ReadOnly.into())?;
+                }
+                else {
+                    match fs.backup_path(&path).and_then(| path | fs.backup(&path)) {
+                        Ok(tid) => respond!(writer, id, msg::bytes(&tid)),
+                        Err(err) => report(writer, client.connection, id, err)?,
+                    }
+                }
+            },
+            msg::Zeo::IncrementalBackup(id, dir) => {
+                if client.read_only {
+                    report(writer, client.connection, id, errors::POSError::ReadOnly.into())?;
+                }
+                else {
+                    match fs.backup_path(&dir).and_then(| dir | fs.incremental_backup(&dir)) {
+                        Ok(entry) => respond!(writer, id, msg::bytes(&entry.tid)),
+                        Err(err) => report(writer, client.connection, id, err)?,
+                    }
+                }
+            },
             msg::Zeo::TpcAbort(id, txn) => {
                 if let Some(trans) = transactions.remove(&txn) {
                     fs.tpc_abort(&trans.id);
                 }
+                failed.remove(&txn);
                 respond!(writer, id, msg::NIL);
 
             },
-            msg::Zeo::End => break,
+            msg::Zeo::Close(reason) => {
+                async_!(writer, "disconnected", (reason,));
+                return Ok(false);
+            },
+            msg::Zeo::End => return Ok(false),
             _ => {}
         }
+        Ok(true)
     }
-    Ok(())
 }
@@ -60,16 +60,55 @@ fn basic() {
             assert_eq!(util::read8(&mut (&*tid)).unwrap(), fs.last_transaction());
         }, _ => panic!("invalid message")
     }
-    // get_info(), mostly punt for now:
+    // get_info()
     writer.write_all(&sencode!((2, "get_info", ())).unwrap()).unwrap();
     match rx.recv().unwrap() {
         msg::Zeo::Raw(r) => {
             let r = unsize(r);
-            let (id, code, info): (u64, String, BTreeMap<String, u64>) =
+            let (id, code, info): (u64, String, BTreeMap<String, msg::InfoValue>) =
                 decode!(&mut (&r as &[u8]),
                         "decoding get_info response").unwrap();
             assert_eq!(id, 2); assert_eq!(&code, "R");
-            assert_eq!(info, BTreeMap::new());
+            assert_eq!(info["length"], msg::InfoValue::Int(2));
+            assert_eq!(info["size"], msg::InfoValue::Int(fs.size()));
+            assert_eq!(info["server_version"],
+                       msg::InfoValue::Str(byteserver::info::VERSION.to_string()));
+            assert_eq!(
+                info.keys().cloned().collect::<Vec<String>>(),
+                vec!["capabilities", "config_digest", "git_revision", "length", "name",
+                     "server_version", "size", "start_time", "supportsUndo", "uptime"]);
+            assert_eq!(info["supportsUndo"], msg::InfoValue::Bool(false));
+        }, _ => panic!("invalid message")
+    }
+    // getExtensionMethods
+    writer.write_all(&sencode!((2, "getExtensionMethods", ())).unwrap()).unwrap();
+    match rx.recv().unwrap() {
+        msg::Zeo::Raw(r) => {
+            let r = unsize(r);
+            let (id, code, methods): (u64, String, BTreeMap<String, Option<u32>>) =
+                decode!(&mut (&r as &[u8]),
+                        "decoding getExtensionMethods response").unwrap();
+            assert_eq!(id, 2); assert_eq!(&code, "R");
+            assert_eq!(methods.get("hot_objects"), Some(&None));
+            assert_eq!(methods.len(), byteserver::info::EXTENSION_METHODS.len());
+        }, _ => panic!("invalid message")
+    }
+    // server_status
+    writer.write_all(&sencode!((2, "server_status", ())).unwrap()).unwrap();
+    match rx.recv().unwrap() {
+        msg::Zeo::Raw(r) => {
+            let r = unsize(r);
+            let (id, code, status): (u64, String, BTreeMap<String, msg::InfoValue>) =
+                decode!(&mut (&r as &[u8]), "decoding server_status response").unwrap();
+            assert_eq!(id, 2); assert_eq!(&code, "R");
+            assert_eq!(
+                status.keys().cloned().collect::<Vec<String>>(),
+                vec!["aborts", "commits", "conflicts", "connections", "last-transaction",
+                     "latencies", "missed-heartbeats", "record-cache-hit-rate",
+                     "record-cache-hits", "record-cache-misses", "record-cache-size",
+                     "start", "storage", "voted", "waiting"]);
+            assert_eq!(status["last-transaction"],
+                       msg::InfoValue::Str(util::hex(&fs.last_transaction())));
         }, _ => panic!("invalid message")
     }
     // loadBefore
@@ -125,13 +164,66 @@ fn basic() {
     match rx.recv().unwrap() {
         msg::Zeo::Raw(r) => {
             let r = unsize(r);
-            let (id, code, (ename, (oid,))): (
-                u64, String, (String, (ByteBuf,))) =
+            let (id, code, (ename, (oid, cid))): (
+                u64, String, (String, (ByteBuf, String))) =
                 decode!(&mut (&r as &[u8]),
                         "decoding loadBefore response").unwrap();
             assert_eq!(id, 3); assert_eq!(&code, "E");
             assert_eq!(ename, "ZODB.POSException.POSKeyError");
-            assert_eq!(&*oid, &util::p64(9))
+            assert_eq!(&*oid, &util::p64(9));
+            assert!(cid.ends_with(".3"), "{}", cid)
+        }, _ => panic!("invalid message")
+    }
+    // loadBefores, with a missing object
+    writer.write_all(
+        &sencode!((3, "loadBefores", (vec![util::Z64, util::p64(9), util::Z64], tid1)))
+            .unwrap()).unwrap();
+    match rx.recv().unwrap() {
+        msg::Zeo::Raw(r) => {
+            let r = unsize(r);
+            type Loaded = (ByteBuf, ByteBuf, Option<ByteBuf>);
+            let (id, code, results): (u64, String, Vec<Option<Loaded>>) =
+                decode!(&mut (&r as &[u8]),
+                        "decoding loadBefores response").unwrap();
+            assert_eq!(id, 3); assert_eq!(&code, "R");
+            assert_eq!(results.len(), 3);
+            let (data, tid, end) = results[0].clone().unwrap();
+            assert_eq!(&*data, b"000");
+            assert_eq!(util::read8(&mut &*tid).unwrap(), tid0);
+            assert_eq!(util::read8(&mut &*end.unwrap()).unwrap(), tid1);
+            assert!(results[1].is_none());
+            assert_eq!(results[2], results[0]);
+        }, _ => panic!("invalid message")
+    }
+    // getInvalidations
+    writer.write_all(&sencode!((3, "getInvalidations", (tid0,))).unwrap()).unwrap();
+    match rx.recv().unwrap() {
+        msg::Zeo::Raw(r) => {
+            let r = unsize(r);
+            let (id, code, (tid, oids)): (u64, String, (ByteBuf, Vec<ByteBuf>)) =
+                decode!(&mut (&r as &[u8]),
+                        "decoding getInvalidations response").unwrap();
+            assert_eq!(id, 3); assert_eq!(&code, "R");
+            assert_eq!(util::read8(&mut &*tid).unwrap(), tid1);
+            assert_eq!(oids.iter().map(| oid | oid.to_vec()).collect::<Vec<_>>(),
+                       vec![util::Z64.to_vec(), util::p64(3).to_vec()]);
+        }, _ => panic!("invalid message")
+    }
+    // load_before_range, loading big objects in pieces
+    writer.write_all(
+        &sencode!((3, "load_before_range", (util::Z64, tid1, 1, 5))).unwrap()).unwrap();
+    match rx.recv().unwrap() {
+        msg::Zeo::Raw(r) => {
+            let r = unsize(r);
+            let (id, code, (data, tid, end, length)): (
+                u64, String, (ByteBuf, ByteBuf, Option<ByteBuf>, u64)) =
+                decode!(&mut (&r as &[u8]),
+                        "decoding load_before_range response").unwrap();
+            assert_eq!(id, 3); assert_eq!(&code, "R");
+            assert_eq!(&*data, b"00");
+            assert_eq!(util::read8(&mut &*tid).unwrap(), tid0);
+            assert_eq!(util::read8(&mut &*end.unwrap()).unwrap(), tid1);
+            assert_eq!(length, 3);
         }, _ => panic!("invalid message")
     }
 
@@ -165,13 +257,55 @@ fn basic() {
             )
         }, _ => panic!("invalid message")
     }
+
+    // new_oid continues where new_oids left off:
+    writer.write_all(&sencode!((5, "new_oid", ())).unwrap()).unwrap();
+    match rx.recv().unwrap() {
+        msg::Zeo::Raw(r) => {
+            let r = unsize(r);
+            let (id, code, oid): (u64, String, ByteBuf) =
+                decode!(&mut (&r as &[u8]), "decoding new_oid response").unwrap();
+            assert_eq!(id, 5); assert_eq!(&code, "R");
+            assert_eq!(util::read8(&mut &*oid).unwrap(), util::p64(104));
+        }, _ => panic!("invalid message")
+    }
+
+    // lastTransaction:
+    writer.write_all(&sencode!((6, "lastTransaction", ())).unwrap()).unwrap();
+    match rx.recv().unwrap() {
+        msg::Zeo::Raw(r) => {
+            let r = unsize(r);
+            let (id, code, tid): (u64, String, ByteBuf) =
+                decode!(&mut (&r as &[u8]), "decoding lastTransaction response").unwrap();
+            assert_eq!(id, 6); assert_eq!(&code, "R");
+            assert_eq!(util::read8(&mut &*tid).unwrap(), fs.last_transaction());
+        }, _ => panic!("invalid message")
+    }
+
+    // record_iternext walks current records:
+    for (start, oid, data, next) in [(None, util::Z64, b"111", Some(util::p64(3))),
+                                     (Some(util::p64(3)), util::p64(3), b"ooo", None)] {
+        writer.write_all(&sencode!((7, "record_iternext", (start,))).unwrap()).unwrap();
+        match rx.recv().unwrap() {
+            msg::Zeo::Raw(r) => {
+                let r = unsize(r);
+                let (id, code, (roid, tid, rdata, rnext)): (
+                    u64, String, (ByteBuf, ByteBuf, ByteBuf, Option<ByteBuf>)) =
+                    decode!(&mut (&r as &[u8]), "decoding record_iternext response").unwrap();
+                assert_eq!(id, 7); assert_eq!(&code, "R");
+                assert_eq!((&*roid, &*rdata), (&oid[..], &data[..]));
+                assert_eq!(util::read8(&mut &*tid).unwrap(), tid1);
+                assert_eq!(rnext.map(| n | util::read8(&mut &*n).unwrap()), next);
+            }, _ => panic!("invalid message")
+        }
+    }
     
     // Requests that deal with transactions are merely forwarded:
     writer.write_all(
         &sencode!((0, "tpc_begin", (42, b"u", b"d", b"e", msg::NIL, b" ")))
             .unwrap()).unwrap();
     match rx.recv().unwrap() {
-        msg::Zeo::TpcBegin(42, user, desc, ext) => {
+        msg::Zeo::TpcBegin(42, user, desc, ext, None) => {
             assert_eq!((user, desc, ext),
                        (b"u".to_vec(), b"d".to_vec(), b"e".to_vec()));
         }, _ => panic!("invalid message")
@@ -185,6 +319,21 @@ fn basic() {
                        (util::Z64, fs.last_transaction(), b"111".to_vec()));
         }, _ => panic!("invalid message")
     }
+    writer.write_all(
+        &sencode!((0, "tpc_begin", (43, b"u", b"d", b"e", msg::bytes(&util::p64(7)), b" ")))
+            .unwrap()).unwrap();
+    match rx.recv().unwrap() {
+        msg::Zeo::TpcBegin(43, _, _, _, Some(tid)) => assert_eq!(tid, util::p64(7)),
+        _ => panic!("invalid message")
+    }
+    writer.write_all(
+        &sencode!((0, "restorea", (util::Z64, util::p64(7), msg::NIL, util::p64(3), 43)))
+                  .unwrap()).unwrap();
+    match rx.recv().unwrap() {
+        msg::Zeo::Restorea(oid, serial, None, Some(prev_txn), 43) => {
+            assert_eq!((oid, serial, prev_txn), (util::Z64, util::p64(7), util::p64(3)));
+        }, _ => panic!("invalid message")
+    }
     writer.write_all(
         &sencode!((4, "vote", (42,))).unwrap()).unwrap();
     match rx.recv().unwrap() {
@@ -204,3 +353,406 @@ fn basic() {
         }, _ => panic!("invalid message")
     }
 }
+
+#[test]
+fn read_view() {
+    let (reader, mut writer) = pipe::pipe();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    storage::testing::make_sample(
+        &path, vec![vec![(util::Z64, b"000")], vec![(util::Z64, b"111")]]).unwrap();
+    let fs = std::sync::Arc::new(
+        storage::FileStorage::<writer::Client>::open(path).unwrap());
+    let read_fs = fs.clone();
+    std::thread::spawn(
+        move || reader::reader(read_fs, reader, tx).unwrap()
+    );
+    writer.write_all(&msg::size_vec(b"M5".to_vec())).unwrap();
+    writer.write_all(&sencode!((1, "register", ("1", true))).unwrap()).unwrap();
+    rx.recv().unwrap();
+
+    let load = | writer: &mut pipe::PipeWriter | {
+        let now = tid::next(&tid::now_tid());
+        writer.write_all(
+            &sencode!((3, "loadBefore", (util::Z64, now))).unwrap()).unwrap();
+        match rx.recv().unwrap() {
+            msg::Zeo::Raw(r) => {
+                let r = unsize(r);
+                let (_, _, (data, _, _)): (
+                    u64, String, (ByteBuf, ByteBuf, Option<ByteBuf>)) =
+                    decode!(&mut (&r as &[u8]),
+                            "decoding loadBefore response").unwrap();
+                data.to_vec()
+            }, _ => panic!("invalid message")
+        }
+    };
+    let set_view = | writer: &mut pipe::PipeWriter, tid: Option<ByteBuf> | {
+        writer.write_all(
+            &sencode!((4, "set_read_view", (tid,))).unwrap()).unwrap();
+        match rx.recv().unwrap() {
+            msg::Zeo::Raw(r) => {
+                let r = unsize(r);
+                let (id, code, _): (u64, String, Option<u32>) =
+                    decode!(&mut (&r as &[u8]),
+                            "decoding set_read_view response").unwrap();
+                assert_eq!((id, &code as &str), (4, "R"));
+            }, _ => panic!("invalid message")
+        }
+    };
+
+    assert_eq!(load(&mut writer), b"111".to_vec());
+    set_view(&mut writer,
+             Some(ByteBuf::from(fs.last_transaction().to_vec())));
+    assert_eq!(load(&mut writer), b"000".to_vec());
+    set_view(&mut writer, None);
+    assert_eq!(load(&mut writer), b"111".to_vec());
+}
+
+#[test]
+fn send_blob() {
+    let (reader, mut writer) = pipe::pipe();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    storage::testing::make_sample(&path, vec![vec![(util::Z64, b"000")]]).unwrap();
+    let fs = std::sync::Arc::new(
+        storage::FileStorage::<writer::Client>::open(path).unwrap());
+    fs.set_blob_dir(&byteserver::util::test::test_path(&tdir, "blobs"), false).unwrap();
+    let tid = fs.last_transaction();
+    let blob_path = fs.blob_dir().unwrap().blob_path(&util::Z64, &tid);
+    std::fs::create_dir_all(blob_path.parent().unwrap()).unwrap();
+    let blob = vec![7u8; reader::BLOB_CHUNK_SIZE + 1];
+    std::fs::write(&blob_path, &blob).unwrap();
+
+    let read_fs = fs.clone();
+    std::thread::spawn(
+        move || reader::reader(read_fs, reader, tx).unwrap()
+    );
+    writer.write_all(&msg::size_vec(b"M5".to_vec())).unwrap();
+    writer.write_all(&sencode!((1, "register", ("1", true))).unwrap()).unwrap();
+    rx.recv().unwrap();
+
+    let next = || match rx.recv().unwrap() {
+        msg::Zeo::Raw(r) => unsize(r),
+        _ => panic!("invalid message"),
+    };
+
+    // The blob is sent in chunks, before the response:
+    writer.write_all(&sencode!((2, "sendBlob", (util::Z64, tid))).unwrap()).unwrap();
+    let (id, method, (oid, serial)): (u64, String, (ByteBuf, ByteBuf)) =
+        decode!(&mut (&next() as &[u8]), "decoding receiveBlobStart").unwrap();
+    assert_eq!((id, &method as &str), (0, "receiveBlobStart"));
+    assert_eq!((&*oid, &*serial), (&util::Z64[..], &tid[..]));
+    let mut received = vec![];
+    for _ in 0..2 {
+        let (id, method, (_, _, chunk)): (u64, String, (ByteBuf, ByteBuf, ByteBuf)) =
+            decode!(&mut (&next() as &[u8]), "decoding receiveBlobChunk").unwrap();
+        assert_eq!((id, &method as &str), (0, "receiveBlobChunk"));
+        received.extend_from_slice(&chunk);
+    }
+    assert_eq!(received, blob);
+    let (id, method, _): (u64, String, (ByteBuf, ByteBuf)) =
+        decode!(&mut (&next() as &[u8]), "decoding receiveBlobStop").unwrap();
+    assert_eq!((id, &method as &str), (0, "receiveBlobStop"));
+    let (id, code, _): (u64, String, Option<u32>) =
+        decode!(&mut (&next() as &[u8]), "decoding sendBlob response").unwrap();
+    assert_eq!((id, &code as &str), (2, "R"));
+
+    // Missing blobs are POSKeyErrors:
+    writer.write_all(&sencode!((3, "sendBlob", (util::p64(1), tid))).unwrap()).unwrap();
+    let (id, code, (name, _)): (u64, String, (String, (ByteBuf, String))) =
+        decode!(&mut (&next() as &[u8]), "decoding sendBlob error").unwrap();
+    assert_eq!((id, &code as &str, &name as &str),
+               (3, "E", "ZODB.POSException.POSKeyError"));
+}
+
+#[test]
+fn undo_log() {
+    let (reader, mut writer) = pipe::pipe();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    storage::testing::make_sample(
+        &path,
+        vec![vec![(util::Z64, b"000")], vec![(util::Z64, b"111")],
+             vec![(util::Z64, b"222")]]).unwrap();
+    let fs = storage::FileStorage::<writer::Client>::open(path.clone()).unwrap();
+    let mut tids = vec![];
+    let mut before = tid::next(&fs.last_transaction());
+    for _ in 0 .. 3 {
+        match fs.load_before(&util::Z64, &before).unwrap() {
+            storage::LoadBeforeResult::Loaded(_, tid, _) => {
+                tids.push(tid);
+                before = tid;
+            },
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+    let fs = std::sync::Arc::new(fs);
+    std::thread::spawn(
+        move || reader::reader(fs, reader, tx).unwrap()
+    );
+    writer.write_all(&msg::size_vec(b"M5".to_vec())).unwrap();
+    writer.write_all(&sencode!((1, "register", ("1", true))).unwrap()).unwrap();
+    rx.recv().unwrap();
+
+    let ids = || {
+        match rx.recv().unwrap() {
+            msg::Zeo::Raw(r) => {
+                let r = unsize(r);
+                let (_, code, log): (u64, String, Vec<BTreeMap<String, msg::InfoValue>>) =
+                    decode!(&mut (&r as &[u8]), "decoding undo response").unwrap();
+                assert_eq!(&code, "R");
+                for d in log.iter() {
+                    assert_eq!(
+                        d.keys().cloned().collect::<Vec<String>>(),
+                        vec!["description", "id", "time", "user_name"]);
+                    assert_eq!(d["user_name"], msg::InfoValue::Bytes(vec![]));
+                }
+                log.iter().map(| d | match d["id"] {
+                    msg::InfoValue::Bytes(ref id) => util::read8(&mut &id[..]).unwrap(),
+                    _ => panic!("bad id"),
+                }).collect::<Vec<util::Tid>>()
+            }, _ => panic!("invalid message")
+        }
+    };
+
+    // Newest first, with a negative last meaning a count:
+    writer.write_all(&sencode!((2, "undoLog", (0, -20))).unwrap()).unwrap();
+    assert_eq!(ids(), vec![tids[0], tids[1], tids[2]]);
+    writer.write_all(&sencode!((2, "undoLog", (1, 2))).unwrap()).unwrap();
+    assert_eq!(ids(), vec![tids[1]]);
+    writer.write_all(&sencode!((2, "undoLog", (1, -1))).unwrap()).unwrap();
+    assert_eq!(ids(), vec![tids[1]]);
+    // Huge counts don't overflow:
+    writer.write_all(&sencode!((2, "undoLog", (1, i64::MIN))).unwrap()).unwrap();
+    assert_eq!(ids(), vec![tids[1], tids[2]]);
+
+    // undoInfo filters by a specification, matching strings to bytes:
+    writer.write_all(&sencode!((2, "undoInfo", (0, 2, msg::NIL))).unwrap()).unwrap();
+    assert_eq!(ids(), vec![tids[0], tids[1]]);
+    let mut spec = BTreeMap::new();
+    spec.insert("user_name", "");
+    writer.write_all(&sencode!((2, "undoInfo", (0, 2, &spec))).unwrap()).unwrap();
+    assert_eq!(ids(), vec![tids[0], tids[1]]);
+    spec.insert("user_name", "bob");
+    writer.write_all(&sencode!((2, "undoInfo", (0, 2, &spec))).unwrap()).unwrap();
+    assert!(ids().is_empty());
+}
+
+#[test]
+fn iterators() {
+    let (reader, mut writer) = pipe::pipe();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    storage::testing::make_sample(
+        &path,
+        vec![vec![(util::Z64, b"000")],
+             vec![(util::Z64, b"111"), (util::p64(1), b"aaa")]]).unwrap();
+    let tids: Vec<util::Tid> = byteserver::scan::TransactionIterator::open(&path).unwrap()
+        .map(| r | r.unwrap().tid()).collect();
+    let fs = std::sync::Arc::new(
+        storage::FileStorage::<writer::Client>::open(path).unwrap());
+    std::thread::spawn(
+        move || reader::reader(fs, reader, tx).unwrap()
+    );
+    writer.write_all(&msg::size_vec(b"M5".to_vec())).unwrap();
+    writer.write_all(&sencode!((1, "register", ("1", true))).unwrap()).unwrap();
+    rx.recv().unwrap();
+
+    let response = || match rx.recv().unwrap() {
+        msg::Zeo::Raw(r) => unsize(r),
+        _ => panic!("invalid message"),
+    };
+    let iid = | r: Vec<u8> | {
+        let (_, code, iid): (u64, String, u64) =
+            decode!(&mut (&r as &[u8]), "decoding iterator id").unwrap();
+        assert_eq!(&code, "R");
+        iid
+    };
+
+    // Iterate from the second transaction on:
+    writer.write_all(
+        &sencode!((2, "iterator_start", (tids[1], msg::NIL))).unwrap()).unwrap();
+    let transactions = iid(response());
+    writer.write_all(&sencode!((3, "iterator_next", (transactions,))).unwrap()).unwrap();
+    let r = response();
+    let (_, _, (tid, status, user, _, _)): (
+        u64, String, (ByteBuf, String, ByteBuf, ByteBuf, ByteBuf)) =
+        decode!(&mut (&r as &[u8]), "decoding iterator_next response").unwrap();
+    assert_eq!(util::read8(&mut &*tid).unwrap(), tids[1]);
+    assert_eq!((&status[..], &*user), (" ", &b""[..]));
+
+    writer.write_all(
+        &sencode!((4, "iterator_record_start", (transactions, tids[1]))).unwrap()).unwrap();
+    let records = iid(response());
+    type RecordResponse = (u64, String, Option<(ByteBuf, ByteBuf, ByteBuf, Option<ByteBuf>)>);
+    let mut found = vec![];
+    loop {
+        writer.write_all(&sencode!((5, "iterator_record_next", (records,))).unwrap())
+            .unwrap();
+        let r = response();
+        let (_, _, record): RecordResponse =
+            decode!(&mut (&r as &[u8]), "decoding iterator_record_next response").unwrap();
+        match record {
+            Some((oid, tid, data, data_txn)) => {
+                assert_eq!(util::read8(&mut &*tid).unwrap(), tids[1]);
+                assert!(data_txn.is_none());
+                found.push((util::read8(&mut &*oid).unwrap(), data.to_vec()));
+            },
+            None => break,
+        }
+    }
+    assert_eq!(found, vec![(util::Z64, b"111".to_vec()), (util::p64(1), b"aaa".to_vec())]);
+
+    writer.write_all(&sencode!((6, "iterator_next", (transactions,))).unwrap()).unwrap();
+    let r = response();
+    let (_, code, end): (u64, String, Option<u32>) =
+        decode!(&mut (&r as &[u8]), "decoding iterator_next response").unwrap();
+    assert_eq!((&code[..], end), ("R", None));
+
+    // Exhausted iterators are gone:
+    writer.write_all(&sencode!((7, "iterator_next", (transactions,))).unwrap()).unwrap();
+    let r = response();
+    let (_, code, (ename, _)): (u64, String, (String, (String, String))) =
+        decode!(&mut (&r as &[u8]), "decoding iterator_next error").unwrap();
+    assert_eq!((&code[..], &ename[..]), ("E", "ZODB.POSException.StorageError"));
+}
+
+#[test]
+fn handshake() {
+    assert_eq!(reader::negotiate(b"M5"), reader::Handshake::Accepted(b"M5".to_vec()));
+    assert_eq!(reader::negotiate(b"Z5"), reader::Handshake::Accepted(b"Z5".to_vec()));
+    assert_eq!(reader::negotiate(b"M5+zstd"),
+               reader::Handshake::Accepted(b"M5+zstd".to_vec()));
+    assert_eq!(reader::negotiate(b"Z4"), reader::Handshake::Unsupported(b"Z4".to_vec()));
+
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    let fs = std::sync::Arc::new(
+        storage::FileStorage::<writer::Client>::open(path).unwrap());
+    let connect = | protocol: &[u8] | {
+        let (reader, mut writer) = pipe::pipe();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let fs = fs.clone();
+        let thread = std::thread::spawn(move || reader::reader(fs, reader, tx));
+        writer.write_all(&msg::size_vec(protocol.to_vec())).unwrap();
+        // Fails if the server has given up on us:
+        let _ = msg::Encoder::new(writer, msg::codec(protocol))
+            .write_all(&sencode!((1, "register", ("1", true))).unwrap());
+        (thread, rx)
+    };
+
+    // ZEO 5 clients reply Z5 unless configured for msgpack, and
+    // speak pickle.  Responses are encoded by the writer.
+    let (_thread, rx) = connect(b"Z5");
+    match rx.recv().unwrap() {
+        msg::Zeo::Raw(r) => {
+            let r = unsize(r);
+            let (id, code, _): (u64, String, ByteBuf) =
+                decode!(&mut (&r as &[u8]), "decoding register response").unwrap();
+            assert_eq!((id, &code[..]), (1, "R"));
+        }, _ => panic!("invalid message")
+    }
+
+    // Clients can ask for large messages to be compressed, and flag
+    // each message:
+    let (_thread, rx) = connect(b"M5+zstd");
+    match rx.recv().unwrap() {
+        msg::Zeo::Raw(r) => {
+            let r = unsize(r);
+            let (id, code, _): (u64, String, ByteBuf) =
+                decode!(&mut (&r as &[u8]), "decoding register response").unwrap();
+            assert_eq!((id, &code[..]), (1, "R"));
+        }, _ => panic!("invalid message")
+    }
+
+    // Older clients are turned away:
+    let (thread, _rx) = connect(b"Z4");
+    let err = thread.join().unwrap().unwrap_err();
+    assert_eq!(format!("{}", err),
+               "Unsupported protocol \"Z4\", supported protocols are \
+                M5, Z5, M5+zstd, Z5+zstd");
+}
+
+#[test]
+fn read_only_checkpoints() {
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    storage::testing::make_sample(&path, vec![vec![(util::Z64, b"000")]]).unwrap();
+    let fs = std::sync::Arc::new(
+        storage::FileStorage::<writer::Client>::open(path).unwrap());
+    let connect = | read_only: bool | {
+        let (reader, mut writer) = pipe::pipe();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let fs = fs.clone();
+        std::thread::spawn(move || reader::reader(fs, reader, tx));
+        writer.write_all(&msg::size_vec(b"M5".to_vec())).unwrap();
+        writer.write_all(&sencode!((1, "register", ("1", read_only))).unwrap()).unwrap();
+        rx.recv().unwrap();
+        (writer, rx)
+    };
+    // Returns the response code, or the error name
+    let call = | writer: &mut pipe::PipeWriter, rx: &std::sync::mpsc::Receiver<msg::Zeo>,
+                 request: Vec<u8> | {
+        writer.write_all(&request).unwrap();
+        match rx.recv().unwrap() {
+            msg::Zeo::Raw(r) => {
+                let r = unsize(r);
+                let mut r = &r as &[u8];
+                assert_eq!(rmp::decode::read_array_size(&mut r).unwrap(), 3);
+                let (id, code): (u64, String) = (
+                    decode!(&mut r, "decoding id").unwrap(),
+                    decode!(&mut r, "decoding code").unwrap());
+                assert_eq!(id, 2);
+                if code == "E" {
+                    assert_eq!(rmp::decode::read_array_size(&mut r).unwrap(), 2);
+                    decode!(&mut r, "decoding error name").unwrap()
+                }
+                else {
+                    code
+                }
+            },
+            _ => panic!("invalid message"),
+        }
+    };
+
+    // Read-only clients can't make the server save its index, but can
+    // get hot objects, which changes nothing:
+    let checkpoint = || sencode!((2, "checkpoint", ())).unwrap();
+    let (mut writer, rx) = connect(true);
+    assert_eq!(call(&mut writer, &rx, checkpoint()), "ZODB.POSException.ReadOnlyError");
+    assert_eq!(call(&mut writer, &rx, sencode!((2, "hot_objects", (10,))).unwrap()), "R");
+
+    let (mut writer, rx) = connect(false);
+    assert_eq!(call(&mut writer, &rx, checkpoint()), "R");
+}
+
+#[test]
+fn missing_extensions() {
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    let fs = storage::FileStorage::<writer::Client>::open(path).unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    // Extension calls the connection has no handler for get errors,
+    // and the connection carries on:
+    let mut state = reader::State::default();
+    let message = msg::Zeo::Extension(3, "frobnicate".to_string(), vec![0x90]);
+    assert!(reader::handle(&fs, &mut state, message, &tx, 1).unwrap());
+    match rx.recv().unwrap() {
+        msg::Zeo::Raw(r) => {
+            let r = unsize(r);
+            let (id, code, (name, (method, _))): (u64, String, (String, (String, String))) =
+                decode!(&mut (&r as &[u8]), "decoding error response").unwrap();
+            assert_eq!((id, &code[..], &name[..], &method[..]),
+                       (3, "E", "builtins.AttributeError", "frobnicate"));
+        }, _ => panic!("invalid message")
+    }
+}
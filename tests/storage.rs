@@ -9,6 +9,8 @@ enum ClientMessage {
     Locked(Tid),
     Finished(Tid, u64, u64),
     Invalidate(Tid, Vec<Oid>),
+    Durable(Tid),
+    Closed(byteserver::storage::DisconnectReason),
 }
 
 #[derive(Debug, Clone)]
@@ -31,7 +33,8 @@ impl PartialEq for Client {
 }
 
 impl byteserver::storage::Client for Client {
-    fn finished(&self, tid: &Tid, len:u64, size: u64) -> Result<()> {
+    fn finished(&self, tid: &Tid, len:u64, size: u64, _oids: &[Oid])
+                -> Result<()> {
         self.send.send(ClientMessage::Finished(tid.clone(), len, size))
             .context("")
     }
@@ -39,7 +42,12 @@ impl byteserver::storage::Client for Client {
         self.send.send(ClientMessage::Invalidate(
             tid.clone(), oids.clone())).context("")
     }
-    fn close(&self) {}
+    fn durable(&self, tid: &Tid) -> Result<()> {
+        self.send.send(ClientMessage::Durable(*tid)).context("")
+    }
+    fn close(&self, reason: &byteserver::storage::DisconnectReason) {
+        let _ = self.send.send(ClientMessage::Closed(reason.clone()));
+    }
 }
 
 #[test]
@@ -75,7 +83,7 @@ fn store() {
     let tid0 = match receive.recv().unwrap() {
         ClientMessage::Finished(tid, len, size) => {
             assert_eq!(len, 2);
-            assert_eq!(size, 4216);
+            assert_eq!(size, 4236);
             tid
         },
         _ => panic!("bad message"),
@@ -143,7 +151,7 @@ fn store() {
     let tid1 = match receive.recv().unwrap() {
         ClientMessage::Finished(tid, len, size) => {
             assert_eq!(len, 2);
-            assert_eq!(size, 4296);
+            assert_eq!(size, 4328);
             tid
         },
         _ => panic!("bad message"),
@@ -246,3 +254,1753 @@ fn abort() {
     }
     assert!(receive.try_recv().is_err());
 }
+
+#[test]
+fn checkpoint() {
+
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")], vec![(p64(1), b"111")]]).unwrap();
+
+    // The index was saved when the sample storage was closed.  Remove
+    // it, to make the data file be replayed:
+    let index_path = path.clone() + byteserver::storage::INDEX_SUFFIX;
+    std::fs::remove_file(&index_path).unwrap();
+
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap();
+    // Both transactions were replayed from the data file:
+    assert_eq!(fs.checkpoint().unwrap(), 2);
+    assert_eq!(fs.checkpoint().unwrap(), 0);
+
+    let (client, _receive) = Client::new("0");
+    byteserver::storage::testing::add_data(
+        &fs, &client, vec![vec![(p64(0), b"001")]]).unwrap();
+    assert_eq!(fs.checkpoint().unwrap(), 1);
+    let tid = fs.last_transaction();
+    drop(fs);
+
+    // Reopening uses the saved index, and there's nothing to replay.
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap();
+    assert_eq!(fs.last_transaction(), tid);
+    assert_eq!(fs.checkpoint().unwrap(), 0);
+    assert_eq!(fs.new_oids()[0], p64(2));
+
+    use byteserver::storage::LoadBeforeResult::*;
+    match fs.load_before(&p64(0), &byteserver::tid::next(&tid)).unwrap() {
+        Loaded(data, ltid, None) => {
+            assert_eq!(data, b"001".to_vec());
+            assert_eq!(ltid, tid);
+        },
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // Closing saves unsaved transactions in the index:
+    byteserver::storage::testing::add_data(
+        &fs, &client, vec![vec![(p64(1), b"112")]]).unwrap();
+    let tid = fs.last_transaction();
+    fs.close().unwrap();
+    assert_eq!(fs.checkpoint().unwrap(), 0);
+    drop(fs);
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap();
+    assert_eq!(fs.last_transaction(), tid);
+    assert_eq!(fs.checkpoint().unwrap(), 0);
+}
+
+#[test]
+fn old_index_files() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")], vec![(p64(1), b"111")]]).unwrap();
+    let index_path = path.clone() + byteserver::storage::INDEX_SUFFIX;
+    let index = std::fs::read(&index_path).unwrap();
+    assert_eq!(&index[..8], b"fs3i\0\0\0\x02");
+
+    // Index files from before index files had versions are read, and
+    // rewritten in the current format:
+    std::fs::write(&index_path, [&b"fs2i"[..], &index[8..]].concat()).unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    assert_eq!(fs.checkpoint().unwrap(), 0);
+    assert_eq!(std::fs::read(&index_path).unwrap(), index);
+}
+
+#[test]
+fn index_journal() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![(0 .. 20).map(| i | (p64(i), &b"000"[..])).collect()]).unwrap();
+    let index_path = path.clone() + byteserver::storage::INDEX_SUFFIX;
+    let journal_path = index_path.clone() + ".journal";
+    let journal_size = || std::fs::metadata(&journal_path).unwrap().len();
+
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    fs.set_index_journal(true).unwrap();
+    assert!(fs.index_journal());
+    let (client, _receive) = Client::new("0");
+    byteserver::storage::testing::add_data(
+        &fs, &client, vec![vec![(p64(0), b"001")], vec![(p64(20), b"222")]]).unwrap();
+
+    // Checkpoints sync the journal, rather than saving the index:
+    let index = std::fs::read(&index_path).unwrap();
+    assert!(journal_size() > 4);
+    assert_eq!(fs.checkpoint().unwrap(), 2);
+    assert_eq!(std::fs::read(&index_path).unwrap(), index);
+    let tid = fs.last_transaction();
+    drop(fs);
+
+    // Journaled changes, and not incomplete entries, are applied on open:
+    let mut journal = std::fs::OpenOptions::new().append(true).open(&journal_path).unwrap();
+    std::io::Write::write_all(&mut journal, b"incomplete").unwrap();
+    drop(journal);
+    let load = | fs: &byteserver::storage::FileStorage<Client>, oid | {
+        match fs.load_before(&p64(oid), &[0xff; 8]).unwrap() {
+            byteserver::storage::LoadBeforeResult::Loaded(data, _, _) => data,
+            r => panic!("unexpected result {:?}", r),
+        }
+    };
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    assert_eq!(fs.last_transaction(), tid);
+    assert_eq!((load(&fs, 0), load(&fs, 20)), (b"001".to_vec(), b"222".to_vec()));
+    assert_eq!(fs.new_oids()[0], p64(21));
+    assert!(fs.verify().unwrap().ok());
+
+    // When the journal grows bigger than the index file, the whole
+    // index is saved, and the journal starts over:
+    fs.set_index_journal(true).unwrap();
+    assert_eq!(journal_size(), 4);
+    for i in 0 .. 8u64 {
+        byteserver::storage::testing::add_data(
+            &fs, &client, vec![vec![(p64(i), b"xxx")]]).unwrap();
+    }
+    let size = journal_size();
+    assert!(size > std::fs::metadata(&index_path).unwrap().len());
+    fs.checkpoint().unwrap();
+    assert_eq!(journal_size(), 4);
+    assert_ne!(std::fs::read(&index_path).unwrap(), index);
+
+    // as it is after packs:
+    byteserver::storage::testing::add_data(
+        &fs, &client, vec![vec![(p64(1), b"yyy")]]).unwrap();
+    assert!(journal_size() > 4);
+    fs.pack(&fs.last_transaction()).unwrap();
+    assert_eq!(journal_size(), 4);
+    byteserver::storage::testing::add_data(
+        &fs, &client, vec![vec![(p64(2), b"zzz")]]).unwrap();
+    drop(fs);
+    assert_eq!(byteserver::fsck::check(&path).unwrap().problems, vec![]);
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    assert_eq!((load(&fs, 1), load(&fs, 2)), (b"yyy".to_vec(), b"zzz".to_vec()));
+    assert!(fs.verify().unwrap().ok());
+
+    // Saving the whole index without the journal removes it:
+    fs.checkpoint().unwrap();
+    assert!(! std::path::Path::new(&journal_path).exists());
+}
+
+#[test]
+fn limits() {
+
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")]]).unwrap();
+    let size = std::fs::metadata(&path).unwrap().len();
+
+    // Read-only storages refuse to lock:
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap()
+        .with_limits(byteserver::storage::Limits {
+            read_only: true, max_clients: Some(1), ..Default::default() });
+    let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+    trans.save(p64(1), util::Z64, b"111").unwrap();
+    let err = fs.lock(&trans, Box::new(| _ | ())).err().unwrap();
+    assert_eq!(err.to_string(), "ZODB.POSException.ReadOnlyError");
+
+    // Client count is limited:
+    let (client, _receive) = Client::new("0");
+    let (client2, _receive2) = Client::new("1");
+    fs.try_add_client(client.clone()).unwrap();
+    assert!(fs.try_add_client(client2.clone()).is_err());
+    fs.remove_client(client, byteserver::storage::DisconnectReason::Kicked);
+    fs.try_add_client(client2).unwrap();
+    drop(trans);
+    drop(fs);
+
+    // Staging fails if the data file would grow too big:
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap()
+        .with_limits(byteserver::storage::Limits {
+            max_size: Some(size + 200), ..Default::default() });
+    let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+    trans.save(p64(1), util::Z64, &[0u8; 200]).unwrap();
+    fs.lock(&trans, Box::new(| _ | ())).unwrap();
+    trans.locked().unwrap();
+    let err = fs.stage(&mut trans).err().unwrap();
+    assert_eq!(err.to_string(), "ZODB.POSException.StorageError");
+    fs.tpc_abort(&trans.id);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+    drop(fs);
+
+    // Transactions can't begin when the data file is full, or the
+    // disk nearly is:
+    let full = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap()
+        .with_limits(byteserver::storage::Limits {
+            max_size: Some(size), ..Default::default() });
+    let err = full.tpc_begin(b"", b"", b"").err().unwrap();
+    assert!(matches!(err.downcast::<byteserver::errors::POSError>(),
+                     Ok(byteserver::errors::POSError::Storage(message))
+                     if message == format!("Storage is full ({} bytes)", size)));
+    drop(full);
+    let full = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap()
+        .with_limits(byteserver::storage::Limits {
+            min_free_space: Some(u64::MAX), ..Default::default() });
+    let err = full.tpc_begin(b"", b"", b"").err().unwrap();
+    assert!(matches!(err.downcast::<byteserver::errors::POSError>(),
+                     Ok(byteserver::errors::POSError::Storage(message))
+                     if message.starts_with("Disk is nearly full")));
+    drop(full);
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap()
+        .with_limits(byteserver::storage::Limits {
+            min_free_space: Some(1), ..Default::default() });
+    let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+    trans.save(p64(1), util::Z64, b"111").unwrap();
+    fs.lock(&trans, Box::new(| _ | ())).unwrap();
+    trans.locked().unwrap();
+    fs.stage(&mut trans).unwrap();
+    fs.tpc_abort(&trans.id);
+    drop(fs);
+
+    // Transactions' sizes and object counts are limited:
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap()
+        .with_limits(byteserver::storage::Limits {
+            max_transaction_size: Some(1000), max_transaction_records: Some(2),
+            ..Default::default() });
+    let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+    let err = trans.save(p64(1), util::Z64, &[0u8; 1000]).unwrap_err();
+    assert_eq!(byteserver::errors::POSError::from_error(err.into()).to_string(),
+               "ZODB.POSException.TransactionTooLargeError");
+    let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+    trans.save(p64(1), util::Z64, b"1").unwrap();
+    trans.save(p64(2), util::Z64, b"2").unwrap();
+    assert!(trans.save(p64(3), util::Z64, b"3").is_err());
+
+    // Small transactions still fit:
+    let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+    trans.save(p64(1), util::Z64, b"1").unwrap();
+    fs.lock(&trans, Box::new(| _ | ())).unwrap();
+    trans.locked().unwrap();
+    assert_eq!(fs.stage(&mut trans).unwrap().len(), 0);
+    drop(trans);
+    drop(fs);
+
+    // Votes wait for room in the commit queue, and then fail:
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap()
+        .with_limits(byteserver::storage::Limits {
+            max_voted: Some(1), ..Default::default() });
+    let (client, _receive) = Client::new("0");
+    let vote = | oid | {
+        let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+        trans.save(p64(oid), util::Z64, b"x").unwrap();
+        fs.lock(&trans, Box::new(| _ | ())).unwrap();
+        trans.locked().unwrap();
+        match fs.stage(&mut trans) {
+            Ok(_) => Ok(trans),
+            Err(err) => {
+                fs.tpc_abort(&trans.id);
+                Err(err)
+            },
+        }
+    };
+    let first = vote(2).unwrap();
+    let start = std::time::Instant::now();
+    let err = vote(3).err().unwrap();
+    assert!(start.elapsed() >= byteserver::storage::VOTE_QUEUE_WAIT);
+    assert_eq!(err.to_string(), "ZODB.POSException.TransientError");
+    std::thread::scope(| scope | {
+        scope.spawn(|| {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            fs.tpc_finish(&first.id, client.clone()).unwrap();
+        });
+        vote(3).unwrap();
+    });
+}
+
+#[test]
+fn abort_stalled() {
+
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap();
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+
+    // A transaction votes, but its client never finishes it:
+    let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+    trans.save(p64(1), util::Z64, b"111").unwrap();
+    fs.lock(&trans, Box::new(| _ | ())).unwrap();
+    trans.locked().unwrap();
+    fs.stage(&mut trans).unwrap();
+
+    // Another transaction for the same object waits for its lock:
+    let mut trans2 = fs.tpc_begin(b"", b"", b"").unwrap();
+    trans2.save(p64(1), util::Z64, b"222").unwrap();
+    let (send, locked) = std::sync::mpsc::channel();
+    fs.lock(&trans2, Box::new(move | _ | send.send(()).unwrap())).unwrap();
+    assert!(locked.try_recv().is_err());
+
+    assert_eq!(fs.abort_stalled(std::time::Duration::from_secs(60)), 0);
+    assert_eq!(fs.abort_stalled(std::time::Duration::ZERO), 1);
+
+    // The stuck transaction is gone, and the waiting one gets its lock:
+    locked.try_recv().unwrap();
+    assert_eq!(fs.tpc_finish(&trans.id, client.clone()).err().unwrap().to_string(),
+               "ZODB.POSException.StorageTransactionError");
+    trans2.locked().unwrap();
+    fs.stage(&mut trans2).unwrap();
+    fs.tpc_finish(&trans2.id, client).unwrap();
+    let tid = fs.last_transaction();
+    match fs.load_before(&p64(1), &byteserver::tid::next(&tid)).unwrap() {
+        byteserver::storage::LoadBeforeResult::Loaded(data, ltid, None) => {
+            assert_eq!(data, b"222".to_vec());
+            assert_eq!(ltid, tid);
+        },
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // The aborted transaction's record is left as padding, which is
+    // skipped when the file is read again:
+    drop(fs);
+    std::fs::remove_file(path.clone() + byteserver::storage::INDEX_SUFFIX).unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(path).unwrap();
+    let history = fs.history(&p64(1), 9).unwrap().unwrap();
+    assert_eq!(history.iter().map(| r | r.tid).collect::<Vec<_>>(), vec![tid]);
+    assert_eq!(fs.undo_log(0, 9, | _ | true).unwrap().len(), 1);
+}
+
+#[test]
+fn incomplete_tail() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")]]).unwrap();
+    let size = std::fs::metadata(&path).unwrap().len();
+
+    // The server stops while a transaction is being voted:
+    {
+        let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+        let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+        trans.save(p64(1), util::Z64, &[1; 100]).unwrap();
+        fs.lock(&trans, Box::new(| _ | ())).unwrap();
+        trans.locked().unwrap();
+        fs.stage(&mut trans).unwrap();
+    }
+    let data = std::fs::read(&path).unwrap();
+    let voted = data.len() as u64;
+    assert!(voted > size);
+    let truncate = | size: u64 | std::fs::write(&path, &data[.. size as usize]).unwrap();
+    std::fs::remove_file(path.clone() + byteserver::storage::INDEX_SUFFIX).unwrap();
+
+    // A complete uncommitted record is skipped:
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    assert_eq!(fs.size(), voted);
+    drop(fs);
+    std::fs::remove_file(path.clone() + byteserver::storage::INDEX_SUFFIX).unwrap();
+
+    // Incomplete ones, and partial headers, are discarded, even
+    // when a read-only storage can't truncate them:
+    for tail in [voted - 1, size + 50, size + 5] {
+        truncate(tail);
+        let fs = byteserver::storage::FileStorage::<Client>::options()
+            .path(path.clone()).read_only(true).open().unwrap();
+        assert_eq!(fs.size(), size);
+        drop(fs);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), tail);
+
+        let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+        assert_eq!(fs.size(), size);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+        assert!(matches!(fs.load_before(&p64(1), &[0xff; 8]).unwrap(),
+                         byteserver::storage::LoadBeforeResult::PosKeyError));
+        drop(fs);
+        std::fs::remove_file(path.clone() + byteserver::storage::INDEX_SUFFIX).unwrap();
+    }
+
+    // Committed transactions that are cut short are errors:
+    truncate(size - 1);
+    assert!(byteserver::storage::FileStorage::<Client>::open(path.clone()).is_err());
+}
+
+#[test]
+fn mismatched_index() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    let other = util::test::test_path(&tmpdir, "other.fs");
+    byteserver::storage::testing::make_sample(&other, vec![vec![(p64(0), b"xxx")]])
+        .unwrap();
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")], vec![(p64(1), b"111")]]).unwrap();
+    let index_path = path.clone() + byteserver::storage::INDEX_SUFFIX;
+
+    // Indexes of other files, and unreadable ones, are ignored, and
+    // the index is rebuilt from the data file:
+    for index in [std::fs::read(other.clone() + byteserver::storage::INDEX_SUFFIX).unwrap(),
+                  b"garbage".to_vec()] {
+        std::fs::write(&index_path, index).unwrap();
+        let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+        for (oid, data) in [(p64(0), b"000"), (p64(1), b"111")] {
+            assert!(matches!(fs.load_before(&oid, &[0xff; 8]).unwrap(),
+                             byteserver::storage::LoadBeforeResult::Loaded(d, _, _)
+                             if d == data));
+        }
+        assert!(fs.verify().unwrap().ok());
+        drop(fs);
+        // and the index is replaced:
+        assert!(byteserver::fsck::check(&path).unwrap().ok());
+    }
+}
+
+#[test]
+fn verify() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")], vec![(p64(0), b"111"), (p64(1), b"aaa")]])
+        .unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    let report = fs.verify().unwrap();
+    assert!(report.ok(), "{:?}", report.problems);
+    assert_eq!((report.transactions, report.records), (2, 3));
+    assert_eq!(report.size, fs.size());
+
+    // Voted transactions, past the committed end, are left out:
+    let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+    trans.save(p64(2), util::Z64, b"new").unwrap();
+    fs.lock(&trans, Box::new(| _ | ())).unwrap();
+    trans.locked().unwrap();
+    fs.stage(&mut trans).unwrap();
+    assert!(fs.verify().unwrap().ok());
+    fs.tpc_abort(&trans.id);
+
+    // Damaged data are reported where they are:
+    let mut data = std::fs::read(&path).unwrap();
+    let at = data.windows(3).position(| w | w == b"aaa").unwrap();
+    data[at] = b'x';
+    std::fs::write(&path, data).unwrap();
+    let report = fs.verify().unwrap();
+    assert!(! report.ok());
+    assert_eq!(report.bad.len(), 1);
+    assert!(report.problems.iter().any(| p | p.message.contains("checksum")),
+            "{:?}", report.problems);
+}
+
+fn data_headers(path: &str) -> Vec<(u64, u32)> {
+    let mut it = byteserver::scan::TransactionIterator::open(path).unwrap();
+    let mut headers = vec![];
+    while let Some(record) = it.next() {
+        headers.extend(record.unwrap().data_headers(it.reader()).unwrap().into_iter()
+                       .map(| (pos, header) | (pos, header.checksum)));
+    }
+    headers
+}
+
+#[test]
+fn version_1_files() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")]]).unwrap();
+    assert_eq!(&std::fs::read(&path).unwrap()[.. 4], b"fs5 ");
+    assert!(data_headers(&path).iter().all(| (_, checksum) | *checksum != 0));
+
+    // Files written before records had checksums have a different
+    // marker, and 0 where checksums are now:
+    byteserver::storage::testing::make_version_1(&path).unwrap();
+
+    // They're read, and written to, without checksums, so servers
+    // that predate them can still read them:
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+    byteserver::storage::testing::add_data(&fs, &client, vec![vec![(p64(1), b"111")]])
+        .unwrap();
+    for (oid, data) in [(p64(0), b"000"), (p64(1), b"111")] {
+        assert!(matches!(fs.load_before(&oid, &[0xff; 8]).unwrap(),
+                         byteserver::storage::LoadBeforeResult::Loaded(d, _, _)
+                         if d == data));
+    }
+    drop(fs);
+    assert_eq!(&std::fs::read(&path).unwrap()[.. 4], b"fs2 ");
+    let headers = data_headers(&path);
+    assert_eq!(headers.len(), 2);
+    assert!(headers.iter().all(| (_, checksum) | *checksum == 0));
+    assert!(byteserver::scan::TransactionIterator::open(&path).unwrap()
+            .all(| record | record.unwrap().header.checksum == 0));
+    assert!(byteserver::fsck::check(&path).unwrap().ok());
+}
+
+#[test]
+fn version_3_files() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")]]).unwrap();
+
+    // Files written before lengths were 64-bit are read, and written
+    // to, in their own format, compressed records included:
+    byteserver::storage::testing::make_version(&path, 3).unwrap();
+    assert_eq!(&std::fs::read(&path).unwrap()[.. 4], b"fs4 ");
+    let big = b"Hello, world. ".repeat(100);
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    fs.set_compression(true).unwrap();
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+    byteserver::storage::testing::add_data(&fs, &client, vec![vec![(p64(1), &big)]])
+        .unwrap();
+    let load = | fs: &byteserver::storage::FileStorage<Client>, oid | {
+        match fs.load_before(&oid, &[0xff; 8]).unwrap() {
+            byteserver::storage::LoadBeforeResult::Loaded(data, _, _) => data,
+            _ => panic!("not loaded"),
+        }
+    };
+    assert_eq!(load(&fs, p64(0)), b"000");
+    assert_eq!(load(&fs, p64(1)), big);
+    drop(fs);
+    assert_eq!(&std::fs::read(&path).unwrap()[.. 4], b"fs4 ");
+    assert!(byteserver::fsck::check(&path).unwrap().ok());
+
+    // Compacting upgrades them, keeping compressed records compressed:
+    byteserver::compact::compact(&path).unwrap();
+    assert_eq!(&std::fs::read(&path).unwrap()[.. 4], b"fs5 ");
+    assert!(byteserver::fsck::check(&path).unwrap().ok());
+    let mut it = byteserver::scan::TransactionIterator::open(&path).unwrap();
+    let mut compressed = vec![];
+    while let Some(record) = it.next() {
+        compressed.extend(record.unwrap().data_headers(it.reader()).unwrap().into_iter()
+                          .map(| (_, header) | header.compressed));
+    }
+    assert_eq!(compressed, vec![false, true]);
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    assert_eq!(load(&fs, p64(0)), b"000");
+    assert_eq!(load(&fs, p64(1)), big);
+
+    // Compressed records can't be written to files that predate them:
+    drop(fs);
+    assert!(byteserver::storage::testing::make_version(&path, 2).is_err());
+}
+
+#[test]
+fn compression_dictionaries() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    let person = | i: u64 | format!("cmyapp\nPerson\nq\x01)q\x02}}q\x03(X\x04name\
+                                     q\x04X\x05p{:04}q\x05X\x03ageq\x06K{}u.", i, i % 90)
+        .into_bytes();
+    let people: Vec<Vec<u8>> = (0 .. 500).map(person).collect();
+    byteserver::storage::testing::make_sample(
+        &path, vec![people.iter().enumerate().map(
+            | (i, data) | (p64(i as u64), &data[..])).collect()]).unwrap();
+    let trained = byteserver::dictionaries::train(&path, 500, 4096).unwrap();
+
+    // Small records are compressed with the dictionary:
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    assert_eq!(fs.dictionaries().ids(), &[trained.id]);
+    fs.set_compression(true).unwrap();
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+    let new = person(1000);
+    byteserver::storage::testing::add_data(&fs, &client, vec![vec![(p64(1000), &new)]])
+        .unwrap();
+    let load = | fs: &byteserver::storage::FileStorage<Client>, oid | {
+        match fs.load_before(&oid, &[0xff; 8]).unwrap() {
+            byteserver::storage::LoadBeforeResult::Loaded(data, _, _) => data,
+            _ => panic!("not loaded"),
+        }
+    };
+    assert_eq!(load(&fs, p64(1000)), new);
+    assert_eq!(load(&fs, p64(7)), people[7]);
+    let mut it = byteserver::scan::TransactionIterator::open(&path).unwrap();
+    let last = it.by_ref().last().unwrap().unwrap();
+    let (_, header) = last.data_headers(it.reader()).unwrap().pop().unwrap();
+    assert!(header.compressed && header.length < new.len() as u64);
+
+    // Backups keep dictionaries:
+    let dir = util::test::test_path(&tmpdir, "backups");
+    fs.incremental_backup(&dir).unwrap();
+    drop(fs);
+    let restored = util::test::test_path(&tmpdir, "restored.fs");
+    byteserver::backup::restore(&dir, &restored).unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(restored.clone()).unwrap();
+    assert_eq!(load(&fs, p64(1000)), new);
+    drop(fs);
+
+    // Records can't be read without their dictionaries:
+    std::fs::remove_file(byteserver::dictionaries::file_path(&restored)).unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(restored.clone()).unwrap();
+    assert!(fs.load_before(&p64(1000), &[0xff; 8]).is_err());
+    assert_eq!(load(&fs, p64(7)), people[7]);
+}
+
+#[test]
+fn deferred_fsync() {
+
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap();
+    let (client, receive) = Client::new("0");
+    fs.add_client(client.clone());
+    assert_eq!(fs.sync_deferred().unwrap(), 0);
+
+    let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+    trans.save(p64(0), util::Z64, b"000").unwrap();
+    fs.lock(&trans, Box::new(| _ | ())).unwrap();
+    trans.locked().unwrap();
+    assert_eq!(fs.stage(&mut trans).unwrap().len(), 0);
+    fs.tpc_finish_deferred(&trans.id, client.clone()).unwrap();
+
+    // The commit is visible right away:
+    let tid = match receive.recv().unwrap() {
+        ClientMessage::Finished(tid, _, _) => tid,
+        _ => panic!("bad message"),
+    };
+    assert_eq!(fs.last_transaction(), tid);
+    assert!(receive.try_recv().is_err());
+
+    // and durable after the next sync:
+    assert_eq!(fs.sync_deferred().unwrap(), 1);
+    match receive.recv().unwrap() {
+        ClientMessage::Durable(dtid) => assert_eq!(dtid, tid),
+        _ => panic!("bad message"),
+    }
+    assert_eq!(fs.sync_deferred().unwrap(), 0);
+    drop(trans);
+
+    // A background thread can do the syncing:
+    let fs = std::sync::Arc::new(fs);
+    byteserver::storage::start_deferred_syncer(&fs);
+    byteserver::storage::testing::add_data(
+        &fs, &client, vec![vec![(p64(0), b"001")]]).unwrap();
+    let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+    trans.save(p64(1), util::Z64, b"111").unwrap();
+    fs.lock(&trans, Box::new(| _ | ())).unwrap();
+    trans.locked().unwrap();
+    fs.stage(&mut trans).unwrap();
+    fs.tpc_finish_deferred(&trans.id, client.clone()).unwrap();
+    let tid = fs.last_transaction();
+    loop {
+        if let ClientMessage::Durable(dtid) = receive.recv().unwrap() {
+            assert_eq!(dtid, tid);
+            break;
+        }
+    }
+}
+
+#[test]
+fn invalidations_since() {
+
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")],
+                    vec![(p64(1), b"111"), (p64(2), b"222")],
+                    vec![(p64(0), b"001"), (p64(1), b"112")]]).unwrap();
+
+    // Invalidations come from the data file, so survive restarts:
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap();
+    let last = fs.last_transaction();
+    let tids = byteserver::scan::TransactionIterator::open(&path).unwrap()
+        .map(| r | r.unwrap().tid()).collect::<Vec<Tid>>();
+
+    assert_eq!(fs.invalidations_since(&last, 10).unwrap(), Some((last, vec![])));
+    assert_eq!(fs.invalidations_since(&tids[1], 10).unwrap(),
+               Some((last, vec![p64(0), p64(1)])));
+    assert_eq!(fs.invalidations_since(&tids[0], 10).unwrap(),
+               Some((last, vec![p64(0), p64(1), p64(2)])));
+    assert_eq!(fs.invalidations_since(&util::Z64, 10).unwrap(),
+               Some((last, vec![p64(0), p64(1), p64(2)])));
+
+    // Too old:
+    assert_eq!(fs.invalidations_since(&tids[0], 1).unwrap(), None);
+    assert_eq!(fs.invalidations_since(&tids[1], 1).unwrap(),
+               Some((last, vec![p64(0), p64(1)])));
+
+    // Transactions committed since the storage was opened are
+    // queued in memory, and the file covers the rest:
+    let (client, _receive) = Client::new("0");
+    byteserver::storage::testing::add_data(
+        &fs, &client, vec![vec![(p64(3), b"333")], vec![(p64(2), b"223")]]).unwrap();
+    let latest = fs.last_transaction();
+    assert_eq!(fs.invalidations_since(&last, 10).unwrap(),
+               Some((latest, vec![p64(2), p64(3)])));
+    assert_eq!(fs.invalidations_since(&last, 1).unwrap(), None);
+    assert_eq!(fs.invalidations_since(&tids[1], 10).unwrap(),
+               Some((latest, vec![p64(0), p64(1), p64(2), p64(3)])));
+
+    // A smaller queue forgets older transactions, which are then
+    // read from the file:
+    fs.set_invalidation_queue_size(1);
+    assert_eq!(fs.invalidation_queue_size(), 1);
+    assert_eq!(fs.invalidations_since(&last, 10).unwrap(),
+               Some((latest, vec![p64(2), p64(3)])));
+    assert_eq!(fs.invalidations_since(&latest, 10).unwrap(), Some((latest, vec![])));
+}
+
+#[test]
+fn snapshot() {
+
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")], vec![(p64(1), b"111")]]).unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap();
+    let tid = fs.last_transaction();
+    let mut snapshot = fs.snapshot().unwrap();
+    assert_eq!(snapshot.tid(), tid);
+    assert_eq!(snapshot.len(), 2);
+
+    // Later commits don't affect the snapshot:
+    let (client, _receive) = Client::new("0");
+    byteserver::storage::testing::add_data(
+        &fs, &client, vec![vec![(p64(0), b"001"), (p64(2), b"222")]]).unwrap();
+    assert!(fs.last_transaction() > tid);
+    assert_eq!(snapshot.tid(), tid);
+    assert_eq!(snapshot.oids().cloned().collect::<Vec<Oid>>(), vec![p64(0), p64(1)]);
+    let (data, dtid) = snapshot.load(&p64(0)).unwrap().unwrap();
+    assert_eq!(data, b"000".to_vec());
+    assert!(dtid < tid);
+    assert_eq!(snapshot.load(&p64(2)).unwrap(), None);
+
+    // Snapshots can be saved, e.g. for backups:
+    let copy = util::test::test_path(&tmpdir, "copy.fs");
+    assert_eq!(snapshot.save(&copy).unwrap(), snapshot.end());
+    let copied = byteserver::storage::FileStorage::<Client>::open(copy).unwrap();
+    assert_eq!(copied.last_transaction(), tid);
+    assert_eq!(copied.checkpoint().unwrap(), 0); // Index was saved too
+    assert_eq!(fs.snapshot().unwrap().len(), 3);
+}
+
+#[test]
+fn backup() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")], vec![(p64(1), b"111")]]).unwrap();
+    let fs = std::sync::Arc::new(
+        byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap());
+    let (client, _receive) = Client::new("0");
+
+    // Backups are consistent, even with commits going on:
+    let committer = {
+        let fs = fs.clone();
+        let client = client.clone();
+        std::thread::spawn(move || {
+            for i in 0 .. 50u64 {
+                byteserver::storage::testing::add_data(
+                    &fs, &client, vec![vec![(p64(i % 3), &i.to_be_bytes())]]).unwrap();
+            }
+        })
+    };
+    let copy = util::test::test_path(&tmpdir, "copy.fs");
+    let tid = fs.backup(&copy).unwrap();
+    committer.join().unwrap();
+    assert!(tid <= fs.last_transaction());
+    assert_eq!(byteserver::fsck::check(&copy).unwrap().problems, vec![]);
+    let copied = byteserver::storage::FileStorage::<Client>::open(copy.clone()).unwrap();
+    assert_eq!(copied.last_transaction(), tid);
+    assert_eq!(copied.checkpoint().unwrap(), 0); // Index was saved too
+    let data = | result | match result {
+        byteserver::storage::LoadBeforeResult::Loaded(data, _, _) => Some(data),
+        _ => None,
+    };
+    for oid in 0 .. 3u64 {
+        assert_eq!(data(copied.load_before(&p64(oid), &[0xff; 8]).unwrap()),
+                   data(fs.load_before(&p64(oid), &byteserver::tid::next(&tid)).unwrap()));
+    }
+
+    // Backups don't overwrite files:
+    assert!(fs.backup(&copy).is_err());
+
+    // A backup after the commits has them all:
+    let copy = util::test::test_path(&tmpdir, "copy2.fs");
+    assert_eq!(fs.backup(&copy).unwrap(), fs.last_transaction());
+    assert_eq!(std::fs::metadata(&copy).unwrap().len(), fs.size());
+}
+
+#[test]
+fn incremental_backup() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")], vec![(p64(1), b"111")]]).unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    let (client, _receive) = Client::new("0");
+    let dir = util::test::test_path(&tmpdir, "backups");
+
+    // The first backup is a full copy:
+    let full = fs.incremental_backup(&dir).unwrap();
+    assert!(full.full());
+    assert_eq!((full.end, full.tid), (fs.size(), fs.last_transaction()));
+
+    // Without commits, there's nothing to add:
+    assert_eq!(fs.incremental_backup(&dir).unwrap(), full);
+
+    // Later backups copy what's been committed since:
+    byteserver::storage::testing::add_data(
+        &fs, &client, vec![vec![(p64(0), b"001")], vec![(p64(2), b"222")]]).unwrap();
+    let delta = fs.incremental_backup(&dir).unwrap();
+    assert!(! delta.full());
+    assert_eq!((delta.start, delta.end, delta.tid),
+               (full.end, fs.size(), fs.last_transaction()));
+    assert_eq!(std::fs::metadata(format!("{}/{}", dir, delta.name)).unwrap().len(),
+               delta.end - delta.start);
+
+    let restore = | name: &str | {
+        let restored = util::test::test_path(&tmpdir, name);
+        assert_eq!(byteserver::backup::restore(&dir, &restored).unwrap().tid,
+                   fs.last_transaction());
+        assert_eq!(std::fs::read(&restored).unwrap(), std::fs::read(&path).unwrap());
+        assert_eq!(byteserver::fsck::check(&restored).unwrap().problems, vec![]);
+    };
+    restore("restored.fs");
+
+    // After a pack, the next backup is a full copy:
+    fs.pack(&fs.last_transaction()).unwrap();
+    byteserver::storage::testing::add_data(&fs, &client, vec![vec![(p64(1), b"112")]])
+        .unwrap();
+    let full = fs.incremental_backup(&dir).unwrap();
+    assert!(full.full());
+    byteserver::storage::testing::add_data(&fs, &client, vec![vec![(p64(1), b"113")]])
+        .unwrap();
+    let delta = fs.incremental_backup(&dir).unwrap();
+    assert_eq!(delta.start, full.end);
+    assert_eq!(byteserver::backup::entries(&dir).unwrap().len(), 4);
+    restore("restored2.fs");
+
+    // Damaged backup files are refused:
+    let delta_path = format!("{}/{}", dir, delta.name);
+    let mut data = std::fs::read(&delta_path).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 1;
+    std::fs::write(&delta_path, data).unwrap();
+    let restored = util::test::test_path(&tmpdir, "restored3.fs");
+    assert_eq!(byteserver::backup::restore(&dir, &restored).unwrap_err().to_string(),
+               format!("Backup file {} is damaged", delta.name));
+}
+
+#[test]
+fn hot_objects() {
+
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000"), (p64(1), b"111"), (p64(2), b"222")]])
+        .unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap();
+    let load = | oid, times | {
+        for _ in 0 .. times {
+            fs.load_before(&p64(oid), byteserver::storage::testing::MAXTID)
+                .unwrap();
+        }
+    };
+
+    // Off by default:
+    load(0, 10);
+    assert_eq!(fs.hot_objects(10), vec![]);
+
+    fs.set_access_sampling(1);
+    load(0, 3);
+    load(1, 5);
+    load(2, 1);
+    assert_eq!(fs.hot_objects(2), vec![(p64(1), 5), (p64(0), 3)]);
+
+    // Counts from sampled loads are scaled up:
+    fs.set_access_sampling(0);
+    fs.set_access_sampling(2);
+    load(1, 10);
+    assert_eq!(fs.hot_objects(10), vec![(p64(1), 10)]);
+}
+
+#[test]
+fn read_view() {
+
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")], vec![(p64(0), b"001")]]).unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap();
+    let tid = fs.last_transaction();
+    let view = fs.read_view(tid);
+    assert_eq!(view.tid(), tid);
+
+    use byteserver::storage::LoadBeforeResult::*;
+    match view.load(&p64(0)).unwrap() {
+        Loaded(data, _, Some(end)) => {
+            assert_eq!(data, b"000".to_vec());
+            assert_eq!(end, tid);
+        },
+        r => panic!("unexpected result {:?}", r),
+    }
+    match view.load_before(&p64(0), byteserver::storage::testing::MAXTID).unwrap() {
+        Loaded(data, _, _) => assert_eq!(data, b"000".to_vec()),
+        r => panic!("unexpected result {:?}", r),
+    }
+    match fs.read_view(byteserver::tid::next(&tid)).load(&p64(0)).unwrap() {
+        Loaded(data, ltid, None) => {
+            assert_eq!(data, b"001".to_vec());
+            assert_eq!(ltid, tid);
+        },
+        r => panic!("unexpected result {:?}", r),
+    }
+    match fs.read_view(util::Z64).load(&p64(0)).unwrap() {
+        NoneBefore => {},
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn limit_warnings() {
+
+    use byteserver::storage::LimitWarning;
+
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")]]).unwrap();
+    let size = std::fs::metadata(&path).unwrap().len();
+
+    let warnings = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let hook_warnings = warnings.clone();
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap()
+        .with_limits(byteserver::storage::Limits {
+            max_size: Some(size * 2), max_clients: Some(4),
+            warning_percent: Some(50), ..Default::default() })
+        .with_warning_hook(Box::new(
+            move | w | hook_warnings.lock().unwrap().push(w.clone())));
+
+    let clients: Vec<Client> = (0 .. 3).map(| i | Client::new(&i.to_string()).0)
+        .collect();
+    fs.try_add_client(clients[0].clone()).unwrap();
+    assert_eq!(*warnings.lock().unwrap(), vec![]);
+    fs.try_add_client(clients[1].clone()).unwrap();
+    fs.try_add_client(clients[2].clone()).unwrap();
+    assert_eq!(*warnings.lock().unwrap(),
+               vec![LimitWarning::Clients { clients: 2, max_clients: 4 }]);
+
+    // Dropping below the threshold rearms the warning:
+    fs.remove_client(clients[2].clone(), byteserver::storage::DisconnectReason::Closed);
+    fs.remove_client(clients[1].clone(), byteserver::storage::DisconnectReason::Closed);
+    fs.try_add_client(clients[1].clone()).unwrap();
+    assert_eq!(warnings.lock().unwrap().len(), 2);
+
+    warnings.lock().unwrap().clear();
+    byteserver::storage::testing::add_data(
+        &fs, &clients[0], vec![vec![(p64(0), b"001")]]).unwrap();
+    let warnings = warnings.lock().unwrap().clone();
+    match warnings[..] {
+        [LimitWarning::Size { size: wsize, max_size }] => {
+            assert!(wsize > size);
+            assert_eq!(max_size, size * 2);
+        },
+        ref w => panic!("unexpected warnings {:?}", w),
+    }
+}
+
+#[test]
+fn disconnect_reasons() {
+
+    use byteserver::storage::DisconnectReason;
+
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap();
+    let (client0, receive0) = Client::new("0");
+    let (client1, receive1) = Client::new("1");
+    let (client2, receive2) = Client::new("2");
+    for client in [&client0, &client1, &client2] {
+        fs.add_client(client.clone());
+    }
+
+    fs.remove_client(client1.clone(), DisconnectReason::Kicked);
+    match receive1.recv().unwrap() {
+        ClientMessage::Closed(reason) => assert_eq!(reason, DisconnectReason::Kicked),
+        _ => panic!("bad message"),
+    }
+    assert_eq!(fs.client_count(), 2);
+
+    // Clients that can't be sent to are removed:
+    drop(receive2);
+    byteserver::storage::testing::add_data(
+        &fs, &client0, vec![vec![(p64(0), b"000")]]).unwrap();
+    assert_eq!(fs.client_count(), 1);
+    receive0.recv().unwrap(); // finished
+
+    fs.remove_clients(DisconnectReason::Shutdown);
+    match receive0.recv().unwrap() {
+        ClientMessage::Closed(reason) => assert_eq!(reason, DisconnectReason::Shutdown),
+        _ => panic!("bad message"),
+    }
+    assert_eq!(fs.client_count(), 0);
+}
+
+struct ChannelSink(std::sync::mpsc::Sender<byteserver::cdc::Commit>);
+
+impl byteserver::cdc::Sink for ChannelSink {
+    fn name(&self) -> String {
+        String::from("channel")
+    }
+    fn commit(&mut self, commit: &byteserver::cdc::Commit) -> Result<()> {
+        self.0.send(commit.clone()).context("")
+    }
+}
+
+#[test]
+fn change_sinks() {
+
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap();
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+
+    let (send, commits) = std::sync::mpsc::channel();
+    let journal = util::test::test_path(&tmpdir, "changes");
+    fs.set_change_sinks(vec![
+        Box::new(byteserver::cdc::Journal::open(&journal).unwrap()),
+        Box::new(ChannelSink(send)),
+    ]);
+    byteserver::storage::testing::add_data(
+        &fs, &client, vec![vec![(p64(0), b"000"), (p64(1), b"111")]]).unwrap();
+
+    // Sinks are called in order, so the journal has been written when
+    // the channel sink gets the commit:
+    let commit = commits.recv().unwrap();
+    assert_eq!(commit.tid, fs.last_transaction());
+    let mut oids = commit.oids.clone();
+    oids.sort();
+    assert_eq!(oids, vec![p64(0), p64(1)]);
+    let line = std::fs::read_to_string(&journal).unwrap();
+    assert!(line.starts_with(&util::hex(&commit.tid)));
+    assert_eq!(line.split_whitespace().count(), 3);
+
+    // Removing sinks stops the thread:
+    fs.set_change_sinks(vec![]);
+    assert!(commits.recv().is_err());
+}
+
+#[test]
+fn historical_loads() {
+
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone())
+        .unwrap();
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+
+    let commit = | data: &'static [u8] | {
+        byteserver::storage::testing::add_data(
+            &fs, &client, vec![vec![(p64(1), data)]]).unwrap();
+        fs.last_transaction()
+    };
+    let tids = vec![commit(b"000"), commit(b"111"), commit(b"222")];
+
+    use byteserver::storage::LoadBeforeResult::*;
+    let check = | tids: &Vec<Tid> | {
+        // Repeated, so later loads use remembered revision chains:
+        for _ in 0 .. 2 {
+            match fs.load_before(&p64(1), &tids[0]).unwrap() {
+                NoneBefore => {},
+                r => panic!("unexpected result {:?}", r),
+            }
+            for (i, tid) in tids.iter().enumerate().skip(1) {
+                match fs.load_before(&p64(1), tid).unwrap() {
+                    Loaded(data, ltid, Some(end)) => {
+                        assert_eq!(data, format!("{}", i - 1).repeat(3).into_bytes());
+                        assert_eq!((ltid, end), (tids[i - 1], *tid));
+                    },
+                    r => panic!("unexpected result {:?}", r),
+                }
+            }
+        }
+    };
+    check(&tids);
+
+    // New revisions don't confuse things:
+    commit(b"333");
+    check(&tids);
+
+    // Revisions can be loaded by serial, too:
+    for (i, tid) in tids.iter().enumerate() {
+        assert_eq!(fs.load_serial(&p64(1), tid).unwrap(),
+                   Some(format!("{}", i).repeat(3).into_bytes()));
+    }
+    assert_eq!(fs.load_serial(&p64(1), &p64(1)).unwrap(), None);
+    assert_eq!(fs.load_serial(&p64(1), &[0xff; 8]).unwrap(), None);
+    assert_eq!(fs.load_serial(&p64(42), &tids[0]).unwrap(), None);
+}
+
+#[test]
+fn exclusive_lock() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+
+    // The lock is on the open file, so a second open fails even in
+    // the same process:
+    match byteserver::storage::FileStorage::<Client>::open(path.clone()) {
+        Err(err) => {
+            assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+            assert!(err.to_string().contains("is locked by another process"));
+        },
+        Ok(_) => panic!("opened a locked file"),
+    }
+
+    // Offline tools that change data files take the same lock:
+    let in_use = | err: anyhow::Error | {
+        assert!(format!("{:#}", err).contains("is locked by another process"), "{:#}", err);
+    };
+    in_use(byteserver::compact::compact(&path).unwrap_err());
+    in_use(byteserver::fsck::repair(&path, &Default::default()).unwrap_err());
+    in_use(byteserver::fsck::rebuild_index(&path).unwrap_err());
+    in_use(byteserver::convert::convert_in_place(&path).unwrap_err());
+
+    drop(fs);
+    assert!(byteserver::fsck::repair(&path, &Default::default()).unwrap().ok());
+    byteserver::storage::FileStorage::<Client>::open(path).unwrap();
+}
+
+#[test]
+fn options() {
+    use byteserver::storage::FileStorage;
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    let tmp_dir = util::test::test_path(&tmpdir, "tmp");
+
+    // Paths are required, and data files aren't always created:
+    assert!(FileStorage::<Client>::options().open().is_err());
+    let err = FileStorage::<Client>::options().path(path.clone()).create(false).open()
+        .err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    assert!(FileStorage::<Client>::options().path(path.clone()).read_only(true).open()
+            .is_err());
+    assert!(! std::path::Path::new(&path).exists());
+
+    let fs: FileStorage<Client> = FileStorage::options()
+        .path(path.clone()).tmp_pool(1).tmp_dir(tmp_dir.clone()).open()
+        .unwrap();
+    assert!(std::path::Path::new(&tmp_dir).is_dir());
+    assert!(! std::path::Path::new(&(path.clone() + ".tmp")).exists());
+    let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+    trans.save(p64(0), Z64, b"000").unwrap();
+    fs.lock(&trans, Box::new(| _ | ())).unwrap();
+    fs.tpc_abort(&trans.id);
+    drop(trans);
+    drop(fs);
+
+    // Read-only storages can be read, but not written:
+    let fs: FileStorage<Client> = FileStorage::options()
+        .path(path.clone()).read_only(true).open().unwrap();
+    assert!(fs.limits().read_only);
+    let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+    trans.save(p64(0), Z64, b"000").unwrap();
+    assert!(fs.lock(&trans, Box::new(| _ | ())).is_err());
+}
+
+#[test]
+fn revision_index() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, (0 .. 5u64).map(| i | vec![(p64(0), &b"00"[..]), (p64(i + 1), &b"11"[..])])
+            .collect()).unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(path).unwrap();
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+    assert_eq!(fs.indexed_revisions(), None);
+    let load = | fs: &byteserver::storage::FileStorage<Client>, tid | {
+        match fs.load_before(&p64(0), &tid).unwrap() {
+            byteserver::storage::LoadBeforeResult::Loaded(data, tid, end) =>
+                Some((data, tid, end)),
+            _ => None,
+        }
+    };
+    let history = | fs: &byteserver::storage::FileStorage<Client> | {
+        let mut loads = vec![];
+        for revision in fs.history(&p64(0), 99).unwrap().unwrap() {
+            loads.push(load(fs, revision.tid));
+            loads.push(load(fs, byteserver::tid::next(&revision.tid)));
+            assert!(fs.load_serial(&p64(0), &revision.tid).unwrap().is_some());
+        }
+        loads
+    };
+    let walked = history(&fs);
+
+    fs.set_revision_index(true).unwrap();
+    assert_eq!(fs.indexed_revisions(), Some(10));
+    assert_eq!(history(&fs), walked);
+    assert!(fs.load_serial(&p64(0), &Z64).unwrap().is_none());
+
+    // Commits and packs keep it up to date:
+    byteserver::storage::testing::add_data(&fs, &client, vec![vec![(p64(0), b"22")]])
+        .unwrap();
+    assert_eq!(fs.indexed_revisions(), Some(11));
+    let walked = history(&fs);
+    fs.set_revision_index(false).unwrap();
+    assert_eq!(history(&fs), walked);
+    fs.set_revision_index(true).unwrap();
+    fs.pack(&fs.last_transaction()).unwrap();
+    assert_eq!(fs.indexed_revisions(), Some(6));
+    assert_eq!(history(&fs).len(), 2);
+}
+
+#[test]
+fn concurrent_loads() {
+    // Loads share a read handle, without sharing file positions.
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    let data: Vec<Vec<u8>> = (0 .. 20u8).map(| i | vec![i; 100 + i as usize]).collect();
+    byteserver::storage::testing::make_sample(
+        &path, vec![(0 .. 20).map(| i | (p64(i), &data[i as usize][..])).collect()]).unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(path).unwrap();
+    std::thread::scope(| scope | {
+        for t in 0 .. 8 {
+            let (fs, data) = (&fs, &data);
+            scope.spawn(move || {
+                for i in 0 .. 200 {
+                    let oid = (t * 7 + i) % 20;
+                    match fs.load_before(&p64(oid), &[0xff; 8]).unwrap() {
+                        byteserver::storage::LoadBeforeResult::Loaded(loaded, _, None) =>
+                            assert_eq!(loaded, data[oid as usize]),
+                        r => panic!("unexpected result {:?}", r),
+                    }
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn mapped_reads() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")], vec![(p64(0), b"111"), (p64(1), b"a")]]).unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(path).unwrap();
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+    assert!(! fs.mapped_reads());
+    fs.set_mapped_reads(true);
+    assert!(fs.mapped_reads());
+    let loads = | fs: &byteserver::storage::FileStorage<Client> | {
+        let mut loads = vec![];
+        for oid in [p64(0), p64(1), p64(2)] {
+            for revision in fs.history(&oid, 9).unwrap().unwrap_or_default() {
+                match fs.load_before(&oid, &byteserver::tid::next(&revision.tid)).unwrap() {
+                    byteserver::storage::LoadBeforeResult::Loaded(data, tid, _) => {
+                        assert_eq!(tid, revision.tid);
+                        assert_eq!(fs.load_serial(&oid, &tid).unwrap().unwrap(), data);
+                        loads.push(data);
+                    },
+                    r => panic!("unexpected result {:?}", r),
+                }
+            }
+        }
+        loads
+    };
+    assert_eq!(loads(&fs), vec![b"111".to_vec(), b"000".to_vec(), b"a".to_vec()]);
+
+    // Records committed after the file was mapped are read too:
+    byteserver::storage::testing::add_data(&fs, &client, vec![vec![(p64(2), b"new")]])
+        .unwrap();
+    assert_eq!(loads(&fs).last().unwrap(), b"new");
+    let record = fs.record_iternext(Some(&p64(2))).unwrap().unwrap();
+    assert_eq!(record.data, b"new");
+
+    // and records moved by packs:
+    fs.pack(&fs.last_transaction()).unwrap();
+    assert_eq!(loads(&fs), vec![b"111".to_vec(), b"a".to_vec(), b"new".to_vec()]);
+}
+
+#[test]
+fn compression() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(&path, vec![vec![(p64(0), b"000")]]).unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+    assert!(! fs.compression());
+    fs.set_compression(true).unwrap();
+    assert!(fs.compression());
+    let text = b"text ".repeat(200);
+    let before = std::fs::metadata(&path).unwrap().len();
+    byteserver::storage::testing::add_data(
+        &fs, &client, vec![vec![(p64(1), &text), (p64(2), b"small")]]).unwrap();
+
+    // Compressible records take less room in the data file:
+    assert!(std::fs::metadata(&path).unwrap().len() - before < text.len() as u64);
+    // but their histories have their sizes:
+    assert_eq!(fs.history(&p64(1), 1).unwrap().unwrap()[0].size, text.len() as u64);
+
+    let check = | fs: &byteserver::storage::FileStorage<Client> | {
+        for (oid, expected) in [(p64(0), &b"000"[..]), (p64(1), &text), (p64(2), b"small")] {
+            let tid = match fs.load_before(&oid, &[0xff; 8]).unwrap() {
+                byteserver::storage::LoadBeforeResult::Loaded(data, tid, _) => {
+                    assert_eq!(data, expected);
+                    tid
+                },
+                r => panic!("unexpected result {:?}", r),
+            };
+            assert_eq!(fs.load_serial(&oid, &tid).unwrap().unwrap(), expected);
+        }
+        match fs.load_before_range(&p64(1), &[0xff; 8], 10, 20).unwrap() {
+            (byteserver::storage::LoadBeforeResult::Loaded(data, _, _), size) => {
+                assert_eq!(data, &text[10 .. 30]);
+                assert_eq!(size, text.len() as u64);
+            },
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(fs.record_iternext(Some(&p64(1))).unwrap().unwrap().data, text);
+    };
+    check(&fs);
+
+    // Compressed records survive reopening and packing:
+    drop(fs);
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    check(&fs);
+    fs.pack(&fs.last_transaction()).unwrap();
+    check(&fs);
+    drop(fs);
+    assert_eq!(byteserver::fsck::check(&path).unwrap().problems, vec![]);
+
+    // Files written before records could be compressed can't have
+    // compressed records:
+    let path = util::test::test_path(&tmpdir, "old.fs");
+    byteserver::storage::testing::make_sample(&path, vec![vec![(p64(0), b"000")]]).unwrap();
+    byteserver::storage::testing::make_version_1(&path).unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    assert!(fs.set_compression(true).is_err());
+    assert!(! fs.compression());
+}
+
+#[test]
+fn record_cache() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    byteserver::storage::testing::make_sample(
+        &path, vec![vec![(p64(0), b"000")], vec![(p64(0), b"111")]]).unwrap();
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    assert_eq!(fs.record_cache_stats(), None);
+    fs.set_record_cache_size(1000);
+    let load = | tid: &Tid | match fs.load_before(&p64(0), tid).unwrap() {
+        byteserver::storage::LoadBeforeResult::Loaded(data, _, _) => data,
+        r => panic!("unexpected result {:?}", r),
+    };
+    let last = fs.history(&p64(0), 9).unwrap().unwrap()[0].tid;
+    assert_eq!(load(&[0xff; 8]), b"111");
+    assert_eq!(load(&last), b"000");
+
+    // Cached records are loaded without reading the data file, so
+    // changing it behind the storage's back goes unnoticed:
+    let mut data = std::fs::read(&path).unwrap();
+    for (old, new) in [(b"000", b"xxx"), (b"111", b"yyy")] {
+        let at = data.windows(3).rposition(| w | w == old).unwrap();
+        data[at .. at + 3].copy_from_slice(new);
+    }
+    std::fs::write(&path, data).unwrap();
+    assert_eq!(load(&[0xff; 8]), b"111");
+    assert_eq!(load(&last), b"000");
+    assert_eq!(fs.load_serial(&p64(0), &last).unwrap(), Some(b"111".to_vec()));
+
+    let stats = fs.record_cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses, stats.size), (3, 2, 6));
+    fs.set_record_cache_size(0);
+
+    // Uncached, the changed data are read, and don't match their
+    // checksums:
+    let err = fs.load_before(&p64(0), &[0xff; 8]).err().unwrap();
+    assert!(matches!(err.downcast_ref::<byteserver::errors::POSError>(),
+                     Some(byteserver::errors::POSError::Corrupted(_))), "{:#}", err);
+}
+
+#[test]
+fn history() {
+    let tmpdir = util::test::dir();
+    let fs = byteserver::storage::FileStorage::<Client>::open(
+        util::test::test_path(&tmpdir, "data.fs")).unwrap();
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+
+    let mut serials = [Z64, Z64];
+    let mut tids = vec![];
+    for i in 0 .. 3u8 {
+        let mut trans = fs.tpc_begin(format!("user{}", i).as_bytes(),
+                                     format!("desc{}", i).as_bytes(), b"{}").unwrap();
+        // Object 1's records are later in their transactions:
+        trans.save(p64(0), serials[0], &[i; 10]).unwrap();
+        trans.save(p64(1), serials[1], &vec![i; 20 + i as usize]).unwrap();
+        fs.lock(&trans, Box::new(| _ | ())).unwrap();
+        trans.locked().unwrap();
+        assert_eq!(fs.stage(&mut trans).unwrap().len(), 0);
+        fs.tpc_finish(&trans.id, client.clone()).unwrap();
+        let tid = fs.last_transaction();
+        serials = [tid, tid];
+        tids.push(tid);
+    }
+
+    let history = fs.history(&p64(1), 2).unwrap().unwrap();
+    assert_eq!(history, vec![
+        byteserver::storage::Revision {
+            tid: tids[2], size: 22, user: b"user2".to_vec(), description: b"desc2".to_vec() },
+        byteserver::storage::Revision {
+            tid: tids[1], size: 21, user: b"user1".to_vec(), description: b"desc1".to_vec() },
+    ]);
+
+    // Asking for more than there are gets them all:
+    let history = fs.history(&p64(0), 10).unwrap().unwrap();
+    assert_eq!(history.iter().map(| r | r.tid).collect::<Vec<_>>(),
+               vec![tids[2], tids[1], tids[0]]);
+    assert_eq!(history[2].user, b"user0".to_vec());
+
+    assert_eq!(fs.history(&p64(0), 0).unwrap().unwrap(), vec![]);
+    assert_eq!(fs.history(&p64(42), 1).unwrap(), None);
+}
+
+#[test]
+fn pack() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    let fs = std::sync::Arc::new(
+        byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap());
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+    let commit = | saves: Vec<(Oid, &[u8])> | {
+        byteserver::storage::testing::add_data(&fs, &client, vec![saves]).unwrap();
+        fs.last_transaction()
+    };
+    commit(vec![(p64(0), b"a0"), (p64(1), b"b0")]);
+    let t2 = commit(vec![(p64(0), b"a1")]);
+    let t3 = commit(vec![(p64(1), b"b1")]);
+
+    // A transaction that's voted when the pack starts:
+    let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+    trans.save(p64(0), t2, b"a2").unwrap();
+    fs.lock(&trans, Box::new(| _ | ())).unwrap();
+    trans.locked().unwrap();
+    assert_eq!(fs.stage(&mut trans).unwrap().len(), 0);
+
+    let packed = fs.pack(&t3).unwrap();
+    assert_eq!((packed.transactions, packed.records), (1, 2));
+    assert!(packed.new_size < packed.old_size);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), packed.new_size);
+    assert!(std::path::Path::new(&(path.clone() + ".old")).exists());
+
+    fs.tpc_finish(&trans.id, client.clone()).unwrap();
+    let history = | oid | fs.history(&p64(oid), 9).unwrap().unwrap().len();
+    assert_eq!((history(0), history(1)), (2, 1));
+    let load = | oid | match fs.load_before(&p64(oid), &[0xff; 8]).unwrap() {
+        byteserver::storage::LoadBeforeResult::Loaded(data, _, _) => data,
+        r => panic!("unexpected result {:?}", r),
+    };
+    assert_eq!((load(0), load(1)), (b"a2".to_vec(), b"b1".to_vec()));
+
+    // Commits can go on during packs:
+    let committer = {
+        let fs = fs.clone();
+        let client = client.clone();
+        std::thread::spawn(move || {
+            for i in 0 .. 50u64 {
+                byteserver::storage::testing::add_data(
+                    &fs, &client, vec![vec![(p64(i % 3), &i.to_be_bytes())]]).unwrap();
+            }
+        })
+    };
+    fs.pack(&fs.last_transaction()).unwrap();
+    committer.join().unwrap();
+    for oid in 0 .. 3u64 {
+        let last = (0 .. 50u64).filter(| i | i % 3 == oid).max().unwrap();
+        assert_eq!(load(oid), last.to_be_bytes().to_vec());
+    }
+
+    // The packed file is consistent, with a good index:
+    drop(std::sync::Arc::try_unwrap(fs).ok().unwrap());
+    assert_eq!(byteserver::fsck::check(&path).unwrap().problems, vec![]);
+    let fs = byteserver::storage::FileStorage::<Client>::open(path).unwrap();
+    for oid in 0 .. 3u64 {
+        let last = (0 .. 50u64).filter(| i | i % 3 == oid).max().unwrap();
+        match fs.load_before(&p64(oid), &[0xff; 8]).unwrap() {
+            byteserver::storage::LoadBeforeResult::Loaded(data, _, _) =>
+                assert_eq!(data, last.to_be_bytes().to_vec()),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn scheduled_pack() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    let fs = std::sync::Arc::new(
+        byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap());
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+    byteserver::storage::testing::add_data(
+        &fs, &client,
+        vec![vec![(p64(0), b"a0")], vec![(p64(0), b"a1")], vec![(p64(0), b"a2")]]).unwrap();
+    let history = || fs.history(&p64(0), 9).unwrap().unwrap().len();
+    assert_eq!(history(), 3);
+
+    // Keeping no history, packs leave only current revisions:
+    byteserver::storage::start_packer(&fs, byteserver::storage::PackSchedule {
+        interval: std::time::Duration::from_millis(50),
+        keep: std::time::Duration::ZERO,
+        rate: Some(1 << 20),
+    });
+    let start = std::time::Instant::now();
+    while history() > 1 {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(std::path::Path::new(&(path + ".old")).exists());
+    match fs.load_before(&p64(0), &[0xff; 8]).unwrap() {
+        byteserver::storage::LoadBeforeResult::Loaded(data, _, _) =>
+            assert_eq!(data, b"a2".to_vec()),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn pack_blobs() {
+    let tmpdir = util::test::dir();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    let blob_dir = util::test::test_path(&tmpdir, "blobs");
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    fs.set_blob_dir(&blob_dir, false).unwrap();
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+    let blobs = fs.blob_dir().unwrap();
+    let upload = | data: &[u8] | {
+        let (file, uploaded) = blobs.temporary().unwrap();
+        std::io::Write::write_all(&mut &file, data).unwrap();
+        uploaded
+    };
+    let commit = | serial: Tid, data: &[u8] | {
+        let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+        trans.save_blob(p64(0), serial, b"record", upload(data)).unwrap();
+        fs.lock(&trans, Box::new(| _ | ())).unwrap();
+        trans.locked().unwrap();
+        fs.stage(&mut trans).unwrap();
+        fs.tpc_finish(&trans.id, client.clone()).unwrap();
+        fs.last_transaction()
+    };
+    let t1 = commit(Z64, b"blob 1");
+    let t2 = commit(t1, b"blob 2");
+    // A blob left by a transaction that didn't commit, and one of a
+    // transaction being committed while packing:
+    blobs.store(&p64(1), &t1, &upload(b"lost")).unwrap();
+    let later = byteserver::tid::next(&t2);
+    let later_path = blobs.store(&p64(1), &later, &upload(b"later")).unwrap();
+
+    let packed = fs.pack(&t2).unwrap();
+    assert_eq!((packed.records, packed.blobs, packed.blob_bytes), (1, 2, 10));
+    assert!(! blobs.blob_path(&p64(0), &t1).exists());
+    assert!(! blobs.blob_path(&p64(1), &t1).exists());
+    assert!(blobs.blob_path(&p64(0), &t2).exists());
+    assert!(later_path.exists());
+
+    // Offline, blobs of no transaction are garbage, and can be
+    // reported without being removed:
+    drop(fs);
+    let garbage = byteserver::pack::collect_blobs(&path, &blob_dir, true).unwrap();
+    assert_eq!(garbage, byteserver::pack::BlobGarbage {
+        files: vec![later_path.clone()], bytes: 5 });
+    assert!(later_path.exists());
+    assert_eq!(byteserver::pack::collect_blobs(&path, &blob_dir, false).unwrap(), garbage);
+    assert!(! later_path.exists());
+    assert_eq!(byteserver::pack::collect_blobs(&path, &blob_dir, true).unwrap().files,
+               Vec::<std::path::PathBuf>::new());
+}
+
+// Data are lists of oids
+struct Oids;
+
+impl byteserver::pack::ReferencesExtractor for Oids {
+    fn references(&self, data: &[u8]) -> Result<Vec<Oid>> {
+        Ok(data.chunks(8).map(| chunk | chunk.try_into().unwrap()).collect())
+    }
+}
+
+#[test]
+fn pack_garbage() {
+    let tmpdir = util::test::dir();
+    let fs = byteserver::storage::FileStorage::<Client>::open(
+        util::test::test_path(&tmpdir, "data.fs")).unwrap();
+    fs.set_references_extractor(Some(std::sync::Arc::new(Oids)));
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+    byteserver::storage::testing::add_data(&fs, &client, vec![
+        vec![(p64(0), &p64(1)), (p64(1), b"")],
+        vec![(p64(0), b"")],
+    ]).unwrap();
+    assert_eq!(fs.len(), 2);
+
+    let packed = fs.pack(&fs.last_transaction()).unwrap();
+    assert_eq!((packed.objects, packed.records), (1, 2));
+    assert_eq!(fs.len(), 1);
+    assert!(matches!(fs.load_before(&p64(1), &[0xff; 8]).unwrap(),
+                     byteserver::storage::LoadBeforeResult::PosKeyError));
+}
+
+#[test]
+fn restore() {
+    let tmpdir = util::test::dir();
+    let source = byteserver::storage::FileStorage::<Client>::open(
+        util::test::test_path(&tmpdir, "source.fs")).unwrap();
+    let path = util::test::test_path(&tmpdir, "data.fs");
+    let fs = byteserver::storage::FileStorage::<Client>::open(path.clone()).unwrap();
+    let (client, _receive) = Client::new("0");
+    source.add_client(client.clone());
+    fs.add_client(client.clone());
+    let commit = | saves: Vec<(Oid, &[u8])> | {
+        byteserver::storage::testing::add_data(&source, &client, vec![saves]).unwrap();
+        source.last_transaction()
+    };
+    let tids = [commit(vec![(p64(0), b"a0"), (p64(1), b"b0")]),
+                    commit(vec![(p64(0), b"a1")]),
+                    commit(vec![(p64(1), b"b1")])];
+
+    // Copy the transactions, with the last as a back-pointer to the
+    // first's revision of object 1, as for an undo:
+    let copy = | tid: Tid, restores: Vec<(Oid, Option<&[u8]>)>, prev_txn: Option<Tid> | {
+        let mut trans = fs.tpc_begin_restore(&tid, b"u", b"d", b"{}").unwrap();
+        for (oid, data) in restores {
+            fs.restore(&mut trans, oid, &tid, data, prev_txn.as_ref()).unwrap();
+        }
+        fs.lock(&trans, Box::new(| _ | ())).unwrap();
+        trans.locked().unwrap();
+        assert_eq!(fs.stage(&mut trans).unwrap().len(), 0);
+        fs.tpc_finish(&trans.id, client.clone()).unwrap();
+    };
+    copy(tids[0], vec![(p64(0), Some(b"a0")), (p64(1), Some(b"b0"))], None);
+    copy(tids[1], vec![(p64(0), Some(b"a1"))], None);
+    copy(tids[2], vec![(p64(1), None)], Some(tids[0]));
+    assert_eq!(fs.last_transaction(), tids[2]);
+
+    let history = | oid | fs.history(&p64(oid), 9).unwrap().unwrap()
+        .iter().map(| r | r.tid).collect::<Vec<_>>();
+    assert_eq!((history(0), history(1)),
+               (vec![tids[1], tids[0]], vec![tids[2], tids[0]]));
+    assert_eq!(fs.load_serial(&p64(1), &tids[2]).unwrap(), Some(b"b0".to_vec()));
+    assert_eq!(fs.load_serial(&p64(0), &tids[0]).unwrap(), Some(b"a0".to_vec()));
+
+    // Transactions can't be restored out of order, and serials must
+    // match the transaction:
+    let mut trans = fs.tpc_begin_restore(&tids[1], b"", b"", b"").unwrap();
+    assert!(fs.restore(&mut trans, p64(0), &tids[0], Some(b"x"), None).is_err());
+    fs.restore(&mut trans, p64(0), &tids[1], Some(b"x"), None).unwrap();
+    fs.lock(&trans, Box::new(| _ | ())).unwrap();
+    trans.locked().unwrap();
+    assert!(fs.stage(&mut trans).is_err());
+    fs.tpc_abort(&trans.id);
+
+    // Later transactions get later tids:
+    byteserver::storage::testing::add_data(&fs, &client, vec![vec![(p64(0), b"a2")]])
+        .unwrap();
+    assert!(fs.last_transaction() > tids[2]);
+
+    drop(fs);
+    assert_eq!(byteserver::fsck::check(&path).unwrap().problems, vec![]);
+}
+
+#[test]
+fn record_iternext() {
+    let tmpdir = util::test::dir();
+    let fs = byteserver::storage::FileStorage::<Client>::open(
+        util::test::test_path(&tmpdir, "data.fs")).unwrap();
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+    assert_eq!(fs.record_iternext(None).unwrap(), None);
+
+    byteserver::storage::testing::add_data(
+        &fs, &client,
+        vec![vec![(p64(2), b"c0"), (p64(0), b"a0"), (p64(5), b"f0")],
+             vec![(p64(2), b"c1")]]).unwrap();
+    let last = fs.last_transaction();
+
+    // Walk the database, getting current records in oid order:
+    let mut records = vec![];
+    let mut next = None;
+    loop {
+        let record = fs.record_iternext(next.as_ref()).unwrap().unwrap();
+        next = record.next;
+        records.push((record.oid, record.tid == last, record.data));
+        if next.is_none() {
+            break;
+        }
+    }
+    assert_eq!(records, vec![(p64(0), false, b"a0".to_vec()),
+                             (p64(2), true, b"c1".to_vec()),
+                             (p64(5), false, b"f0".to_vec())]);
+
+    // Cursors needn't be oids of objects:
+    assert_eq!(fs.record_iternext(Some(&p64(3))).unwrap().unwrap().oid, p64(5));
+    assert_eq!(fs.record_iternext(Some(&p64(6))).unwrap(), None);
+}
+
+#[test]
+fn status() {
+    let tmpdir = util::test::dir();
+    let fs = byteserver::storage::FileStorage::<Client>::open(
+        util::test::test_path(&tmpdir, "data.fs")).unwrap();
+    let (client, _receive) = Client::new("0");
+    fs.add_client(client.clone());
+    byteserver::storage::testing::add_data(
+        &fs, &client, vec![vec![(p64(0), b"a")], vec![(p64(0), b"b")]]).unwrap();
+    let last = fs.last_transaction();
+
+    // A conflict, and a transaction waiting for another's locks:
+    let mut trans = fs.tpc_begin(b"", b"", b"").unwrap();
+    trans.save(p64(0), Z64, b"c").unwrap();
+    fs.lock(&trans, Box::new(| _ | ())).unwrap();
+    trans.locked().unwrap();
+    assert_eq!(fs.stage(&mut trans).unwrap().len(), 1);
+    let mut voted = fs.tpc_begin(b"", b"", b"").unwrap();
+    voted.save(p64(0), last, b"c").unwrap();
+    fs.lock(&voted, Box::new(| _ | ())).unwrap();
+    voted.locked().unwrap();
+    assert_eq!(fs.stage(&mut voted).unwrap().len(), 0);
+    let mut waiting = fs.tpc_begin(b"", b"", b"").unwrap();
+    waiting.save(p64(0), last, b"d").unwrap();
+    fs.lock(&waiting, Box::new(| _ | panic!("shouldn't get locks"))).unwrap();
+
+    assert_eq!(fs.status(), byteserver::storage::Status {
+        commits: 2, aborts: 0, conflicts: 1, lock_waits: 1, clients: 1, voted: 1,
+        last_tid: last,
+    });
+
+    fs.tpc_abort(&trans.id);
+    fs.tpc_abort(&waiting.id);
+    fs.tpc_finish(&voted.id, client.clone()).unwrap();
+    let status = fs.status();
+    assert_eq!((status.commits, status.aborts, status.lock_waits, status.voted),
+               (3, 2, 0, 0));
+    assert_eq!(status.last_tid, fs.last_transaction());
+}
@@ -40,7 +40,7 @@ fn basic() {
     assert_eq!(&reader.next_vec().unwrap(), b"M5");
 
     // Lets write some data:
-    tx.send(msg::Zeo::TpcBegin(42, b"u".to_vec(), b"d".to_vec(), b"{}".to_vec()))
+    tx.send(msg::Zeo::TpcBegin(42, b"u".to_vec(), b"d".to_vec(), b"{}".to_vec(), None))
         .unwrap();
     tx.send(msg::Zeo::Storea(util::p64(1), util::Z64, b"ooo".to_vec(), 42)).unwrap();
     tx.send(msg::Zeo::Vote(11, 42)).unwrap();
@@ -54,13 +54,20 @@ fn basic() {
     // There weren't any:
     assert_eq!(conflicts.len(), 0);
 
-    // And we finish, getting back a tid and info:
+    // And we finish, getting back new serials, a tid and info:
     tx.send(msg::Zeo::TpcFinish(12, 42)).unwrap();
+    let (msgid, method, (serials,)): (i64, String, (Vec<(ByteBuf, ByteBuf)>,)) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding serialnos").unwrap();
+    assert_eq!((msgid, &method as &str), (0, "serialnos"));
+    assert_eq!(serials.len(), 1);
+    assert_eq!(&*serials[0].0, &util::p64(1));
     let (msgid, flag, tid): (i64, String, ByteBuf) =
         decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
                 "decoding finish response").unwrap();
     assert_eq!((msgid, &flag as &str), (12, "R"));
     assert_eq!(tid.len(), 8);
+    assert_eq!(serials[0].1, tid);
     let (msgid, method, (info,)): (i64, String, (BTreeMap<String, u64>,)) =
         decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
                 "decoding info").unwrap();
@@ -68,7 +75,7 @@ fn basic() {
     assert_eq!(info, {
         let mut map = BTreeMap::new();
         map.insert("length".to_string(), 2);
-        map.insert("size".to_string(), 4337);
+        map.insert("size".to_string(), 4373);
         map
     });
     
@@ -93,3 +100,473 @@ fn basic() {
     assert!(itid > tid);
     assert_eq!(oids, vec![ByteBuf::from(util::p64(3).to_vec())]);
 }
+
+#[test]
+fn storage_errors_are_reported() {
+    let (reader, writer) = pipe::pipe();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    let fs = std::sync::Arc::new(
+        storage::FileStorage::<writer::Client>::open(path).unwrap()
+            .with_limits(storage::Limits { read_only: true, ..Default::default() }));
+
+    let client = writer::Client::new("test".to_string(), tx.clone());
+    let connection = client.connection();
+    let write_fs = fs.clone();
+    std::thread::spawn(
+        move || writer::writer(write_fs, writer, rx, client).unwrap());
+
+    let mut reader = msg::ZeoIter::new(reader);
+    assert_eq!(&reader.next_vec().unwrap(), b"M5");
+
+    tx.send(msg::Zeo::TpcBegin(42, b"u".to_vec(), b"d".to_vec(), b"{}".to_vec(), None))
+        .unwrap();
+    tx.send(msg::Zeo::Storea(util::p64(1), util::Z64, b"ooo".to_vec(), 42)).unwrap();
+    tx.send(msg::Zeo::Vote(11, 42)).unwrap();
+
+    // The vote fails, but the connection is still usable:
+    // Its last argument is a correlation id for matching server logs.
+    let (msgid, flag, (name, (cid,))): (i64, String, (String, (String,))) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding vote error").unwrap();
+    assert_eq!((msgid, &flag as &str, &name as &str),
+               (11, "E", "ZODB.POSException.ReadOnlyError"));
+    assert_eq!(cid, writer::correlation_id(connection, 11));
+
+    tx.send(msg::Zeo::TpcAbort(12, 42)).unwrap();
+    let (msgid, flag, _): (i64, String, ()) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding abort response").unwrap();
+    assert_eq!((msgid, &flag as &str), (12, "R"));
+
+    // Voting on an unknown transaction is a StorageTransactionError:
+    tx.send(msg::Zeo::Vote(13, 42)).unwrap();
+    let (msgid, flag, (name, (message, _))): (i64, String, (String, (String, String))) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding vote error").unwrap();
+    assert_eq!((msgid, &flag as &str, &name as &str, &message as &str),
+               (13, "E", "ZODB.POSException.StorageTransactionError",
+                "Invalid transaction 42"));
+}
+
+#[test]
+fn full_storages_are_reported() {
+    let (reader, writer) = pipe::pipe();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    let fs = std::sync::Arc::new(
+        storage::FileStorage::<writer::Client>::open(path).unwrap()
+            .with_limits(storage::Limits { max_size: Some(1), ..Default::default() }));
+
+    let client = writer::Client::new("test".to_string(), tx.clone());
+    let write_fs = fs.clone();
+    std::thread::spawn(
+        move || writer::writer(write_fs, writer, rx, client).unwrap());
+
+    let mut reader = msg::ZeoIter::new(reader);
+    assert_eq!(&reader.next_vec().unwrap(), b"M5");
+
+    // Transactions can't begin, and votes get the StorageError saying
+    // why:
+    tx.send(msg::Zeo::TpcBegin(42, b"u".to_vec(), b"d".to_vec(), b"{}".to_vec(), None))
+        .unwrap();
+    tx.send(msg::Zeo::Storea(util::p64(1), util::Z64, b"ooo".to_vec(), 42)).unwrap();
+    tx.send(msg::Zeo::Vote(11, 42)).unwrap();
+    let (msgid, flag, (name, (message, _))): (i64, String, (String, (String, String))) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding vote error").unwrap();
+    assert_eq!((msgid, &flag as &str, &name as &str, &message as &str),
+               (11, "E", "ZODB.POSException.StorageError", "Storage is full (1 bytes)"));
+}
+
+#[test]
+fn deferred_fsync() {
+    let (reader, writer) = pipe::pipe();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    let fs = std::sync::Arc::new(
+        storage::FileStorage::<writer::Client>::open(path).unwrap());
+
+    let client = writer::Client::new("test".to_string(), tx.clone());
+    fs.add_client(client.clone());
+    let write_fs = fs.clone();
+    std::thread::spawn(
+        move || writer::writer(write_fs, writer, rx, client).unwrap());
+
+    let mut reader = msg::ZeoIter::new(reader);
+    assert_eq!(&reader.next_vec().unwrap(), b"M5");
+
+    tx.send(msg::Zeo::DeferFsync(10, true)).unwrap();
+    let (msgid, flag, _): (i64, String, ()) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding defer_fsync response").unwrap();
+    assert_eq!((msgid, &flag as &str), (10, "R"));
+
+    tx.send(msg::Zeo::TpcBegin(42, b"u".to_vec(), b"d".to_vec(), b"{}".to_vec(), None))
+        .unwrap();
+    tx.send(msg::Zeo::Storea(util::p64(1), util::Z64, b"ooo".to_vec(), 42)).unwrap();
+    tx.send(msg::Zeo::Vote(11, 42)).unwrap();
+    reader.next_vec().unwrap(); // conflicts
+    tx.send(msg::Zeo::TpcFinish(12, 42)).unwrap();
+    reader.next_vec().unwrap(); // serialnos
+    let (msgid, flag, tid): (i64, String, ByteBuf) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding finish response").unwrap();
+    assert_eq!((msgid, &flag as &str), (12, "R"));
+    reader.next_vec().unwrap(); // info
+
+    // The client is told when the transaction is on disk:
+    assert_eq!(fs.sync_deferred().unwrap(), 1);
+    let (msgid, method, (dtid,)): (i64, String, (ByteBuf,)) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding durable").unwrap();
+    assert_eq!((msgid, &method as &str), (0, "durable"));
+    assert_eq!(dtid, tid);
+}
+
+#[test]
+fn close() {
+    use byteserver::storage::Client;
+
+    let (reader, writer) = pipe::pipe();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    let fs = std::sync::Arc::new(
+        storage::FileStorage::<writer::Client>::open(path).unwrap());
+    let client = writer::Client::new("test".to_string(), tx.clone());
+    let write_fs = fs.clone();
+    let write_client = client.clone();
+    let thread = std::thread::spawn(
+        move || writer::writer(write_fs, writer, rx, write_client).unwrap());
+
+    let mut reader = msg::ZeoIter::new(reader);
+    assert_eq!(&reader.next_vec().unwrap(), b"M5");
+
+    // Clients are told why they're disconnected, and the writer stops:
+    client.close(&storage::DisconnectReason::Shutdown);
+    let (msgid, method, (reason,)): (i64, String, (String,)) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding disconnected").unwrap();
+    assert_eq!((msgid, &method as &str, &reason as &str),
+               (0, "disconnected", "server shutting down"));
+    thread.join().unwrap();
+}
+
+#[test]
+fn read_only_connections() {
+    let (reader, writer) = pipe::pipe();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    let fs = std::sync::Arc::new(
+        storage::FileStorage::<writer::Client>::open(path).unwrap());
+    let last = fs.last_transaction();
+
+    let client = writer::Client::new("test".to_string(), tx.clone())
+        .with_read_only(true);
+    let write_fs = fs.clone();
+    std::thread::spawn(
+        move || writer::writer(write_fs, writer, rx, client).unwrap());
+
+    let mut reader = msg::ZeoIter::new(reader);
+    assert_eq!(&reader.next_vec().unwrap(), b"M5");
+
+    tx.send(msg::Zeo::TpcBegin(42, b"u".to_vec(), b"d".to_vec(), b"{}".to_vec(), None))
+        .unwrap();
+    tx.send(msg::Zeo::Storea(util::p64(1), util::Z64, b"ooo".to_vec(), 42)).unwrap();
+    tx.send(msg::Zeo::Vote(11, 42)).unwrap();
+    let (msgid, flag, (name, _)): (i64, String, (String, (String,))) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding vote error").unwrap();
+    assert_eq!((msgid, &flag as &str, &name as &str),
+               (11, "E", "ZODB.POSException.ReadOnlyError"));
+
+    // Nothing was committed:
+    tx.send(msg::Zeo::TpcFinish(12, 42)).unwrap();
+    let (msgid, flag, (name, _)): (i64, String, (String, (String, String))) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding finish error").unwrap();
+    assert_eq!((msgid, &flag as &str, &name as &str),
+               (12, "E", "ZODB.POSException.StorageTransactionError"));
+    assert_eq!(fs.last_transaction(), last);
+
+    // Nor can they make backups:
+    let copy = byteserver::util::test::test_path(&tdir, "copy.fs");
+    tx.send(msg::Zeo::Backup(13, copy.clone())).unwrap();
+    let (msgid, flag, (name, _)): (i64, String, (String, (String,))) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding backup error").unwrap();
+    assert_eq!((msgid, &flag as &str, &name as &str),
+               (13, "E", "ZODB.POSException.ReadOnlyError"));
+    assert!(! std::path::Path::new(&copy).exists());
+}
+
+#[test]
+fn backup() {
+    let (reader, writer) = pipe::pipe();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    storage::testing::make_sample(&path, vec![vec![(util::p64(0), b"000")]]).unwrap();
+    let fs = std::sync::Arc::new(
+        storage::FileStorage::<writer::Client>::open(path).unwrap());
+
+    let client = writer::Client::new("test".to_string(), tx.clone());
+    let write_fs = fs.clone();
+    std::thread::spawn(
+        move || writer::writer(write_fs, writer, rx, client).unwrap());
+
+    let mut reader = msg::ZeoIter::new(reader);
+    assert_eq!(&reader.next_vec().unwrap(), b"M5");
+
+    let error = | response: Vec<u8>, id: i64 | {
+        let (msgid, flag, (name, _)): (i64, String, (String, (String, String))) =
+            decode!(&mut (&response as &[u8]), "decoding backup error").unwrap();
+        assert_eq!((msgid, &flag as &str, &name as &str),
+                   (id, "E", "ZODB.POSException.StorageError"));
+    };
+
+    // Storages without backup directories can't be backed up by
+    // clients:
+    tx.send(msg::Zeo::Backup(9, "copy.fs".to_string())).unwrap();
+    error(reader.next_vec().unwrap(), 9);
+    let backups = byteserver::util::test::test_path(&tdir, "backup-dir");
+    fs.set_backup_dir(&backups).unwrap();
+
+    // Backups respond with the last transaction copied:
+    tx.send(msg::Zeo::Backup(10, "copy.fs".to_string())).unwrap();
+    let (msgid, flag, tid): (i64, String, ByteBuf) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding backup response").unwrap();
+    assert_eq!((msgid, &flag as &str), (10, "R"));
+    assert_eq!(&*tid, &fs.last_transaction());
+    let copy = format!("{}/copy.fs", backups);
+    assert_eq!(std::fs::metadata(&copy).unwrap().len(), fs.size());
+
+    // Existing files aren't overwritten:
+    tx.send(msg::Zeo::Backup(11, "copy.fs".to_string())).unwrap();
+    error(reader.next_vec().unwrap(), 11);
+
+    // Paths outside the backup directory are refused:
+    let outside = byteserver::util::test::test_path(&tdir, "outside.fs");
+    for path in [outside.clone(), "../outside.fs".to_string(), "a/../../outside.fs".to_string()] {
+        tx.send(msg::Zeo::Backup(12, path)).unwrap();
+        error(reader.next_vec().unwrap(), 12);
+    }
+    assert!(! std::path::Path::new(&outside).exists());
+
+    // Incremental backups respond with the last transaction backed up:
+    tx.send(msg::Zeo::IncrementalBackup(13, "incremental".to_string())).unwrap();
+    let (msgid, flag, tid): (i64, String, ByteBuf) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding incremental_backup response").unwrap();
+    assert_eq!((msgid, &flag as &str), (13, "R"));
+    assert_eq!(&*tid, &fs.last_transaction());
+    assert_eq!(byteserver::backup::entries(&format!("{}/incremental", backups))
+               .unwrap().len(), 1);
+
+    // and are confined to the backup directory too, so directories
+    // aren't created elsewhere:
+    let outside = byteserver::util::test::test_path(&tdir, "outside");
+    for dir in [outside.clone(), "../outside".to_string()] {
+        tx.send(msg::Zeo::IncrementalBackup(14, dir)).unwrap();
+        error(reader.next_vec().unwrap(), 14);
+    }
+    assert!(! std::path::Path::new(&outside).exists());
+}
+
+#[test]
+fn chunked_stores() {
+    let (reader, writer) = pipe::pipe();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    let fs = std::sync::Arc::new(
+        storage::FileStorage::<writer::Client>::open(path).unwrap());
+
+    let client = writer::Client::new("test".to_string(), tx.clone());
+    fs.add_client(client.clone());
+    let write_fs = fs.clone();
+    std::thread::spawn(
+        move || writer::writer(write_fs, writer, rx, client).unwrap());
+
+    let mut reader = msg::ZeoIter::new(reader);
+    assert_eq!(&reader.next_vec().unwrap(), b"M5");
+
+    // A record's data can be sent in pieces:
+    tx.send(msg::Zeo::TpcBegin(42, b"u".to_vec(), b"d".to_vec(), b"{}".to_vec(), None))
+        .unwrap();
+    tx.send(msg::Zeo::StoreaStart(util::p64(1), util::Z64, 6, 42)).unwrap();
+    tx.send(msg::Zeo::StoreaChunk(b"abc".to_vec(), 42)).unwrap();
+    tx.send(msg::Zeo::StoreaChunk(b"def".to_vec(), 42)).unwrap();
+    tx.send(msg::Zeo::Storea(util::p64(2), util::Z64, b"ooo".to_vec(), 42)).unwrap();
+    tx.send(msg::Zeo::Vote(11, 42)).unwrap();
+    let (msgid, flag, conflicts): (i64, String, Vec<BTreeMap<String, ByteBuf>>) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding conflicts").unwrap();
+    assert_eq!((msgid, &flag as &str, conflicts.len()), (11, "R", 0));
+    tx.send(msg::Zeo::TpcFinish(12, 42)).unwrap();
+    reader.next_vec().unwrap(); // serialnos
+    reader.next_vec().unwrap(); // finish response
+    reader.next_vec().unwrap(); // info
+    match fs.load_before(&util::p64(1), storage::testing::MAXTID).unwrap() {
+        storage::LoadBeforeResult::Loaded(data, _, _) => assert_eq!(&data, b"abcdef"),
+        _ => panic!("Couldn't load"),
+    }
+
+    // Votes fail if a record's data is incomplete:
+    tx.send(msg::Zeo::TpcBegin(43, b"u".to_vec(), b"d".to_vec(), b"{}".to_vec(), None))
+        .unwrap();
+    tx.send(msg::Zeo::StoreaStart(util::p64(1), util::Z64, 6, 43)).unwrap();
+    tx.send(msg::Zeo::StoreaChunk(b"abc".to_vec(), 43)).unwrap();
+    tx.send(msg::Zeo::Vote(13, 43)).unwrap();
+    let (msgid, flag, (name, (message, _))): (i64, String, (String, (String, String))) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding vote error").unwrap();
+    assert_eq!((msgid, &flag as &str, &name as &str, &message as &str),
+               (13, "E", "ZODB.POSException.StorageError",
+                "A record's data is incomplete"));
+}
+
+#[test]
+fn blobs() {
+    let (reader, writer) = pipe::pipe();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    let blob_dir = byteserver::util::test::test_path(&tdir, "blobs");
+    let fs = std::sync::Arc::new(
+        storage::FileStorage::<writer::Client>::open(path).unwrap());
+    fs.set_blob_dir(&blob_dir, false).unwrap();
+
+    let client = writer::Client::new("test".to_string(), tx.clone());
+    fs.add_client(client.clone());
+    let write_fs = fs.clone();
+    std::thread::spawn(
+        move || writer::writer(write_fs, writer, rx, client).unwrap());
+
+    let mut reader = msg::ZeoIter::new(reader);
+    assert_eq!(&reader.next_vec().unwrap(), b"M5");
+
+    let tmp = std::path::Path::new(&blob_dir).join("tmp");
+    let uploads = || std::fs::read_dir(&tmp).unwrap().count();
+
+    // Blobs are uploaded in chunks, and stored with the object's
+    // data when the transaction is voted:
+    tx.send(msg::Zeo::TpcBegin(42, b"u".to_vec(), b"d".to_vec(), b"{}".to_vec(), None))
+        .unwrap();
+    tx.send(msg::Zeo::StoreBlobStart).unwrap();
+    tx.send(msg::Zeo::StoreBlobChunk(b"blob ".to_vec())).unwrap();
+    tx.send(msg::Zeo::StoreBlobChunk(b"data".to_vec())).unwrap();
+    tx.send(msg::Zeo::StoreBlobEnd(util::p64(1), util::Z64, b"ooo".to_vec(), 42)).unwrap();
+    tx.send(msg::Zeo::Vote(11, 42)).unwrap();
+    reader.next_vec().unwrap(); // conflicts
+    assert_eq!(uploads(), 0);
+    tx.send(msg::Zeo::TpcFinish(12, 42)).unwrap();
+    reader.next_vec().unwrap(); // serialnos
+    let (_, _, tid): (i64, String, ByteBuf) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding finish response").unwrap();
+    reader.next_vec().unwrap(); // info
+    let tid = util::read8(&mut &*tid).unwrap();
+    let mut blob = String::new();
+    std::io::Read::read_to_string(
+        &mut fs.load_blob(&util::p64(1), &tid).unwrap(), &mut blob).unwrap();
+    assert_eq!(blob, "blob data");
+    assert!(fs.blob_dir().unwrap().blob_path(&util::p64(1), &tid).exists());
+
+    // Uploads of aborted transactions are removed:
+    tx.send(msg::Zeo::TpcBegin(43, b"u".to_vec(), b"d".to_vec(), b"{}".to_vec(), None))
+        .unwrap();
+    tx.send(msg::Zeo::StoreBlobStart).unwrap();
+    tx.send(msg::Zeo::StoreBlobChunk(b"more".to_vec())).unwrap();
+    tx.send(msg::Zeo::StoreBlobEnd(util::p64(2), util::Z64, b"ooo".to_vec(), 43)).unwrap();
+    tx.send(msg::Zeo::TpcAbort(13, 43)).unwrap();
+    reader.next_vec().unwrap(); // abort response
+    assert_eq!(uploads(), 0);
+
+    // Clients can't store blobs they wrote themselves, since the blob
+    // directory isn't shared:
+    tx.send(msg::Zeo::TpcBegin(44, b"u".to_vec(), b"d".to_vec(), b"{}".to_vec(), None))
+        .unwrap();
+    tx.send(msg::Zeo::StoreBlobShared(
+        util::p64(2), util::Z64, b"ooo".to_vec(), "x.tmp".to_string(), 44)).unwrap();
+    tx.send(msg::Zeo::Vote(14, 44)).unwrap();
+    let (msgid, flag, (name, (message, _))): (i64, String, (String, (String, String))) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding vote error").unwrap();
+    assert_eq!((msgid, &flag as &str, &name as &str, &message as &str),
+               (14, "E", "ZODB.POSException.StorageError",
+                "The blob directory isn't shared"));
+}
+
+#[test]
+fn shared_blobs() {
+    let (reader, writer) = pipe::pipe();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let tdir = byteserver::util::test::dir();
+    let path = byteserver::util::test::test_path(&tdir, "data.fs");
+    let blob_dir = byteserver::util::test::test_path(&tdir, "blobs");
+    let fs = std::sync::Arc::new(
+        storage::FileStorage::<writer::Client>::open(path).unwrap());
+    fs.set_blob_dir(&blob_dir, true).unwrap();
+
+    let client = writer::Client::new("test".to_string(), tx.clone());
+    fs.add_client(client.clone());
+    let write_fs = fs.clone();
+    std::thread::spawn(
+        move || writer::writer(write_fs, writer, rx, client).unwrap());
+
+    let mut reader = msg::ZeoIter::new(reader);
+    assert_eq!(&reader.next_vec().unwrap(), b"M5");
+
+    // The client writes the blob to the object's directory and sends
+    // its name, and the server renames it when the transaction is
+    // voted:
+    let oid_path = fs.blob_dir().unwrap().oid_path(&util::p64(1));
+    std::fs::create_dir_all(&oid_path).unwrap();
+    let written = oid_path.join("abc.tmp");
+    std::fs::write(&written, b"shared").unwrap();
+    tx.send(msg::Zeo::TpcBegin(42, b"u".to_vec(), b"d".to_vec(), b"{}".to_vec(), None))
+        .unwrap();
+    tx.send(msg::Zeo::StoreBlobShared(
+        util::p64(1), util::Z64, b"ooo".to_vec(), "abc.tmp".to_string(), 42)).unwrap();
+    tx.send(msg::Zeo::Vote(11, 42)).unwrap();
+    reader.next_vec().unwrap(); // conflicts
+    tx.send(msg::Zeo::TpcFinish(12, 42)).unwrap();
+    reader.next_vec().unwrap(); // serialnos
+    let (_, _, tid): (i64, String, ByteBuf) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding finish response").unwrap();
+    reader.next_vec().unwrap(); // info
+    let stored = fs.blob_dir().unwrap().blob_path(
+        &util::p64(1), &util::read8(&mut &*tid).unwrap());
+    assert!(! written.exists());
+    assert_eq!(std::fs::read(&stored).unwrap(), b"shared");
+    assert!(std::fs::metadata(&stored).unwrap().permissions().readonly());
+
+    // Files must be named as ZEO clients name them:
+    tx.send(msg::Zeo::TpcBegin(43, b"u".to_vec(), b"d".to_vec(), b"{}".to_vec(), None))
+        .unwrap();
+    tx.send(msg::Zeo::StoreBlobShared(
+        util::p64(2), util::Z64, b"ooo".to_vec(), "../x.blob".to_string(), 43)).unwrap();
+    tx.send(msg::Zeo::Vote(13, 43)).unwrap();
+    let (msgid, flag, (name, (message, _))): (i64, String, (String, (String, String))) =
+        decode!(&mut (&reader.next_vec().unwrap() as &[u8]),
+                "decoding vote error").unwrap();
+    assert_eq!((msgid, &flag as &str, &name as &str, &message as &str),
+               (13, "E", "ZODB.POSException.StorageError",
+                "Invalid blob file name ../x.blob"));
+}